@@ -173,6 +173,7 @@ extension EntryGenerator: WebSocketDelegate {
                 if let receivedEntry = parseReceivedEntry(from: message) {
                     self.lastReceivedMessage.append(receivedEntry)
                     self.saveServerEntries()
+                    acknowledge(message, client: client)
                 }
             }
             self.isServerConnected = self.wantToBeConnected
@@ -226,10 +227,18 @@ extension EntryGenerator: WebSocketDelegate {
     }
 
     
+    // the last part of every message from the server is its id, which has to be acknowledged
+    private func acknowledge(_ message: String, client: WebSocket) {
+        guard let id = message.components(separatedBy: "#").last else {
+            return
+        }
+        client.write(string: "ACK#" + useridUUID + "#" + id)
+    }
+
     private func parseReceivedEntry(from message: String) -> ServerEntry? {
             var components = message.components(separatedBy: "#")
             components.removeFirst()
-            guard components.count == 2,
+            guard components.count == 4,
                   let timestamp = Double(components[0]),
                   let measuredNumber = Float(components[1]) else {
                     print("whyyyyyyyyyyy")
@@ -0,0 +1,39 @@
+use rand::Rng;
+use std::time::Duration;
+
+// delays between reconnects, doubling from `initial` up to `max`. a share of every delay is
+// random, so devices losing the server at the same time don't all come back at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    // 0 waits the full delay, 1 anything between none and the full delay
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    // the delay before the given reconnect attempt, counted from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with(attempt, rand::thread_rng().gen())
+    }
+
+    // the delay with `random` from 0 to 1 taking the place of the random share
+    pub fn delay_with(&self, attempt: u32, random: f64) -> Duration {
+        let full = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt.min(31)))
+            .min(self.max);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random.clamp(0.0, 1.0);
+        full.mul_f64(1.0 - jitter)
+    }
+}
@@ -0,0 +1,402 @@
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot, watch},
+    time,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
+
+use crate::{Avg, Backoff, Cmd, Error, ServerError};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Callback<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+// seconds the server has to answer CONN
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+// seconds the server gets to close the connection after DISCONN
+const DISCONN_TIMEOUT_SECS: u64 = 5;
+// times a message is written before it is given up, if the builder doesn't say otherwise
+const SEND_ATTEMPTS: u32 = 3;
+
+struct Settings {
+    url: String,
+    uid: String,
+    options: Option<String>,
+    backoff: Backoff,
+    send_attempts: u32,
+    on_avg: Option<Callback<Avg>>,
+    on_cmd: Option<Callback<Cmd>>,
+    on_error: Option<Callback<ServerError>>,
+}
+
+// configures a DeviceClient, nothing connects before start
+pub struct Builder {
+    settings: Settings,
+}
+
+impl Builder {
+    // options appended to CONN, e.g. "interval=5"
+    pub fn options(mut self, options: &str) -> Self {
+        self.settings.options = Some(options.to_string());
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.settings.backoff = backoff;
+        self
+    }
+
+    // times a message is written, across reconnects, before send gives up
+    pub fn send_attempts(mut self, attempts: u32) -> Self {
+        self.settings.send_attempts = attempts.max(1);
+        self
+    }
+
+    // called for every AVG before it is acknowledged
+    pub fn on_avg(mut self, callback: impl Fn(&Avg) + Send + Sync + 'static) -> Self {
+        self.settings.on_avg = Some(Box::new(callback));
+        self
+    }
+
+    // called for every CMD before it is acknowledged, so the command is carried out first
+    pub fn on_cmd(mut self, callback: impl Fn(&Cmd) + Send + Sync + 'static) -> Self {
+        self.settings.on_cmd = Some(Box::new(callback));
+        self
+    }
+
+    // called for every ERR of the server, including the ones rejecting CONN
+    pub fn on_error(mut self, callback: impl Fn(&ServerError) + Send + Sync + 'static) -> Self {
+        self.settings.on_error = Some(Box::new(callback));
+        self
+    }
+
+    // connects in the background and keeps reconnecting until the client is closed
+    pub fn start(self) -> DeviceClient {
+        let settings = Arc::new(self.settings);
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let (session_tx, session_rx) = watch::channel(None);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        tokio::spawn(run(settings.clone(), outgoing_rx, session_tx, stop_rx));
+        DeviceClient {
+            settings,
+            outgoing: outgoing_tx,
+            session: session_rx,
+            stop: stop_tx,
+        }
+    }
+}
+
+// a message waiting to be written and the sender waiting for it
+struct Outgoing {
+    msg: String,
+    attempts: u32,
+    done: oneshot::Sender<Result<(), Error>>,
+}
+
+// a device connected to a fog or cloud server. it sends CONN on every connection,
+// reconnects with backoff when the connection is lost and acknowledges AVG and CMD
+// deliveries after passing them to the callbacks
+pub struct DeviceClient {
+    settings: Arc<Settings>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    session: watch::Receiver<Option<String>>,
+    stop: watch::Sender<bool>,
+}
+
+impl DeviceClient {
+    pub fn builder(url: &str, uid: &str) -> Builder {
+        Builder {
+            settings: Settings {
+                url: url.to_string(),
+                uid: uid.to_string(),
+                options: None,
+                backoff: Backoff::default(),
+                send_attempts: SEND_ATTEMPTS,
+                on_avg: None,
+                on_cmd: None,
+                on_error: None,
+            },
+        }
+    }
+
+    pub fn uid(&self) -> &str {
+        &self.settings.uid
+    }
+
+    // the id of the current session, None while disconnected
+    pub fn session(&self) -> Option<String> {
+        self.session.borrow().clone()
+    }
+
+    // waits for a session and returns its id
+    pub async fn connected(&self) -> Result<String, Error> {
+        let mut session = self.session.clone();
+        let id = session
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Error::Closed)?;
+        Ok(id.clone().unwrap_or_default())
+    }
+
+    // resolves once the message was written to the server. messages sent while disconnected
+    // wait for the next connection, a failed write is retried on it
+    pub async fn send(&self, msg: &str) -> Result<(), Error> {
+        let (done, result) = oneshot::channel();
+        let outgoing = Outgoing {
+            msg: msg.to_string(),
+            attempts: 0,
+            done,
+        };
+        self.outgoing.send(outgoing).map_err(|_| Error::Closed)?;
+        result.await.unwrap_or(Err(Error::Closed))
+    }
+
+    // sends a reading of the channel taken now
+    pub async fn reading(&self, channel: &str, value: f64) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let msg = format!("SENSOR#{}#{}#{}#{}", self.settings.uid, now, value, channel);
+        self.send(&msg).await
+    }
+
+    // says DISCONN if connected and stops reconnecting, sends still waiting fail
+    pub async fn close(&self) {
+        self.stop.send_replace(true);
+        // the session is dropped once the client stopped
+        let mut session = self.session.clone();
+        while session.changed().await.is_ok() {}
+    }
+}
+
+// how a session ended
+#[derive(PartialEq)]
+enum Ended {
+    // the client was closed
+    Closed,
+    // the connection was lost or the server drained it, the client reconnects
+    Lost,
+}
+
+async fn run(
+    settings: Arc<Settings>,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    session: watch::Sender<Option<String>>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let uid = &settings.uid;
+    // messages whose write failed, they go first on the next connection
+    let mut retry: VecDeque<Outgoing> = VecDeque::new();
+    // reconnects since the last session
+    let mut attempt = 0;
+    loop {
+        let connected = tokio::select! {
+            connected = handshake(&settings) => connected,
+            _ = stop.changed() => break,
+        };
+        match connected {
+            Ok((socket, id)) => {
+                info!(
+                    "Device {} connected to {}, session {}",
+                    uid, settings.url, id
+                );
+                attempt = 0;
+                session.send_replace(Some(id));
+                let ended =
+                    run_session(socket, &settings, &mut outgoing, &mut retry, &mut stop).await;
+                session.send_replace(None);
+                if ended == Ended::Closed {
+                    break;
+                }
+                warn!("Device {} lost the connection to {}", uid, settings.url);
+            }
+            Err(e) => warn!(
+                "Device {} could not connect to {}: {}",
+                uid, settings.url, e
+            ),
+        }
+
+        let delay = settings.backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = stop.changed() => break,
+        }
+    }
+
+    // nothing is written anymore
+    outgoing.close();
+    while let Ok(msg) = outgoing.try_recv() {
+        retry.push_back(msg);
+    }
+    for msg in retry {
+        let _ = msg.done.send(Err(Error::Closed));
+    }
+}
+
+// connects and sends CONN, the session id is the answer of the server
+async fn handshake(settings: &Settings) -> Result<(Socket, String), String> {
+    let (mut socket, _) = connect_async(settings.url.as_str())
+        .await
+        .map_err(|e| e.to_string())?;
+    let conn = match &settings.options {
+        Some(options) => format!("CONN#{}#{}", settings.uid, options),
+        None => format!("CONN#{}", settings.uid),
+    };
+    socket
+        .send(Message::Text(conn))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let answer = async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) => return Some(text),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => continue,
+            }
+        }
+    };
+    let answer = time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), answer)
+        .await
+        .map_err(|_| "no answer to CONN".to_string())?
+        .ok_or_else(|| "closed before answering CONN".to_string())?;
+
+    if let Some(id) = answer.strip_prefix("SESSION#") {
+        return Ok((socket, id.to_string()));
+    }
+    if let Some(err) = ServerError::from_msg(&answer) {
+        if let Some(on_error) = &settings.on_error {
+            on_error(&err);
+        }
+        return Err(format!("CONN rejected with {}: {}", err.code, err.reason));
+    }
+    Err(format!("unexpected answer to CONN: {}", answer))
+}
+
+async fn run_session(
+    mut socket: Socket,
+    settings: &Settings,
+    outgoing: &mut mpsc::UnboundedReceiver<Outgoing>,
+    retry: &mut VecDeque<Outgoing>,
+    stop: &mut watch::Receiver<bool>,
+) -> Ended {
+    loop {
+        if let Some(msg) = retry.pop_front() {
+            if !write(&mut socket, msg, settings, retry).await {
+                return Ended::Lost;
+            }
+            continue;
+        }
+
+        tokio::select! {
+            msg = outgoing.recv() => {
+                let Some(msg) = msg else {
+                    disconnect(socket, &settings.uid).await;
+                    return Ended::Closed;
+                };
+                if !write(&mut socket, msg, settings, retry).await {
+                    return Ended::Lost;
+                }
+            }
+            incoming = socket.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ended::Lost,
+                    Some(Ok(_)) => continue,
+                };
+                // the server is shutting down, leave without DISCONN and come back later
+                if text.starts_with("DRAIN#") {
+                    info!("Device {} was drained", settings.uid);
+                    let _ = socket.close(None).await;
+                    return Ended::Lost;
+                }
+                if let Some(reply) = answer(settings, &text) {
+                    if socket.send(Message::Text(reply)).await.is_err() {
+                        return Ended::Lost;
+                    }
+                }
+            }
+            _ = stop.changed() => {
+                disconnect(socket, &settings.uid).await;
+                return Ended::Closed;
+            }
+        }
+    }
+}
+
+// writes the message, false if the connection is lost. the message is then retried on
+// the next connection unless it ran out of attempts
+async fn write(
+    socket: &mut Socket,
+    mut msg: Outgoing,
+    settings: &Settings,
+    retry: &mut VecDeque<Outgoing>,
+) -> bool {
+    msg.attempts += 1;
+    match socket.send(Message::Text(msg.msg.clone())).await {
+        Ok(()) => {
+            let _ = msg.done.send(Ok(()));
+            true
+        }
+        Err(e) => {
+            warn!("Device {} could not send {}: {}", settings.uid, msg.msg, e);
+            if msg.attempts >= settings.send_attempts {
+                let _ = msg.done.send(Err(Error::GaveUp(msg.attempts)));
+            } else {
+                retry.push_front(msg);
+            }
+            false
+        }
+    }
+}
+
+// passes the message to its callback and returns the acknowledgement, if it needs one
+fn answer(settings: &Settings, msg: &str) -> Option<String> {
+    let uid = &settings.uid;
+    if let Some(avg) = Avg::from_msg(msg) {
+        if let Some(on_avg) = &settings.on_avg {
+            on_avg(&avg);
+        }
+        return Some(format!("ACK#{}#{}", uid, avg.id));
+    }
+    if let Some(cmd) = Cmd::from_msg(msg) {
+        if let Some(on_cmd) = &settings.on_cmd {
+            on_cmd(&cmd);
+        }
+        return Some(format!("CMD_ACK#{}#{}", uid, cmd.id));
+    }
+    if let Some(err) = ServerError::from_msg(msg) {
+        warn!("Device {} received {}", uid, msg);
+        if let Some(on_error) = &settings.on_error {
+            on_error(&err);
+        }
+    }
+    None
+}
+
+// says DISCONN and waits for the server to close the connection
+async fn disconnect(mut socket: Socket, uid: &str) {
+    if socket
+        .send(Message::Text(format!("DISCONN#{}", uid)))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let closed = async { while let Some(Ok(_)) = socket.next().await {} };
+    if time::timeout(Duration::from_secs(DISCONN_TIMEOUT_SECS), closed)
+        .await
+        .is_err()
+    {
+        warn!("Device {} was not disconnected by the server", uid);
+        let _ = socket.close(None).await;
+    }
+}
@@ -0,0 +1,17 @@
+// the device side of the websocket protocol of the fog-hw servers, for firmware running
+// on a full OS and for fog nodes forwarding their devices
+mod backoff;
+mod client;
+mod messages;
+
+pub use backoff::Backoff;
+pub use client::{Builder, DeviceClient};
+pub use messages::{Avg, Cmd, ServerError};
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum Error {
+    #[error("gave up sending after {0} attempts")]
+    GaveUp(u32),
+    #[error("the client is closed")]
+    Closed,
+}
@@ -0,0 +1,72 @@
+// the messages of the server a device reacts to. deliveries end with the id the device
+// acknowledges them with
+
+// AVG#<timestamp>#<data>#<channel>#<id>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Avg {
+    pub timestamp: i64,
+    pub data: f64,
+    pub channel: String,
+    pub id: i64,
+}
+
+impl Avg {
+    pub fn from_msg(msg: &str) -> Option<Self> {
+        let mut fields = msg.strip_prefix("AVG#")?.split('#');
+        let avg = Self {
+            timestamp: fields.next()?.parse().ok()?,
+            data: fields.next()?.parse().ok()?,
+            channel: fields.next()?.to_string(),
+            id: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(avg)
+    }
+}
+
+// CMD#<command>[#<argument>]#<id>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cmd {
+    pub command: String,
+    pub argument: Option<String>,
+    pub id: i64,
+}
+
+impl Cmd {
+    pub fn from_msg(msg: &str) -> Option<Self> {
+        let (rest, id) = msg.strip_prefix("CMD#")?.rsplit_once('#')?;
+        let (command, argument) = match rest.split_once('#') {
+            Some((command, argument)) => (command, Some(argument.to_string())),
+            None => (rest, None),
+        };
+        Some(Self {
+            command: command.to_string(),
+            argument,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+// ERR#<code>#<reason>
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerError {
+    pub code: String,
+    pub reason: String,
+}
+
+impl ServerError {
+    pub fn from_msg(msg: &str) -> Option<Self> {
+        let (code, reason) = msg.strip_prefix("ERR#")?.split_once('#')?;
+        Some(Self {
+            code: code.to_string(),
+            reason: reason.to_string(),
+        })
+    }
+
+    // the server closes the connection after these
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self.code.as_str(),
+            "UID_MISMATCH" | "AUTH_FAILED" | "SESSION_EXISTS" | "MAINTENANCE"
+        )
+    }
+}
@@ -0,0 +1,198 @@
+use fog_hw_client::{Avg, Backoff, Cmd, DeviceClient, Error};
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+const UID: &str = "2d9f6b1e-8a3c-4e7d-b5f2-1c4a9e6d3b8f";
+
+// a server accepting websocket connections, the test plays its part of the protocol
+struct Server {
+    url: String,
+    listener: TcpListener,
+}
+
+impl Server {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        Self { url, listener }
+    }
+
+    // the next connection of the client, after it sent CONN and got its session
+    async fn accept(&self, session: &str) -> Connection {
+        let (stream, _) = tokio::time::timeout(Duration::from_secs(5), self.listener.accept())
+            .await
+            .expect("the client didn't connect")
+            .unwrap();
+        let mut conn = Connection(accept_async(stream).await.unwrap());
+        assert!(conn.recv().await.starts_with(&format!("CONN#{}", UID)));
+        conn.send(&format!("SESSION#{}", session)).await;
+        conn
+    }
+}
+
+struct Connection(WebSocketStream<TcpStream>);
+
+impl Connection {
+    async fn send(&mut self, msg: &str) {
+        self.0.send(Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> String {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), self.0.next())
+                .await
+                .expect("no message from the client");
+            match msg {
+                Some(Ok(Message::Text(text))) => return text,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => panic!("client went away"),
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+}
+
+fn fast_backoff() -> Backoff {
+    Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        jitter: 0.5,
+    }
+}
+
+#[test]
+fn reconnects_back_off_exponentially_with_jitter() {
+    let backoff = Backoff {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(30),
+        jitter: 0.5,
+    };
+    assert_eq!(backoff.delay_with(0, 0.0), Duration::from_secs(1));
+    assert_eq!(backoff.delay_with(3, 0.0), Duration::from_secs(8));
+    assert_eq!(backoff.delay_with(3, 1.0), Duration::from_secs(4));
+    assert_eq!(backoff.delay_with(10, 0.0), Duration::from_secs(30));
+    assert_eq!(backoff.delay_with(u32::MAX, 0.0), Duration::from_secs(30));
+    for attempt in 0..10 {
+        let delay = backoff.delay(attempt);
+        let full = backoff.delay_with(attempt, 0.0);
+        assert!(delay <= full && delay >= full / 2, "{:?}", delay);
+    }
+
+    assert_eq!(
+        Cmd::from_msg("CMD#fan#on#8"),
+        Some(Cmd {
+            command: "fan".to_string(),
+            argument: Some("on".to_string()),
+            id: 8
+        })
+    );
+    assert_eq!(Cmd::from_msg("CMD#reboot#9").unwrap().argument, None);
+    assert_eq!(Avg::from_msg("AVG#100#21.5#temperature"), None);
+}
+
+#[tokio::test]
+async fn deliveries_are_passed_on_and_acknowledged() {
+    let server = Server::start().await;
+    let avgs = Arc::new(Mutex::new(Vec::new()));
+    let cmds = Arc::new(Mutex::new(Vec::new()));
+    let client = DeviceClient::builder(&server.url, UID)
+        .options("interval=5")
+        .on_avg({
+            let avgs = avgs.clone();
+            move |avg| avgs.lock().unwrap().push(avg.clone())
+        })
+        .on_cmd({
+            let cmds = cmds.clone();
+            move |cmd| cmds.lock().unwrap().push(cmd.command.clone())
+        })
+        .start();
+
+    let (stream, _) = server.listener.accept().await.unwrap();
+    let mut conn = Connection(accept_async(stream).await.unwrap());
+    assert_eq!(conn.recv().await, format!("CONN#{}#interval=5", UID));
+    conn.send("SESSION#first").await;
+    assert_eq!(client.connected().await.unwrap(), "first");
+
+    client.reading("temperature", 21.5).await.unwrap();
+    let reading = conn.recv().await;
+    assert!(
+        reading.starts_with(&format!("SENSOR#{}#", UID)),
+        "{}",
+        reading
+    );
+    assert!(reading.ends_with("#21.5#temperature"), "{}", reading);
+
+    conn.send("AVG#100#21.5#temperature#7").await;
+    assert_eq!(conn.recv().await, format!("ACK#{}#7", UID));
+    conn.send("CMD#fan#on#8").await;
+    assert_eq!(conn.recv().await, format!("CMD_ACK#{}#8", UID));
+    assert_eq!(
+        *avgs.lock().unwrap(),
+        vec![Avg {
+            timestamp: 100,
+            data: 21.5,
+            channel: "temperature".to_string(),
+            id: 7
+        }]
+    );
+    assert_eq!(*cmds.lock().unwrap(), vec!["fan".to_string()]);
+
+    // closing says goodbye
+    let closing = tokio::spawn(async move { client.close().await });
+    assert_eq!(conn.recv().await, format!("DISCONN#{}", UID));
+    drop(conn);
+    closing.await.unwrap();
+}
+
+#[tokio::test]
+async fn lost_connections_are_reestablished_and_sends_wait_for_them() {
+    let server = Server::start().await;
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let client = Arc::new(
+        DeviceClient::builder(&server.url, UID)
+            .backoff(fast_backoff())
+            .on_error({
+                let errors = errors.clone();
+                move |err| errors.lock().unwrap().push(err.code.clone())
+            })
+            .start(),
+    );
+
+    // a rejected CONN is reported and tried again
+    let (stream, _) = server.listener.accept().await.unwrap();
+    let mut conn = Connection(accept_async(stream).await.unwrap());
+    conn.recv().await;
+    conn.send("ERR#OVERLOADED#try again later").await;
+    drop(conn);
+
+    let conn = server.accept("first").await;
+    assert_eq!(client.connected().await.unwrap(), "first");
+    assert_eq!(*errors.lock().unwrap(), vec!["OVERLOADED".to_string()]);
+    drop(conn);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // a message sent while the server is away goes out on the next connection
+    let sending = tokio::spawn({
+        let client = client.clone();
+        async move { client.reading("temperature", 19.0).await }
+    });
+    let mut conn = server.accept("second").await;
+    assert!(conn.recv().await.ends_with("#19#temperature"));
+    assert_eq!(sending.await.unwrap(), Ok(()));
+    assert_eq!(client.session().as_deref(), Some("second"));
+
+    // a closed client fails the sends still waiting
+    drop(conn);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let sending = tokio::spawn({
+        let client = client.clone();
+        async move { client.send("late").await }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    client.close().await;
+    assert_eq!(sending.await.unwrap(), Err(Error::Closed));
+}
@@ -0,0 +1,12 @@
+fn main() {
+    // the gRPC service is generated from proto/fog.proto, with a vendored protoc so the
+    // build doesn't need one installed
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/fog.proto").expect("could not compile proto/fog.proto");
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,25 @@
+-- per device thresholds, an alert fires once a reading stayed beyond the threshold for duration_secs
+CREATE TABLE IF NOT EXISTS alert_rules (
+    id INTEGER PRIMARY KEY,
+    uid TEXT NOT NULL,
+    -- NULL matches readings of every channel
+    channel TEXT,
+    op TEXT NOT NULL CHECK (op IN ('>', '<')),
+    threshold REAL NOT NULL,
+    duration_secs INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_alert_rules_uid ON alert_rules(uid);
+
+CREATE TABLE IF NOT EXISTS alerts (
+    id INTEGER PRIMARY KEY,
+    rule_id INTEGER NOT NULL,
+    uid TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    data REAL NOT NULL,
+    triggered_at INTEGER NOT NULL,
+    -- NULL while the alert is active
+    resolved_at INTEGER,
+    FOREIGN KEY(rule_id) REFERENCES alert_rules(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_alerts_active ON alerts(resolved_at);
@@ -0,0 +1,3 @@
+-- queued messages for a single device, like commands. NULL targets every device.
+ALTER TABLE queued_messages ADD COLUMN target_uid TEXT;
+CREATE INDEX IF NOT EXISTS idx_queued_target ON queued_messages(target_uid);
@@ -0,0 +1,10 @@
+-- actions taken through the admin endpoints
+CREATE TABLE IF NOT EXISTS admin_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    action TEXT NOT NULL,
+    uid TEXT NOT NULL,
+    message TEXT NOT NULL,
+    -- why the action was taken, e.g. a support ticket
+    note TEXT,
+    created_at INTEGER NOT NULL
+);
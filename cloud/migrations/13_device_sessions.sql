@@ -0,0 +1,10 @@
+-- websocket sessions of devices, a new CONN ends the open sessions of the device
+CREATE TABLE IF NOT EXISTS device_sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    uid TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    -- closed or replaced
+    end_reason TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_device_sessions_uid ON device_sessions(uid, started_at);
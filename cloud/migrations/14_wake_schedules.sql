@@ -0,0 +1,4 @@
+-- wake schedules devices declare in CONN, counted from woke_at. NULL for devices that don't sleep
+ALTER TABLE connections ADD COLUMN wake_period_secs INTEGER;
+ALTER TABLE connections ADD COLUMN wake_window_secs INTEGER;
+ALTER TABLE connections ADD COLUMN woke_at INTEGER;
@@ -0,0 +1,2 @@
+-- set when a device sends DISCONN, its history is kept until it is purged explicitly
+ALTER TABLE connections ADD COLUMN disconnected_at INTEGER;
@@ -0,0 +1,12 @@
+-- X25519 key devices announce in CONN, customers seal messages for the device with it
+ALTER TABLE connections ADD COLUMN public_key TEXT;
+
+-- end-to-end encrypted readings, stored as the hex envelopes devices sent
+CREATE TABLE IF NOT EXISTS sealed_messages (
+    id INTEGER PRIMARY KEY,
+    uid TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    envelope TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_sealed_uid_created ON sealed_messages(uid, created_at);
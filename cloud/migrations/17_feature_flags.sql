@@ -0,0 +1,8 @@
+-- runtime feature flags, for the whole deployment (empty group_name) or a device group
+CREATE TABLE IF NOT EXISTS feature_flags (
+    name TEXT NOT NULL,
+    group_name TEXT NOT NULL DEFAULT '',
+    enabled INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (name, group_name)
+);
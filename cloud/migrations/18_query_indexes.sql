@@ -0,0 +1,5 @@
+-- indexes of the frequent queries: readings of a device by time, queued messages by time
+-- and deliveries of a recipient
+CREATE INDEX IF NOT EXISTS idx_received_uid_created ON received_messages(uid, created_at);
+CREATE INDEX IF NOT EXISTS idx_queued_created ON queued_messages(created_at);
+CREATE INDEX IF NOT EXISTS idx_delivered_uid_message ON delivered_messages(uid, queued_message_id);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS pending_deliveries (
+    id INTEGER PRIMARY KEY,
+    uid TEXT NOT NULL,
+    queued_message_id INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    FOREIGN KEY(queued_message_id) REFERENCES queued_messages(id) ON DELETE CASCADE,
+    FOREIGN KEY(uid) REFERENCES connections(uid) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_pending_uid_message ON pending_deliveries(uid, queued_message_id);
@@ -0,0 +1,4 @@
+-- command sent to an actuator as soon as the rule fires, before the alert is reported
+ALTER TABLE alert_rules ADD COLUMN action_uid TEXT;
+ALTER TABLE alert_rules ADD COLUMN action_command TEXT;
+ALTER TABLE alert_rules ADD COLUMN action_argument TEXT;
@@ -0,0 +1,12 @@
+-- version of the alert rules of each device, bumped when they change. devices are sent their
+-- rules until they acknowledge the current version
+CREATE TABLE IF NOT EXISTS rule_versions (
+    uid TEXT PRIMARY KEY NOT NULL,
+    version INTEGER NOT NULL,
+    acked_version INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL,
+    acked_at INTEGER
+);
+-- rules added before the versioning are pushed on the next CONN
+INSERT OR IGNORE INTO rule_versions ( uid, version, updated_at )
+SELECT DISTINCT uid, 1, CAST(strftime('%s', 'now') AS INTEGER) FROM alert_rules;
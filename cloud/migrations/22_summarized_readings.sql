@@ -0,0 +1,6 @@
+-- rows summarizing the readings of a device and channel in one minute, written instead of
+-- the raw readings when received_messages nears its row cap. data is their mean, raw
+-- readings leave the columns NULL
+ALTER TABLE received_messages ADD COLUMN summary_min REAL;
+ALTER TABLE received_messages ADD COLUMN summary_max REAL;
+ALTER TABLE received_messages ADD COLUMN summary_count INTEGER;
@@ -0,0 +1,15 @@
+-- customers hosted on the server. devices join a tenant in CONN, their readings and the
+-- averages of them are kept apart from those of other tenants
+CREATE TABLE IF NOT EXISTS tenants (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+-- NULL for devices outside of any tenant
+ALTER TABLE connections ADD COLUMN tenant TEXT REFERENCES tenants(id);
+ALTER TABLE received_messages ADD COLUMN tenant TEXT;
+-- broadcast AVG messages are delivered to the devices of their tenant only
+ALTER TABLE queued_messages ADD COLUMN tenant TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_received_tenant_channel_created ON received_messages(tenant, channel, created_at);
@@ -0,0 +1,11 @@
+-- keys of the REST api, only the SHA-256 digest of a key is stored. viewers may read,
+-- admins may also change devices and push commands
+CREATE TABLE IF NOT EXISTS api_keys (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    role TEXT NOT NULL CHECK (role IN ('viewer', 'admin')),
+    created_at INTEGER NOT NULL,
+    -- revoked keys are kept, so the key management keeps their history
+    revoked_at INTEGER
+);
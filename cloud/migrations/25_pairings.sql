@@ -0,0 +1,10 @@
+-- devices provisioned in pairing mode, waiting for an admin to approve or reject them.
+-- rejected devices can't connect with their uid anymore
+CREATE TABLE IF NOT EXISTS pairings (
+    uid TEXT PRIMARY KEY,
+    -- what the device called itself in PAIR, e.g. its model
+    name TEXT,
+    paired_at INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'rejected')),
+    decided_at INTEGER
+);
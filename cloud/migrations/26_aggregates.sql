@@ -0,0 +1,15 @@
+-- the averages computed by the AVG service, the queued AVG messages only keep them as text.
+-- averages span all devices of a tenant, so rows are per tenant and channel
+CREATE TABLE IF NOT EXISTS aggregates (
+    id INTEGER PRIMARY KEY,
+    -- NULL for devices without a tenant
+    tenant TEXT,
+    channel TEXT NOT NULL,
+    -- the readings averaged were received in [window_start, window_end]
+    window_start INTEGER NOT NULL,
+    window_end INTEGER NOT NULL,
+    value REAL NOT NULL,
+    -- number of readings averaged
+    count INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_aggregates_window ON aggregates(channel, window_end);
@@ -0,0 +1,3 @@
+-- seconds between the newest reading of an aggregate and its computation,
+-- NULL for backfilled windows, which only average readings of their window
+ALTER TABLE aggregates ADD COLUMN staleness INTEGER;
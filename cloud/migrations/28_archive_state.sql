@@ -0,0 +1,5 @@
+-- start of the first day whose readings and rollups were not archived yet
+CREATE TABLE IF NOT EXISTS archive_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    next_day INTEGER NOT NULL
+);
@@ -0,0 +1,4 @@
+-- devices whose readings are passed through as they come instead of being averaged,
+-- like door contacts. readings keep the setting of their device at the time they came in
+ALTER TABLE connections ADD COLUMN pass_through INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE received_messages ADD COLUMN pass_through INTEGER NOT NULL DEFAULT 0;
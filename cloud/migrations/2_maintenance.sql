@@ -0,0 +1 @@
+ALTER TABLE connections ADD COLUMN maintenance INTEGER NOT NULL DEFAULT 0;
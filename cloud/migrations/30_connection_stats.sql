@@ -0,0 +1,13 @@
+-- counters of the last connection of each device, snapshotted with the bandwidth samples
+-- while it is connected and once more when it disconnects
+CREATE TABLE IF NOT EXISTS connection_stats (
+    uid TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    frames_in INTEGER NOT NULL,
+    frames_out INTEGER NOT NULL,
+    bytes_in INTEGER NOT NULL,
+    bytes_out INTEGER NOT NULL,
+    parse_errors INTEGER NOT NULL,
+    last_activity INTEGER,
+    updated_at INTEGER NOT NULL
+);
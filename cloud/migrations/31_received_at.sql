@@ -0,0 +1,3 @@
+-- when the server received a reading, created_at is the timestamp the device sent.
+-- readings stored before have none
+ALTER TABLE received_messages ADD COLUMN received_at INTEGER;
@@ -0,0 +1,6 @@
+-- sequence number the device gave a reading. devices re-send their buffered readings after
+-- a reconnect, the same reading of a device is stored once. the timestamp is part of the
+-- key, so a device that starts counting again after a reboot isn't taken for re-sending
+ALTER TABLE received_messages ADD COLUMN seq INTEGER;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_received_uid_seq ON received_messages(uid, seq, created_at)
+    WHERE seq IS NOT NULL;
@@ -0,0 +1,12 @@
+-- static attributes of a device like its site or asset tag, kept in the device registry
+CREATE TABLE IF NOT EXISTS device_attributes (
+    uid TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (uid, name),
+    FOREIGN KEY(uid) REFERENCES connections(uid) ON DELETE CASCADE
+);
+
+-- the attributes of the device when the reading was stored, as a JSON object. NULL if it
+-- had none
+ALTER TABLE received_messages ADD COLUMN attributes TEXT;
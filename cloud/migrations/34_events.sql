@@ -0,0 +1,9 @@
+-- significant things that happened to the server, like service restarts or shedding
+-- episodes, so anomalies in the data can be told apart from operational causes
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_events_created ON events(created_at);
@@ -0,0 +1,6 @@
+-- fog node the readings of a device are forwarded by, NULL for devices connected to the
+-- cloud themselves. readings keep the fog node they came through
+ALTER TABLE connections ADD COLUMN fog_uid TEXT;
+ALTER TABLE received_messages ADD COLUMN fog_uid TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_connection_fog ON connections(fog_uid);
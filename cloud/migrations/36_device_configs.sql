@@ -0,0 +1,11 @@
+-- parameters of each device set by the operators, like its sampling rate or thresholds.
+-- bumped to a new version on every change, devices are sent them until they acknowledge it
+CREATE TABLE IF NOT EXISTS device_configs (
+    uid TEXT PRIMARY KEY NOT NULL,
+    -- JSON object of the parameters
+    params TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    acked_version INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL,
+    acked_at INTEGER
+);
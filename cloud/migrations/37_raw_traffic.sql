@@ -0,0 +1,3 @@
+-- size of the protocol messages before compression, next to the bytes on the wire
+ALTER TABLE connection_stats ADD COLUMN raw_bytes_in INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE connection_stats ADD COLUMN raw_bytes_out INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+-- what devices tell about themselves in CONN or operators set, kept apart from the
+-- connections so devices can be registered before they first connect
+CREATE TABLE IF NOT EXISTS devices (
+    uid TEXT PRIMARY KEY NOT NULL,
+    name TEXT,
+    location TEXT,
+    sensor_type TEXT,
+    firmware TEXT,
+    registered_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_device_location ON devices(location);
@@ -0,0 +1,5 @@
+-- where a device is, in degrees, for the map of the dashboard
+ALTER TABLE devices ADD COLUMN latitude REAL;
+ALTER TABLE devices ADD COLUMN longitude REAL;
+
+CREATE INDEX IF NOT EXISTS idx_device_position ON devices(latitude, longitude);
@@ -0,0 +1,9 @@
+-- kept after a device disconnects, since the traffic is billed per device
+CREATE TABLE IF NOT EXISTS bandwidth_samples (
+    id INTEGER PRIMARY KEY,
+    uid TEXT NOT NULL,
+    bytes_in INTEGER NOT NULL,
+    bytes_out INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_bandwidth_uid_created ON bandwidth_samples(uid, created_at);
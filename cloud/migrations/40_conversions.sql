@@ -0,0 +1,18 @@
+-- conversions of the readings of a channel to a derived unit, applied before they are stored
+CREATE TABLE IF NOT EXISTS conversions (
+    uid TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    -- linear, fahrenheit_to_celsius or celsius_to_fahrenheit
+    kind TEXT NOT NULL,
+    -- linear conversions compute data * scale + offset
+    scale REAL NOT NULL DEFAULT 1,
+    offset REAL NOT NULL DEFAULT 0,
+    -- unit of the converted readings, like °C
+    unit TEXT,
+    -- the reading as the device sent it is kept in raw_data
+    keep_raw INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (uid, channel)
+);
+
+ALTER TABLE received_messages ADD COLUMN raw_data REAL;
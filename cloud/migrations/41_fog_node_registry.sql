@@ -0,0 +1,6 @@
+-- fog nodes an admin registered. only they may claim role=fog in CONN, and they only forward
+-- the readings of the devices an admin registered behind them with connections.fog_uid
+CREATE TABLE IF NOT EXISTS fog_nodes (
+    uid TEXT PRIMARY KEY NOT NULL,
+    registered_at INTEGER NOT NULL
+);
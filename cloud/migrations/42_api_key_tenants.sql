@@ -0,0 +1,3 @@
+-- keys of a tenant only read the devices of that tenant and their readings. NULL for keys of
+-- the operator, they see every tenant
+ALTER TABLE api_keys ADD COLUMN tenant TEXT REFERENCES tenants(id);
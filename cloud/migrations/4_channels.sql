@@ -0,0 +1,2 @@
+ALTER TABLE received_messages ADD COLUMN channel TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS idx_received_channel_created ON received_messages(channel, created_at);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS device_groups (
+    uid TEXT PRIMARY KEY,
+    group_name TEXT NOT NULL
+);
+
+-- readings of devices in critical groups are admitted even when the ingest is saturated
+CREATE TABLE IF NOT EXISTS group_policies (
+    group_name TEXT PRIMARY KEY,
+    critical INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,10 @@
+ALTER TABLE connections ADD COLUMN clock_skew INTEGER NOT NULL DEFAULT 0;
+
+-- devices needing attention, refreshed periodically by the attention service
+CREATE TABLE IF NOT EXISTS attention (
+    uid TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    since INTEGER NOT NULL,
+    PRIMARY KEY (uid, reason)
+);
@@ -0,0 +1,8 @@
+-- small per-device key-value store for application data
+CREATE TABLE IF NOT EXISTS device_kv (
+    uid TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (uid, key)
+);
@@ -0,0 +1,5 @@
+-- time of the last AVG service tick, used to backfill windows missed during downtime
+CREATE TABLE IF NOT EXISTS aggregation_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    last_tick INTEGER NOT NULL
+);
@@ -0,0 +1,28 @@
+-- aggregates of received readings per device, channel and bucket, kept after the raw rows are pruned
+CREATE TABLE IF NOT EXISTS rollups_hourly (
+    uid TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    avg REAL NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (uid, channel, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS rollups_daily (
+    uid TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    avg REAL NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (uid, channel, bucket)
+);
+
+-- start of the first bucket of each period that was not rolled up yet
+CREATE TABLE IF NOT EXISTS rollup_state (
+    period TEXT PRIMARY KEY,
+    next_bucket INTEGER NOT NULL
+);
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    // routine telemetry, shed when the ingest is saturated
+    Routine,
+    // alarm readings and readings of critical devices, always admitted
+    High,
+}
+
+// when the readings being written were admitted, by the id of their permit. ids only grow,
+// so the first entry is the oldest reading
+type InFlight = Arc<Mutex<BTreeMap<u64, Instant>>>;
+
+// limits the readings being written to the db at the same time,
+// once saturated only high priority readings are admitted
+#[derive(Default)]
+pub struct Admission {
+    in_flight: InFlight,
+    next_id: AtomicU64,
+    shed: AtomicU64,
+    duplicates: AtomicU64,
+    last_flush: Mutex<Option<FlushReport>>,
+}
+
+// held while a reading is written to the db
+pub struct Permit {
+    in_flight: InFlight,
+    id: u64,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+// the outcome of waiting for the readings being written
+#[derive(Serialize, Clone, Debug)]
+pub struct FlushReport {
+    // unix timestamp of the flush
+    pub flushed_at: i64,
+    pub waited_ms: u64,
+    // whether every reading admitted before the flush was written
+    pub drained: bool,
+    // readings admitted before the flush that were still being written when it gave up
+    pub remaining: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestStatus {
+    pub in_flight: usize,
+    // how long the oldest reading being written has been waiting
+    pub oldest_ms: Option<u64>,
+    pub shed: u64,
+    pub duplicates: u64,
+    pub last_flush: Option<FlushReport>,
+}
+
+impl Admission {
+    // returns None if the reading has to be shed
+    pub fn try_admit(&self, priority: Priority, max_in_flight: usize) -> Option<Permit> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.len() >= max_in_flight && priority == Priority::Routine {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        in_flight.insert(id, Instant::now());
+        Some(Permit {
+            in_flight: self.in_flight.clone(),
+            id,
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    // readings shed since the server started
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // readings dropped since the server started because their device sent them before
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> IngestStatus {
+        let (in_flight, oldest_ms) = {
+            let in_flight = self.in_flight.lock().unwrap();
+            let oldest = in_flight.values().next();
+            (
+                in_flight.len(),
+                oldest.map(|admitted| admitted.elapsed().as_millis() as u64),
+            )
+        };
+        IngestStatus {
+            in_flight,
+            oldest_ms,
+            shed: self.shed(),
+            duplicates: self.duplicates(),
+            last_flush: self.last_flush.lock().unwrap().clone(),
+        }
+    }
+
+    // waits until the readings admitted so far are written, readings admitted meanwhile
+    // don't hold it up. gives up after the timeout
+    pub async fn flush(&self, clock: &dyn Clock, timeout: Duration) -> FlushReport {
+        let started = Instant::now();
+        // ids are taken under the lock, so every id below this one is already in the map
+        let until = {
+            let _in_flight = self.in_flight.lock().unwrap();
+            self.next_id.load(Ordering::Relaxed)
+        };
+        let pending = || self.in_flight.lock().unwrap().range(..until).count();
+
+        let mut remaining = pending();
+        while remaining > 0 && started.elapsed() < timeout {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            remaining = pending();
+        }
+
+        let report = FlushReport {
+            flushed_at: clock.unix_now(),
+            waited_ms: started.elapsed().as_millis() as u64,
+            drained: remaining == 0,
+            remaining,
+        };
+        *self.last_flush.lock().unwrap() = Some(report.clone());
+        report
+    }
+}
@@ -0,0 +1,251 @@
+use hyper::{header, Body, Client, Request};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::{
+    db, events::StreamEvent, protocols, sessions::Control, webhooks::WebhookEvent, AppState,
+};
+
+// evaluation state of a rule
+#[derive(Default)]
+struct RuleState {
+    // time since when the readings are beyond the threshold
+    breached_since: Option<i64>,
+    firing: bool,
+}
+
+// evaluates the alert rules against incoming readings
+pub async fn alert_service(state: Arc<AppState>) {
+    let mut events = state.events.subscribe();
+    let mut rules = load_rules(&state).await;
+    let mut rule_states: HashMap<i64, RuleState> = HashMap::new();
+
+    // rules with an active alert keep firing across restarts
+    match db::get_active_alerts(&state.pool, None).await {
+        Ok(alerts) => {
+            for alert in alerts {
+                rule_states.entry(alert.rule_id).or_default().firing = true;
+            }
+        }
+        Err(_) => error!("Alert service: Failed to load the active alerts"),
+    }
+
+    loop {
+        tokio::select! {
+            _ = state.alert_rules_changed.notified() => {
+                rules = load_rules(&state).await;
+                rule_states.retain(|id, _| rules.values().flatten().any(|r| r.id == *id));
+            }
+            event = events.recv() => match event {
+                Ok(StreamEvent::Sensor { uid, data, channel, .. }) => {
+                    let Some(device_rules) = rules.get(&uid) else {
+                        continue;
+                    };
+                    for rule in device_rules {
+                        if rule.channel.as_ref().is_some_and(|c| *c != channel) {
+                            continue;
+                        }
+                        let rule_state = rule_states.entry(rule.id).or_default();
+                        evaluate(&state, rule, rule_state, &channel, data).await;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Alert service: Lagged behind, skipped {} readings", skipped)
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+// rules by uid
+async fn load_rules(state: &AppState) -> HashMap<String, Vec<db::AlertRule>> {
+    let mut rules: HashMap<String, Vec<db::AlertRule>> = HashMap::new();
+    match db::get_alert_rules(&state.pool).await {
+        Ok(all) => {
+            for rule in all {
+                rules.entry(rule.uid.clone()).or_default().push(rule);
+            }
+        }
+        Err(_) => error!("Alert service: Failed to load the alert rules"),
+    }
+    rules
+}
+
+fn is_breached(rule: &db::AlertRule, data: f64) -> bool {
+    match rule.op.as_str() {
+        ">" => data > rule.threshold,
+        "<" => data < rule.threshold,
+        _ => false,
+    }
+}
+
+async fn evaluate(
+    state: &AppState,
+    rule: &db::AlertRule,
+    rule_state: &mut RuleState,
+    channel: &str,
+    data: f64,
+) {
+    let now = state.clock.unix_now();
+
+    if !is_breached(rule, data) {
+        rule_state.breached_since = None;
+        if rule_state.firing {
+            rule_state.firing = false;
+            info!("Alert rule {} of {} resolved", rule.id, rule.uid);
+            if db::resolve_alerts(&state.pool, rule.id).await.is_err() {
+                error!("Error resolving the alerts of rule {}", rule.id);
+            }
+        }
+        return;
+    }
+
+    let since = *rule_state.breached_since.get_or_insert(now);
+    if rule_state.firing || now - since < rule.duration_secs {
+        return;
+    }
+    rule_state.firing = true;
+
+    // actuate first, the alert is stored and reported afterwards
+    act(state, rule, now).await;
+
+    let alert = match db::add_alert(&state.pool, rule, channel, data).await {
+        Ok(id) => db::Alert {
+            id,
+            rule_id: rule.id,
+            uid: rule.uid.clone(),
+            channel: channel.to_string(),
+            data,
+            triggered_at: now,
+            resolved_at: None,
+        },
+        Err(_) => {
+            error!("Error storing alert of rule {}", rule.id);
+            return;
+        }
+    };
+    warn!(
+        "Alert {} of {}: {} {} {} on channel {}",
+        alert.id, rule.uid, data, rule.op, rule.threshold, channel
+    );
+
+    // tell the device, if it is connected
+    let msg = protocols::AlertMsg {
+        rule_id: rule.id,
+        channel: channel.to_string(),
+        data,
+    };
+    state
+        .sessions
+        .send(&rule.uid, Control::Send(msg.to_msg()))
+        .await;
+
+    state.webhooks.publish(WebhookEvent::Alert(alert.clone()));
+    if let Some(url) = state.config.alert_webhook_url.clone() {
+        tokio::spawn(post_webhook(url, alert));
+    }
+}
+
+// sends the CMD of a fired rule to its device, right away if the device is connected.
+// the command is queued like any other, so it is retried until acknowledged
+async fn act(state: &AppState, rule: &db::AlertRule, now: i64) {
+    let (Some(uid), Some(command)) = (&rule.action_uid, &rule.action_command) else {
+        return;
+    };
+    let cmd = protocols::CmdMsg {
+        command: command.clone(),
+        argument: rule.action_argument.clone(),
+    };
+    let command_id = match db::add_targeted_message(&state.pool, uid, cmd.to_msg()).await {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Error queueing the action of rule {} for {}", rule.id, uid);
+            return;
+        }
+    };
+    let live = state.sessions.send(uid, Control::Deliver).await;
+    info!(
+        "Rule {} sent {} to {} ({})",
+        rule.id,
+        cmd.command,
+        uid,
+        if live { "delivered now" } else { "queued" }
+    );
+
+    state.webhooks.publish(WebhookEvent::Action {
+        rule_id: rule.id,
+        uid: uid.clone(),
+        command: cmd.command,
+        argument: cmd.argument,
+        command_id,
+        triggered_at: now,
+    });
+}
+
+async fn post_webhook(url: String, alert: db::Alert) {
+    let Ok(body) = serde_json::to_string(&alert) else {
+        return;
+    };
+    let req = Request::post(&url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body));
+    let Ok(req) = req else {
+        error!("Invalid alert webhook url: {}", url);
+        return;
+    };
+
+    match Client::new().request(req).await {
+        Ok(res) if res.status().is_success() => info!("Alert {} posted to webhook", alert.id),
+        Ok(res) => error!(
+            "Alert webhook answered {} for alert {}",
+            res.status(),
+            alert.id
+        ),
+        Err(_) => error!("Error posting alert {} to the webhook", alert.id),
+    }
+}
+
+// the RULES message a device still has to apply, None if it runs the current version.
+// devices evaluate their rules themselves, so they keep alerting while the server is away
+pub(crate) async fn pending_rules(state: &AppState, uid: &str) -> Option<String> {
+    let version = match db::get_rule_version(&state.pool, uid).await {
+        Ok(version) if !version.is_synced() => version.version,
+        Ok(_) => return None,
+        Err(_) => {
+            error!("Error getting the rule version of {}", uid);
+            return None;
+        }
+    };
+    let rules = match db::get_device_alert_rules(&state.pool, uid).await {
+        Ok(rules) => rules,
+        Err(_) => {
+            error!("Error getting the alert rules of {}", uid);
+            return None;
+        }
+    };
+    let msg = protocols::RulesMsg {
+        version,
+        rules: rules.iter().map(protocols::EdgeRule::from_rule).collect(),
+    };
+    Some(msg.to_msg())
+}
+
+// called after the rules of a device changed, pushes the new version if it is connected.
+// otherwise it is sent on the next CONN
+pub(crate) async fn rules_changed(state: &AppState, uid: &str) {
+    state.alert_rules_changed.notify_one();
+
+    match db::bump_rule_version(&state.pool, uid).await {
+        Ok(version) => info!("Alert rules of {} are at version {}", uid, version),
+        Err(_) => {
+            error!("Error bumping the rule version of {}", uid);
+            return;
+        }
+    }
+    if let Some(msg) = pending_rules(state, uid).await {
+        state.sessions.send(uid, Control::Send(msg)).await;
+    }
+}
@@ -0,0 +1,311 @@
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{clock::Interval, AppState};
+
+// what an archive run wrote
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ArchiveReport {
+    // the days archived, as YYYY-MM-DD in UTC
+    pub days: Vec<String>,
+    pub files: u64,
+    // readings and rollups written
+    pub rows: u64,
+    // raw readings deleted after they were archived
+    pub pruned: u64,
+}
+
+// archives completed days of readings and rollups as parquet files, partitioned by day and
+// device like raw/day=2024-01-31/uid=<uid>/data.parquet. one run at a time, a run asked for
+// while another one is going is turned down
+#[derive(Default)]
+pub struct Archive {
+    running: Mutex<()>,
+}
+
+impl Archive {
+    // archives the completed days not archived yet, None if a run is already going
+    pub async fn run(&self, state: &AppState) -> Option<crate::Result<ArchiveReport>> {
+        let _running = self.running.try_lock().ok()?;
+        Some(archive(state).await)
+    }
+}
+
+// archives on a schedule, when ARCHIVE_INTERVAL_SECS is set
+pub async fn archive_service(state: Arc<AppState>) {
+    let Some(interval_secs) = state.config.archive_interval_secs else {
+        return;
+    };
+    let mut interval = Interval::new(state.clock.clone(), Duration::from_secs(interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        match state.archive.run(&state).await {
+            None => info!("Archive service: The last run is still going, skipping"),
+            Some(Ok(report)) if report.days.is_empty() => {}
+            Some(Ok(report)) => info!(
+                "Archive service: Archived {} rows of {} days in {} files, pruned {} readings",
+                report.rows,
+                report.days.len(),
+                report.files,
+                report.pruned
+            ),
+            Some(Err(e)) => error!("Archive service: Failed to archive: {}", e),
+        }
+    }
+}
+
+// the day as YYYY-MM-DD
+#[cfg(feature = "parquet")]
+fn date(day: i64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(day)
+        .map(|time| time.date().to_string())
+        .unwrap_or_else(|_| day.to_string())
+}
+
+#[cfg(feature = "parquet")]
+async fn archive(state: &AppState) -> crate::Result<ArchiveReport> {
+    use crate::db::{self, RollupPeriod};
+    use futures_util::TryStreamExt;
+
+    let Some(url) = &state.config.archive_url else {
+        return Err(crate::Error::Protocol("ARCHIVE_URL is not set".to_string()));
+    };
+    let store = files::Store::open(url)?;
+    let pool = &state.pool;
+    let mut report = ArchiveReport::default();
+
+    let mut day = match db::get_next_archive_day(pool).await? {
+        Some(day) => day,
+        None => match db::get_oldest_archivable_time(pool).await? {
+            Some(oldest) => RollupPeriod::Day.bucket(oldest),
+            None => return Ok(report),
+        },
+    };
+    // days are archived once they are over and rolled up, unless there are no rollups at all
+    let today = RollupPeriod::Day.bucket(state.clock.unix_now());
+    let until = match db::get_next_rollup_bucket(pool, RollupPeriod::Day).await? {
+        Some(next_bucket) => next_bucket.min(today),
+        None => today,
+    };
+
+    while day < until {
+        let end = day + RollupPeriod::Day.secs();
+        let partition = format!("day={}", date(day));
+
+        for uid in db::get_archivable_uids(pool, day, end).await? {
+            let readings: Vec<db::ReceivedMessage> =
+                db::stream_received_messages(pool, &uid, None, day, end)
+                    .try_collect()
+                    .await?;
+            if let Some(last) = readings.iter().map(|reading| reading.id).max() {
+                let path = format!("raw/{}/uid={}/data.parquet", partition, uid);
+                store.put(&path, files::readings(&readings)?).await?;
+                report.files += 1;
+                report.rows += readings.len() as u64;
+
+                if state.config.archive_prune {
+                    report.pruned += db::delete_readings(pool, &uid, day, end, last).await?;
+                }
+            }
+
+            let mut rollups = Vec::new();
+            for period in [RollupPeriod::Hour, RollupPeriod::Day] {
+                for rollup in db::get_rollups(pool, period, &uid, None, day, end).await? {
+                    rollups.push((period, rollup));
+                }
+            }
+            if !rollups.is_empty() {
+                let path = format!("rollups/{}/uid={}/data.parquet", partition, uid);
+                store.put(&path, files::rollups(&rollups)?).await?;
+                report.files += 1;
+                report.rows += rollups.len() as u64;
+            }
+        }
+
+        report.days.push(date(day));
+        day = end;
+        db::set_next_archive_day(pool, day).await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(not(feature = "parquet"))]
+async fn archive(_state: &AppState) -> crate::Result<ArchiveReport> {
+    Err(crate::Error::Protocol(
+        "the server was built without the parquet feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "parquet")]
+mod files {
+    use object_store::{aws::AmazonS3Builder, local::LocalFileSystem, path::Path, ObjectStore};
+    use parquet::{
+        basic::Compression,
+        column::writer::ColumnWriter,
+        data_type::ByteArray,
+        errors::ParquetError,
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::sync::Arc;
+
+    use crate::{config::ArchiveUrl, db};
+
+    const READINGS: &str = "message reading {
+        REQUIRED BYTE_ARRAY uid (UTF8);
+        REQUIRED BYTE_ARRAY channel (UTF8);
+        REQUIRED INT64 created_at;
+        REQUIRED DOUBLE data;
+        OPTIONAL DOUBLE summary_min;
+        OPTIONAL DOUBLE summary_max;
+        OPTIONAL INT64 summary_count;
+    }";
+
+    const ROLLUPS: &str = "message rollup {
+        REQUIRED BYTE_ARRAY period (UTF8);
+        REQUIRED BYTE_ARRAY uid (UTF8);
+        REQUIRED BYTE_ARRAY channel (UTF8);
+        REQUIRED INT64 bucket;
+        REQUIRED DOUBLE avg;
+        REQUIRED DOUBLE min;
+        REQUIRED DOUBLE max;
+        REQUIRED INT64 count;
+    }";
+
+    // io errors of the archive, the run is retried with the next one
+    fn archive_error(e: impl std::error::Error + Send + Sync + 'static) -> crate::Error {
+        crate::Error::Io(std::io::Error::other(e))
+    }
+
+    pub struct Store {
+        store: Box<dyn ObjectStore>,
+        prefix: String,
+    }
+
+    impl Store {
+        pub fn open(url: &ArchiveUrl) -> crate::Result<Self> {
+            match url {
+                ArchiveUrl::Dir(dir) => {
+                    std::fs::create_dir_all(dir)?;
+                    Ok(Self {
+                        store: Box::new(
+                            LocalFileSystem::new_with_prefix(dir).map_err(archive_error)?,
+                        ),
+                        prefix: String::new(),
+                    })
+                }
+                ArchiveUrl::S3 { bucket, prefix } => Ok(Self {
+                    store: Box::new(
+                        AmazonS3Builder::from_env()
+                            .with_bucket_name(bucket)
+                            .build()
+                            .map_err(archive_error)?,
+                    ),
+                    prefix: prefix.clone(),
+                }),
+            }
+        }
+
+        // writes the file, replacing the one of an earlier run that was cut short
+        pub async fn put(&self, path: &str, file: Vec<u8>) -> crate::Result<()> {
+            let path = Path::from(format!("{}{}", self.prefix, path));
+            self.store
+                .put(&path, file.into())
+                .await
+                .map_err(archive_error)?;
+            Ok(())
+        }
+    }
+
+    enum Column {
+        Text(Vec<ByteArray>),
+        Int(Vec<Option<i64>>),
+        Float(Vec<Option<f64>>),
+    }
+
+    // the values that are set and the definition levels telling which ones are
+    fn levels<T: Copy>(values: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+        (
+            values.iter().flatten().copied().collect(),
+            values.iter().map(|value| value.is_some() as i16).collect(),
+        )
+    }
+
+    // a file with a single row group of the columns in the order of the schema
+    fn write(schema: &str, columns: Vec<Column>) -> crate::Result<Vec<u8>> {
+        let schema = Arc::new(parse_message_type(schema).map_err(archive_error)?);
+        let properties = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build(),
+        );
+        let mut writer =
+            SerializedFileWriter::new(Vec::new(), schema, properties).map_err(archive_error)?;
+        let mut row_group = writer.next_row_group().map_err(archive_error)?;
+        let mut columns = columns.into_iter();
+
+        while let Some(mut column) = row_group.next_column().map_err(archive_error)? {
+            let written = match (columns.next(), column.untyped()) {
+                (Some(Column::Text(values)), ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                    writer.write_batch(&values, None, None)
+                }
+                (Some(Column::Int(values)), ColumnWriter::Int64ColumnWriter(writer)) => {
+                    let (values, levels) = levels(&values);
+                    writer.write_batch(&values, Some(&levels), None)
+                }
+                (Some(Column::Float(values)), ColumnWriter::DoubleColumnWriter(writer)) => {
+                    let (values, levels) = levels(&values);
+                    writer.write_batch(&values, Some(&levels), None)
+                }
+                _ => Err(ParquetError::General(
+                    "the columns don't match the schema".to_string(),
+                )),
+            };
+            written.map_err(archive_error)?;
+            column.close().map_err(archive_error)?;
+        }
+
+        row_group.close().map_err(archive_error)?;
+        writer.into_inner().map_err(archive_error)
+    }
+
+    fn text<'a>(values: impl Iterator<Item = &'a str>) -> Column {
+        Column::Text(values.map(ByteArray::from).collect())
+    }
+
+    pub fn readings(readings: &[db::ReceivedMessage]) -> crate::Result<Vec<u8>> {
+        write(
+            READINGS,
+            vec![
+                text(readings.iter().map(|r| r.uid.as_str())),
+                text(readings.iter().map(|r| r.channel.as_str())),
+                Column::Int(readings.iter().map(|r| Some(r.created_at)).collect()),
+                Column::Float(readings.iter().map(|r| Some(r.data)).collect()),
+                Column::Float(readings.iter().map(|r| r.summary_min).collect()),
+                Column::Float(readings.iter().map(|r| r.summary_max).collect()),
+                Column::Int(readings.iter().map(|r| r.summary_count).collect()),
+            ],
+        )
+    }
+
+    pub fn rollups(rollups: &[(db::RollupPeriod, db::Rollup)]) -> crate::Result<Vec<u8>> {
+        write(
+            ROLLUPS,
+            vec![
+                text(rollups.iter().map(|(period, _)| period.name())),
+                text(rollups.iter().map(|(_, r)| r.uid.as_str())),
+                text(rollups.iter().map(|(_, r)| r.channel.as_str())),
+                Column::Int(rollups.iter().map(|(_, r)| Some(r.bucket)).collect()),
+                Column::Float(rollups.iter().map(|(_, r)| Some(r.avg)).collect()),
+                Column::Float(rollups.iter().map(|(_, r)| Some(r.min)).collect()),
+                Column::Float(rollups.iter().map(|(_, r)| Some(r.max)).collect()),
+                Column::Int(rollups.iter().map(|(_, r)| Some(r.count)).collect()),
+            ],
+        )
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{error, info};
+
+use crate::{cache, clock::Interval, db, AppState};
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+// periodically refreshes the table of devices needing attention
+pub async fn attention_service(state: Arc<AppState>) {
+    let mut interval = Interval::new(
+        state.clock.clone(),
+        Duration::from_secs(state.config.attention_refresh_secs),
+    );
+
+    loop {
+        interval.tick().await;
+
+        match refresh(&state).await {
+            Ok(count) => info!("Attention service: {} devices need attention", count),
+            Err(e) => error!(
+                "Attention service: Failed to refresh the attention table: {}",
+                e
+            ),
+        }
+    }
+}
+
+async fn refresh(state: &AppState) -> crate::Result<usize> {
+    let now = state.clock.unix_now();
+    let config = &state.config;
+
+    // keep the time since when a device needs attention for the same reason
+    let previous: HashMap<(String, String), i64> = db::get_attention(&state.pool, None)
+        .await?
+        .into_iter()
+        .map(|item| ((item.uid, item.reason), item.since))
+        .collect();
+    let mut items = Vec::new();
+    let mut add = |uid: &str, reason: &str, detail: String| {
+        let since = previous
+            .get(&(uid.to_string(), reason.to_string()))
+            .copied()
+            .unwrap_or(now);
+        items.push(db::AttentionItem {
+            uid: uid.to_string(),
+            reason: reason.to_string(),
+            detail,
+            since,
+        });
+    };
+
+    for conn in db::get_connections(&state.pool, None).await? {
+        // devices that said goodbye are neither offline nor off
+        if conn.disconnected_at.is_some() {
+            continue;
+        }
+
+        // devices in maintenance are expected to be offline, sleeping ones until they miss a wake
+        if !conn.maintenance
+            && now - conn.last_seen > conn.expected_silence_secs(config.offline_after_secs)
+            && !state.sessions.is_connected(&conn.uid).await
+        {
+            add(
+                &conn.uid,
+                "offline",
+                format!("last seen {} seconds ago", now - conn.last_seen),
+            );
+        }
+
+        if conn.clock_skew.abs() > config.max_clock_skew_secs {
+            add(
+                &conn.uid,
+                "clock_skew",
+                format!("clock is off by {} seconds", conn.clock_skew),
+            );
+        }
+    }
+
+    let mut alerts: HashMap<String, usize> = HashMap::new();
+    for alert in db::get_active_alerts(&state.pool, None).await? {
+        *alerts.entry(alert.uid).or_default() += 1;
+    }
+    for (uid, count) in alerts {
+        add(&uid, "alert", format!("{} active alerts", count));
+    }
+
+    if let Some(quota) = config.bandwidth_quota_bytes {
+        for (uid, bytes) in db::get_bandwidth_totals(&state.pool, now - DAY_SECS).await? {
+            if bytes > quota {
+                add(
+                    &uid,
+                    "quota",
+                    format!("used {} of {} bytes in the last 24 hours", bytes, quota),
+                );
+            }
+        }
+    }
+
+    let count = items.len();
+    db::replace_attention(&state.pool, &items).await?;
+    state.cache.invalidate(cache::ATTENTION_KEY).await;
+
+    Ok(count)
+}
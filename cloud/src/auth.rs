@@ -0,0 +1,242 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{header, request::Parts, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use tracing::{error, warn};
+
+use crate::{db, AppState};
+
+// what the holder of an API key may do on the REST api
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // reads devices, readings and averages
+    Viewer,
+    // also deletes connections, pushes commands and changes settings
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Admin => "admin",
+        }
+    }
+
+    // viewers may only read
+    pub fn allows(&self, method: &Method) -> bool {
+        *self == Role::Admin || method == Method::GET || method == Method::HEAD
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Invalid role: {}", s)),
+        }
+    }
+}
+
+// the tenant whose data a request reads, None for the data of every tenant. keys of a
+// tenant only see its devices, the ADMIN_TOKEN and the keys of the operator see all
+#[derive(Clone, Default, Debug)]
+pub struct Scope(pub Option<String>);
+
+impl Scope {
+    // the tenant a request reads, a tenant it asks for has to be its own
+    pub fn tenant(&self, requested: Option<String>) -> Result<Option<String>, StatusCode> {
+        match (&self.0, requested) {
+            (Some(own), Some(requested)) if *own != requested => Err(StatusCode::FORBIDDEN),
+            (Some(own), _) => Ok(Some(own.clone())),
+            (None, requested) => Ok(requested),
+        }
+    }
+
+    // whether the data of a device in the tenant is in scope
+    pub fn allows(&self, tenant: Option<&str>) -> bool {
+        self.0.is_none() || self.0.as_deref() == tenant
+    }
+
+    // the server-wide endpoints are not for the keys of a tenant
+    pub fn is_tenant(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Scope {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        // require_api_key already looked the key up
+        if let Some(scope) = parts.extensions.get::<Scope>() {
+            return Ok(scope.clone());
+        }
+        // without API_AUTH, and outside of the REST api, a request without a valid key
+        // sees every tenant
+        match caller(state, &parts.headers).await {
+            Ok(caller) => Ok(caller.map(|(_, scope)| scope).unwrap_or_default()),
+            Err(e) => {
+                error!("Error checking an API key: {}", e);
+                Err(status(&e))
+            }
+        }
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// a new API key, handed out once. only its digest is stored
+pub fn generate_key() -> String {
+    format!(
+        "fog_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+// hex SHA-256 of a key, keys are random so they don't need a salt
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// whether the request carries the admin token as a bearer token
+pub fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let token = match &state.config.admin_token {
+        Some(token) => token,
+        None => return false,
+    };
+
+    // compare digests, so the comparison doesn't leak how much of the token matched
+    bearer(headers).map(|given| Sha256::digest(given.as_bytes()))
+        == Some(Sha256::digest(token.expose().as_bytes()))
+}
+
+// the role and the scope of the caller, None without a valid key. the ADMIN_TOKEN is an
+// admin key of every tenant
+async fn caller(state: &AppState, headers: &HeaderMap) -> crate::Result<Option<(Role, Scope)>> {
+    if is_admin(state, headers) {
+        return Ok(Some((Role::Admin, Scope(None))));
+    }
+    let Some(key) = bearer(headers) else {
+        return Ok(None);
+    };
+    let grant = db::get_api_key_grant(&state.pool, &hash_key(key)).await?;
+    Ok(grant.and_then(|(role, tenant)| Some((role.parse().ok()?, Scope(tenant)))))
+}
+
+fn status(e: &crate::Error) -> StatusCode {
+    if e.is_transient() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+// with API_AUTH every request to the REST api needs the key of a role allowing it,
+// the api docs stay public. the admin endpoints are guarded by require_admin
+pub async fn require_api_key<B>(
+    State(state): State<Arc<AppState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path();
+    if !state.config.api_auth || !path.starts_with("/api/") || path.starts_with("/api/docs") {
+        return next.run(request).await;
+    }
+
+    match caller(&state, request.headers()).await {
+        Ok(Some((role, scope))) if role.allows(request.method()) => {
+            request.extensions_mut().insert(scope);
+            next.run(request).await
+        }
+        Ok(Some((role, _))) => {
+            warn!(
+                "Rejected {} {} of a {} key",
+                request.method(),
+                path,
+                role.as_str()
+            );
+            StatusCode::FORBIDDEN.into_response()
+        }
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Error checking an API key: {}", e);
+            status(&e).into_response()
+        }
+    }
+}
+
+// the keys of a tenant only reach the devices of their tenant, the other devices are not
+// found for them. guards every route naming a device by its uid
+pub async fn require_device_in_scope<B>(
+    State(state): State<Arc<AppState>>,
+    scope: Scope,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let uid = match params.as_ref().and_then(|Path(params)| params.get("uid")) {
+        Some(uid) if scope.is_tenant() => uid,
+        _ => return next.run(request).await,
+    };
+    match db::get_device_tenant(&state.pool, uid).await {
+        Ok(tenant) if scope.allows(tenant.as_deref()) => next.run(request).await,
+        Ok(_) => {
+            warn!(
+                "Rejected {} {} of a key of tenant {:?}",
+                request.method(),
+                request.uri().path(),
+                scope.0
+            );
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            error!("Error getting the tenant of {}: {}", uid, e);
+            status(&e).into_response()
+        }
+    }
+}
+
+// the admin endpoints need the ADMIN_TOKEN, without one configured they don't exist
+pub async fn require_admin<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if state.config.admin_token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !is_admin(&state, request.headers()) {
+        warn!(
+            "Rejected unauthenticated {} {}",
+            request.method(),
+            request.uri().path()
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,399 @@
+// fake devices for load testing and demos. each device connects to the fog or cloud server,
+// sends CONN, then randomized SENSOR readings, acknowledges AVG and CMD deliveries
+// and says DISCONN when the simulation ends.
+//
+// configured with environment variables (or the .env file):
+// SIM_URL           server to connect to, ws://localhost:3000/ws
+// SIM_DEVICES       number of devices, 10
+// SIM_RATE          readings each device sends per second, 1
+// SIM_CHANNELS      comma separated channels the readings are spread over, temperature,humidity
+// SIM_DURATION_SECS seconds the simulation runs, unset runs until ctrl-c
+// SIM_CONN_OPTIONS  options appended to CONN, e.g. interval=5
+//
+// with --bench the latency from sending a reading to receiving the first AVG of its channel
+// computed after it, the sustained throughput and the error rates are measured and printed
+// as a report when the simulation ends
+use cloud::protocols::{self, Protocol};
+use dotenvy::dotenv;
+use futures_util::{SinkExt, StreamExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::VecDeque,
+    env, fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{signal, sync::watch, time};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{info, warn};
+
+// milliseconds between device connects, so the server isn't hit by all CONNs at once
+const CONNECT_SPREAD_MS: u64 = 10;
+// seconds between progress reports
+const REPORT_INTERVAL_SECS: u64 = 10;
+// seconds the server gets to close the connection after DISCONN
+const DISCONN_TIMEOUT_SECS: u64 = 5;
+// readings per device and channel waiting for an AVG in bench mode, older ones are dropped
+const MAX_PENDING_READINGS: usize = 1000;
+
+struct Settings {
+    url: String,
+    devices: usize,
+    rate_per_sec: f64,
+    channels: Vec<String>,
+    duration: Option<Duration>,
+    conn_options: Option<String>,
+    bench: bool,
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        let channels: String = env_or("SIM_CHANNELS", "temperature,humidity".to_string());
+        let channels: Vec<String> = channels.split(',').map(str::to_string).collect();
+        if let Some(channel) = channels.iter().find(|c| !protocols::is_valid_channel(c)) {
+            panic!("SIM_CHANNELS has an invalid channel: {:?}", channel);
+        }
+
+        let rate_per_sec: f64 = env_or("SIM_RATE", 1.0);
+        if !(rate_per_sec > 0.0 && rate_per_sec.is_finite()) {
+            panic!("SIM_RATE must be positive");
+        }
+
+        Self {
+            url: env_or("SIM_URL", "ws://localhost:3000/ws".to_string()),
+            devices: env_or("SIM_DEVICES", 10),
+            rate_per_sec,
+            channels,
+            duration: env_opt("SIM_DURATION_SECS").map(Duration::from_secs),
+            conn_options: env_opt("SIM_CONN_OPTIONS"),
+            bench: false,
+        }
+    }
+}
+
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value: {:?}", key, value))
+    })
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
+
+// counters over all devices
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    readings: AtomicU64,
+    averages: AtomicU64,
+    commands: AtomicU64,
+    errors: AtomicU64,
+    failed: AtomicU64,
+    // from sending a reading to receiving an AVG of its channel, only measured in bench mode
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connected, {} readings sent, {} AVG and {} CMD received, {} ERR, {} devices failed",
+            self.connected.load(Ordering::Relaxed),
+            self.readings.load(Ordering::Relaxed),
+            self.averages.load(Ordering::Relaxed),
+            self.commands.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let mut settings = Settings::from_env();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bench" => settings.bench = true,
+            _ => panic!("Unknown argument {:?}, usage: simulator [--bench]", arg),
+        }
+    }
+    let settings = Arc::new(settings);
+    let stats = Arc::new(Stats::default());
+    info!(
+        "Simulating {} devices sending {} readings per second to {}",
+        settings.devices, settings.rate_per_sec, settings.url
+    );
+
+    let started = Instant::now();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut devices = Vec::with_capacity(settings.devices);
+    for _ in 0..settings.devices {
+        let uid = uuid::Uuid::new_v4().to_string();
+        let device = run_device(uid, settings.clone(), stats.clone(), stop_rx.clone());
+        devices.push(tokio::spawn(device));
+        time::sleep(Duration::from_millis(CONNECT_SPREAD_MS)).await;
+    }
+
+    let mut report = time::interval(Duration::from_secs(REPORT_INTERVAL_SECS));
+    report.tick().await;
+    let until = async {
+        match settings.duration {
+            Some(duration) => time::sleep(duration.saturating_sub(started.elapsed())).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(until);
+    loop {
+        tokio::select! {
+            _ = report.tick() => info!("{}", stats),
+            _ = &mut until => break,
+            _ = signal::ctrl_c() => break,
+        }
+    }
+
+    info!("Disconnecting the devices");
+    stop_tx.send(true).ok();
+    for device in devices {
+        device.await.ok();
+    }
+    let elapsed = started.elapsed();
+    info!("Done after {:?}: {}", elapsed, stats);
+    if settings.bench {
+        print_report(&settings, &stats, elapsed);
+    }
+}
+
+fn print_report(settings: &Settings, stats: &Stats, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let readings = stats.readings.load(Ordering::Relaxed);
+    let averages = stats.averages.load(Ordering::Relaxed);
+    let errors = stats.errors.load(Ordering::Relaxed);
+    let failed = stats.failed.load(Ordering::Relaxed);
+    let percent = |count: u64, of: u64| 100.0 * count as f64 / of.max(1) as f64;
+
+    println!();
+    println!(
+        "{} devices for {:.1} s, {} readings per second each",
+        settings.devices, secs, settings.rate_per_sec
+    );
+    println!(
+        "throughput:  {} readings sent, {:.1}/s sustained",
+        readings,
+        readings as f64 / secs
+    );
+    println!(
+        "             {} AVG received, {:.1}/s",
+        averages,
+        averages as f64 / secs
+    );
+    println!(
+        "errors:      {} ERR ({:.2}% of readings), {} of {} devices failed ({:.1}%)",
+        errors,
+        percent(errors, readings),
+        failed,
+        settings.devices,
+        percent(failed, settings.devices as u64)
+    );
+
+    let mut latencies = stats.latencies.lock().unwrap().clone();
+    if latencies.is_empty() {
+        println!("latency:     no AVG received");
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_millis();
+    println!(
+        "latency:     p50 {} ms, p90 {} ms, p99 {} ms, max {} ms over {} readings",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+        latencies.len()
+    );
+}
+
+async fn run_device(
+    uid: String,
+    settings: Arc<Settings>,
+    stats: Arc<Stats>,
+    mut stop: watch::Receiver<bool>,
+) {
+    if let Err(e) = simulate(&uid, &settings, &stats, &mut stop).await {
+        warn!("Device {} failed: {}", uid, e);
+        stats.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn simulate(
+    uid: &str,
+    settings: &Settings,
+    stats: &Stats,
+    stop: &mut watch::Receiver<bool>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let (ws, _) = connect_async(settings.url.as_str()).await?;
+    let (mut sender, mut receiver) = ws.split();
+
+    let conn = match &settings.conn_options {
+        Some(options) => format!("CONN#{}#{}", uid, options),
+        None => format!("CONN#{}", uid),
+    };
+    sender.send(Message::Text(conn)).await?;
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    // every device wanders around its own baseline, so the averages have something to show
+    let mut rng = StdRng::from_entropy();
+    let mut values: Vec<f64> = settings
+        .channels
+        .iter()
+        .map(|_| rng.gen_range(0.0..100.0))
+        .collect();
+    // readings of every channel sent since its last AVG, with their unix and send times
+    let mut pending: Vec<VecDeque<(u64, Instant)>> = vec![VecDeque::new(); values.len()];
+    let mut readings = time::interval(Duration::from_secs_f64(1.0 / settings.rate_per_sec));
+    // don't make up for readings missed while the socket was busy
+    readings.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = readings.tick() => {
+                let i = rng.gen_range(0..values.len());
+                values[i] += rng.gen_range(-1.0..1.0);
+                let now = unix_now();
+                let reading = format!(
+                    "SENSOR#{}#{}#{:.2}#{}",
+                    uid,
+                    now,
+                    values[i],
+                    settings.channels[i]
+                );
+                sender.send(Message::Text(reading)).await?;
+                stats.readings.fetch_add(1, Ordering::Relaxed);
+                if settings.bench {
+                    if pending[i].len() == MAX_PENDING_READINGS {
+                        pending[i].pop_front();
+                    }
+                    pending[i].push_back((now, Instant::now()));
+                }
+            }
+            msg = receiver.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None => {
+                        info!("Device {} was disconnected by the server", uid);
+                        stats.connected.fetch_sub(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        stats.connected.fetch_sub(1, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                };
+                if settings.bench {
+                    measure_latency(&msg, settings, stats, &mut pending);
+                }
+                if let Some(reply) = answer(uid, &msg, stats) {
+                    sender.send(Message::Text(reply)).await?;
+                }
+                // the server is shutting down, leave without DISCONN so the device isn't
+                // marked as disconnected
+                if matches!(protocols::get_protocol(&msg), Ok(Protocol::DRAIN)) {
+                    info!("Device {} was drained", uid);
+                    sender.close().await?;
+                    stats.connected.fetch_sub(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            }
+            _ = stop.changed() => break,
+        }
+    }
+
+    sender
+        .send(Message::Text(format!("DISCONN#{}", uid)))
+        .await?;
+    // the server closes the connection once it processed DISCONN
+    let closed = async { while let Some(Ok(_)) = receiver.next().await {} };
+    if time::timeout(Duration::from_secs(DISCONN_TIMEOUT_SECS), closed)
+        .await
+        .is_err()
+    {
+        warn!("Device {} was not disconnected by the server", uid);
+        sender.close().await?;
+    }
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+    Ok(())
+}
+
+// readings of the channel of an AVG sent up to the second it was computed in
+// have made it through the pipeline
+fn measure_latency(
+    msg: &str,
+    settings: &Settings,
+    stats: &Stats,
+    pending: &mut [VecDeque<(u64, Instant)>],
+) {
+    // the queued message id is appended on delivery
+    let avg = match msg
+        .rsplit_once('#')
+        .map(|(avg, _)| protocols::AvgMsg::from_msg(avg))
+    {
+        Some(Ok(avg)) => avg,
+        _ => return,
+    };
+    let channel = match settings.channels.iter().position(|c| *c == avg.channel) {
+        Some(channel) => channel,
+        None => return,
+    };
+
+    let received = Instant::now();
+    let mut latencies = stats.latencies.lock().unwrap();
+    while let Some(&(sent_at, sent)) = pending[channel].front() {
+        if sent_at as i64 > avg.timestamp {
+            break;
+        }
+        latencies.push(received - sent);
+        pending[channel].pop_front();
+    }
+}
+
+// the acknowledgement of a message from the server, if it needs one
+fn answer(uid: &str, msg: &str, stats: &Stats) -> Option<String> {
+    // deliveries end with the queued message id
+    let delivery_id = || msg.rsplit_once('#').map(|(_, id)| id);
+    match protocols::get_protocol(msg) {
+        Ok(Protocol::AVG) => {
+            stats.averages.fetch_add(1, Ordering::Relaxed);
+            delivery_id().map(|id| format!("ACK#{}#{}", uid, id))
+        }
+        Ok(Protocol::CMD) => {
+            stats.commands.fetch_add(1, Ordering::Relaxed);
+            info!("Device {} received {}", uid, msg);
+            delivery_id().map(|id| format!("CMD_ACK#{}#{}", uid, id))
+        }
+        Ok(Protocol::ERR) => {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            warn!("Device {} received {}", uid, msg);
+            None
+        }
+        _ => None,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,202 @@
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{clock::Interval, webhooks::WebhookEvent, AppState};
+
+// the budget is renewed at the start of every hour
+pub const WINDOW_SECS: i64 = 3600;
+// share of the budget raw readings may use, the rest is kept for aggregates
+pub const RAW_SHARE_PERCENT: u64 = 50;
+// seconds between checks for a finished window
+const REPORT_CHECK_SECS: u64 = 10;
+
+// what is sent upstream, in the order it gets the budget
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Class {
+    // alerts and rule actions, never held back but still counted
+    Alert,
+    // AVG events, may use the whole budget
+    Aggregate,
+    // readings published to the streaming platform, deferred once they used their share
+    Raw,
+}
+
+impl Class {
+    // the class of a webhook event by its name, budget reports are sent like alerts
+    pub fn of_event(name: &str) -> Self {
+        match name {
+            "avg" => Class::Aggregate,
+            "reading" => Class::Raw,
+            _ => Class::Alert,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// bytes sent upstream within one window, reported upstream when it ends
+#[derive(Clone, Serialize, Debug, Default, PartialEq)]
+pub struct BudgetReport {
+    // unix timestamp the window started at
+    pub period_start: i64,
+    // None without a budget
+    pub budget_bytes: Option<u64>,
+    pub used_bytes: u64,
+    pub alert_bytes: u64,
+    pub aggregate_bytes: u64,
+    pub raw_bytes: u64,
+    // sends that had to wait for a later window
+    pub deferred: u64,
+}
+
+#[derive(Default)]
+struct Window {
+    started_at: i64,
+    // bytes by class
+    used: [u64; 3],
+    deferred: u64,
+}
+
+impl Window {
+    fn report(&self, limit: Option<u64>) -> BudgetReport {
+        BudgetReport {
+            period_start: self.started_at,
+            budget_bytes: limit,
+            used_bytes: self.used.iter().sum(),
+            alert_bytes: self.used[Class::Alert.index()],
+            aggregate_bytes: self.used[Class::Aggregate.index()],
+            raw_bytes: self.used[Class::Raw.index()],
+            deferred: self.deferred,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Windows {
+    current: Window,
+    // the last window that ended, until it was reported
+    finished: Option<BudgetReport>,
+}
+
+// bytes the server may send upstream per hour, for sites on metered links. payloads are
+// counted, not the overhead of the protocols carrying them
+pub struct UpstreamBudget {
+    limit: Option<u64>,
+    windows: Mutex<Windows>,
+}
+
+impl UpstreamBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            windows: Mutex::new(Windows::default()),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    // records the bytes if the class may still send them, false if they have to wait. now
+    // is the unix time of the state clock, like in the other methods
+    pub fn try_spend_at(&self, class: Class, bytes: usize, now: i64) -> bool {
+        let mut windows = self.windows_at(now);
+        let window = &mut windows.current;
+        let bytes = bytes as u64;
+        let used: u64 = window.used.iter().sum();
+        let allowed = match (self.limit, class) {
+            (None, _) | (_, Class::Alert) => true,
+            (Some(limit), Class::Aggregate) => used + bytes <= limit,
+            (Some(limit), Class::Raw) => {
+                window.used[Class::Raw.index()] + bytes <= limit * RAW_SHARE_PERCENT / 100
+                    && used + bytes <= limit
+            }
+        };
+        if allowed {
+            window.used[class.index()] += bytes;
+        }
+        allowed
+    }
+
+    // whether the class has any of its share left in the current window
+    pub fn has_room_at(&self, class: Class, now: i64) -> bool {
+        let windows = self.windows_at(now);
+        let window = &windows.current;
+        let used: u64 = window.used.iter().sum();
+        match (self.limit, class) {
+            (None, _) | (_, Class::Alert) => true,
+            (Some(limit), Class::Aggregate) => used < limit,
+            (Some(limit), Class::Raw) => {
+                window.used[Class::Raw.index()] < limit * RAW_SHARE_PERCENT / 100 && used < limit
+            }
+        }
+    }
+
+    // counts a send that waits for the next window because try_spend_at turned it down
+    pub fn defer_at(&self, now: i64) {
+        self.windows_at(now).current.deferred += 1;
+    }
+
+    // the consumption of the current window
+    pub fn report_at(&self, now: i64) -> BudgetReport {
+        self.windows_at(now).current.report(self.limit)
+    }
+
+    // the report of the window that ended last, only returned once
+    pub fn take_finished_at(&self, now: i64) -> Option<BudgetReport> {
+        self.windows_at(now).finished.take()
+    }
+
+    // time until the budget is renewed
+    pub fn until_next_window(&self, now: i64) -> Duration {
+        Duration::from_secs((WINDOW_SECS - now.rem_euclid(WINDOW_SECS)) as u64)
+    }
+
+    // the windows with the current one starting at the last full hour
+    fn windows_at(&self, now: i64) -> std::sync::MutexGuard<'_, Windows> {
+        let mut windows = self.windows.lock().unwrap();
+        let start = now - now.rem_euclid(WINDOW_SECS);
+        if windows.current.started_at != start {
+            // nothing was sent before the first window, so it isn't reported
+            if windows.current.started_at != 0 {
+                windows.finished = Some(windows.current.report(self.limit));
+            }
+            windows.current = Window {
+                started_at: start,
+                ..Default::default()
+            };
+        }
+        windows
+    }
+}
+
+// reports the consumption of every finished window upstream as a budget webhook event
+pub async fn budget_service(state: Arc<AppState>) {
+    if state.budget.limit().is_none() {
+        return;
+    }
+    let mut checks = Interval::new(state.clock.clone(), Duration::from_secs(REPORT_CHECK_SECS));
+    loop {
+        checks.tick().await;
+        let Some(report) = state.budget.take_finished_at(state.clock.unix_now()) else {
+            continue;
+        };
+        if report.deferred > 0 {
+            warn!(
+                "Upstream budget of {:?} bytes used up, {} sends deferred",
+                report.budget_bytes, report.deferred
+            );
+        }
+        info!(
+            "Sent {} bytes upstream in the last window: {} alerts, {} aggregates, {} raw",
+            report.used_bytes, report.alert_bytes, report.aggregate_bytes, report.raw_bytes
+        );
+        state.webhooks.publish(WebhookEvent::Budget(report));
+    }
+}
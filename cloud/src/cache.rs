@@ -0,0 +1,89 @@
+#[cfg(feature = "redis")]
+use redis::{aio::ConnectionManager, AsyncCommands};
+use tracing::warn;
+#[cfg(feature = "redis")]
+use tracing::{error, info};
+
+// optional redis cache for hot reads, the db stays the source of truth.
+// without the redis feature or REDIS_URL every lookup misses.
+#[derive(Clone, Default)]
+pub struct Cache {
+    #[cfg(feature = "redis")]
+    conn: Option<ConnectionManager>,
+    // seconds cached values are kept
+    ttl_secs: usize,
+}
+
+impl Cache {
+    pub async fn connect(url: Option<&str>, ttl_secs: usize) -> Self {
+        let Some(url) = url else {
+            return Self::default();
+        };
+
+        #[cfg(feature = "redis")]
+        {
+            let conn = match redis::Client::open(url) {
+                Ok(client) => ConnectionManager::new(client).await.ok(),
+                Err(_) => None,
+            };
+            match &conn {
+                Some(_) => info!("Caching hot reads in redis at {}", url),
+                None => error!("Could not connect to redis at {}, caching disabled", url),
+            }
+            Self { conn, ttl_secs }
+        }
+
+        #[cfg(not(feature = "redis"))]
+        {
+            warn!(
+                "REDIS_URL is set to {} but the server was built without the redis feature",
+                url
+            );
+            Self { ttl_secs }
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<String> {
+        #[cfg(feature = "redis")]
+        if let Some(mut conn) = self.conn.clone() {
+            return conn.get(key).await.unwrap_or_else(|_| {
+                warn!("Cache lookup of {} failed", key);
+                None
+            });
+        }
+
+        let _ = key;
+        None
+    }
+
+    pub async fn set(&self, key: &str, value: &str) {
+        #[cfg(feature = "redis")]
+        if let Some(mut conn) = self.conn.clone() {
+            let res: redis::RedisResult<()> = conn.set_ex(key, value, self.ttl_secs).await;
+            if res.is_err() {
+                warn!("Caching {} failed", key);
+            }
+        }
+
+        let _ = (key, value, self.ttl_secs);
+    }
+
+    // removes a value after its source in the db changed
+    pub async fn invalidate(&self, key: &str) {
+        #[cfg(feature = "redis")]
+        if let Some(mut conn) = self.conn.clone() {
+            let res: redis::RedisResult<()> = conn.del(key).await;
+            if res.is_err() {
+                warn!("Invalidating cached {} failed", key);
+            }
+        }
+
+        let _ = key;
+    }
+}
+
+pub fn kv_key(uid: &str, key: &str) -> String {
+    format!("kv:{}:{}", uid, key)
+}
+
+pub const ATTENTION_KEY: &str = "attention";
@@ -0,0 +1,126 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::watch, time::Instant};
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// the time the services run on. the server uses the system clock, tests a MockClock to
+// step through time dependent behavior deterministically and faster than real time
+pub trait Clock: Send + Sync {
+    // seconds since the unix epoch
+    fn unix_now(&self) -> i64;
+    // a monotonic instant, for deadlines and elapsed time
+    fn now(&self) -> Instant;
+    // resolves once the duration passed on this clock
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_now(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// a clock that only moves when it is advanced, sleeps on it resolve once enough time was added
+pub struct MockClock {
+    start_unix: i64,
+    start: Instant,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl MockClock {
+    // a clock starting at the unix timestamp
+    pub fn new(unix_now: i64) -> Self {
+        Self {
+            start_unix: unix_now,
+            start: Instant::now(),
+            elapsed: watch::Sender::new(Duration::ZERO),
+        }
+    }
+
+    // moves the clock forward and wakes the sleeps that are due
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += by);
+    }
+}
+
+impl Clock for MockClock {
+    fn unix_now(&self) -> i64 {
+        self.start_unix + self.elapsed.borrow().as_secs() as i64
+    }
+
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let deadline = *self.elapsed.borrow() + duration;
+        let mut elapsed = self.elapsed.subscribe();
+        Box::pin(async move {
+            // a dropped clock never gets there
+            if elapsed.wait_for(|e| *e >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+// ticks every period on a clock. like tokio's interval the first tick completes right
+// away and missed ticks are caught up on. tick is cancel safe, so it can be used in select!
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        let next = clock.now();
+        Self {
+            clock,
+            period,
+            next,
+        }
+    }
+
+    // an interval whose first tick is one period from now
+    pub fn delayed(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        let next = clock.now() + period;
+        Self {
+            clock,
+            period,
+            next,
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub async fn tick(&mut self) -> Instant {
+        let now = self.clock.now();
+        if self.next > now {
+            self.clock.sleep(self.next - now).await;
+        }
+        let tick = self.next;
+        self.next = tick + self.period;
+        tick
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+use crate::{admission::Priority, db, handlers, protocols, sensors, AppState, Error, Result};
+
+// largest datagram read, a SENSOR message fits many times over
+const MAX_DATAGRAM_LEN: usize = 1152;
+// how long a confirmable request is answered from the cache when it is retransmitted,
+// EXCHANGE_LIFETIME of RFC 7252
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+// the only resource, readings are posted to coap://<server>/sensor
+const SENSOR_PATH: &str = "sensor";
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+// option numbers of RFC 7252
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+// content format of text/plain;charset=utf-8
+const TEXT_PLAIN: u8 = 0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+// request methods and response codes as class.detail, like 0.02 for POST and 2.04 for Changed
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const CHANGED: u8 = 0x44;
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+}
+
+// the subset of CoAP constrained devices need to post readings: no blockwise transfers,
+// observation or discovery
+#[derive(Clone, PartialEq, Debug)]
+pub struct CoapMessage {
+    pub kind: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    // by option number, in the order they were sent
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl CoapMessage {
+    pub fn parse(datagram: &[u8]) -> Result<Self> {
+        let invalid = |reason: &str| Error::Protocol(reason.to_string());
+
+        if datagram.len() < 4 {
+            return Err(invalid("message is shorter than the header"));
+        }
+        if datagram[0] >> 6 != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let kind = match (datagram[0] >> 4) & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        };
+        let token_len = (datagram[0] & 0x0f) as usize;
+        if token_len > 8 {
+            return Err(invalid("token is longer than 8 bytes"));
+        }
+        let code = datagram[1];
+        let message_id = u16::from_be_bytes([datagram[2], datagram[3]]);
+        let token = datagram
+            .get(4..4 + token_len)
+            .ok_or_else(|| invalid("message is shorter than its token"))?
+            .to_vec();
+
+        let mut rest = &datagram[4 + token_len..];
+        let mut options = Vec::new();
+        let mut number = 0u16;
+        while let Some((&first, tail)) = rest.split_first() {
+            if first == PAYLOAD_MARKER {
+                if tail.is_empty() {
+                    return Err(invalid("payload marker without payload"));
+                }
+                return Ok(Self {
+                    kind,
+                    code,
+                    message_id,
+                    token,
+                    options,
+                    payload: tail.to_vec(),
+                });
+            }
+            rest = tail;
+            let delta = read_extended(first >> 4, &mut rest)?;
+            let len = read_extended(first & 0x0f, &mut rest)? as usize;
+            number = number
+                .checked_add(delta)
+                .ok_or_else(|| invalid("option number out of range"))?;
+            let value = rest
+                .get(..len)
+                .ok_or_else(|| invalid("option is longer than the message"))?;
+            options.push((number, value.to_vec()));
+            rest = &rest[len..];
+        }
+
+        Ok(Self {
+            kind,
+            code,
+            message_id,
+            token,
+            options,
+            payload: Vec::new(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            MessageType::Confirmable => 0,
+            MessageType::NonConfirmable => 1,
+            MessageType::Acknowledgement => 2,
+            MessageType::Reset => 3,
+        };
+        let mut datagram = vec![VERSION << 6 | kind << 4 | self.token.len() as u8, self.code];
+        datagram.extend_from_slice(&self.message_id.to_be_bytes());
+        datagram.extend_from_slice(&self.token);
+
+        let mut options = self.options.clone();
+        options.sort_by_key(|(number, _)| *number);
+        let mut last = 0;
+        for (number, value) in &options {
+            let (delta, delta_ext) = extended(number - last);
+            let (len, len_ext) = extended(value.len() as u16);
+            datagram.push(delta << 4 | len);
+            datagram.extend_from_slice(&delta_ext);
+            datagram.extend_from_slice(&len_ext);
+            datagram.extend_from_slice(value);
+            last = *number;
+        }
+
+        if !self.payload.is_empty() {
+            datagram.push(PAYLOAD_MARKER);
+            datagram.extend_from_slice(&self.payload);
+        }
+        datagram
+    }
+
+    // the Uri-Path options joined by '/'
+    pub fn path(&self) -> String {
+        let segments: Vec<String> = self
+            .options
+            .iter()
+            .filter(|(number, _)| *number == URI_PATH)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+            .collect();
+        segments.join("/")
+    }
+}
+
+// option deltas and lengths above 12 are extended by one or two bytes
+fn read_extended(nibble: u8, rest: &mut &[u8]) -> Result<u16> {
+    let invalid = || Error::Protocol("option is longer than the message".to_string());
+    match nibble {
+        0..=12 => Ok(nibble as u16),
+        13 => {
+            let (&byte, tail) = rest.split_first().ok_or_else(invalid)?;
+            *rest = tail;
+            Ok(byte as u16 + 13)
+        }
+        14 => {
+            let bytes = rest.get(..2).ok_or_else(invalid)?;
+            let value = u16::from_be_bytes([bytes[0], bytes[1]]);
+            *rest = &rest[2..];
+            value
+                .checked_add(269)
+                .ok_or_else(|| Error::Protocol("option number out of range".to_string()))
+        }
+        _ => Err(Error::Protocol(
+            "reserved option delta or length".to_string(),
+        )),
+    }
+}
+
+fn extended(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+// a confirmable request, answered from the cache when it is retransmitted
+struct Exchange {
+    started_at: Instant,
+    // None while the request is handled
+    response: Option<Vec<u8>>,
+}
+
+// confirmable requests by peer and message id, so a retransmitted reading isn't stored twice
+#[derive(Default)]
+struct Exchanges {
+    exchanges: HashMap<(SocketAddr, u16), Exchange>,
+}
+
+impl Exchanges {
+    // the answer to a retransmitted request, None for new requests, which are marked as
+    // being handled
+    fn begin(&mut self, peer: SocketAddr, message_id: u16) -> Option<Option<Vec<u8>>> {
+        let now = Instant::now();
+        self.exchanges
+            .retain(|_, exchange| now.duration_since(exchange.started_at) < EXCHANGE_LIFETIME);
+        match self.exchanges.get(&(peer, message_id)) {
+            Some(exchange) => Some(exchange.response.clone()),
+            None => {
+                self.exchanges.insert(
+                    (peer, message_id),
+                    Exchange {
+                        started_at: now,
+                        response: None,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self, peer: SocketAddr, message_id: u16, response: Vec<u8>) {
+        if let Some(exchange) = self.exchanges.get_mut(&(peer, message_id)) {
+            exchange.response = Some(response);
+        }
+    }
+}
+
+// accepts readings of devices that can't keep a websocket open, posted as SENSOR messages
+// to /sensor. the response carries the latest AVG message of the reading's channel
+pub async fn listen(socket: UdpSocket, state: Arc<AppState>) {
+    let socket = Arc::new(socket);
+    let exchanges = Arc::new(Mutex::new(Exchanges::default()));
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    let mut next_message_id = uuid::Uuid::new_v4().as_u128() as u16;
+
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving a CoAP datagram: {}", e);
+                continue;
+            }
+        };
+        let request = match CoapMessage::parse(&buf[..len]) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Invalid CoAP message from {}: {}", peer, e);
+                continue;
+            }
+        };
+
+        let (kind, message_id) = match request.kind {
+            // an empty confirmable message is a ping, answered with a reset
+            MessageType::Confirmable if request.code == code::EMPTY => {
+                let pong = CoapMessage {
+                    kind: MessageType::Reset,
+                    code: code::EMPTY,
+                    message_id: request.message_id,
+                    token: Vec::new(),
+                    options: Vec::new(),
+                    payload: Vec::new(),
+                };
+                let _ = socket.send_to(&pong.encode(), peer).await;
+                continue;
+            }
+            MessageType::Confirmable => {
+                let retransmitted = exchanges.lock().unwrap().begin(peer, request.message_id);
+                match retransmitted {
+                    Some(Some(response)) => {
+                        let _ = socket.send_to(&response, peer).await;
+                        continue;
+                    }
+                    // the client tries again if the first answer takes too long
+                    Some(None) => continue,
+                    None => (MessageType::Acknowledgement, request.message_id),
+                }
+            }
+            MessageType::NonConfirmable => {
+                next_message_id = next_message_id.wrapping_add(1);
+                (MessageType::NonConfirmable, next_message_id)
+            }
+            // nothing the server sends is confirmable, so there is nothing to acknowledge
+            MessageType::Acknowledgement | MessageType::Reset => continue,
+        };
+
+        // readings are stored in the background, so a slow db doesn't hold up other devices
+        let state = state.clone();
+        let socket = socket.clone();
+        let exchanges = exchanges.clone();
+        tokio::spawn(async move {
+            let (code, payload) = handle(&state, &request).await;
+            let options = if payload.is_empty() {
+                Vec::new()
+            } else {
+                vec![(CONTENT_FORMAT, vec![TEXT_PLAIN])]
+            };
+            let response = CoapMessage {
+                kind,
+                code,
+                message_id,
+                token: request.token,
+                options,
+                payload: payload.into_bytes(),
+            }
+            .encode();
+
+            if request.kind == MessageType::Confirmable {
+                exchanges
+                    .lock()
+                    .unwrap()
+                    .finish(peer, request.message_id, response.clone());
+            }
+            if let Err(e) = socket.send_to(&response, peer).await {
+                error!("Error answering the CoAP request of {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+// stores the reading of a request, returns the response code and payload
+async fn handle(state: &AppState, request: &CoapMessage) -> (u8, String) {
+    if request.path() != SENSOR_PATH {
+        return (code::NOT_FOUND, String::new());
+    }
+    if request.code != code::POST && request.code != code::PUT {
+        return (code::METHOD_NOT_ALLOWED, String::new());
+    }
+
+    let sensor_data = match std::str::from_utf8(&request.payload)
+        .map_err(|_| "payload is not UTF-8".to_string())
+        .and_then(|msg| {
+            let mut sensor_data = protocols::SensorMsg::from_msg(msg).map_err(|e| e.to_string())?;
+            handlers::check_timestamp(state, &mut sensor_data).map_err(|e| e.to_string())?;
+            Ok(sensor_data)
+        }) {
+        Ok(sensor_data) => sensor_data,
+        Err(e) => return (code::BAD_REQUEST, e),
+    };
+
+    // devices posting readings never send CONN
+    if let Err(e) = sensors::register(state, &sensor_data.uid).await {
+        error!("Error registering {}: {}", sensor_data.uid, e);
+        return (code::SERVICE_UNAVAILABLE, String::new());
+    }
+
+    let priority = if sensor_data.alarm {
+        Priority::High
+    } else {
+        Priority::Routine
+    };
+    let max_in_flight = state
+        .latency
+        .max_in_flight(state.config.ingest_max_in_flight);
+    let Some(_permit) = state.admission.try_admit(priority, max_in_flight) else {
+        warn!(
+            "Ingest saturated, shed a CoAP reading of {}",
+            sensor_data.uid
+        );
+        return (code::SERVICE_UNAVAILABLE, "reading shed".to_string());
+    };
+    let uid = sensor_data.uid.clone();
+    let channel = sensor_data.channel.clone();
+    info!("CoAP reading of {} on {}", uid, channel);
+    handlers::ingest_sensor(state, sensor_data).await;
+
+    // devices are answered with the averages of their tenant
+    let average = match db::get_device_tenant(&state.pool, &uid).await {
+        Ok(tenant) => db::get_latest_average(&state.pool, tenant.as_deref(), &channel).await,
+        Err(e) => Err(e),
+    };
+    match average {
+        Ok(average) => (
+            code::CHANGED,
+            average.map(|average| average.message).unwrap_or_default(),
+        ),
+        Err(e) => {
+            error!("Error getting the latest average of {}: {}", channel, e);
+            (code::INTERNAL_SERVER_ERROR, String::new())
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use axum::extract::ws::Message;
+use std::error::Error;
+
+use super::{Codec, Record};
+
+// Binary encoding, a type byte followed by big endian fields.
+//   SENSOR:  0x01 | timestamp i64 | data f64 [| channel length u8 | channel]
+//   AVG:     0x02 | queued message id i64 | timestamp i64 | data f64 | channel length u8 | channel
+//   ACK:     0x03 | queued message id i64
+//   DISCONN: 0x04
+// Alarm readings have no binary layout and are sent as text frames.
+const BINARY_SENSOR: u8 = 0x01;
+const BINARY_AVG: u8 = 0x02;
+const BINARY_ACK: u8 = 0x03;
+const BINARY_DISCONN: u8 = 0x04;
+
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn accepts(&self, frame: &Message) -> bool {
+        matches!(frame, Message::Binary(_))
+    }
+
+    fn encode(&self, record: &Record) -> Option<Message> {
+        let mut out = Vec::new();
+
+        match record {
+            Record::Sensor {
+                timestamp,
+                data,
+                channel,
+                alarm: false,
+            } => {
+                out.push(BINARY_SENSOR);
+                out.extend(timestamp.to_be_bytes());
+                out.extend(data.to_be_bytes());
+                if let Some(channel) = channel {
+                    out.push(u8::try_from(channel.len()).ok()?);
+                    out.extend(channel.as_bytes());
+                }
+            }
+            Record::Sensor { alarm: true, .. } => return None,
+            Record::Avg {
+                id,
+                timestamp,
+                data,
+                channel,
+            } => {
+                out.push(BINARY_AVG);
+                out.extend(id.to_be_bytes());
+                out.extend(timestamp.to_be_bytes());
+                out.extend(data.to_be_bytes());
+                out.push(u8::try_from(channel.len()).ok()?);
+                out.extend(channel.as_bytes());
+            }
+            Record::Ack { id } => {
+                out.push(BINARY_ACK);
+                out.extend(id.to_be_bytes());
+            }
+            Record::Disconn => out.push(BINARY_DISCONN),
+        }
+
+        Some(Message::Binary(out))
+    }
+
+    fn decode(&self, frame: &Message) -> Result<Record, Box<dyn Error>> {
+        let Message::Binary(data) = frame else {
+            return Err("Expected a binary frame".into());
+        };
+        let (kind, body) = data.split_first().ok_or("Empty binary message")?;
+
+        match (*kind, body.len()) {
+            (BINARY_SENSOR, 16) => Ok(Record::Sensor {
+                timestamp: i64::from_be_bytes(body[0..8].try_into()?),
+                data: f64::from_be_bytes(body[8..16].try_into()?),
+                channel: None,
+                alarm: false,
+            }),
+            (BINARY_SENSOR, len) if len > 17 && body[16] as usize == len - 17 => {
+                Ok(Record::Sensor {
+                    timestamp: i64::from_be_bytes(body[0..8].try_into()?),
+                    data: f64::from_be_bytes(body[8..16].try_into()?),
+                    channel: Some(std::str::from_utf8(&body[17..])?.to_string()),
+                    alarm: false,
+                })
+            }
+            (BINARY_AVG, len) if len > 25 && body[24] as usize == len - 25 => Ok(Record::Avg {
+                id: i64::from_be_bytes(body[0..8].try_into()?),
+                timestamp: i64::from_be_bytes(body[8..16].try_into()?),
+                data: f64::from_be_bytes(body[16..24].try_into()?),
+                channel: std::str::from_utf8(&body[25..])?.to_string(),
+            }),
+            (BINARY_ACK, 8) => Ok(Record::Ack {
+                id: i64::from_be_bytes(body.try_into()?),
+            }),
+            (BINARY_DISCONN, 0) => Ok(Record::Disconn),
+            _ => Err("Invalid binary message".into()),
+        }
+    }
+}
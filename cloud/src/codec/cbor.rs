@@ -0,0 +1,26 @@
+use axum::extract::ws::Message;
+use std::error::Error;
+
+use super::{Codec, Record};
+
+// records as CBOR maps in binary frames, with the same fields as the JSON codec
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn accepts(&self, frame: &Message) -> bool {
+        matches!(frame, Message::Binary(_))
+    }
+
+    fn encode(&self, record: &Record) -> Option<Message> {
+        let mut out = Vec::new();
+        ciborium::ser::into_writer(record, &mut out).ok()?;
+        Some(Message::Binary(out))
+    }
+
+    fn decode(&self, frame: &Message) -> Result<Record, Box<dyn Error>> {
+        let Message::Binary(data) = frame else {
+            return Err("Expected a binary frame".into());
+        };
+        Ok(ciborium::de::from_reader(data.as_slice())?)
+    }
+}
@@ -0,0 +1,24 @@
+use axum::extract::ws::Message;
+use std::error::Error;
+
+use super::{Codec, Record};
+
+// records as JSON objects in text frames, e.g. {"type":"ack","id":42}
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn accepts(&self, frame: &Message) -> bool {
+        matches!(frame, Message::Text(text) if text.starts_with('{'))
+    }
+
+    fn encode(&self, record: &Record) -> Option<Message> {
+        serde_json::to_string(record).ok().map(Message::Text)
+    }
+
+    fn decode(&self, frame: &Message) -> Result<Record, Box<dyn Error>> {
+        let Message::Text(text) = frame else {
+            return Err("Expected a text frame".into());
+        };
+        Ok(serde_json::from_str(text)?)
+    }
+}
@@ -0,0 +1,242 @@
+use axum::extract::ws::Message;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, io::Read};
+
+use crate::protocols::{Compression, Encoding, TimestampFormat, DEFAULT_CHANNEL};
+
+#[cfg(feature = "binary")]
+mod binary;
+#[cfg(feature = "cbor")]
+mod cbor;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+
+// header of base64 encoded compressed text frames
+const COMPRESSED_HEADER: &str = "ZSTD#";
+// messages in a batch are separated by newlines before compression
+const BATCH_SEPARATOR: char = '\n';
+const ZSTD_LEVEL: i32 = 3;
+
+// how the messages of a connection are framed, negotiated by the client in the CONN message
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Framing {
+    pub compression: Compression,
+    pub encoding: Encoding,
+    // how SENSOR timestamps are written, applied when the decoded messages are parsed
+    pub timestamps: TimestampFormat,
+    // longest decompressed batch accepted, None for no limit
+    pub max_batch_bytes: Option<usize>,
+}
+
+// a compressed batch decompressed to more than Framing::max_batch_bytes
+#[derive(Debug)]
+pub struct BatchTooLarge;
+
+impl fmt::Display for BatchTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "decompressed batch too large")
+    }
+}
+
+impl Error for BatchTooLarge {}
+
+// structured form of the SENSOR, AVG, ACK and DISCONN messages, which have an encoding
+// other than text. the uid is implied by the connection and never encoded.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Sensor {
+        timestamp: i64,
+        data: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        alarm: bool,
+    },
+    Avg {
+        id: i64,
+        timestamp: i64,
+        data: f64,
+        channel: String,
+    },
+    Ack {
+        id: i64,
+    },
+    Disconn,
+}
+
+impl Record {
+    // structured form of a text protocol message, None if it has none
+    pub fn parse(msg: &str) -> Option<Self> {
+        let parts: Vec<&str> = msg.split('#').collect();
+
+        match parts.as_slice() {
+            ["SENSOR", _, timestamp, data, rest @ ..] if rest.len() <= 2 => Some(Self::Sensor {
+                timestamp: timestamp.parse().ok()?,
+                data: data.parse().ok()?,
+                channel: rest.first().map(|c| c.to_string()),
+                alarm: match rest.get(1) {
+                    None => false,
+                    Some(&"alarm") => true,
+                    Some(_) => return None,
+                },
+            }),
+            ["AVG", timestamp, data, channel, id] => Some(Self::Avg {
+                id: id.parse().ok()?,
+                timestamp: timestamp.parse().ok()?,
+                data: data.parse().ok()?,
+                channel: channel.to_string(),
+            }),
+            ["ACK", _, id] => Some(Self::Ack {
+                id: id.parse().ok()?,
+            }),
+            ["DISCONN", _] => Some(Self::Disconn),
+            _ => None,
+        }
+    }
+
+    // text protocol message of the connection with the given uid
+    pub fn to_text(&self, uid: &str) -> String {
+        match self {
+            Self::Sensor {
+                timestamp,
+                data,
+                channel,
+                alarm,
+            } => {
+                let mut msg = format!("SENSOR#{}#{}#{}", uid, timestamp, data);
+                if channel.is_some() || *alarm {
+                    msg = format!("{}#{}", msg, channel.as_deref().unwrap_or(DEFAULT_CHANNEL));
+                }
+                if *alarm {
+                    msg.push_str("#alarm");
+                }
+                msg
+            }
+            Self::Avg {
+                id,
+                timestamp,
+                data,
+                channel,
+            } => format!("AVG#{}#{}#{}#{}", timestamp, data, channel, id),
+            Self::Ack { id } => format!("ACK#{}#{}", uid, id),
+            Self::Disconn => format!("DISCONN#{}", uid),
+        }
+    }
+}
+
+// a wire format for records, selected by the encoding option of the CONN message
+pub trait Codec: Sync {
+    // whether a frame is in this format, other frames are read as text protocol messages
+    fn accepts(&self, frame: &Message) -> bool;
+
+    // None if the record can't be represented, it is sent as text then
+    fn encode(&self, record: &Record) -> Option<Message>;
+
+    fn decode(&self, frame: &Message) -> Result<Record, Box<dyn Error>>;
+}
+
+// codec of an encoding, None for text and for formats this build does not include
+pub fn codec_for(encoding: Encoding) -> Option<&'static dyn Codec> {
+    match encoding {
+        Encoding::Text => None,
+        #[cfg(feature = "binary")]
+        Encoding::Binary => Some(&binary::BinaryCodec),
+        #[cfg(feature = "json")]
+        Encoding::Json => Some(&json::JsonCodec),
+        #[cfg(feature = "cbor")]
+        Encoding::Cbor => Some(&cbor::CborCodec),
+        #[cfg(feature = "protobuf")]
+        Encoding::Protobuf => Some(&protobuf::ProtobufCodec),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+// turns a batch of outgoing protocol messages into websocket frames
+pub fn encode(msgs: &[String], framing: Framing) -> Result<Vec<Message>, Box<dyn Error>> {
+    match framing.compression {
+        Compression::None => Ok(msgs
+            .iter()
+            .map(|m| encode_single(m, framing.encoding))
+            .collect()),
+        Compression::Zstd => Ok(vec![Message::Binary(compress(msgs)?)]),
+        Compression::ZstdBase64 => Ok(vec![Message::Text(format!(
+            "{}{}",
+            COMPRESSED_HEADER,
+            STANDARD.encode(compress(msgs)?)
+        ))]),
+    }
+}
+
+// turns an incoming websocket frame of the connection with the given uid
+// into the text protocol messages it contains
+pub fn decode(msg: Message, framing: Framing, uid: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    if let Some(codec) = codec_for(framing.encoding) {
+        if codec.accepts(&msg) {
+            return Ok(vec![codec.decode(&msg)?.to_text(uid)]);
+        }
+    }
+
+    if framing.compression == Compression::None {
+        return Ok(vec![msg.into_text()?]);
+    }
+
+    let limit = framing.max_batch_bytes;
+    match msg {
+        Message::Binary(data) => decompress(&data, limit),
+        Message::Text(text) => match text.strip_prefix(COMPRESSED_HEADER) {
+            Some(encoded) => decompress(&STANDARD.decode(encoded)?, limit),
+            None => Ok(vec![text]),
+        },
+        other => Ok(vec![other.into_text()?]),
+    }
+}
+
+// payload size of a websocket frame in bytes
+pub fn frame_len(msg: &Message) -> usize {
+    match msg {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |f| 2 + f.reason.len()),
+    }
+}
+
+fn encode_single(msg: &str, encoding: Encoding) -> Message {
+    let frame = codec_for(encoding)
+        .zip(Record::parse(msg))
+        .and_then(|(codec, record)| codec.encode(&record));
+
+    frame.unwrap_or_else(|| Message::Text(msg.to_string()))
+}
+
+fn compress(msgs: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let batch = msgs.join(&BATCH_SEPARATOR.to_string());
+    Ok(zstd::encode_all(batch.as_bytes(), ZSTD_LEVEL)?)
+}
+
+// stops decompressing past the limit, so a small frame can't blow up in memory
+fn decompress(data: &[u8], limit: Option<usize>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut decoder = zstd::stream::read::Decoder::new(data)?;
+    let mut batch = Vec::new();
+    match limit {
+        Some(limit) => {
+            decoder.take(limit as u64 + 1).read_to_end(&mut batch)?;
+            if batch.len() > limit {
+                return Err(Box::new(BatchTooLarge));
+            }
+        }
+        None => {
+            decoder.read_to_end(&mut batch)?;
+        }
+    }
+    let batch = String::from_utf8(batch)?;
+    Ok(batch
+        .split(BATCH_SEPARATOR)
+        .filter(|m| !m.is_empty())
+        .map(|m| m.to_string())
+        .collect())
+}
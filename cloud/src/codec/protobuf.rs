@@ -0,0 +1,134 @@
+use axum::extract::ws::Message;
+use prost::Message as _;
+use std::error::Error;
+
+use super::{Codec, Record};
+
+// records as protobuf messages in binary frames, the schema is
+//
+//   message Envelope {
+//     oneof body {
+//       Sensor sensor = 1;
+//       Avg avg = 2;
+//       Ack ack = 3;
+//       Disconn disconn = 4;
+//     }
+//   }
+//   message Sensor { int64 timestamp = 1; double data = 2; optional string channel = 3; bool alarm = 4; }
+//   message Avg { int64 id = 1; int64 timestamp = 2; double data = 3; string channel = 4; }
+//   message Ack { int64 id = 1; }
+//   message Disconn {}
+pub struct ProtobufCodec;
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Envelope {
+    #[prost(oneof = "Body", tags = "1, 2, 3, 4")]
+    body: Option<Body>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Body {
+    #[prost(message, tag = "1")]
+    Sensor(Sensor),
+    #[prost(message, tag = "2")]
+    Avg(Avg),
+    #[prost(message, tag = "3")]
+    Ack(Ack),
+    #[prost(message, tag = "4")]
+    Disconn(Disconn),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Sensor {
+    #[prost(int64, tag = "1")]
+    timestamp: i64,
+    #[prost(double, tag = "2")]
+    data: f64,
+    #[prost(string, optional, tag = "3")]
+    channel: Option<String>,
+    #[prost(bool, tag = "4")]
+    alarm: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Avg {
+    #[prost(int64, tag = "1")]
+    id: i64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+    #[prost(double, tag = "3")]
+    data: f64,
+    #[prost(string, tag = "4")]
+    channel: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Ack {
+    #[prost(int64, tag = "1")]
+    id: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Disconn {}
+
+impl Codec for ProtobufCodec {
+    fn accepts(&self, frame: &Message) -> bool {
+        matches!(frame, Message::Binary(_))
+    }
+
+    fn encode(&self, record: &Record) -> Option<Message> {
+        let body = match record.clone() {
+            Record::Sensor {
+                timestamp,
+                data,
+                channel,
+                alarm,
+            } => Body::Sensor(Sensor {
+                timestamp,
+                data,
+                channel,
+                alarm,
+            }),
+            Record::Avg {
+                id,
+                timestamp,
+                data,
+                channel,
+            } => Body::Avg(Avg {
+                id,
+                timestamp,
+                data,
+                channel,
+            }),
+            Record::Ack { id } => Body::Ack(Ack { id }),
+            Record::Disconn => Body::Disconn(Disconn {}),
+        };
+
+        let envelope = Envelope { body: Some(body) };
+        Some(Message::Binary(envelope.encode_to_vec()))
+    }
+
+    fn decode(&self, frame: &Message) -> Result<Record, Box<dyn Error>> {
+        let Message::Binary(data) = frame else {
+            return Err("Expected a binary frame".into());
+        };
+
+        match Envelope::decode(data.as_slice())?.body {
+            Some(Body::Sensor(sensor)) => Ok(Record::Sensor {
+                timestamp: sensor.timestamp,
+                data: sensor.data,
+                channel: sensor.channel,
+                alarm: sensor.alarm,
+            }),
+            Some(Body::Avg(avg)) => Ok(Record::Avg {
+                id: avg.id,
+                timestamp: avg.timestamp,
+                data: avg.data,
+                channel: avg.channel,
+            }),
+            Some(Body::Ack(ack)) => Ok(Record::Ack { id: ack.id }),
+            Some(Body::Disconn(_)) => Ok(Record::Disconn),
+            None => Err("Empty protobuf message".into()),
+        }
+    }
+}
@@ -0,0 +1,953 @@
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use std::{env, fmt, str::FromStr};
+
+use crate::{forwarding::ForwardingMode, send_queue::QueuePolicy};
+
+// runtime configuration, read from environment variables (or the .env file)
+#[derive(Debug)]
+pub struct Config {
+    // port the server listens on
+    pub port: u16,
+    // tells websockets, the REST api and the line protocol apart on the port,
+    // for sites whose firewalls only let a single port through. can't be combined with TLS
+    pub sniff_protocols: bool,
+    // port devices speaking the line protocol connect to, unset only accepts them when sniffing
+    pub line_port: Option<u16>,
+    // port of the gRPC api, only served by builds with the grpc feature
+    pub grpc_port: Option<u16>,
+    // UDP port of the CoAP listener for devices that can't keep a websocket open
+    pub coap_port: Option<u16>,
+    // advertises the websocket endpoint as <name>._foghw._tcp.local over mDNS, so devices on the
+    // local network find the server without a configured address. unset disables the responder
+    pub mdns_name: Option<String>,
+    // address advertised over mDNS, unset uses the one of the interface multicast leaves from
+    pub mdns_address: Option<std::net::Ipv4Addr>,
+    // readings of a channel aggregated into an AVG message, the most recent ones
+    pub avg_window_size: i64,
+    // seconds between ticks of the AVG service, also the length of backfilled windows
+    pub avg_interval_secs: u64,
+    // how the readings of a window are aggregated
+    pub avg_aggregation: Aggregation,
+    // which timestamp orders and windows the readings aggregated, the one of the device or the
+    // time the server received them
+    pub avg_timestamp: AggregationTimestamp,
+    // AVG values closer than this to the last emitted one are not queued, unset disables suppression
+    pub avg_epsilon: Option<f64>,
+    // seconds after which an AVG message is queued even if its value did not change
+    pub avg_heartbeat_secs: i64,
+    // longest gap in the AVG history that is backfilled on startup
+    pub avg_backfill_max_secs: i64,
+    // decimal places computed values are rounded to in AVG messages and the REST api,
+    // unset keeps every digit
+    pub value_decimals: Option<u32>,
+    // AVG messages whose newest reading is older than this are reported as stale
+    pub avg_stale_secs: i64,
+    // whether readings are forwarded to the webhooks besides the averages, raw or aggregated
+    pub forwarding_mode: ForwardingMode,
+    // seconds the readings of a device and channel are aggregated over before they are forwarded
+    pub forwarding_window_secs: u64,
+    // how the readings of a forwarding window are aggregated
+    pub forwarding_aggregation: Aggregation,
+    // SENSOR messages each connection may send per second on average
+    pub rate_limit_per_sec: f64,
+    // SENSOR messages each connection may send in a burst
+    pub rate_limit_burst: f64,
+    // SENSOR messages a fog node may send per second on average, for all the devices
+    // behind it together
+    pub fog_rate_limit_per_sec: f64,
+    // SENSOR messages a fog node may send in a burst, for all the devices behind it together
+    pub fog_rate_limit_burst: f64,
+    // what happens to SENSOR messages exceeding the rate limit
+    pub rate_limit_mode: RateLimitMode,
+    // what happens to a second session of the same uid
+    pub duplicate_sessions: DuplicateSessions,
+    // seconds a drained device has to acknowledge its pending deliveries before it is disconnected
+    pub drain_timeout_secs: u64,
+    // longest websocket frame, and decompressed batch, accepted from a device in bytes. longer
+    // ones are rejected with an ERR
+    pub max_frame_bytes: usize,
+    // seconds connected devices get on shutdown to acknowledge their pending deliveries
+    pub shutdown_grace_secs: u64,
+    // messages waiting to be sent to a device, also the most deliveries fetched from the db at once
+    pub send_queue_capacity: usize,
+    // what happens when the send queue of a slow device is full
+    pub send_queue_policy: QueuePolicy,
+    // consecutive invalid messages after which a connection is closed
+    pub max_consecutive_errors: u32,
+    // seconds between persisted bandwidth samples of a connection
+    pub bandwidth_sample_secs: u64,
+    // readings written to the db at the same time before routine telemetry is shed
+    pub ingest_max_in_flight: usize,
+    // seconds between refreshes of the devices needing attention
+    pub attention_refresh_secs: u64,
+    // seconds without a reading after which a device counts as offline
+    pub offline_after_secs: i64,
+    // seconds a device clock may differ from the server clock
+    pub max_clock_skew_secs: i64,
+    // what happens to readings whose timestamp is off the server clock by more than
+    // max_timestamp_skew_secs, like readings of devices with a reset RTC
+    pub timestamp_policy: TimestampPolicy,
+    pub max_timestamp_skew_secs: i64,
+    // bytes a device may send and receive per day, unset disables the quota
+    pub bandwidth_quota_bytes: Option<i64>,
+    // broker every stored reading is published to, nats://<host>:<port> or
+    // kafka://<broker>,<broker>,... requires the nats or kafka feature, unset disables publishing
+    pub stream_url: Option<StreamUrl>,
+    // NATS subject prefix or Kafka topic readings are published to
+    pub stream_topic: String,
+    // downstream systems every reading and average is exported to, separated by commas.
+    // file://<path> appends them to a file as ndjson, influx://<host>:<port>/<write path>
+    // posts them as line protocol over plain http. unset disables the sinks
+    pub sink_urls: Option<SinkUrls>,
+    // token of the influx sinks, sent as Authorization: Token <token>
+    pub sink_influx_token: Option<Secret>,
+    // events buffered per sink while it is unreachable, newer events are dropped beyond it
+    pub sink_buffer: usize,
+    // attributes of the device registry, like site or asset_tag, attached to every reading as
+    // it is stored and passed on with it to the exports and sinks. separated by commas, unset
+    // attaches none
+    pub enrich_attributes: Option<AttributeNames>,
+    // bytes the webhooks and the streaming platform may be sent per hour, for sites on metered
+    // links. alerts always go out, readings wait for the next hour first. unset is unlimited
+    pub upstream_budget_bytes: Option<u64>,
+    // redis used to cache hot reads, unset disables the cache
+    pub redis_url: Option<String>,
+    // seconds cached reads are served before they are read from the db again
+    pub cache_ttl_secs: usize,
+    // seconds between runs of the retention service
+    pub retention_interval_secs: u64,
+    pub received_retention: RetentionPolicy,
+    // percent of the received row cap at which the oldest readings are summarized per minute,
+    // so long gaps in the upstream keep their shape instead of being pruned. unset only prunes
+    pub received_summarize_percent: Option<u8>,
+    pub queued_retention: RetentionPolicy,
+    pub delivered_retention: RetentionPolicy,
+    // seconds between runs of the rollup service
+    pub rollup_interval_secs: u64,
+    // where readings and rollups are archived as parquet files, unset disables archiving
+    pub archive_url: Option<ArchiveUrl>,
+    // seconds between scheduled archive runs, unset only archives when an admin asks for it
+    pub archive_interval_secs: Option<u64>,
+    // whether the archived raw readings are deleted from the db, rollups are always kept
+    pub archive_prune: bool,
+    // url fired alerts are POSTed to as JSON, plain http only
+    pub alert_webhook_url: Option<String>,
+    // urls every AVG, ALERT and rule action event is POSTed to as signed JSON, separated by commas.
+    // a url may be followed by fallbacks separated by |, events go to them while the urls before
+    // can't be reached. plain http only, unset disables the webhooks
+    pub webhook_urls: Option<WebhookUrls>,
+    // key of the HMAC-SHA256 signature of webhook payloads, required with webhook urls
+    pub webhook_secret: Option<Secret>,
+    // attempts at delivering an event to a webhook before it is given up
+    pub webhook_max_attempts: u32,
+    // milliseconds before the first retry of a failed delivery, doubled with every retry
+    pub webhook_backoff_ms: u64,
+    // milliseconds between health checks of the primary url of a webhook on a fallback,
+    // its events go back to it once it answers
+    pub webhook_health_ms: u64,
+    // server certificate chain and private key, PEM. setting them serves wss:// directly,
+    // the files are read again on SIGHUP.
+    // with the CA signing device certificates the server requires mutual TLS,
+    // devices may then only claim the uid of their certificate
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    // secret the signing keys of devices are derived from. setting it requires every message
+    // after CONN to be SIGNED, unset accepts unsigned messages
+    pub signing_secret: Option<Secret>,
+    // seconds the timestamp of a signed message may differ from the server clock,
+    // nonces are remembered for as long
+    pub replay_window_secs: i64,
+    // bearer token of the admin endpoints, unset disables them
+    pub admin_token: Option<Secret>,
+    // requires an API key on the REST api, see auth. the keys are managed with the admin token
+    pub api_auth: bool,
+    // unix timestamp the unversioned /api routes go away at, announced in their Sunset header
+    pub api_sunset: Option<i64>,
+    // connections of the sqlite pool
+    pub db_max_connections: u32,
+    // milliseconds a connection waits for the lock of another writer before failing
+    pub db_busy_timeout_ms: u64,
+    // WAL lets readers go on while a device writes
+    pub db_journal_mode: SqliteJournalMode,
+    // how often sqlite syncs to disk, normal is safe in WAL mode
+    pub db_synchronous: SqliteSynchronous,
+    // milliseconds after which a db query is logged and reported as slow
+    pub slow_query_ms: u64,
+    // how log lines are written, json for log collectors like Loki or Elasticsearch
+    pub log_format: LogFormat,
+    // milliseconds readings may take from ingest to the delivery of their AVG, not counting the
+    // averaging window. setting it enables soft real-time mode, unset disables it
+    pub latency_target_ms: Option<u64>,
+    // serial ports local sensors are read from, as <path>=<uid>[@<baud rate>] separated by commas.
+    // requires the serial feature
+    pub serial_ports: Option<SerialPorts>,
+    // I2C sensors attached to the server, as <driver>:<bus>:<address>=<uid>[@<interval secs>]
+    // separated by commas. requires the feature of each driver
+    pub local_sensors: Option<LocalSensors>,
+    // seconds between samples of local sensors without an interval of their own
+    pub local_sensor_interval_secs: u64,
+    // longest random delay of a sample, so sensors sharing a bus don't all wake at once
+    pub local_sensor_jitter_ms: u64,
+    // calibration and valid range of channels of the local sensors, as
+    // <uid>/<channel>:<option>=<value>,... separated by semicolons.
+    // options are scale, offset, min and max
+    pub local_sensor_channels: Option<LocalChannels>,
+}
+
+// a value that is left out when the configuration is logged
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+// how long the rows of a table are kept, unset limits keep rows forever
+#[derive(Debug, Default)]
+pub struct RetentionPolicy {
+    pub max_age_secs: Option<i64>,
+    pub max_rows: Option<i64>,
+}
+
+impl RetentionPolicy {
+    // reads <PREFIX>_MAX_AGE_SECS and <PREFIX>_MAX_ROWS
+    fn from_env(prefix: &str) -> Self {
+        Self {
+            max_age_secs: env_opt(&format!("{}_MAX_AGE_SECS", prefix)),
+            max_rows: env_opt(&format!("{}_MAX_ROWS", prefix)),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_secs.is_none() && self.max_rows.is_none()
+    }
+}
+
+// function the AVG service aggregates the readings of a window with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Mean,
+    Median,
+    Min,
+    Max,
+}
+
+// a reading of a window, or a summary standing in for count readings between min and max
+// with value as their mean
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub value: f64,
+    pub count: i64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Sample {
+    pub fn raw(value: f64) -> Self {
+        Self {
+            value,
+            count: 1,
+            min: value,
+            max: value,
+        }
+    }
+}
+
+impl Aggregation {
+    // None for an empty window
+    pub fn apply(&self, values: &[f64]) -> Option<f64> {
+        let samples: Vec<Sample> = values.iter().copied().map(Sample::raw).collect();
+        self.apply_samples(&samples)
+    }
+
+    // summaries weigh as much as the readings they stand in for
+    pub fn apply_samples(&self, samples: &[Sample]) -> Option<f64> {
+        if samples.is_empty() {
+            return None;
+        }
+        let total: i64 = samples.iter().map(|s| s.count.max(1)).sum();
+        let value = match self {
+            Aggregation::Mean => {
+                samples
+                    .iter()
+                    .map(|s| s.value * s.count.max(1) as f64)
+                    .sum::<f64>()
+                    / total as f64
+            }
+            Aggregation::Median => {
+                let mut sorted = samples.to_vec();
+                sorted.sort_by(|a, b| a.value.total_cmp(&b.value));
+                // the value of the n-th reading in order, a summary counts as count readings
+                let nth = |n: i64| {
+                    let mut seen = 0;
+                    for sample in &sorted {
+                        seen += sample.count.max(1);
+                        if n < seen {
+                            return sample.value;
+                        }
+                    }
+                    sorted[sorted.len() - 1].value
+                };
+                if total % 2 == 0 {
+                    (nth(total / 2 - 1) + nth(total / 2)) / 2.0
+                } else {
+                    nth(total / 2)
+                }
+            }
+            Aggregation::Min => samples.iter().map(|s| s.min).fold(f64::INFINITY, f64::min),
+            Aggregation::Max => samples
+                .iter()
+                .map(|s| s.max)
+                .fold(f64::NEG_INFINITY, f64::max),
+        };
+        Some(value)
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "median" => Ok(Self::Median),
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            _ => Err(format!("Invalid aggregation: {}", s)),
+        }
+    }
+}
+
+// timestamp of the readings the AVG service aggregates by
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregationTimestamp {
+    // the timestamp the device sent
+    #[default]
+    Device,
+    // the time the server received the reading, for devices whose clocks can't be trusted.
+    // readings stored before the receive time was recorded fall back to the device timestamp
+    Received,
+}
+
+impl AggregationTimestamp {
+    // the column of received_messages holding the timestamp
+    pub fn column(&self) -> &'static str {
+        match self {
+            AggregationTimestamp::Device => "created_at",
+            AggregationTimestamp::Received => "COALESCE(received_at, created_at)",
+        }
+    }
+}
+
+impl FromStr for AggregationTimestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "device" => Ok(Self::Device),
+            "received" => Ok(Self::Received),
+            _ => Err(format!("Invalid aggregation timestamp: {}", s)),
+        }
+    }
+}
+
+// what happens to a reading whose timestamp is too far off the server clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampPolicy {
+    // stored with the timestamp of the device
+    Accept,
+    // stored with the time the server received it instead
+    Clamp,
+    // not stored, the device gets a CLOCK_SKEW error
+    Reject,
+}
+
+impl FromStr for TimestampPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(Self::Accept),
+            "clamp" => Ok(Self::Clamp),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("Invalid timestamp policy: {}", s)),
+        }
+    }
+}
+
+// a serial port a local sensor is read from, readings are stored under the uid of the port
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialPort {
+    pub path: String,
+    pub uid: String,
+    pub baud_rate: u32,
+}
+
+impl FromStr for SerialPort {
+    type Err = String;
+
+    // <path>=<uid>[@<baud rate>], 9600 baud by default
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid serial port: {}", s))?;
+        let (uid, baud_rate) = match rest.split_once('@') {
+            Some((uid, baud_rate)) => (
+                uid,
+                baud_rate
+                    .parse()
+                    .map_err(|_| format!("Invalid baud rate: {}", baud_rate))?,
+            ),
+            None => (rest, 9600),
+        };
+        if path.is_empty() || uuid::Uuid::try_parse(uid).is_err() {
+            return Err(format!("Invalid serial port: {}", s));
+        }
+        Ok(Self {
+            path: path.to_string(),
+            uid: uid.to_string(),
+            baud_rate,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialPorts(pub Vec<SerialPort>);
+
+impl FromStr for SerialPorts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|port| port.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+// drivers of the sensors that can be attached to the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorDriver {
+    // temperature, pressure and humidity
+    Bme280,
+    // four channel analog to digital converter
+    Ads1115,
+}
+
+impl FromStr for SensorDriver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bme280" => Ok(Self::Bme280),
+            "ads1115" => Ok(Self::Ads1115),
+            _ => Err(format!("Invalid sensor driver: {}", s)),
+        }
+    }
+}
+
+// a sensor on an I2C bus of the server, readings are stored under its uid
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalSensor {
+    pub driver: SensorDriver,
+    pub bus: String,
+    pub address: u16,
+    pub uid: String,
+    // seconds between samples, unset uses LOCAL_SENSOR_INTERVAL_SECS
+    pub interval_secs: Option<u64>,
+}
+
+impl FromStr for LocalSensor {
+    type Err = String;
+
+    // <driver>:<bus>:<address>=<uid>[@<interval secs>], the address in hex with 0x or in decimal
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid local sensor: {}", s);
+        let (device, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let (uid, interval_secs) = match rest.split_once('@') {
+            Some((uid, interval)) => match interval.parse() {
+                Ok(secs) if secs > 0 => (uid, Some(secs)),
+                _ => return Err(format!("Invalid sampling interval: {}", interval)),
+            },
+            None => (rest, None),
+        };
+        let (driver, rest) = device.split_once(':').ok_or_else(invalid)?;
+        let (bus, address) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        let address = match address.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .map_err(|_| format!("Invalid I2C address: {}", address))?;
+        if bus.is_empty() || uuid::Uuid::try_parse(uid).is_err() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            driver: driver.parse()?,
+            bus: bus.to_string(),
+            address,
+            uid: uid.to_string(),
+            interval_secs,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalSensors(pub Vec<LocalSensor>);
+
+impl FromStr for LocalSensors {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|sensor| sensor.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+// corrects the readings of a channel of a local sensor and rejects those out of its range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelCalibration {
+    pub scale: f64,
+    pub offset: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            offset: 0.0,
+            min: None,
+            max: None,
+        }
+    }
+}
+
+impl ChannelCalibration {
+    // the calibrated value, None if it is not a valid reading of the channel
+    pub fn apply(&self, value: f64) -> Option<f64> {
+        let value = value * self.scale + self.offset;
+        let valid = value.is_finite()
+            && self.min.is_none_or(|min| value >= min)
+            && self.max.is_none_or(|max| value <= max);
+        valid.then_some(value)
+    }
+}
+
+// the calibration of a channel of a local sensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalChannel {
+    pub uid: String,
+    pub channel: String,
+    pub calibration: ChannelCalibration,
+}
+
+impl FromStr for LocalChannel {
+    type Err = String;
+
+    // <uid>/<channel>:<option>=<value>,..., with the options scale, offset, min and max
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid local sensor channel: {}", s);
+        let (name, options) = s.split_once(':').ok_or_else(invalid)?;
+        let (uid, channel) = name.split_once('/').ok_or_else(invalid)?;
+        if channel.is_empty() || uuid::Uuid::try_parse(uid).is_err() {
+            return Err(invalid());
+        }
+
+        let mut calibration = ChannelCalibration::default();
+        for option in options.split(',') {
+            let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+            let value: f64 = value
+                .parse()
+                .map_err(|_| format!("Invalid value of {}: {}", key, value))?;
+            match key {
+                "scale" => calibration.scale = value,
+                "offset" => calibration.offset = value,
+                "min" => calibration.min = Some(value),
+                "max" => calibration.max = Some(value),
+                _ => return Err(format!("Unknown channel option: {}", key)),
+            }
+        }
+        Ok(Self {
+            uid: uid.to_string(),
+            channel: channel.to_string(),
+            calibration,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalChannels(pub Vec<LocalChannel>);
+
+impl FromStr for LocalChannels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(|channel| channel.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookUrls(pub Vec<String>);
+
+impl FromStr for WebhookUrls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|webhook| {
+                webhook
+                    .split('|')
+                    .map(|url| {
+                        let url = url.trim();
+                        // the client only speaks plain http
+                        if url.starts_with("http://") {
+                            Ok(url)
+                        } else {
+                            Err(format!("Invalid webhook url, plain http only: {}", url))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|urls| urls.join("|"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+// storage the parquet archive is written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveUrl {
+    // a local directory
+    Dir(String),
+    // a bucket of S3 or S3-compatible storage, credentials and endpoint come from the AWS_*
+    // variables. the prefix is empty or ends with a slash
+    S3 { bucket: String, prefix: String },
+}
+
+impl FromStr for ArchiveUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(location) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+            if bucket.is_empty() {
+                return Err(format!("Invalid archive url, no bucket: {}", s));
+            }
+            let prefix = prefix.trim_matches('/');
+            Ok(Self::S3 {
+                bucket: bucket.to_string(),
+                prefix: if prefix.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", prefix)
+                },
+            })
+        } else {
+            let dir = s.strip_prefix("file://").unwrap_or(s);
+            if dir.is_empty() {
+                return Err(format!("Invalid archive url: {}", s));
+            }
+            Ok(Self::Dir(dir.to_string()))
+        }
+    }
+}
+
+// streaming platform received readings are published to
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamUrl {
+    // the url of a NATS server
+    Nats(String),
+    // the bootstrap servers of a Kafka cluster, separated by commas
+    Kafka(String),
+}
+
+impl FromStr for StreamUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("nats://") {
+            Ok(Self::Nats(s.to_string()))
+        } else if let Some(brokers) = s.strip_prefix("kafka://").filter(|b| !b.is_empty()) {
+            Ok(Self::Kafka(brokers.to_string()))
+        } else {
+            Err(format!("Invalid stream url: {}", s))
+        }
+    }
+}
+
+// a downstream system readings and averages are exported to
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkUrl {
+    // a file the events are appended to
+    File(String),
+    // the http url of the write endpoint of InfluxDB
+    Influx(String),
+}
+
+impl FromStr for SinkUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file://").filter(|p| !p.is_empty()) {
+            Ok(Self::File(path.to_string()))
+        } else if let Some(url) = s.strip_prefix("influx://").filter(|u| !u.is_empty()) {
+            Ok(Self::Influx(format!("http://{}", url)))
+        } else {
+            Err(format!("Invalid sink url: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for SinkUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkUrl::File(path) => write!(f, "file://{}", path),
+            SinkUrl::Influx(url) => write!(f, "influx://{}", url.trim_start_matches("http://")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkUrls(pub Vec<SinkUrl>);
+
+impl FromStr for SinkUrls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|url| url.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+// fields of a reading an attribute can't be named after
+const RESERVED_ATTRIBUTES: [&str; 2] = ["uid", "channel"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeNames(pub Vec<String>);
+
+impl AttributeNames {
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|n| n == name)
+    }
+}
+
+impl FromStr for AttributeNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|name| {
+                let name = name.trim();
+                let valid = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+                if valid && !RESERVED_ATTRIBUTES.contains(&name) {
+                    Ok(name.to_string())
+                } else {
+                    Err(format!("Invalid attribute name: {}", name))
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitMode {
+    // excess messages are discarded
+    Drop,
+    // excess messages are delayed until the rate allows them
+    Throttle,
+}
+
+impl FromStr for RateLimitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "throttle" => Ok(Self::Throttle),
+            _ => Err(format!("Invalid rate limit mode: {}", s)),
+        }
+    }
+}
+
+// what happens when a device connects while it still has a live session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateSessions {
+    // the new session closes the old one, e.g. after the device lost its connection unnoticed
+    KickOld,
+    // the new connection is turned away, so two devices can't fight over a uid
+    RejectNew,
+}
+
+impl FromStr for DuplicateSessions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick-old" => Ok(Self::KickOld),
+            "reject-new" => Ok(Self::RejectNew),
+            _ => Err(format!("Invalid duplicate session policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // one object per line, with the fields of the session and the message being handled
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Invalid log format: {}", s)),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            port: env_or("PORT", 3000),
+            sniff_protocols: env_or("SNIFF_PROTOCOLS", false),
+            line_port: env_opt("LINE_PORT"),
+            grpc_port: env_opt("GRPC_PORT"),
+            coap_port: env_opt("COAP_PORT"),
+            mdns_name: env_opt("MDNS_NAME"),
+            mdns_address: env_opt("MDNS_ADDRESS"),
+            avg_window_size: env_or("AVG_WINDOW_SIZE", 5),
+            avg_interval_secs: env_or("AVG_INTERVAL_SECS", 10),
+            avg_aggregation: env_or("AVG_AGGREGATION", Aggregation::Mean),
+            avg_timestamp: env_or("AVG_TIMESTAMP", AggregationTimestamp::Device),
+            avg_epsilon: env_opt("AVG_EPSILON"),
+            avg_heartbeat_secs: env_or("AVG_HEARTBEAT_SECS", 60),
+            avg_backfill_max_secs: env_or("AVG_BACKFILL_MAX_SECS", 3600),
+            value_decimals: env_opt("VALUE_DECIMALS"),
+            avg_stale_secs: env_or("AVG_STALE_SECS", 300),
+            forwarding_mode: env_or("FORWARDING_MODE", ForwardingMode::Off),
+            forwarding_window_secs: env_or("FORWARDING_WINDOW_SECS", 60),
+            forwarding_aggregation: env_or("FORWARDING_AGGREGATION", Aggregation::Mean),
+            rate_limit_per_sec: env_or("RATE_LIMIT_PER_SEC", 5.0),
+            rate_limit_burst: env_or("RATE_LIMIT_BURST", 10.0),
+            fog_rate_limit_per_sec: env_or("FOG_RATE_LIMIT_PER_SEC", 100.0),
+            fog_rate_limit_burst: env_or("FOG_RATE_LIMIT_BURST", 200.0),
+            rate_limit_mode: env_or("RATE_LIMIT_MODE", RateLimitMode::Drop),
+            duplicate_sessions: env_or("DUPLICATE_SESSIONS", DuplicateSessions::KickOld),
+            drain_timeout_secs: env_or("DRAIN_TIMEOUT_SECS", 30),
+            max_frame_bytes: env_or("MAX_FRAME_BYTES", 64 * 1024),
+            shutdown_grace_secs: env_or("SHUTDOWN_GRACE_SECS", 10),
+            send_queue_capacity: env_or("SEND_QUEUE_CAPACITY", 256),
+            send_queue_policy: env_or("SEND_QUEUE_POLICY", QueuePolicy::Backpressure),
+            max_consecutive_errors: env_or("MAX_CONSECUTIVE_ERRORS", 5),
+            bandwidth_sample_secs: env_or("BANDWIDTH_SAMPLE_SECS", 60),
+            ingest_max_in_flight: env_or("INGEST_MAX_IN_FLIGHT", 256),
+            attention_refresh_secs: env_or("ATTENTION_REFRESH_SECS", 60),
+            offline_after_secs: env_or("OFFLINE_AFTER_SECS", 300),
+            max_clock_skew_secs: env_or("MAX_CLOCK_SKEW_SECS", 120),
+            timestamp_policy: env_or("TIMESTAMP_POLICY", TimestampPolicy::Accept),
+            max_timestamp_skew_secs: env_or("MAX_TIMESTAMP_SKEW_SECS", 86400),
+            bandwidth_quota_bytes: env_opt("BANDWIDTH_QUOTA_BYTES"),
+            stream_url: env_opt("STREAM_URL"),
+            stream_topic: env_or("STREAM_TOPIC", "readings".to_string()),
+            sink_urls: env_opt("SINK_URLS"),
+            sink_influx_token: env_opt("SINK_INFLUX_TOKEN"),
+            sink_buffer: env_or("SINK_BUFFER", 10_000),
+            enrich_attributes: env_opt("ENRICH_ATTRIBUTES"),
+            upstream_budget_bytes: env_opt("UPSTREAM_BUDGET_BYTES"),
+            redis_url: env_opt("REDIS_URL"),
+            cache_ttl_secs: env_or("CACHE_TTL_SECS", 30),
+            retention_interval_secs: env_or("RETENTION_INTERVAL_SECS", 3600),
+            received_retention: RetentionPolicy::from_env("RETENTION_RECEIVED"),
+            received_summarize_percent: env_opt("RETENTION_RECEIVED_SUMMARIZE_PERCENT"),
+            queued_retention: RetentionPolicy::from_env("RETENTION_QUEUED"),
+            delivered_retention: RetentionPolicy::from_env("RETENTION_DELIVERED"),
+            rollup_interval_secs: env_or("ROLLUP_INTERVAL_SECS", 300),
+            archive_url: env_opt("ARCHIVE_URL"),
+            archive_interval_secs: env_opt("ARCHIVE_INTERVAL_SECS"),
+            archive_prune: env_or("ARCHIVE_PRUNE", false),
+            alert_webhook_url: env_opt("ALERT_WEBHOOK_URL"),
+            webhook_urls: env_opt("WEBHOOK_URLS"),
+            webhook_secret: env_opt("WEBHOOK_SECRET"),
+            webhook_max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", 5),
+            webhook_backoff_ms: env_or("WEBHOOK_BACKOFF_MS", 1000),
+            webhook_health_ms: env_or("WEBHOOK_HEALTH_MS", 30000),
+            tls_cert_path: env_opt("TLS_CERT_PATH"),
+            tls_key_path: env_opt("TLS_KEY_PATH"),
+            tls_client_ca_path: env_opt("TLS_CLIENT_CA_PATH"),
+            signing_secret: env_opt("SIGNING_SECRET"),
+            replay_window_secs: env_or("REPLAY_WINDOW_SECS", 300),
+            admin_token: env_opt("ADMIN_TOKEN"),
+            api_auth: env_or("API_AUTH", false),
+            api_sunset: env_opt("API_SUNSET"),
+            db_max_connections: env_or("DB_MAX_CONNECTIONS", 10),
+            db_busy_timeout_ms: env_or("DB_BUSY_TIMEOUT_MS", 5000),
+            db_journal_mode: env_or("DB_JOURNAL_MODE", SqliteJournalMode::Wal),
+            db_synchronous: env_or("DB_SYNCHRONOUS", SqliteSynchronous::Normal),
+            slow_query_ms: env_or("SLOW_QUERY_MS", 100),
+            log_format: env_or("LOG_FORMAT", LogFormat::Text),
+            latency_target_ms: env_opt("LATENCY_TARGET_MS"),
+            serial_ports: env_opt("SERIAL_PORTS"),
+            local_sensors: env_opt("LOCAL_SENSORS"),
+            local_sensor_interval_secs: env_or("LOCAL_SENSOR_INTERVAL_SECS", 10),
+            local_sensor_jitter_ms: env_or("LOCAL_SENSOR_JITTER_MS", 500),
+            local_sensor_channels: env_opt("LOCAL_SENSOR_CHANNELS"),
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert_path.is_some()
+            || self.tls_key_path.is_some()
+            || self.tls_client_ca_path.is_some()
+    }
+
+    pub fn mtls_enabled(&self) -> bool {
+        self.tls_client_ca_path.is_some()
+    }
+}
+
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value: {:?}", key, value))
+    })
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
@@ -0,0 +1,24 @@
+use crate::db::Conversion;
+
+// data * scale + offset, like raw ADC counts to °C
+pub const LINEAR: &str = "linear";
+pub const FAHRENHEIT_TO_CELSIUS: &str = "fahrenheit_to_celsius";
+pub const CELSIUS_TO_FAHRENHEIT: &str = "celsius_to_fahrenheit";
+
+pub const KINDS: [&str; 3] = [LINEAR, FAHRENHEIT_TO_CELSIUS, CELSIUS_TO_FAHRENHEIT];
+
+pub fn is_valid_kind(kind: &str) -> bool {
+    KINDS.contains(&kind)
+}
+
+// the reading in the unit of the conversion, None if the conversion doesn't give a finite
+// number, then the reading is stored as it came
+pub fn apply(conversion: &Conversion, data: f64) -> Option<f64> {
+    let converted = match conversion.kind.as_str() {
+        LINEAR => data * conversion.scale + conversion.offset,
+        FAHRENHEIT_TO_CELSIUS => (data - 32.0) * 5.0 / 9.0,
+        CELSIUS_TO_FAHRENHEIT => data * 9.0 / 5.0 + 32.0,
+        _ => return None,
+    };
+    converted.is_finite().then_some(converted)
+}
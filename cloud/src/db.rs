@@ -1,12 +1,48 @@
-use sqlx::{migrate, migrate::MigrateDatabase, FromRow, Pool, Sqlite, SqlitePool};
+use futures_util::{stream::BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    migrate,
+    migrate::MigrateDatabase,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    FromRow, Pool, Sqlite,
+};
 use std::{
+    collections::BTreeMap,
     env,
-    error::Error,
-    time::{SystemTime, UNIX_EPOCH},
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    config::{AggregationTimestamp, Config, Sample},
+    error::Result,
+    geo, protocols,
+    slow_queries::SlowQueries,
+    timeline::EventKind,
 };
-use tracing::info;
 
-use crate::protocols;
+// slow queries of all pools, the threshold is set on startup
+pub static SLOW_QUERIES: SlowQueries = SlowQueries::new(100);
+
+// runs a query of this module, recording it if it was slow
+async fn timed<T>(query: &'static str, run: impl Future<Output = Result<T>>) -> Result<T> {
+    let start = Instant::now();
+    let res = run.await;
+    SLOW_QUERIES.record(query, start.elapsed());
+    res
+}
+
+// the system time, for stamps the services never compare against the state clock.
+// the others are passed in by the caller
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
 
 #[derive(FromRow, Debug)]
 pub struct Metrics {
@@ -14,21 +50,112 @@ pub struct Metrics {
     pub received_messages: Option<i32>,
     pub queued_messages: Option<i32>,
     pub delivered_messages: Option<i32>,
+    pub pending_deliveries: Option<i32>,
+    pub maintenance: Option<i32>,
 }
 
-#[derive(FromRow, Debug)]
+#[derive(FromRow, Serialize, Debug, ToSchema)]
 pub struct Connection {
     pub id: i64,
     pub uid: String,
     pub last_seen: i64,
+    pub maintenance: bool,
+    // difference between the device clock and the server clock in seconds
+    pub clock_skew: i64,
+    // wake schedule declared in the last CONN, see protocols::WakeSchedule
+    pub wake_period_secs: Option<i64>,
+    pub wake_window_secs: Option<i64>,
+    pub woke_at: Option<i64>,
+    // when the device sent DISCONN, NULL while it is registered
+    pub disconnected_at: Option<i64>,
+    // hex X25519 key for sealed messages to the device
+    pub public_key: Option<String>,
+    // tenant the device joined in CONN, NULL outside of any tenant
+    pub tenant: Option<String>,
+    // readings are forwarded as they come and left out of the averages
+    pub pass_through: bool,
+    // fog node the readings of the device come through, NULL if it connects itself
+    pub fog_uid: Option<String>,
 }
 
-#[derive(FromRow, Debug)]
+impl Connection {
+    // the stored wake schedule and the timestamp its windows are counted from
+    pub fn wake_schedule(&self) -> Option<(protocols::WakeSchedule, i64)> {
+        Some((
+            protocols::WakeSchedule {
+                period_secs: self.wake_period_secs?,
+                window_secs: self.wake_window_secs?,
+            },
+            self.woke_at?,
+        ))
+    }
+
+    // longest time the device may be silent before it counts as offline
+    pub fn expected_silence_secs(&self, offline_after_secs: i64) -> i64 {
+        self.wake_period_secs.unwrap_or(0) + offline_after_secs
+    }
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
 pub struct ReceivedMessage {
     pub id: i64,
     pub uid: String,
     pub data: f64,
     pub created_at: i64,
+    pub channel: String,
+    // unix timestamp the server received the reading at, created_at is the one of the device.
+    // unset on readings stored before it was recorded and on summaries
+    pub received_at: Option<i64>,
+    // set on rows summarizing the readings of a minute, data is then their mean
+    pub summary_min: Option<f64>,
+    pub summary_max: Option<f64>,
+    pub summary_count: Option<i64>,
+    // fog node that forwarded the reading
+    pub fog_uid: Option<String>,
+    // attributes of the device when the reading was stored, see Config::enrich_attributes
+    #[serde(serialize_with = "serialize_attributes")]
+    #[schema(value_type = Option<Object>)]
+    pub attributes: Option<String>,
+    // the reading as the device sent it, kept if its conversion asks for it
+    pub raw_data: Option<f64>,
+}
+
+// device attributes as the JSON object stored with a reading
+pub type Attributes = BTreeMap<String, String>;
+
+pub fn parse_attributes(json: Option<&str>) -> Attributes {
+    json.and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+// the stored attributes as an object instead of a string
+fn serialize_attributes<S: serde::Serializer>(
+    json: &Option<String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    json.as_deref()
+        .map(|json| parse_attributes(Some(json)))
+        .serialize(serializer)
+}
+
+impl ReceivedMessage {
+    // the timestamp the reading is aggregated by
+    pub fn timestamp(&self, by: AggregationTimestamp) -> i64 {
+        match by {
+            AggregationTimestamp::Device => self.created_at,
+            AggregationTimestamp::Received => self.received_at.unwrap_or(self.created_at),
+        }
+    }
+
+    // the reading as a sample of its window, summaries stand in for all their readings
+    pub fn sample(&self) -> Sample {
+        Sample {
+            value: self.data,
+            count: self.summary_count.unwrap_or(1),
+            min: self.summary_min.unwrap_or(self.data),
+            max: self.summary_max.unwrap_or(self.data),
+        }
+    }
 }
 
 #[derive(FromRow, Debug)]
@@ -38,11 +165,71 @@ pub struct QueuedMessage {
     pub created_at: i64,
 }
 
-pub async fn initialize_db() -> Pool<Sqlite> {
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct AttentionItem {
+    pub uid: String,
+    pub reason: String,
+    pub detail: String,
+    pub since: i64,
+}
+
+// counters of the current or last connection of a device
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct ConnectionStats {
+    pub uid: String,
+    pub session_id: String,
+    pub frames_in: i64,
+    pub frames_out: i64,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    // see TrafficStats
+    pub raw_bytes_in: i64,
+    pub raw_bytes_out: i64,
+    pub parse_errors: i64,
+    pub last_activity: Option<i64>,
+    // when the counters were taken, now for a live connection
+    pub updated_at: i64,
+    // whether the counters are of a live connection
+    #[sqlx(skip)]
+    pub live: bool,
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct BandwidthSample {
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub created_at: i64,
+}
+
+// settings of the sqlite pool, the defaults of sqlx lock up under concurrent writers
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    pub busy_timeout: Duration,
+    pub journal_mode: SqliteJournalMode,
+    pub synchronous: SqliteSynchronous,
+}
+
+impl PoolSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_connections: config.db_max_connections,
+            busy_timeout: Duration::from_millis(config.db_busy_timeout_ms),
+            journal_mode: config.db_journal_mode,
+            synchronous: config.db_synchronous,
+        }
+    }
+}
+
+pub async fn initialize_db(config: &Config) -> Pool<Sqlite> {
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    open_db(&db_url, &PoolSettings::from_config(config)).await
+}
 
-    if !Sqlite::database_exists(&db_url).await.unwrap_or(false) {
-        Sqlite::create_database(&db_url)
+// creates the sqlite db at the url if needed and migrates it
+pub async fn open_db(db_url: &str, settings: &PoolSettings) -> Pool<Sqlite> {
+    if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
+        Sqlite::create_database(db_url)
             .await
             .expect("Could not create the sqlite db");
         info!("Created new sqlite db")
@@ -50,157 +237,3001 @@ pub async fn initialize_db() -> Pool<Sqlite> {
         info!("Using an existing sqlite db")
     }
 
-    let pool = SqlitePool::connect(&db_url)
+    if settings.max_connections == 0 {
+        panic!("The sqlite pool needs at least one connection");
+    }
+    let options = SqliteConnectOptions::from_str(db_url)
+        .expect("Invalid sqlite url")
+        .journal_mode(settings.journal_mode)
+        .busy_timeout(settings.busy_timeout)
+        .synchronous(settings.synchronous);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(settings.max_connections)
+        .connect_with(options)
         .await
         .expect("Could not connect to the sqlite db");
 
+    // a new db has no table of applied migrations yet
+    let pending = pending_migrations(&pool)
+        .await
+        .unwrap_or_else(|_| migrate!().iter().count());
     migrate!()
         .run(&pool)
         .await
         .expect("Could not migrate the db");
+    if pending > 0 {
+        let detail = format!("applied {} migrations", pending);
+        info!("Migrations: {}", detail);
+        if let Err(e) = add_system_event(&pool, EventKind::Migration.as_str(), &detail).await {
+            warn!("Could not record the migrations: {}", e);
+        }
+    }
+
+    // sqlite may not apply what was asked for, in-memory dbs can't use WAL
+    match effective_settings(&pool).await {
+        Ok((journal_mode, busy_timeout_ms, synchronous)) => info!(
+            "Sqlite pool: {} connections, journal_mode={}, busy_timeout={}ms, synchronous={}",
+            settings.max_connections, journal_mode, busy_timeout_ms, synchronous
+        ),
+        Err(e) => warn!("Could not read the sqlite settings: {}", e),
+    }
 
     pool
 }
 
-pub async fn get_metrics(pool: &Pool<Sqlite>) -> Result<Metrics, Box<dyn Error + Send + Sync>> {
-    let metrics = sqlx::query_as::<_, Metrics>(
-        r#" SELECT 
-            (SELECT COUNT(*) FROM connections) as connections,
-            (SELECT COUNT(*) FROM received_messages) as received_messages,
-            (SELECT COUNT(*) FROM queued_messages) as queued_messages,
-            (SELECT COUNT(*) FROM delivered_messages) as delivered_messages
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
+// the journal mode, busy timeout and synchronous level a connection of the pool uses
+async fn effective_settings(pool: &Pool<Sqlite>) -> Result<(String, i64, &'static str)> {
+    let mut conn = pool.acquire().await?;
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(&mut *conn)
+        .await?;
+    let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+        .fetch_one(&mut *conn)
+        .await?;
+    let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+        .fetch_one(&mut *conn)
+        .await?;
+    let synchronous = match synchronous {
+        0 => "off",
+        1 => "normal",
+        2 => "full",
+        3 => "extra",
+        _ => "unknown",
+    };
+    Ok((journal_mode, busy_timeout, synchronous))
+}
+
+// migrations of the binary the db hasn't applied, fails if the db isn't reachable
+pub async fn pending_migrations(pool: &Pool<Sqlite>) -> Result<usize> {
+    timed("pending_migrations", async move {
+        let applied: Vec<i64> =
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+                .fetch_all(pool)
+                .await?;
 
-    Ok(metrics)
+        Ok(migrate!()
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+            .count())
+    })
+    .await
 }
 
-pub async fn add_connection(
-    pool: &Pool<Sqlite>,
-    uid: &str,
-) -> Result<Connection, Box<dyn Error + Send + Sync>> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+// the counts of a tenant, or of all tenants
+pub async fn get_metrics(pool: &Pool<Sqlite>, tenant: Option<&str>) -> Result<Metrics> {
+    timed("get_metrics", async move {
+        let metrics = sqlx::query_as::<_, Metrics>(
+            r#" SELECT 
+                (SELECT COUNT(*) FROM connections WHERE ?1 IS NULL OR tenant = ?1) as connections,
+                (SELECT COUNT(*) FROM received_messages WHERE ?1 IS NULL OR tenant = ?1) as received_messages,
+                (SELECT COUNT(*) FROM queued_messages WHERE ?1 IS NULL OR tenant = ?1
+                    OR target_uid IN ( SELECT uid FROM connections WHERE tenant = ?1 )) as queued_messages,
+                (SELECT COUNT(*) FROM delivered_messages
+                    WHERE ?1 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?1 )) as delivered_messages,
+                (SELECT COUNT(*) FROM pending_deliveries
+                    WHERE ?1 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?1 )) as pending_deliveries,
+                (SELECT COUNT(*) FROM connections WHERE maintenance = 1 AND ( ?1 IS NULL OR tenant = ?1 )) as maintenance
+            "#,
+        )
+        .bind(tenant)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(metrics)
+    })
+    .await
+}
+
+// adds the connection of a device, a uid that is already known keeps its row untouched
+// and is returned as it is, so concurrent calls don't fail on the unique uid
+pub async fn add_connection(pool: &Pool<Sqlite>, uid: &str) -> Result<Connection> {
+    timed("add_connection", async move {
+        let added = sqlx::query_as::<_, Connection>(
+            r#"INSERT INTO connections ( uid, last_seen ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( uid ) DO NOTHING
+            RETURNING *"#,
+        )
+        .bind(uid)
+        .bind(unix_now())
+        .fetch_optional(pool)
+        .await?;
+        if let Some(conn) = added {
+            return Ok(conn);
+        }
+
+        let conn = sqlx::query_as::<_, Connection>("SELECT * FROM connections WHERE uid = ?1")
+            .bind(uid)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(conn)
+    })
+    .await
+}
 
-    let id = sqlx::query("INSERT INTO connections ( uid, last_seen ) VALUES ( ?1, ?2 )")
+// creates the connection of a device or updates its last_seen in one statement, so
+// two CONNs of a new device can't both try to insert it. the row is returned as it is
+// afterwards, disconnected devices stay disconnected
+pub async fn upsert_connection(pool: &Pool<Sqlite>, uid: &str, now: i64) -> Result<Connection> {
+    timed("upsert_connection", async move {
+        let conn = sqlx::query_as::<_, Connection>(
+            r#"INSERT INTO connections ( uid, last_seen ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( uid ) DO UPDATE SET last_seen = excluded.last_seen
+            RETURNING *"#,
+        )
         .bind(uid)
         .bind(now)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(conn)
+    })
+    .await
+}
+
+// registers a fog node, returns false if it already was
+pub async fn add_fog_node(pool: &Pool<Sqlite>, uid: &str) -> Result<bool> {
+    timed("add_fog_node", async move {
+        let res = sqlx::query(
+            r#"INSERT INTO fog_nodes ( uid, registered_at ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( uid ) DO NOTHING"#,
+        )
+        .bind(uid)
+        .bind(unix_now())
         .execute(pool)
-        .await?
-        .last_insert_rowid();
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+// takes the fog role from a node, the devices behind it stay recorded with it
+pub async fn delete_fog_node(pool: &Pool<Sqlite>, uid: &str) -> Result<bool> {
+    timed("delete_fog_node", async move {
+        let res = sqlx::query("DELETE FROM fog_nodes WHERE uid = ?1")
+            .bind(uid)
+            .execute(pool)
+            .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+pub async fn is_fog_node(pool: &Pool<Sqlite>, uid: &str) -> Result<bool> {
+    timed("is_fog_node", async move {
+        let registered: bool =
+            sqlx::query_scalar("SELECT EXISTS ( SELECT 1 FROM fog_nodes WHERE uid = ?1 )")
+                .bind(uid)
+                .fetch_one(pool)
+                .await?;
+
+        Ok(registered)
+    })
+    .await
+}
+
+// registers a device behind a fog node, it never connects itself. a new device joins the
+// tenant of the fog node, returns false if the device is in another tenant
+pub async fn add_fog_device(pool: &Pool<Sqlite>, uid: &str, fog_uid: &str) -> Result<bool> {
+    timed("add_fog_device", async move {
+        let res = sqlx::query(
+            r#"INSERT INTO connections ( uid, last_seen, fog_uid, tenant )
+            VALUES ( ?1, ?2, ?3, ( SELECT tenant FROM connections WHERE uid = ?3 ) )
+            ON CONFLICT ( uid ) DO UPDATE SET fog_uid = excluded.fog_uid,
+                tenant = COALESCE(connections.tenant, excluded.tenant)
+            WHERE connections.tenant IS NULL OR connections.tenant IS excluded.tenant"#,
+        )
+        .bind(uid)
+        .bind(unix_now())
+        .bind(fog_uid)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+// whether the fog node may forward the readings of the device: it is still registered, the
+// device was registered behind it in its tenant and isn't drained for servicing
+pub async fn may_forward(pool: &Pool<Sqlite>, uid: &str, fog_uid: &str) -> Result<bool> {
+    timed("may_forward", async move {
+        let allowed: bool = sqlx::query_scalar(
+            r#"SELECT EXISTS ( SELECT 1 FROM connections AS device
+                JOIN fog_nodes ON fog_nodes.uid = device.fog_uid
+                LEFT JOIN connections AS fog ON fog.uid = device.fog_uid
+                WHERE device.uid = ?1 AND device.fog_uid = ?2 AND NOT device.maintenance
+                    AND device.tenant IS fog.tenant )"#,
+        )
+        .bind(uid)
+        .bind(fog_uid)
+        .fetch_one(pool)
+        .await?;
 
-    Ok(Connection {
-        id: id,
-        uid: uid.to_string(),
-        last_seen: now,
+        Ok(allowed)
     })
+    .await
 }
 
-pub async fn get_connection(
+// a fog node with the devices behind it
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct FogNode {
+    pub uid: String,
+    pub devices: i64,
+    // the latest last_seen of its devices
+    pub last_seen: i64,
+}
+
+// what a device tells about itself in CONN or an operator sets, fields left out are kept
+#[derive(Deserialize, Serialize, Default, Clone, Debug, PartialEq, ToSchema)]
+pub struct DeviceMetadata {
+    // friendly name, like Greenhouse 2 north
+    pub name: Option<String>,
+    pub location: Option<String>,
+    // kind of the sensor, like bme280
+    pub sensor_type: Option<String>,
+    pub firmware: Option<String>,
+    // position in degrees, set together
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl DeviceMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// a device in the registry, it may not have connected yet
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct Device {
+    pub uid: String,
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub sensor_type: Option<String>,
+    pub firmware: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub registered_at: i64,
+    pub updated_at: i64,
+}
+
+// registers the device or updates the fields of its metadata that are set
+pub async fn set_device_metadata(
     pool: &Pool<Sqlite>,
     uid: &str,
-) -> Result<Connection, Box<dyn Error + Send + Sync>> {
-    let conn = sqlx::query_as::<_, Connection>("SELECT * FROM connections WHERE uid = ?1")
+    metadata: &DeviceMetadata,
+) -> Result<Device> {
+    timed("set_device_metadata", async move {
+        let now = unix_now();
+        let device = sqlx::query_as::<_, Device>(
+            r#"INSERT INTO devices ( uid, name, location, sensor_type, firmware, latitude, longitude,
+                registered_at, updated_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8 )
+            ON CONFLICT ( uid ) DO UPDATE SET name = COALESCE(excluded.name, name),
+                location = COALESCE(excluded.location, location),
+                sensor_type = COALESCE(excluded.sensor_type, sensor_type),
+                firmware = COALESCE(excluded.firmware, firmware),
+                latitude = COALESCE(excluded.latitude, latitude),
+                longitude = COALESCE(excluded.longitude, longitude),
+                updated_at = excluded.updated_at
+            RETURNING *"#,
+        )
         .bind(uid)
+        .bind(&metadata.name)
+        .bind(&metadata.location)
+        .bind(&metadata.sensor_type)
+        .bind(&metadata.firmware)
+        .bind(metadata.latitude)
+        .bind(metadata.longitude)
+        .bind(now)
         .fetch_one(pool)
         .await?;
 
-    Ok(conn)
+        Ok(device)
+    })
+    .await
+}
+
+pub async fn get_device(pool: &Pool<Sqlite>, uid: &str) -> Result<Device> {
+    timed("get_device", async move {
+        let device = sqlx::query_as::<_, Device>("SELECT * FROM devices WHERE uid = ?1")
+            .bind(uid)
+            .fetch_optional(pool)
+            .await?;
+
+        device.ok_or(crate::Error::NotFound)
+    })
+    .await
+}
+
+// devices of a tenant, or of all tenants, in the registry. filters left out match every
+// device, the name matches if it contains the given one, ignoring case
+pub async fn get_devices(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    filter: &DeviceMetadata,
+) -> Result<Vec<Device>> {
+    timed("get_devices", async move {
+        let devices = sqlx::query_as::<_, Device>(
+            r#"SELECT * FROM devices
+            WHERE ( ?1 IS NULL OR instr(lower(name), lower(?1)) > 0 )
+            AND ( ?2 IS NULL OR location = ?2 )
+            AND ( ?3 IS NULL OR sensor_type = ?3 )
+            AND ( ?4 IS NULL OR firmware = ?4 )
+            AND ( ?5 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?5 ) )
+            ORDER BY uid"#,
+        )
+        .bind(&filter.name)
+        .bind(&filter.location)
+        .bind(&filter.sensor_type)
+        .bind(&filter.firmware)
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(devices)
+    })
+    .await
+}
+
+// the devices of a tenant, or of all tenants, with a position in the box, only the fog
+// nodes or only the other devices if fog is set
+pub async fn get_devices_in(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    bounds: &geo::BoundingBox,
+    fog: Option<bool>,
+) -> Result<Vec<Device>> {
+    timed("get_devices_in", async move {
+        let devices = sqlx::query_as::<_, Device>(
+            r#"SELECT * FROM devices
+            WHERE latitude BETWEEN ?1 AND ?3
+            AND ( ( ?2 <= ?4 AND longitude BETWEEN ?2 AND ?4 )
+                OR ( ?2 > ?4 AND ( longitude >= ?2 OR longitude <= ?4 ) ) )
+            AND ( ?5 IS NULL OR ( uid IN ( SELECT fog_uid FROM connections WHERE fog_uid IS NOT NULL ) ) = ?5 )
+            AND ( ?6 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?6 ) )
+            ORDER BY uid"#,
+        )
+        .bind(bounds.min_lat)
+        .bind(bounds.min_lon)
+        .bind(bounds.max_lat)
+        .bind(bounds.max_lon)
+        .bind(fog)
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(devices)
+    })
+    .await
+}
+
+// how the readings of a channel are converted before they are stored
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct Conversion {
+    #[serde(default)]
+    pub uid: String,
+    #[serde(default)]
+    pub channel: String,
+    // one of conversions::KINDS
+    pub kind: String,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub keep_raw: bool,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+fn default_scale() -> f64 {
+    1.0
 }
 
-pub async fn update_connection(
+pub async fn get_conversion(
     pool: &Pool<Sqlite>,
     uid: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    channel: &str,
+) -> Result<Option<Conversion>> {
+    timed("get_conversion", async move {
+        let conversion = sqlx::query_as::<_, Conversion>(
+            "SELECT * FROM conversions WHERE uid = ?1 AND channel = ?2",
+        )
+        .bind(uid)
+        .bind(channel)
+        .fetch_optional(pool)
+        .await?;
 
-    sqlx::query("UPDATE connections SET last_seen = ?1 WHERE id = ?2")
-        .bind(now)
+        Ok(conversion)
+    })
+    .await
+}
+
+pub async fn get_conversions(pool: &Pool<Sqlite>, uid: &str) -> Result<Vec<Conversion>> {
+    timed("get_conversions", async move {
+        let conversions = sqlx::query_as::<_, Conversion>(
+            "SELECT * FROM conversions WHERE uid = ?1 ORDER BY channel",
+        )
         .bind(uid)
-        .execute(pool)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(conversions)
+    })
+    .await
+}
+
+// sets or replaces the conversion of a channel, readings stored before aren't converted
+pub async fn set_conversion(pool: &Pool<Sqlite>, conversion: &Conversion) -> Result<Conversion> {
+    timed("set_conversion", async move {
+        let conversion = sqlx::query_as::<_, Conversion>(
+            r#"INSERT OR REPLACE INTO conversions ( uid, channel, kind, scale, offset, unit,
+                keep_raw, updated_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 )
+            RETURNING *"#,
+        )
+        .bind(&conversion.uid)
+        .bind(&conversion.channel)
+        .bind(&conversion.kind)
+        .bind(conversion.scale)
+        .bind(conversion.offset)
+        .bind(&conversion.unit)
+        .bind(conversion.keep_raw)
+        .bind(unix_now())
+        .fetch_one(pool)
+        .await?;
+
+        Ok(conversion)
+    })
+    .await
+}
+
+// returns whether the channel had a conversion
+pub async fn delete_conversion(pool: &Pool<Sqlite>, uid: &str, channel: &str) -> Result<bool> {
+    timed("delete_conversion", async move {
+        let res = sqlx::query("DELETE FROM conversions WHERE uid = ?1 AND channel = ?2")
+            .bind(uid)
+            .bind(channel)
+            .execute(pool)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    })
+    .await
+}
+
+// the fog nodes devices of a tenant, or of all tenants, were forwarded by, by uid
+pub async fn get_fog_nodes(pool: &Pool<Sqlite>, tenant: Option<&str>) -> Result<Vec<FogNode>> {
+    timed("get_fog_nodes", async move {
+        let nodes = sqlx::query_as::<_, FogNode>(
+            r#"SELECT fog_uid AS uid, COUNT(*) AS devices, MAX(last_seen) AS last_seen
+            FROM connections WHERE fog_uid IS NOT NULL AND ( ?1 IS NULL OR tenant = ?1 )
+            GROUP BY fog_uid ORDER BY fog_uid"#,
+        )
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(nodes)
+    })
+    .await
+}
+
+// the devices behind a fog node
+pub async fn get_fog_devices(pool: &Pool<Sqlite>, fog_uid: &str) -> Result<Vec<Connection>> {
+    timed("get_fog_devices", async move {
+        let devices = sqlx::query_as::<_, Connection>(
+            "SELECT * FROM connections WHERE fog_uid = ?1 ORDER BY uid",
+        )
+        .bind(fog_uid)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(devices)
+    })
+    .await
+}
+
+pub async fn get_connection(pool: &Pool<Sqlite>, uid: &str) -> Result<Connection> {
+    timed("get_connection", async move {
+        let conn = sqlx::query_as::<_, Connection>("SELECT * FROM connections WHERE uid = ?1")
+            .bind(uid)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(conn)
+    })
+    .await
+}
+
+// the devices of a tenant, or of all tenants
+pub async fn get_connections(pool: &Pool<Sqlite>, tenant: Option<&str>) -> Result<Vec<Connection>> {
+    timed("get_connections", async move {
+        let conns = sqlx::query_as::<_, Connection>(
+            "SELECT * FROM connections WHERE ?1 IS NULL OR tenant = ?1 ORDER BY uid",
+        )
+        .bind(tenant)
+        .fetch_all(pool)
         .await?;
 
-    Ok(())
+        Ok(conns)
+    })
+    .await
+}
+
+pub async fn update_connection(pool: &Pool<Sqlite>, uid: &str, now: i64) -> Result<()> {
+    timed("update_connection", async move {
+        sqlx::query("UPDATE connections SET last_seen = ?1 WHERE uid = ?2")
+            .bind(now)
+            .bind(uid)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
 }
 
-pub async fn delete_connection(
+// stores the wake schedule of the last CONN, devices without one clear it
+pub async fn set_wake_schedule(
     pool: &Pool<Sqlite>,
     uid: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    sqlx::query("DELETE FROM connections WHERE uid = ?1")
+    wake: Option<protocols::WakeSchedule>,
+    woke_at: i64,
+) -> Result<()> {
+    timed("set_wake_schedule", async move {
+        sqlx::query(
+            r#"UPDATE connections SET wake_period_secs = ?2, wake_window_secs = ?3, woke_at = ?4
+            WHERE uid = ?1"#,
+        )
         .bind(uid)
+        .bind(wake.map(|w| w.period_secs))
+        .bind(wake.map(|w| w.window_secs))
+        .bind(wake.map(|_| woke_at))
         .execute(pool)
         .await?;
 
-    Ok(())
+        Ok(())
+    })
+    .await
+}
+
+pub async fn update_clock_skew(pool: &Pool<Sqlite>, uid: &str, clock_skew: i64) -> Result<()> {
+    timed("update_clock_skew", async move {
+        sqlx::query("UPDATE connections SET clock_skew = ?1 WHERE uid = ?2")
+            .bind(clock_skew)
+            .bind(uid)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// whether readings of the device are passed through from now on
+pub async fn set_pass_through(pool: &Pool<Sqlite>, uid: &str, pass_through: bool) -> Result<()> {
+    timed("set_pass_through", async move {
+        let res = sqlx::query("UPDATE connections SET pass_through = ?1 WHERE uid = ?2")
+            .bind(pass_through)
+            .bind(uid)
+            .execute(pool)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(crate::Error::NotFound);
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn set_maintenance(pool: &Pool<Sqlite>, uid: &str, maintenance: bool) -> Result<()> {
+    timed("set_maintenance", async move {
+        sqlx::query("UPDATE connections SET maintenance = ?1 WHERE uid = ?2")
+            .bind(maintenance)
+            .bind(uid)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// marks a device as disconnected, keeping its readings and delivery history
+pub async fn disconnect_connection(pool: &Pool<Sqlite>, uid: &str, now: i64) -> Result<()> {
+    timed("disconnect_connection", async move {
+        sqlx::query("UPDATE connections SET disconnected_at = ?2 WHERE uid = ?1")
+            .bind(uid)
+            .bind(now)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// keeps the key a device announced, devices that don't announce one keep the previous key
+pub async fn set_public_key(pool: &Pool<Sqlite>, uid: &str, public_key: &str) -> Result<()> {
+    timed("set_public_key", async move {
+        sqlx::query("UPDATE connections SET public_key = ?2 WHERE uid = ?1")
+            .bind(uid)
+            .bind(public_key)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// devices join a tenant once, see handlers::handle_socket
+pub async fn set_connection_tenant(pool: &Pool<Sqlite>, uid: &str, tenant: &str) -> Result<()> {
+    timed("set_connection_tenant", async move {
+        sqlx::query("UPDATE connections SET tenant = ?2 WHERE uid = ?1")
+            .bind(uid)
+            .bind(tenant)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// a disconnected device sent CONN again
+pub async fn reconnect_connection(pool: &Pool<Sqlite>, uid: &str) -> Result<()> {
+    timed("reconnect_connection", async move {
+        sqlx::query("UPDATE connections SET disconnected_at = NULL WHERE uid = ?1")
+            .bind(uid)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// tables purge_connection deletes the rows of a device from. bandwidth samples are
+// billed and audit rows document the purge, so both are kept
+const PURGED_TABLES: [&str; 17] = [
+    "received_messages",
+    "sealed_messages",
+    "delivered_messages",
+    "pending_deliveries",
+    "device_kv",
+    "device_attributes",
+    "devices",
+    "device_groups",
+    "attention",
+    "alerts",
+    "alert_rules",
+    "rule_versions",
+    "device_configs",
+    "conversions",
+    "rollups_hourly",
+    "rollups_daily",
+    "device_sessions",
+];
+
+// deletes a device and all data it left, returns the number of deleted rows
+pub async fn purge_connection(pool: &Pool<Sqlite>, uid: &str) -> Result<u64> {
+    timed("purge_connection", async move {
+        let mut tx = pool.begin().await?;
+        let mut deleted = 0;
+
+        for table in PURGED_TABLES {
+            deleted += sqlx::query(&format!("DELETE FROM {} WHERE uid = ?1", table))
+                .bind(uid)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        deleted += sqlx::query("DELETE FROM queued_messages WHERE target_uid = ?1")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let connection = sqlx::query("DELETE FROM connections WHERE uid = ?1")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        if connection.rows_affected() == 0 {
+            return Err(crate::Error::NotFound);
+        }
+        deleted += connection.rows_affected();
+
+        tx.commit().await?;
+        Ok(deleted)
+    })
+    .await
+}
+
+// a stored reading, with what it took from its device
+pub struct StoredReading {
+    pub pass_through: bool,
+    pub attributes: Attributes,
+    pub tenant: Option<String>,
 }
 
+// stores a reading with the tenant, the pass-through setting and the attributes of its
+// device, returns None if the device sent it before
 pub async fn add_received_message(
     pool: &Pool<Sqlite>,
     msg: &protocols::SensorMsg,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    sqlx::query("INSERT INTO received_messages ( uid, data, created_at ) VALUES ( ?1, ?2, ?3 )")
+    received_at: i64,
+) -> Result<Option<StoredReading>> {
+    add_converted_message(pool, msg, None, received_at).await
+}
+
+// stores a reading converted to a derived unit, with the reading as it came if raw_data is set
+pub async fn add_converted_message(
+    pool: &Pool<Sqlite>,
+    msg: &protocols::SensorMsg,
+    raw_data: Option<f64>,
+    received_at: i64,
+) -> Result<Option<StoredReading>> {
+    timed("add_received_message", async move {
+        let stored = sqlx::query_as::<_, (bool, Option<String>, Option<String>)>(
+            r#"INSERT INTO received_messages ( uid, data, created_at, channel, tenant, pass_through,
+                received_at, seq, fog_uid, attributes, raw_data )
+            VALUES ( ?1, ?2, ?3, ?4, ( SELECT tenant FROM connections WHERE uid = ?1 ),
+                COALESCE(( SELECT pass_through FROM connections WHERE uid = ?1 ), 0), ?5, ?6, ?7,
+                ( SELECT NULLIF(json_group_object(name, value), '{}') FROM device_attributes
+                    WHERE uid = ?1 ), ?8 )
+            ON CONFLICT DO NOTHING
+            RETURNING pass_through, attributes, tenant"#,
+        )
+        .bind(&msg.uid)
+        .bind(msg.data)
+        .bind(msg.timestamp)
+        .bind(&msg.channel)
+        .bind(received_at)
+        .bind(msg.seq)
+        .bind(&msg.via)
+        .bind(raw_data)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(
+            stored.map(|(pass_through, attributes, tenant)| StoredReading {
+                pass_through,
+                attributes: parse_attributes(attributes.as_deref()),
+                tenant,
+            }),
+        )
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct SealedMessage {
+    pub id: i64,
+    pub uid: String,
+    pub channel: String,
+    pub envelope: String,
+    pub created_at: i64,
+}
+
+pub async fn add_sealed_message(pool: &Pool<Sqlite>, msg: &protocols::SealedMsg) -> Result<()> {
+    timed("add_sealed_message", async move {
+        sqlx::query(
+            "INSERT INTO sealed_messages ( uid, channel, envelope, created_at ) VALUES ( ?1, ?2, ?3, ?4 )",
+        )
         .bind(&msg.uid)
-        .bind(&msg.data)
-        .bind(&msg.timestamp)
+        .bind(&msg.channel)
+        .bind(msg.envelope.to_hex())
+        .bind(msg.timestamp)
         .execute(pool)
         .await?;
 
-    Ok(())
+        Ok(())
+    })
+    .await
 }
 
-pub async fn get_last_received_messages(
+// sealed messages of a device created after `since`, oldest first
+pub async fn get_sealed_messages(
     pool: &Pool<Sqlite>,
+    uid: &str,
+    since: i64,
     limit: i64,
-) -> Result<Vec<ReceivedMessage>, Box<dyn Error + Send + Sync>> {
-    let messages = sqlx::query_as::<_, ReceivedMessage>(
-        "SELECT * FROM received_messages ORDER BY created_at DESC LIMIT ?1",
-    )
-    .bind(limit)
-    .fetch_all(pool)
-    .await?;
+) -> Result<Vec<SealedMessage>> {
+    timed("get_sealed_messages", async move {
+        let messages = sqlx::query_as::<_, SealedMessage>(
+            r#"SELECT * FROM sealed_messages WHERE uid = ?1 AND created_at > ?2
+            ORDER BY created_at ASC, id ASC LIMIT ?3"#,
+        )
+        .bind(uid)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
 
-    Ok(messages)
+        Ok(messages)
+    })
+    .await
 }
 
-pub async fn add_queued_message(
-    pool: &Pool<Sqlite>,
-    msg: String,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+// streams the readings of a device between `since` and `until` with a db cursor,
+// so exports of any size don't have to fit into memory
+pub fn stream_received_messages<'a>(
+    pool: &'a Pool<Sqlite>,
+    uid: &'a str,
+    channel: Option<&'a str>,
+    since: i64,
+    until: i64,
+) -> BoxStream<'a, Result<ReceivedMessage>> {
+    sqlx::query_as::<_, ReceivedMessage>(
+        r#"SELECT * FROM received_messages
+        WHERE uid = ?1 AND ( ?2 IS NULL OR channel = ?2 ) AND created_at >= ?3 AND created_at < ?4
+        ORDER BY created_at ASC, id ASC"#,
+    )
+    .bind(uid)
+    .bind(channel)
+    .bind(since)
+    .bind(until)
+    .fetch(pool)
+    .map(|row| row.map_err(Into::into))
+    .boxed()
+}
 
-    sqlx::query("INSERT INTO queued_messages ( message, created_at ) VALUES ( ?1, ?2 )")
-        .bind(msg)
-        .bind(now)
-        .execute(pool)
+// the most recent readings of all devices, newest first
+pub async fn get_recent_readings(pool: &Pool<Sqlite>, limit: i64) -> Result<Vec<ReceivedMessage>> {
+    timed("get_recent_readings", async move {
+        let readings = sqlx::query_as::<_, ReceivedMessage>(
+            "SELECT * FROM received_messages ORDER BY id DESC LIMIT ?1",
+        )
+        .bind(limit)
+        .fetch_all(pool)
         .await?;
 
-    Ok(())
+        Ok(readings)
+    })
+    .await
 }
 
-pub async fn get_new_queued_messages(
+// the last reading of every device and channel of a tenant, or of all tenants
+pub async fn get_latest_readings(
     pool: &Pool<Sqlite>,
-) -> Result<Vec<QueuedMessage>, Box<dyn Error + Send + Sync>> {
-    let messages = sqlx::query_as::<_, QueuedMessage>(
-        "SELECT * FROM queued_messages WHERE id NOT IN ( SELECT queued_message_id FROM delivered_messages ) ORDER BY created_at ASC")
-        .bind(0)
+    tenant: Option<&str>,
+) -> Result<Vec<ReceivedMessage>> {
+    timed("get_latest_readings", async move {
+        let readings = sqlx::query_as::<_, ReceivedMessage>(
+            r#"SELECT * FROM received_messages
+            WHERE id IN (
+                SELECT MAX(id) FROM received_messages WHERE ?1 IS NULL OR tenant = ?1
+                GROUP BY uid, channel
+            )
+            ORDER BY uid, channel"#,
+        )
+        .bind(tenant)
         .fetch_all(pool)
         .await?;
 
-    Ok(messages)
+        Ok(readings)
+    })
+    .await
 }
 
-pub async fn add_delivered_message(
+// the latest reading of every channel of the devices of a tenant, or of all tenants, with a
+// position in the box, only of the fog nodes or only of the other devices if fog is set
+pub async fn get_latest_readings_in(
     pool: &Pool<Sqlite>,
-    uid: &str,
-    queued_message_id: &i64,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    sqlx::query("INSERT INTO delivered_messages ( uid, queued_message_id ) VALUES ( ?1, ?2 )")
-        .bind(uid)
-        .bind(queued_message_id)
-        .execute(pool)
+    tenant: Option<&str>,
+    bounds: &geo::BoundingBox,
+    fog: Option<bool>,
+) -> Result<Vec<ReceivedMessage>> {
+    timed("get_latest_readings_in", async move {
+        let readings = sqlx::query_as::<_, ReceivedMessage>(
+            r#"SELECT * FROM received_messages
+            WHERE id IN (
+                SELECT MAX(id) FROM received_messages
+                WHERE uid IN (
+                    SELECT uid FROM devices
+                    WHERE latitude BETWEEN ?1 AND ?3
+                    AND ( ( ?2 <= ?4 AND longitude BETWEEN ?2 AND ?4 )
+                        OR ( ?2 > ?4 AND ( longitude >= ?2 OR longitude <= ?4 ) ) )
+                    AND ( ?5 IS NULL OR ( uid IN ( SELECT fog_uid FROM connections WHERE fog_uid IS NOT NULL ) ) = ?5 )
+                )
+                AND ( ?6 IS NULL OR tenant = ?6 )
+                GROUP BY uid, channel
+            )
+            ORDER BY uid, channel"#,
+        )
+        .bind(bounds.min_lat)
+        .bind(bounds.min_lon)
+        .bind(bounds.max_lat)
+        .bind(bounds.max_lon)
+        .bind(fog)
+        .bind(tenant)
+        .fetch_all(pool)
         .await?;
 
-    Ok(())
+        Ok(readings)
+    })
+    .await
+}
+
+// the latest reading of every device and channel a fog node forwarded
+pub async fn get_latest_fog_readings(
+    pool: &Pool<Sqlite>,
+    fog_uid: &str,
+) -> Result<Vec<ReceivedMessage>> {
+    timed("get_latest_fog_readings", async move {
+        let readings = sqlx::query_as::<_, ReceivedMessage>(
+            r#"SELECT * FROM received_messages
+            WHERE id IN (
+                SELECT MAX(id) FROM received_messages
+                WHERE uid IN ( SELECT uid FROM connections WHERE fog_uid = ?1 ) AND fog_uid = ?1
+                GROUP BY uid, channel
+            )
+            ORDER BY uid, channel"#,
+        )
+        .bind(fog_uid)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(readings)
+    })
+    .await
+}
+
+// the most recent broadcast AVG messages of a tenant, or of all tenants, newest first
+pub async fn get_recent_averages(
+    pool: &Pool<Sqlite>,
+    limit: i64,
+    tenant: Option<&str>,
+) -> Result<Vec<QueuedMessage>> {
+    timed("get_recent_averages", async move {
+        let averages = sqlx::query_as::<_, QueuedMessage>(
+            r#"SELECT id, message, created_at FROM queued_messages
+            WHERE target_uid IS NULL AND message LIKE 'AVG#%' AND ( ?2 IS NULL OR tenant = ?2 )
+            ORDER BY id DESC LIMIT ?1"#,
+        )
+        .bind(limit)
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(averages)
+    })
+    .await
+}
+
+// the last AVG message of a channel of a tenant, None before the first one. channels can't
+// contain glob patterns, so the channel is matched as the last field
+pub async fn get_latest_average(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    channel: &str,
+) -> Result<Option<QueuedMessage>> {
+    timed("get_latest_average", async move {
+        let average = sqlx::query_as::<_, QueuedMessage>(
+            r#"SELECT id, message, created_at FROM queued_messages
+            WHERE target_uid IS NULL AND message GLOB 'AVG#*#' || ?1 AND tenant IS ?2
+            ORDER BY id DESC LIMIT 1"#,
+        )
+        .bind(channel)
+        .bind(tenant)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(average)
+    })
+    .await
+}
+
+// the channels of every tenant, averaged separately. None is the tenant of devices
+// outside of any tenant
+pub async fn get_channels(pool: &Pool<Sqlite>) -> Result<Vec<(Option<String>, String)>> {
+    timed("get_channels", async move {
+        let channels = sqlx::query_as(
+            r#"SELECT DISTINCT tenant, channel FROM received_messages WHERE pass_through = 0
+            ORDER BY tenant, channel"#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(channels)
+    })
+    .await
+}
+
+pub async fn get_last_received_messages(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    channel: &str,
+    by: AggregationTimestamp,
+    limit: i64,
+) -> Result<Vec<ReceivedMessage>> {
+    timed("get_last_received_messages", async move {
+        let query = format!(
+            r#"SELECT * FROM received_messages WHERE tenant IS ?1 AND channel = ?2
+            AND pass_through = 0 ORDER BY {} DESC LIMIT ?3"#,
+            by.column()
+        );
+        let messages = sqlx::query_as::<_, ReceivedMessage>(&query)
+            .bind(tenant)
+            .bind(channel)
+            .bind(limit)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(messages)
+    })
+    .await
+}
+
+// the readings of a channel of a tenant with a timestamp in [start, end), summaries with
+// the number of readings they stand in for
+pub async fn get_window_data(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    channel: &str,
+    by: AggregationTimestamp,
+    start: i64,
+    end: i64,
+) -> Result<Vec<Sample>> {
+    timed("get_window_data", async move {
+        let query = format!(
+            r#"SELECT data, COALESCE(summary_count, 1), COALESCE(summary_min, data),
+                COALESCE(summary_max, data)
+            FROM received_messages
+            WHERE tenant IS ?4 AND channel = ?1 AND {0} >= ?2 AND {0} < ?3
+            AND pass_through = 0"#,
+            by.column()
+        );
+        let data = sqlx::query_as::<_, (f64, i64, f64, f64)>(&query)
+            .bind(channel)
+            .bind(start)
+            .bind(end)
+            .bind(tenant)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(data
+            .into_iter()
+            .map(|(value, count, min, max)| Sample {
+                value,
+                count,
+                min,
+                max,
+            })
+            .collect())
+    })
+    .await
+}
+
+pub async fn get_last_aggregation_tick(pool: &Pool<Sqlite>) -> Result<Option<i64>> {
+    timed("get_last_aggregation_tick", async move {
+        let last_tick =
+            sqlx::query_scalar::<_, i64>("SELECT last_tick FROM aggregation_state WHERE id = 0")
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(last_tick)
+    })
+    .await
+}
+
+pub async fn set_last_aggregation_tick(pool: &Pool<Sqlite>, last_tick: i64) -> Result<()> {
+    timed("set_last_aggregation_tick", async move {
+        sqlx::query(
+            r#"INSERT INTO aggregation_state ( id, last_tick ) VALUES ( 0, ?1 )
+            ON CONFLICT ( id ) DO UPDATE SET last_tick = excluded.last_tick"#,
+        )
+        .bind(last_tick)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// queues a message for all devices of a tenant
+pub async fn add_queued_message(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    msg: String,
+) -> Result<()> {
+    timed("add_queued_message", async move {
+        let now = unix_now();
+
+        sqlx::query(
+            "INSERT INTO queued_messages ( message, created_at, tenant ) VALUES ( ?1, ?2, ?3 )",
+        )
+        .bind(msg)
+        .bind(now)
+        .bind(tenant)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// an average computed by the AVG service
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct Aggregate {
+    #[serde(skip)]
+    pub id: i64,
+    pub tenant: Option<String>,
+    pub channel: String,
+    pub window_start: i64,
+    pub window_end: i64,
+    pub value: f64,
+    pub count: i64,
+    // seconds the newest reading was old when the aggregate was computed, unset for backfills
+    pub staleness: Option<i64>,
+}
+
+pub async fn add_aggregate(pool: &Pool<Sqlite>, aggregate: &Aggregate) -> Result<()> {
+    timed("add_aggregate", async move {
+        sqlx::query(
+            r#"INSERT INTO aggregates ( tenant, channel, window_start, window_end, value, count, staleness )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )"#,
+        )
+        .bind(&aggregate.tenant)
+        .bind(&aggregate.channel)
+        .bind(aggregate.window_start)
+        .bind(aggregate.window_end)
+        .bind(aggregate.value)
+        .bind(aggregate.count)
+        .bind(aggregate.staleness)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// the aggregates whose window overlaps [since, until), oldest first. None for the tenant
+// or channel includes all of them
+pub async fn get_aggregates(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+    channel: Option<&str>,
+    since: i64,
+    until: i64,
+    limit: i64,
+) -> Result<Vec<Aggregate>> {
+    timed("get_aggregates", async move {
+        let aggregates = sqlx::query_as::<_, Aggregate>(
+            r#"SELECT * FROM aggregates
+            WHERE ( ?1 IS NULL OR tenant = ?1 ) AND ( ?2 IS NULL OR channel = ?2 )
+            AND window_end >= ?3 AND window_start < ?4
+            ORDER BY window_end ASC, id ASC LIMIT ?5"#,
+        )
+        .bind(tenant)
+        .bind(channel)
+        .bind(since)
+        .bind(until)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(aggregates)
+    })
+    .await
+}
+
+// queues a message for a single device, returns its id
+pub async fn add_targeted_message(pool: &Pool<Sqlite>, uid: &str, msg: String) -> Result<i64> {
+    timed("add_targeted_message", async move {
+        let now = unix_now();
+
+        let res = sqlx::query(
+            "INSERT INTO queued_messages ( message, created_at, target_uid ) VALUES ( ?1, ?2, ?3 )",
+        )
+        .bind(msg)
+        .bind(now)
+        .bind(uid)
+        .execute(pool)
+        .await?;
+
+        Ok(res.last_insert_rowid())
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct Command {
+    pub id: i64,
+    pub message: String,
+    pub created_at: i64,
+    // sent but not acknowledged yet
+    pub pending: bool,
+    pub acknowledged: bool,
+}
+
+// messages queued for a single device, oldest first
+pub async fn get_targeted_messages(pool: &Pool<Sqlite>, uid: &str) -> Result<Vec<Command>> {
+    timed("get_targeted_messages", async move {
+        let commands = sqlx::query_as::<_, Command>(
+            r#"SELECT id, message, created_at,
+                EXISTS ( SELECT 1 FROM pending_deliveries p WHERE p.queued_message_id = q.id ) as pending,
+                EXISTS ( SELECT 1 FROM delivered_messages d WHERE d.queued_message_id = q.id ) as acknowledged
+            FROM queued_messages q WHERE target_uid = ?1 ORDER BY created_at ASC, id ASC"#,
+        )
+        .bind(uid)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(commands)
+    })
+    .await
+}
+
+// returns up to `limit` of the oldest queued messages for the given uid it neither acknowledged
+// nor was sent after `resend_before`, so unacknowledged messages are picked up again after a timeout.
+// every connection receives the broadcast messages of its tenant once, independent of the others
+pub async fn get_new_queued_messages(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    resend_before: i64,
+    limit: i64,
+) -> Result<Vec<QueuedMessage>> {
+    timed("get_new_queued_messages", async move {
+        let messages = sqlx::query_as::<_, QueuedMessage>(
+            r#"SELECT * FROM queued_messages
+            WHERE ( target_uid = ?1 OR ( target_uid IS NULL
+                AND tenant IS ( SELECT tenant FROM connections WHERE uid = ?1 ) ) )
+            AND id NOT IN ( SELECT queued_message_id FROM delivered_messages WHERE uid = ?1 )
+            AND id NOT IN ( SELECT queued_message_id FROM pending_deliveries WHERE uid = ?1 AND sent_at > ?2 )
+            ORDER BY created_at ASC, id ASC
+            LIMIT ?3"#,
+        )
+        .bind(uid)
+        .bind(resend_before)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(messages)
+    })
+    .await
+}
+
+// a delivery sent at the unix timestamp, it is resent if it isn't acknowledged in time
+pub async fn add_pending_delivery(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    queued_message_id: i64,
+    sent_at: i64,
+) -> Result<()> {
+    timed("add_pending_delivery", async move {
+        sqlx::query(
+            r#"INSERT INTO pending_deliveries ( uid, queued_message_id, sent_at ) VALUES ( ?1, ?2, ?3 )
+            ON CONFLICT ( uid, queued_message_id ) DO UPDATE SET sent_at = excluded.sent_at, attempts = attempts + 1"#,
+        )
+        .bind(uid)
+        .bind(queued_message_id)
+        .bind(sent_at)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn count_pending_deliveries(pool: &Pool<Sqlite>, uid: &str) -> Result<i64> {
+    timed("count_pending_deliveries", async move {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM pending_deliveries WHERE uid = ?1")
+                .bind(uid)
+                .fetch_one(pool)
+                .await?;
+
+        Ok(count)
+    })
+    .await
+}
+
+// makes all deliveries that were still waiting for an ACK when the server stopped due again,
+// they are then sent in queue order on the next writer tick of each connection
+pub async fn recover_pending_deliveries(pool: &Pool<Sqlite>) -> Result<u64> {
+    timed("recover_pending_deliveries", async move {
+        let recovered = sqlx::query("UPDATE pending_deliveries SET sent_at = 0")
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+        Ok(recovered)
+    })
+    .await
+}
+
+// moves a pending delivery to the delivered messages, returns false if there was nothing to acknowledge
+pub async fn acknowledge_delivery(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    queued_message_id: i64,
+) -> Result<bool> {
+    timed("acknowledge_delivery", async move {
+        let mut tx = pool.begin().await?;
+
+        let removed =
+            sqlx::query("DELETE FROM pending_deliveries WHERE uid = ?1 AND queued_message_id = ?2")
+                .bind(uid)
+                .bind(queued_message_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+
+        if removed == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("INSERT INTO delivered_messages ( uid, queued_message_id ) VALUES ( ?1, ?2 )")
+            .bind(uid)
+            .bind(queued_message_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    })
+    .await
+}
+
+pub async fn add_bandwidth_sample(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    bytes_in: i64,
+    bytes_out: i64,
+    now: i64,
+) -> Result<()> {
+    timed("add_bandwidth_sample", async move {
+        sqlx::query(
+            "INSERT INTO bandwidth_samples ( uid, bytes_in, bytes_out, created_at ) VALUES ( ?1, ?2, ?3, ?4 )",
+        )
+        .bind(uid)
+        .bind(bytes_in)
+        .bind(bytes_out)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn save_connection_stats(pool: &Pool<Sqlite>, stats: &ConnectionStats) -> Result<()> {
+    timed("save_connection_stats", async move {
+        sqlx::query(
+            r#"INSERT INTO connection_stats ( uid, session_id, frames_in, frames_out, bytes_in,
+            bytes_out, parse_errors, last_activity, updated_at, raw_bytes_in, raw_bytes_out )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11 )
+            ON CONFLICT ( uid ) DO UPDATE SET session_id = excluded.session_id,
+            frames_in = excluded.frames_in, frames_out = excluded.frames_out,
+            bytes_in = excluded.bytes_in, bytes_out = excluded.bytes_out,
+            raw_bytes_in = excluded.raw_bytes_in, raw_bytes_out = excluded.raw_bytes_out,
+            parse_errors = excluded.parse_errors, last_activity = excluded.last_activity,
+            updated_at = excluded.updated_at"#,
+        )
+        .bind(&stats.uid)
+        .bind(&stats.session_id)
+        .bind(stats.frames_in)
+        .bind(stats.frames_out)
+        .bind(stats.bytes_in)
+        .bind(stats.bytes_out)
+        .bind(stats.parse_errors)
+        .bind(stats.last_activity)
+        .bind(stats.updated_at)
+        .bind(stats.raw_bytes_in)
+        .bind(stats.raw_bytes_out)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn get_connection_stats(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+) -> Result<Option<ConnectionStats>> {
+    timed("get_connection_stats", async move {
+        let stats =
+            sqlx::query_as::<_, ConnectionStats>("SELECT * FROM connection_stats WHERE uid = ?1")
+                .bind(uid)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(stats)
+    })
+    .await
+}
+
+pub async fn get_bandwidth_samples(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    since: i64,
+) -> Result<Vec<BandwidthSample>> {
+    timed("get_bandwidth_samples", async move {
+        let samples = sqlx::query_as::<_, BandwidthSample>(
+            r#"SELECT bytes_in, bytes_out, created_at FROM bandwidth_samples
+            WHERE uid = ?1 AND created_at >= ?2 ORDER BY created_at ASC"#,
+        )
+        .bind(uid)
+        .bind(since)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(samples)
+    })
+    .await
+}
+
+// bytes sent and received per device since the given time
+pub async fn get_bandwidth_totals(pool: &Pool<Sqlite>, since: i64) -> Result<Vec<(String, i64)>> {
+    timed("get_bandwidth_totals", async move {
+        let totals = sqlx::query_as::<_, (String, i64)>(
+            r#"SELECT uid, SUM(bytes_in + bytes_out) FROM bandwidth_samples
+            WHERE created_at >= ?1 GROUP BY uid"#,
+        )
+        .bind(since)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(totals)
+    })
+    .await
+}
+
+// the devices of a tenant, or of all tenants, needing attention
+pub async fn get_attention(
+    pool: &Pool<Sqlite>,
+    tenant: Option<&str>,
+) -> Result<Vec<AttentionItem>> {
+    timed("get_attention", async move {
+        let items = sqlx::query_as::<_, AttentionItem>(
+            r#"SELECT * FROM attention
+            WHERE ?1 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?1 )
+            ORDER BY since ASC, uid ASC"#,
+        )
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(items)
+    })
+    .await
+}
+
+pub async fn replace_attention(pool: &Pool<Sqlite>, items: &[AttentionItem]) -> Result<()> {
+    timed("replace_attention", async move {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM attention")
+            .execute(&mut *tx)
+            .await?;
+
+        for item in items {
+            sqlx::query(
+                "INSERT INTO attention ( uid, reason, detail, since ) VALUES ( ?1, ?2, ?3, ?4 )",
+            )
+            .bind(&item.uid)
+            .bind(&item.reason)
+            .bind(&item.detail)
+            .bind(item.since)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct FeatureFlag {
+    pub name: String,
+    // empty for the whole deployment
+    pub group_name: String,
+    pub enabled: bool,
+    pub updated_at: i64,
+}
+
+pub async fn get_feature_flags(pool: &Pool<Sqlite>) -> Result<Vec<FeatureFlag>> {
+    timed("get_feature_flags", async move {
+        let flags = sqlx::query_as::<_, FeatureFlag>(
+            "SELECT * FROM feature_flags ORDER BY name, group_name",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(flags)
+    })
+    .await
+}
+
+pub async fn set_feature_flag(
+    pool: &Pool<Sqlite>,
+    name: &str,
+    group_name: &str,
+    enabled: bool,
+) -> Result<()> {
+    timed("set_feature_flag", async move {
+        sqlx::query(
+            r#"INSERT INTO feature_flags ( name, group_name, enabled, updated_at ) VALUES ( ?1, ?2, ?3, ?4 )
+            ON CONFLICT ( name, group_name ) DO UPDATE SET enabled = excluded.enabled, updated_at = excluded.updated_at"#,
+        )
+        .bind(name)
+        .bind(group_name)
+        .bind(enabled)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn delete_feature_flag(pool: &Pool<Sqlite>, name: &str, group_name: &str) -> Result<()> {
+    timed("delete_feature_flag", async move {
+        sqlx::query("DELETE FROM feature_flags WHERE name = ?1 AND group_name = ?2")
+            .bind(name)
+            .bind(group_name)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// a tenant with the number of its devices and stored readings
+#[derive(FromRow, Serialize, Debug)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub devices: i64,
+    pub received_messages: i64,
+}
+
+const TENANT_QUERY: &str = r#"SELECT id, name, created_at,
+    ( SELECT COUNT(*) FROM connections WHERE tenant = tenants.id ) AS devices,
+    ( SELECT COUNT(*) FROM received_messages WHERE tenant = tenants.id ) AS received_messages
+    FROM tenants"#;
+
+// returns false if the id is taken
+pub async fn add_tenant(pool: &Pool<Sqlite>, id: &str, name: &str) -> Result<bool> {
+    timed("add_tenant", async move {
+        let res = sqlx::query(
+            r#"INSERT INTO tenants ( id, name, created_at ) VALUES ( ?1, ?2, ?3 )
+            ON CONFLICT ( id ) DO NOTHING"#,
+        )
+        .bind(id)
+        .bind(name)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+pub async fn get_tenant(pool: &Pool<Sqlite>, id: &str) -> Result<Tenant> {
+    timed("get_tenant", async move {
+        let tenant = sqlx::query_as::<_, Tenant>(&format!("{} WHERE id = ?1", TENANT_QUERY))
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(tenant)
+    })
+    .await
+}
+
+pub async fn get_tenants(pool: &Pool<Sqlite>) -> Result<Vec<Tenant>> {
+    timed("get_tenants", async move {
+        let tenants = sqlx::query_as::<_, Tenant>(&format!("{} ORDER BY id", TENANT_QUERY))
+            .fetch_all(pool)
+            .await?;
+
+        Ok(tenants)
+    })
+    .await
+}
+
+// None for unknown devices and devices outside of any tenant
+pub async fn get_device_tenant(pool: &Pool<Sqlite>, uid: &str) -> Result<Option<String>> {
+    timed("get_device_tenant", async move {
+        let tenant: Option<Option<String>> =
+            sqlx::query_scalar("SELECT tenant FROM connections WHERE uid = ?1")
+                .bind(uid)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(tenant.flatten())
+    })
+    .await
+}
+
+pub async fn get_device_group(pool: &Pool<Sqlite>, uid: &str) -> Result<Option<String>> {
+    timed("get_device_group", async move {
+        let group = sqlx::query_scalar("SELECT group_name FROM device_groups WHERE uid = ?1")
+            .bind(uid)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(group)
+    })
+    .await
+}
+
+// whether the device belongs to a critical group
+pub async fn is_critical_device(pool: &Pool<Sqlite>, uid: &str) -> Result<bool> {
+    timed("is_critical_device", async move {
+        let critical: Option<bool> = sqlx::query_scalar(
+            r#"SELECT group_policies.critical FROM device_groups
+            JOIN group_policies ON group_policies.group_name = device_groups.group_name
+            WHERE device_groups.uid = ?1"#,
+        )
+        .bind(uid)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(critical.unwrap_or(false))
+    })
+    .await
+}
+
+pub async fn set_device_group(pool: &Pool<Sqlite>, uid: &str, group_name: &str) -> Result<()> {
+    timed("set_device_group", async move {
+        sqlx::query(
+            r#"INSERT INTO device_groups ( uid, group_name ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( uid ) DO UPDATE SET group_name = excluded.group_name"#,
+        )
+        .bind(uid)
+        .bind(group_name)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// the attributes of a device in the registry
+pub async fn get_device_attributes(pool: &Pool<Sqlite>, uid: &str) -> Result<Attributes> {
+    timed("get_device_attributes", async move {
+        let attributes = sqlx::query_as::<_, (String, String)>(
+            "SELECT name, value FROM device_attributes WHERE uid = ?1",
+        )
+        .bind(uid)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(attributes.into_iter().collect())
+    })
+    .await
+}
+
+// replaces the attributes of a known device, readings stored from now on carry them
+pub async fn set_device_attributes(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    attributes: &Attributes,
+) -> Result<()> {
+    timed("set_device_attributes", async move {
+        let mut tx = pool.begin().await?;
+        let known = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM connections WHERE uid = ?1")
+            .bind(uid)
+            .fetch_one(&mut *tx)
+            .await?;
+        if known == 0 {
+            return Err(crate::Error::NotFound);
+        }
+
+        sqlx::query("DELETE FROM device_attributes WHERE uid = ?1")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        for (name, value) in attributes {
+            sqlx::query("INSERT INTO device_attributes ( uid, name, value ) VALUES ( ?1, ?2, ?3 )")
+                .bind(uid)
+                .bind(name)
+                .bind(value)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn set_group_policy(pool: &Pool<Sqlite>, group_name: &str, critical: bool) -> Result<()> {
+    timed("set_group_policy", async move {
+        sqlx::query(
+            r#"INSERT INTO group_policies ( group_name, critical ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( group_name ) DO UPDATE SET critical = excluded.critical"#,
+        )
+        .bind(group_name)
+        .bind(critical)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+pub async fn get_device_value(pool: &Pool<Sqlite>, uid: &str, key: &str) -> Result<Option<String>> {
+    timed("get_device_value", async move {
+        let value = sqlx::query_scalar::<_, String>(
+            "SELECT value FROM device_kv WHERE uid = ?1 AND key = ?2",
+        )
+        .bind(uid)
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(value)
+    })
+    .await
+}
+
+pub async fn set_device_value(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    timed("set_device_value", async move {
+        let now = unix_now();
+
+        sqlx::query(
+            r#"INSERT INTO device_kv ( uid, key, value, updated_at ) VALUES ( ?1, ?2, ?3, ?4 )
+            ON CONFLICT ( uid, key ) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at"#,
+        )
+        .bind(uid)
+        .bind(key)
+        .bind(value)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// tables the retention service prunes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PrunableTable {
+    Received,
+    Queued,
+    Delivered,
+}
+
+impl PrunableTable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PrunableTable::Received => "received_messages",
+            PrunableTable::Queued => "queued_messages",
+            PrunableTable::Delivered => "delivered_messages",
+        }
+    }
+}
+
+// deletes up to `batch` rows created before the given time, returns the number deleted.
+// delivered messages have no creation time, they go along with their queued message.
+pub async fn prune_older_than(
+    pool: &Pool<Sqlite>,
+    table: PrunableTable,
+    before: i64,
+    batch: i64,
+) -> Result<u64> {
+    timed("prune_older_than", async move {
+        if table == PrunableTable::Delivered {
+            return Ok(0);
+        }
+
+        let query = format!(
+            "DELETE FROM {0} WHERE id IN ( SELECT id FROM {0} WHERE created_at < ?1 ORDER BY id LIMIT ?2 )",
+            table.name()
+        );
+        let res = sqlx::query(&query)
+            .bind(before)
+            .bind(batch)
+            .execute(pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    })
+    .await
+}
+
+// deletes up to `batch` of the oldest rows exceeding `max_rows`, returns the number deleted
+pub async fn prune_excess_rows(
+    pool: &Pool<Sqlite>,
+    table: PrunableTable,
+    max_rows: i64,
+    batch: i64,
+) -> Result<u64> {
+    timed("prune_excess_rows", async move {
+        let query = format!(
+            r#"DELETE FROM {0} WHERE id IN (
+                SELECT id FROM {0} ORDER BY id
+                LIMIT MIN(?2, MAX(0, ( SELECT COUNT(*) FROM {0} ) - ?1))
+            )"#,
+            table.name()
+        );
+        let res = sqlx::query(&query)
+            .bind(max_rows)
+            .bind(batch)
+            .execute(pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    })
+    .await
+}
+
+pub async fn count_received_messages(pool: &Pool<Sqlite>) -> Result<i64> {
+    timed("count_received_messages", async move {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM received_messages")
+            .fetch_one(pool)
+            .await?;
+
+        Ok(count)
+    })
+    .await
+}
+
+// replaces the oldest `batch` raw readings, and the rest of the minutes they fall into, by
+// readings of a device and channel in one minute
+#[derive(FromRow)]
+struct Summary {
+    id: i64,
+    uid: String,
+    channel: String,
+    tenant: Option<String>,
+    pass_through: bool,
+    fog_uid: Option<String>,
+    attributes: Option<String>,
+    received_at: Option<i64>,
+    minute: i64,
+    avg: f64,
+    min: f64,
+    max: f64,
+    count: i64,
+}
+
+// one row per device, channel and minute with their mean, min, max and count. the rows
+// take the smallest id of their readings, so they are still pruned first. readings
+// forwarded by different fog nodes or stored with different attributes are summarized
+// apart, a summary is received when its last reading was. the raw_data of converted
+// readings and the seq of the device are lost, a summary is no reading the device sent.
+// returns the number of raw readings summarized
+pub async fn summarize_oldest_readings(pool: &Pool<Sqlite>, batch: i64) -> Result<u64> {
+    timed("summarize_oldest_readings", async move {
+        let mut tx = pool.begin().await?;
+
+        let newest: Option<i64> = sqlx::query_scalar(
+            r#"SELECT MAX(created_at) FROM (
+                SELECT created_at FROM received_messages WHERE summary_count IS NULL
+                ORDER BY id LIMIT ?1
+            )"#,
+        )
+        .bind(batch)
+        .fetch_one(&mut *tx)
+        .await?;
+        let Some(newest) = newest else {
+            return Ok(0);
+        };
+        let until = newest - newest.rem_euclid(60) + 60;
+
+        let summaries = sqlx::query_as::<_, Summary>(
+            r#"SELECT MIN(id) AS id, uid, channel, tenant, pass_through, fog_uid, attributes,
+                MAX(received_at) AS received_at, created_at - created_at % 60 AS minute,
+                AVG(data) AS avg, MIN(data) AS min, MAX(data) AS max, COUNT(*) AS count
+            FROM received_messages WHERE summary_count IS NULL AND created_at < ?1
+            GROUP BY uid, channel, tenant, pass_through, fog_uid, attributes,
+                created_at - created_at % 60"#,
+        )
+        .bind(until)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let summarized = sqlx::query(
+            "DELETE FROM received_messages WHERE summary_count IS NULL AND created_at < ?1",
+        )
+        .bind(until)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        for summary in summaries {
+            sqlx::query(
+                r#"INSERT INTO received_messages ( id, uid, channel, tenant, created_at, data,
+                    summary_min, summary_max, summary_count, pass_through, fog_uid, attributes,
+                    received_at )
+                VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13 )"#,
+            )
+            .bind(summary.id)
+            .bind(summary.uid)
+            .bind(summary.channel)
+            .bind(summary.tenant)
+            .bind(summary.minute)
+            .bind(summary.avg)
+            .bind(summary.min)
+            .bind(summary.max)
+            .bind(summary.count)
+            .bind(summary.pass_through)
+            .bind(summary.fog_uid)
+            .bind(summary.attributes)
+            .bind(summary.received_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(summarized)
+    })
+    .await
+}
+
+// returns the pages of deleted rows to the file system. a db created without
+// incremental auto vacuum is converted once with a full VACUUM.
+pub async fn reclaim_space(pool: &Pool<Sqlite>) -> Result<()> {
+    timed("reclaim_space", async move {
+        // 2 is INCREMENTAL
+        let mode = sqlx::query_scalar::<_, i64>("PRAGMA auto_vacuum")
+            .fetch_one(pool)
+            .await?;
+
+        if mode == 2 {
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(pool)
+                .await?;
+        } else {
+            // the mode only changes with the next VACUUM, both have to use the same connection
+            let mut conn = pool.acquire().await?;
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+            info!("Switched the db to incremental auto vacuum");
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RollupPeriod {
+    Hour,
+    Day,
+}
+
+impl RollupPeriod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RollupPeriod::Hour => "hour",
+            RollupPeriod::Day => "day",
+        }
+    }
+
+    fn table(&self) -> &'static str {
+        match self {
+            RollupPeriod::Hour => "rollups_hourly",
+            RollupPeriod::Day => "rollups_daily",
+        }
+    }
+
+    pub fn secs(&self) -> i64 {
+        match self {
+            RollupPeriod::Hour => 60 * 60,
+            RollupPeriod::Day => 24 * 60 * 60,
+        }
+    }
+
+    // start of the bucket containing the given time
+    pub fn bucket(&self, time: i64) -> i64 {
+        time - time.rem_euclid(self.secs())
+    }
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct Rollup {
+    pub uid: String,
+    pub channel: String,
+    pub bucket: i64,
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: i64,
+}
+
+pub async fn get_next_rollup_bucket(
+    pool: &Pool<Sqlite>,
+    period: RollupPeriod,
+) -> Result<Option<i64>> {
+    timed("get_next_rollup_bucket", async move {
+        let next_bucket =
+            sqlx::query_scalar::<_, i64>("SELECT next_bucket FROM rollup_state WHERE period = ?1")
+                .bind(period.name())
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(next_bucket)
+    })
+    .await
+}
+
+// rolls up the buckets in [start, end) and moves the period's next bucket to end.
+// hours are rolled up from the received readings, days from the hours.
+pub async fn roll_up(
+    pool: &Pool<Sqlite>,
+    period: RollupPeriod,
+    start: i64,
+    end: i64,
+) -> Result<u64> {
+    timed("roll_up", async move {
+        let query = match period {
+            RollupPeriod::Hour => {
+                r#"INSERT OR REPLACE INTO rollups_hourly ( uid, channel, bucket, avg, min, max, count )
+                SELECT uid, channel, created_at - created_at % ?3,
+                    SUM(data * COALESCE(summary_count, 1)) / SUM(COALESCE(summary_count, 1)),
+                    MIN(COALESCE(summary_min, data)), MAX(COALESCE(summary_max, data)),
+                    SUM(COALESCE(summary_count, 1))
+                FROM received_messages WHERE created_at >= ?1 AND created_at < ?2
+                GROUP BY uid, channel, created_at - created_at % ?3"#
+            }
+            RollupPeriod::Day => {
+                r#"INSERT OR REPLACE INTO rollups_daily ( uid, channel, bucket, avg, min, max, count )
+                SELECT uid, channel, bucket - bucket % ?3, SUM(avg * count) / SUM(count), MIN(min), MAX(max), SUM(count)
+                FROM rollups_hourly WHERE bucket >= ?1 AND bucket < ?2
+                GROUP BY uid, channel, bucket - bucket % ?3"#
+            }
+        };
+
+        let mut tx = pool.begin().await?;
+
+        let res = sqlx::query(query)
+            .bind(start)
+            .bind(end)
+            .bind(period.secs())
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"INSERT INTO rollup_state ( period, next_bucket ) VALUES ( ?1, ?2 )
+            ON CONFLICT ( period ) DO UPDATE SET next_bucket = excluded.next_bucket"#,
+        )
+        .bind(period.name())
+        .bind(end)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(res.rows_affected())
+    })
+    .await
+}
+
+// rollups of a device with buckets starting in [since, until), oldest first
+pub async fn get_rollups(
+    pool: &Pool<Sqlite>,
+    period: RollupPeriod,
+    uid: &str,
+    channel: Option<&str>,
+    since: i64,
+    until: i64,
+) -> Result<Vec<Rollup>> {
+    timed("get_rollups", async move {
+        let query = format!(
+            r#"SELECT * FROM {} WHERE uid = ?1 AND ( ?2 IS NULL OR channel = ?2 )
+            AND bucket >= ?3 AND bucket < ?4 ORDER BY bucket ASC, channel ASC"#,
+            period.table()
+        );
+        let rollups = sqlx::query_as::<_, Rollup>(&query)
+            .bind(uid)
+            .bind(channel)
+            .bind(since)
+            .bind(until)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rollups)
+    })
+    .await
+}
+
+pub async fn get_next_archive_day(pool: &Pool<Sqlite>) -> Result<Option<i64>> {
+    timed("get_next_archive_day", async move {
+        let next_day =
+            sqlx::query_scalar::<_, i64>("SELECT next_day FROM archive_state WHERE id = 0")
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(next_day)
+    })
+    .await
+}
+
+pub async fn set_next_archive_day(pool: &Pool<Sqlite>, next_day: i64) -> Result<()> {
+    timed("set_next_archive_day", async move {
+        sqlx::query(
+            r#"INSERT INTO archive_state ( id, next_day ) VALUES ( 0, ?1 )
+            ON CONFLICT ( id ) DO UPDATE SET next_day = excluded.next_day"#,
+        )
+        .bind(next_day)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// time of the oldest reading or hourly rollup, the archive starts with its day
+pub async fn get_oldest_archivable_time(pool: &Pool<Sqlite>) -> Result<Option<i64>> {
+    timed("get_oldest_archivable_time", async move {
+        let oldest = sqlx::query_scalar::<_, Option<i64>>(
+            r#"SELECT MIN(oldest) FROM (
+                SELECT MIN(created_at) AS oldest FROM received_messages
+                UNION ALL SELECT MIN(bucket) FROM rollups_hourly
+            )"#,
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(oldest)
+    })
+    .await
+}
+
+// devices with readings or hourly rollups in [since, until)
+pub async fn get_archivable_uids(
+    pool: &Pool<Sqlite>,
+    since: i64,
+    until: i64,
+) -> Result<Vec<String>> {
+    timed("get_archivable_uids", async move {
+        let uids = sqlx::query_scalar::<_, String>(
+            r#"SELECT uid FROM received_messages WHERE created_at >= ?1 AND created_at < ?2
+            UNION SELECT uid FROM rollups_hourly WHERE bucket >= ?1 AND bucket < ?2
+            ORDER BY uid"#,
+        )
+        .bind(since)
+        .bind(until)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(uids)
+    })
+    .await
+}
+
+// deletes the readings of a device in [since, until) up to the row id, so readings
+// received late are kept. returns how many were deleted
+pub async fn delete_readings(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    since: i64,
+    until: i64,
+    last_id: i64,
+) -> Result<u64> {
+    timed("delete_readings", async move {
+        let deleted = sqlx::query(
+            r#"DELETE FROM received_messages
+            WHERE uid = ?1 AND created_at >= ?2 AND created_at < ?3 AND id <= ?4"#,
+        )
+        .bind(uid)
+        .bind(since)
+        .bind(until)
+        .bind(last_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+        Ok(deleted)
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct AlertRule {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub uid: String,
+    pub channel: Option<String>,
+    pub op: String,
+    pub threshold: f64,
+    #[serde(default)]
+    pub duration_secs: i64,
+    #[serde(default)]
+    pub created_at: i64,
+    // device a CMD is sent to as soon as the rule fires, unset only alerts
+    pub action_uid: Option<String>,
+    pub action_command: Option<String>,
+    pub action_argument: Option<String>,
+}
+
+#[derive(FromRow, Serialize, Clone, Debug, ToSchema)]
+pub struct Alert {
+    pub id: i64,
+    pub rule_id: i64,
+    pub uid: String,
+    pub channel: String,
+    pub data: f64,
+    pub triggered_at: i64,
+    pub resolved_at: Option<i64>,
+}
+
+pub async fn add_alert_rule(pool: &Pool<Sqlite>, rule: &AlertRule) -> Result<i64> {
+    timed("add_alert_rule", async move {
+        let now = unix_now();
+
+        let res = sqlx::query(
+            r#"INSERT INTO alert_rules ( uid, channel, op, threshold, duration_secs, created_at,
+                action_uid, action_command, action_argument )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 )"#,
+        )
+        .bind(&rule.uid)
+        .bind(&rule.channel)
+        .bind(&rule.op)
+        .bind(rule.threshold)
+        .bind(rule.duration_secs)
+        .bind(now)
+        .bind(&rule.action_uid)
+        .bind(&rule.action_command)
+        .bind(&rule.action_argument)
+        .execute(pool)
+        .await?;
+
+        Ok(res.last_insert_rowid())
+    })
+    .await
+}
+
+pub async fn get_alert_rules(pool: &Pool<Sqlite>) -> Result<Vec<AlertRule>> {
+    timed("get_alert_rules", async move {
+        let rules = sqlx::query_as::<_, AlertRule>("SELECT * FROM alert_rules ORDER BY id ASC")
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rules)
+    })
+    .await
+}
+
+pub async fn get_device_alert_rules(pool: &Pool<Sqlite>, uid: &str) -> Result<Vec<AlertRule>> {
+    timed("get_device_alert_rules", async move {
+        let rules = sqlx::query_as::<_, AlertRule>(
+            "SELECT * FROM alert_rules WHERE uid = ?1 ORDER BY id ASC",
+        )
+        .bind(uid)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rules)
+    })
+    .await
+}
+
+// returns the uid of the removed rule, None if there is no rule with the id
+pub async fn delete_alert_rule(pool: &Pool<Sqlite>, id: i64) -> Result<Option<String>> {
+    timed("delete_alert_rule", async move {
+        let uid = sqlx::query_scalar("DELETE FROM alert_rules WHERE id = ?1 RETURNING uid")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(uid)
+    })
+    .await
+}
+
+// which version of its alert rules a device runs
+#[derive(FromRow, Serialize, Default, Debug, ToSchema)]
+pub struct RuleVersion {
+    pub version: i64,
+    // 0 until the device acknowledged a version
+    pub acked_version: i64,
+    pub updated_at: i64,
+    pub acked_at: Option<i64>,
+}
+
+impl RuleVersion {
+    pub fn is_synced(&self) -> bool {
+        self.acked_version >= self.version
+    }
+}
+
+// called whenever the rules of a device change, returns the new version
+pub async fn bump_rule_version(pool: &Pool<Sqlite>, uid: &str) -> Result<i64> {
+    timed("bump_rule_version", async move {
+        let version = sqlx::query_scalar(
+            r#"INSERT INTO rule_versions ( uid, version, updated_at ) VALUES ( ?1, 1, ?2 )
+            ON CONFLICT ( uid ) DO UPDATE SET version = version + 1, updated_at = excluded.updated_at
+            RETURNING version"#,
+        )
+        .bind(uid)
+        .bind(unix_now())
+        .fetch_one(pool)
+        .await?;
+
+        Ok(version)
+    })
+    .await
+}
+
+// devices that never had rules are at version 0
+pub async fn get_rule_version(pool: &Pool<Sqlite>, uid: &str) -> Result<RuleVersion> {
+    timed("get_rule_version", async move {
+        let version = sqlx::query_as::<_, RuleVersion>(
+            "SELECT version, acked_version, updated_at, acked_at FROM rule_versions WHERE uid = ?1",
+        )
+        .bind(uid)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(version.unwrap_or_default())
+    })
+    .await
+}
+
+// returns false if the version is unknown or older than the acknowledged one
+pub async fn acknowledge_rule_version(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    version: i64,
+) -> Result<bool> {
+    timed("acknowledge_rule_version", async move {
+        let res = sqlx::query(
+            r#"UPDATE rule_versions SET acked_version = ?2, acked_at = ?3
+            WHERE uid = ?1 AND ?2 <= version AND ?2 > acked_version"#,
+        )
+        .bind(uid)
+        .bind(version)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    })
+    .await
+}
+
+// parameters of a device by name, like its sampling rate or thresholds
+pub type ConfigParams = BTreeMap<String, serde_json::Value>;
+
+// the parameters of a device, their version and the last one the device applied
+#[derive(Serialize, Default, Debug, ToSchema)]
+pub struct DeviceConfig {
+    #[schema(value_type = Object)]
+    pub params: ConfigParams,
+    pub version: i64,
+    // 0 until the device acknowledged a version
+    pub acked_version: i64,
+    pub updated_at: i64,
+    pub acked_at: Option<i64>,
+}
+
+impl DeviceConfig {
+    pub fn is_synced(&self) -> bool {
+        self.acked_version >= self.version
+    }
+}
+
+// devices without parameters are at version 0
+pub async fn get_device_config(pool: &Pool<Sqlite>, uid: &str) -> Result<DeviceConfig> {
+    timed("get_device_config", async move {
+        let row = sqlx::query_as::<_, (String, i64, i64, i64, Option<i64>)>(
+            r#"SELECT params, version, acked_version, updated_at, acked_at
+            FROM device_configs WHERE uid = ?1"#,
+        )
+        .bind(uid)
+        .fetch_optional(pool)
+        .await?;
+
+        let Some((params, version, acked_version, updated_at, acked_at)) = row else {
+            return Ok(DeviceConfig::default());
+        };
+        Ok(DeviceConfig {
+            params: serde_json::from_str(&params).unwrap_or_default(),
+            version,
+            acked_version,
+            updated_at,
+            acked_at,
+        })
+    })
+    .await
+}
+
+// replaces the parameters of a known device, returns their new version
+pub async fn set_device_config(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    params: &ConfigParams,
+) -> Result<i64> {
+    let params =
+        serde_json::to_string(params).map_err(|e| crate::Error::Protocol(e.to_string()))?;
+    timed("set_device_config", async move {
+        let mut tx = pool.begin().await?;
+        let known = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM connections WHERE uid = ?1")
+            .bind(uid)
+            .fetch_one(&mut *tx)
+            .await?;
+        if known == 0 {
+            return Err(crate::Error::NotFound);
+        }
+
+        let version = sqlx::query_scalar(
+            r#"INSERT INTO device_configs ( uid, params, version, updated_at ) VALUES ( ?1, ?2, 1, ?3 )
+            ON CONFLICT ( uid ) DO UPDATE SET params = excluded.params, version = version + 1,
+                updated_at = excluded.updated_at
+            RETURNING version"#,
+        )
+        .bind(uid)
+        .bind(&params)
+        .bind(unix_now())
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(version)
+    })
+    .await
+}
+
+// returns false if the version is unknown or older than the acknowledged one. with fog_uid
+// only the parameters of devices behind that fog node are acknowledged
+pub async fn acknowledge_device_config(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    version: i64,
+    fog_uid: Option<&str>,
+) -> Result<bool> {
+    timed("acknowledge_device_config", async move {
+        let res = sqlx::query(
+            r#"UPDATE device_configs SET acked_version = ?2, acked_at = ?3
+            WHERE uid = ?1 AND ?2 <= version AND ?2 > acked_version
+            AND ( ?4 IS NULL OR uid IN ( SELECT uid FROM connections WHERE fog_uid = ?4 ) )"#,
+        )
+        .bind(uid)
+        .bind(version)
+        .bind(unix_now())
+        .bind(fog_uid)
+        .execute(pool)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    })
+    .await
+}
+
+pub async fn add_alert(
+    pool: &Pool<Sqlite>,
+    rule: &AlertRule,
+    channel: &str,
+    data: f64,
+) -> Result<i64> {
+    timed("add_alert", async move {
+        let now = unix_now();
+
+        let res = sqlx::query(
+            r#"INSERT INTO alerts ( rule_id, uid, channel, data, triggered_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5 )"#,
+        )
+        .bind(rule.id)
+        .bind(&rule.uid)
+        .bind(channel)
+        .bind(data)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(res.last_insert_rowid())
+    })
+    .await
+}
+
+pub async fn resolve_alerts(pool: &Pool<Sqlite>, rule_id: i64) -> Result<()> {
+    timed("resolve_alerts", async move {
+        let now = unix_now();
+
+        sqlx::query(
+            "UPDATE alerts SET resolved_at = ?1 WHERE rule_id = ?2 AND resolved_at IS NULL",
+        )
+        .bind(now)
+        .bind(rule_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// the active alerts of the devices of a tenant, or of all tenants
+pub async fn get_active_alerts(pool: &Pool<Sqlite>, tenant: Option<&str>) -> Result<Vec<Alert>> {
+    timed("get_active_alerts", async move {
+        let alerts = sqlx::query_as::<_, Alert>(
+            r#"SELECT * FROM alerts WHERE resolved_at IS NULL
+            AND ( ?1 IS NULL OR uid IN ( SELECT uid FROM connections WHERE tenant = ?1 ) )
+            ORDER BY triggered_at ASC"#,
+        )
+        .bind(tenant)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(alerts)
+    })
+    .await
+}
+
+// a significant thing that happened to the server, see timeline
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct SystemEvent {
+    pub id: i64,
+    // see timeline::EventKind
+    pub kind: String,
+    pub detail: String,
+    pub created_at: i64,
+}
+
+pub async fn add_system_event(pool: &Pool<Sqlite>, kind: &str, detail: &str) -> Result<()> {
+    timed("add_system_event", async move {
+        sqlx::query("INSERT INTO events ( kind, detail, created_at ) VALUES ( ?1, ?2, ?3 )")
+            .bind(kind)
+            .bind(detail)
+            .bind(unix_now())
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// the events from `from` until before `to`, oldest first
+pub async fn get_system_events(
+    pool: &Pool<Sqlite>,
+    from: i64,
+    to: i64,
+    limit: i64,
+) -> Result<Vec<SystemEvent>> {
+    timed("get_system_events", async move {
+        let events = sqlx::query_as::<_, SystemEvent>(
+            r#"SELECT * FROM events WHERE created_at >= ?1 AND created_at < ?2
+            ORDER BY created_at ASC, id ASC LIMIT ?3"#,
+        )
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(events)
+    })
+    .await
+}
+
+// records an action taken through the admin endpoints
+pub async fn add_admin_audit(
+    pool: &Pool<Sqlite>,
+    action: &str,
+    uid: &str,
+    message: &str,
+    note: Option<&str>,
+) -> Result<()> {
+    timed("add_admin_audit", async move {
+        let now = unix_now();
+
+        sqlx::query(
+            r#"INSERT INTO admin_audit ( action, uid, message, note, created_at )
+            VALUES ( ?1, ?2, ?3, ?4, ?5 )"#,
+        )
+        .bind(action)
+        .bind(uid)
+        .bind(message)
+        .bind(note)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// an API key without its digest
+#[derive(FromRow, Serialize, Debug)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub role: String,
+    // the tenant whose data the key reads, None for every tenant
+    pub tenant: Option<String>,
+    pub created_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+pub async fn add_api_key(
+    pool: &Pool<Sqlite>,
+    name: &str,
+    key_hash: &str,
+    role: &str,
+    tenant: Option<&str>,
+) -> Result<ApiKey> {
+    timed("add_api_key", async move {
+        let key = sqlx::query_as::<_, ApiKey>(
+            r#"INSERT INTO api_keys ( name, key_hash, role, created_at, tenant )
+            VALUES ( ?1, ?2, ?3, ?4, ?5 )
+            RETURNING id, name, role, tenant, created_at, revoked_at"#,
+        )
+        .bind(name)
+        .bind(key_hash)
+        .bind(role)
+        .bind(unix_now())
+        .bind(tenant)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(key)
+    })
+    .await
+}
+
+pub async fn get_api_keys(pool: &Pool<Sqlite>) -> Result<Vec<ApiKey>> {
+    timed("get_api_keys", async move {
+        let keys = sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, role, tenant, created_at, revoked_at FROM api_keys ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(keys)
+    })
+    .await
+}
+
+// the role and the tenant of a key that wasn't revoked, None for unknown keys
+pub async fn get_api_key_grant(
+    pool: &Pool<Sqlite>,
+    key_hash: &str,
+) -> Result<Option<(String, Option<String>)>> {
+    timed("get_api_key_grant", async move {
+        let grant = sqlx::query_as(
+            "SELECT role, tenant FROM api_keys WHERE key_hash = ?1 AND revoked_at IS NULL",
+        )
+        .bind(key_hash)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(grant)
+    })
+    .await
+}
+
+// returns false if the key doesn't exist or was revoked already
+pub async fn revoke_api_key(pool: &Pool<Sqlite>, id: i64) -> Result<bool> {
+    timed("revoke_api_key", async move {
+        let res =
+            sqlx::query("UPDATE api_keys SET revoked_at = ?2 WHERE id = ?1 AND revoked_at IS NULL")
+                .bind(id)
+                .bind(unix_now())
+                .execute(pool)
+                .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+// a device provisioned in pairing mode
+#[derive(FromRow, Serialize, Debug)]
+pub struct Pairing {
+    pub uid: String,
+    pub name: Option<String>,
+    pub paired_at: i64,
+    // pending, approved or rejected
+    pub status: String,
+    pub decided_at: Option<i64>,
+}
+
+pub async fn add_pairing(pool: &Pool<Sqlite>, uid: &str, name: Option<&str>) -> Result<Pairing> {
+    timed("add_pairing", async move {
+        let pairing = sqlx::query_as::<_, Pairing>(
+            "INSERT INTO pairings ( uid, name, paired_at ) VALUES ( ?1, ?2, ?3 ) RETURNING *",
+        )
+        .bind(uid)
+        .bind(name)
+        .bind(unix_now())
+        .fetch_one(pool)
+        .await?;
+
+        Ok(pairing)
+    })
+    .await
+}
+
+// all pairings, the newest first. a status only returns the pairings in it
+pub async fn get_pairings(pool: &Pool<Sqlite>, status: Option<&str>) -> Result<Vec<Pairing>> {
+    timed("get_pairings", async move {
+        let pairings = sqlx::query_as::<_, Pairing>(
+            r#"SELECT * FROM pairings WHERE ?1 IS NULL OR status = ?1
+            ORDER BY paired_at DESC, uid"#,
+        )
+        .bind(status)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(pairings)
+    })
+    .await
+}
+
+// whether the pairing of a device was rejected, devices that weren't paired never are
+pub async fn is_pairing_rejected(pool: &Pool<Sqlite>, uid: &str) -> Result<bool> {
+    timed("is_pairing_rejected", async move {
+        let rejected = sqlx::query_scalar(
+            "SELECT EXISTS ( SELECT 1 FROM pairings WHERE uid = ?1 AND status = 'rejected' )",
+        )
+        .bind(uid)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(rejected)
+    })
+    .await
+}
+
+// approves or rejects a pairing, returns false if the device wasn't paired
+pub async fn decide_pairing(pool: &Pool<Sqlite>, uid: &str, status: &str) -> Result<bool> {
+    timed("decide_pairing", async move {
+        let res = sqlx::query("UPDATE pairings SET status = ?2, decided_at = ?3 WHERE uid = ?1")
+            .bind(uid)
+            .bind(status)
+            .bind(unix_now())
+            .execute(pool)
+            .await?;
+
+        Ok(res.rows_affected() == 1)
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub last_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+pub async fn add_webhook_delivery(
+    pool: &Pool<Sqlite>,
+    url: &str,
+    event: &str,
+    payload: &str,
+) -> Result<i64> {
+    timed("add_webhook_delivery", async move {
+        let now = unix_now();
+
+        let res = sqlx::query(
+            r#"INSERT INTO webhook_deliveries ( url, event, payload, status, created_at, updated_at )
+            VALUES ( ?1, ?2, ?3, 'pending', ?4, ?4 )"#,
+        )
+        .bind(url)
+        .bind(event)
+        .bind(payload)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(res.last_insert_rowid())
+    })
+    .await
+}
+
+// records an attempt of a delivery, status is pending while it is retried
+pub async fn update_webhook_delivery(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    status: &str,
+    attempts: i64,
+    last_status: Option<i64>,
+    last_error: Option<&str>,
+) -> Result<()> {
+    timed("update_webhook_delivery", async move {
+        let now = unix_now();
+
+        sqlx::query(
+            r#"UPDATE webhook_deliveries
+            SET status = ?2, attempts = ?3, last_status = ?4, last_error = ?5, updated_at = ?6
+            WHERE id = ?1"#,
+        )
+        .bind(id)
+        .bind(status)
+        .bind(attempts)
+        .bind(last_status)
+        .bind(last_error)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// deliveries that were still retried when the server stopped
+pub async fn get_pending_webhook_deliveries(pool: &Pool<Sqlite>) -> Result<Vec<WebhookDelivery>> {
+    timed("get_pending_webhook_deliveries", async move {
+        let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+            "SELECT * FROM webhook_deliveries WHERE status = 'pending' ORDER BY id",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(deliveries)
+    })
+    .await
+}
+
+// number of webhook deliveries by status
+pub async fn count_webhook_deliveries(pool: &Pool<Sqlite>) -> Result<Vec<(String, i64)>> {
+    timed("count_webhook_deliveries", async move {
+        let counts = sqlx::query_as::<_, (String, i64)>(
+            "SELECT status, COUNT(*) FROM webhook_deliveries GROUP BY status ORDER BY status",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(counts)
+    })
+    .await
+}
+
+// the most recent deliveries, newest first
+pub async fn get_webhook_deliveries(
+    pool: &Pool<Sqlite>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>> {
+    timed("get_webhook_deliveries", async move {
+        let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+            "SELECT * FROM webhook_deliveries ORDER BY id DESC LIMIT ?1",
+        )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(deliveries)
+    })
+    .await
+}
+
+#[derive(FromRow, Serialize, Debug, ToSchema)]
+pub struct DeviceSession {
+    pub id: String,
+    pub uid: String,
+    pub started_at: i64,
+    pub ended_at: Option<i64>,
+    pub end_reason: Option<String>,
+}
+
+// starts a session with a new id and ends the open sessions of the device, returns the id
+pub async fn start_session(pool: &Pool<Sqlite>, uid: &str) -> Result<String> {
+    timed("start_session", async move {
+        let now = unix_now();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"UPDATE device_sessions SET ended_at = ?2, end_reason = 'replaced'
+            WHERE uid = ?1 AND ended_at IS NULL"#,
+        )
+        .bind(uid)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("INSERT INTO device_sessions ( id, uid, started_at ) VALUES ( ?1, ?2, ?3 )")
+            .bind(&id)
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(id)
+    })
+    .await
+}
+
+// ends a session, unless it was already ended by a newer one
+pub async fn end_session(pool: &Pool<Sqlite>, id: &str) -> Result<()> {
+    timed("end_session", async move {
+        sqlx::query(
+            r#"UPDATE device_sessions SET ended_at = ?2, end_reason = 'closed'
+            WHERE id = ?1 AND ended_at IS NULL"#,
+        )
+        .bind(id)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    })
+    .await
+}
+
+// sessions of a device, newest first
+pub async fn get_device_sessions(
+    pool: &Pool<Sqlite>,
+    uid: &str,
+    limit: i64,
+) -> Result<Vec<DeviceSession>> {
+    timed("get_device_sessions", async move {
+        let sessions = sqlx::query_as::<_, DeviceSession>(
+            "SELECT * FROM device_sessions WHERE uid = ?1 ORDER BY started_at DESC, rowid DESC LIMIT ?2",
+        )
+        .bind(uid)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(sessions)
+    })
+    .await
 }
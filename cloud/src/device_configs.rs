@@ -0,0 +1,57 @@
+use tracing::{error, info};
+
+use crate::{db, protocols, sessions::Control, AppState};
+
+// the CONFIG message a device still has to apply, None if it runs the current version
+pub(crate) async fn pending_config(state: &AppState, uid: &str) -> Option<String> {
+    let config = match db::get_device_config(&state.pool, uid).await {
+        Ok(config) if !config.is_synced() => config,
+        Ok(_) => return None,
+        Err(_) => {
+            error!("Error getting the config of {}", uid);
+            return None;
+        }
+    };
+    let msg = protocols::ConfigMsg {
+        uid: uid.to_string(),
+        version: config.version,
+        params: config.params,
+    };
+    Some(msg.to_msg())
+}
+
+// the CONFIG messages sent on CONN, the one of the device and, for a fog node, the ones of
+// the devices behind it
+pub(crate) async fn pending_configs(state: &AppState, uid: &str, fog: bool) -> Vec<String> {
+    let mut configs: Vec<String> = pending_config(state, uid).await.into_iter().collect();
+    if !fog {
+        return configs;
+    }
+    match db::get_fog_devices(&state.pool, uid).await {
+        Ok(devices) => {
+            for device in devices {
+                configs.extend(pending_config(state, &device.uid).await);
+            }
+        }
+        Err(_) => error!("Error getting the devices behind {}", uid),
+    }
+    configs
+}
+
+// called after the config of a device changed, pushes the new version to the device or to
+// the fog node it is behind if it is connected. otherwise it is sent on the next CONN
+pub(crate) async fn config_changed(state: &AppState, uid: &str) {
+    let Some(msg) = pending_config(state, uid).await else {
+        return;
+    };
+    let target = match db::get_connection(&state.pool, uid).await {
+        Ok(connection) => connection.fog_uid.unwrap_or(connection.uid),
+        Err(_) => {
+            error!("Error getting the connection of {}", uid);
+            return;
+        }
+    };
+    if state.sessions.send(&target, Control::Send(msg)).await {
+        info!("Config of {} pushed to {}", uid, target);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::protocols::ParseError;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+// largest sealed payload, so a device can't fill the db with a single message
+pub const MAX_ENVELOPE_LEN: usize = 4096;
+
+// an end-to-end encrypted payload, sealed with X25519 and ChaCha20-Poly1305 to the public key of
+// the recipient. the cloud stores and forwards envelopes, but can't open them.
+//
+// wire format, hex encoded in messages:
+// <ephemeral public key, 32 bytes><nonce, 12 bytes><ciphertext with poly1305 tag>
+// the key is sha256("fog-hw e2e v1" | x25519 shared secret | ephemeral key | recipient key)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Envelope {
+    pub ephemeral_key: [u8; KEY_LEN],
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn from_hex(value: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidEnvelope;
+        if value.len() > 2 * MAX_ENVELOPE_LEN {
+            return Err(invalid());
+        }
+        let bytes = hex::decode(value).map_err(|_| invalid())?;
+        if bytes.len() < KEY_LEN + NONCE_LEN + TAG_LEN {
+            return Err(invalid());
+        }
+
+        let (ephemeral_key, rest) = bytes.split_at(KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        Ok(Self {
+            ephemeral_key: ephemeral_key.try_into().map_err(|_| invalid())?,
+            nonce: nonce.try_into().map_err(|_| invalid())?,
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        let mut bytes = Vec::with_capacity(KEY_LEN + NONCE_LEN + self.ciphertext.len());
+        bytes.extend_from_slice(&self.ephemeral_key);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.ciphertext);
+        hex::encode(bytes)
+    }
+}
+
+// sealing and opening envelopes, for devices and customers. the server never does either
+#[cfg(feature = "e2e")]
+pub mod crypto {
+    use super::{Envelope, KEY_LEN, NONCE_LEN};
+    use chacha20poly1305::{
+        aead::{Aead, Payload},
+        ChaCha20Poly1305, KeyInit,
+    };
+    use rand_core::{OsRng, RngCore};
+    use sha2::{Digest, Sha256};
+    use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+    const KDF_CONTEXT: &[u8] = b"fog-hw e2e v1";
+
+    // a new secret key and the public key to announce in CONN
+    pub fn keypair() -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    fn cipher(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+        let key = Sha256::new()
+            .chain_update(KDF_CONTEXT)
+            .chain_update(shared)
+            .chain_update(ephemeral.as_bytes())
+            .chain_update(recipient.as_bytes())
+            .finalize();
+        ChaCha20Poly1305::new(&key)
+    }
+
+    // encrypts a payload for the recipient. the associated data, e.g. "<uid>#<channel>",
+    // is authenticated but not sent, so the cloud can't attribute an envelope to another device
+    pub fn seal(recipient: &[u8; KEY_LEN], plaintext: &[u8], aad: &[u8]) -> Envelope {
+        let recipient = PublicKey::from(*recipient);
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&recipient);
+
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher(shared.as_bytes(), &ephemeral, &recipient)
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("ChaCha20-Poly1305 encrypts payloads of any size");
+
+        Envelope {
+            ephemeral_key: ephemeral.to_bytes(),
+            nonce,
+            ciphertext,
+        }
+    }
+
+    // decrypts an envelope sealed to the public key of the secret, None if it was tampered with,
+    // sealed to another key or with other associated data
+    pub fn open(secret: &StaticSecret, envelope: &Envelope, aad: &[u8]) -> Option<Vec<u8>> {
+        let ephemeral = PublicKey::from(envelope.ephemeral_key);
+        let shared = secret.diffie_hellman(&ephemeral);
+        cipher(shared.as_bytes(), &ephemeral, &PublicKey::from(secret))
+            .decrypt(
+                &envelope.nonce.into(),
+                Payload {
+                    msg: &envelope.ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
@@ -0,0 +1,48 @@
+use crate::protocols::ParseError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// errors of the db and protocol layers, so callers can tell a missing row from a broken db
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // the queried row does not exist
+    #[error("not found")]
+    NotFound,
+    #[error("database error: {0}")]
+    Db(sqlx::Error),
+    #[error("invalid message: {0}")]
+    Parse(#[from] ParseError),
+    // a well formed message or request the protocol doesn't allow
+    #[error("protocol violation: {0}")]
+    Protocol(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    // no db connection became available in time, worth retrying
+    #[error("timed out")]
+    Timeout,
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::PoolTimedOut => Self::Timeout,
+            sqlx::Error::Io(e) => Self::Io(e),
+            e => Self::Db(e),
+        }
+    }
+}
+
+impl Error {
+    // whether the same operation may succeed later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout | Self::Io(_) => true,
+            Self::Db(sqlx::Error::Database(e)) => {
+                // SQLITE_BUSY and SQLITE_LOCKED
+                matches!(e.code().as_deref(), Some("5") | Some("6"))
+            }
+            _ => false,
+        }
+    }
+}
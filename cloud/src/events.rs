@@ -0,0 +1,75 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::db::Attributes;
+
+// events a dashboard stream can fall behind by before it skips some
+const EVENT_BUFFER: usize = 1024;
+
+// live data pushed to dashboards
+#[derive(Clone, Serialize, Debug)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    Sensor {
+        uid: String,
+        timestamp: i64,
+        data: f64,
+        channel: String,
+        alarm: bool,
+        // attributes of the device, see Config::enrich_attributes
+        #[serde(skip_serializing_if = "Attributes::is_empty")]
+        attributes: Attributes,
+        // readings of devices outside of any tenant leave it out
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+    Avg {
+        timestamp: i64,
+        data: f64,
+        channel: String,
+        // averages of devices outside of any tenant leave it out
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+}
+
+impl StreamEvent {
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            StreamEvent::Sensor { tenant, .. } | StreamEvent::Avg { tenant, .. } => {
+                tenant.as_deref()
+            }
+        }
+    }
+
+    // name of the server-sent event
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Sensor { .. } => "sensor",
+            StreamEvent::Avg { .. } => "avg",
+        }
+    }
+}
+
+// fans incoming readings and computed averages out to all subscribed dashboards
+pub struct Events {
+    sender: broadcast::Sender<StreamEvent>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: StreamEvent) {
+        // sending only fails if nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.sender.subscribe()
+    }
+}
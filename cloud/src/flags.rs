@@ -0,0 +1,83 @@
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+use crate::{db, Result};
+
+// rolling readings up into hourly and daily aggregates
+pub const ROLLUPS: &str = "rollups";
+// accepting end-to-end encrypted SEALED readings
+pub const SEALED_MESSAGES: &str = "sealed_messages";
+
+// flags subsystems consult and whether they are enabled when nobody set them
+pub const KNOWN: [(&str, bool); 2] = [(ROLLUPS, true), (SEALED_MESSAGES, true)];
+
+pub fn is_known(name: &str) -> bool {
+    KNOWN.iter().any(|(known, _)| *known == name)
+}
+
+fn default_of(name: &str) -> bool {
+    KNOWN
+        .iter()
+        .find(|(known, _)| *known == name)
+        .is_some_and(|(_, enabled)| *enabled)
+}
+
+// runtime feature flags, persisted in the db and cached here so lookups don't hit it.
+// a flag is set for the whole deployment or for a device group, the group setting wins
+#[derive(Default)]
+pub struct Flags {
+    // (name, group) to enabled, the empty group is the deployment
+    values: RwLock<HashMap<(String, String), bool>>,
+}
+
+impl Flags {
+    // replaces the cached flags with the ones in the db
+    pub async fn load(&self, pool: &Pool<Sqlite>) -> Result<()> {
+        let flags = db::get_feature_flags(pool).await?;
+        *self.values.write().await = flags
+            .into_iter()
+            .map(|f| ((f.name, f.group_name), f.enabled))
+            .collect();
+        Ok(())
+    }
+
+    pub async fn is_enabled(&self, name: &str) -> bool {
+        self.is_enabled_for(name, None).await
+    }
+
+    // whether the flag is enabled for devices of the group
+    pub async fn is_enabled_for(&self, name: &str, group: Option<&str>) -> bool {
+        let values = self.values.read().await;
+        let lookup = |group: &str| values.get(&(name.to_string(), group.to_string())).copied();
+
+        group
+            .and_then(lookup)
+            .or_else(|| lookup(""))
+            .unwrap_or_else(|| default_of(name))
+    }
+
+    // sets the flag for the deployment or a group, None goes back to the default of the
+    // deployment or the setting of the deployment for a group
+    pub async fn set(
+        &self,
+        pool: &Pool<Sqlite>,
+        name: &str,
+        group: Option<&str>,
+        enabled: Option<bool>,
+    ) -> Result<()> {
+        let group = group.unwrap_or("");
+        match enabled {
+            Some(enabled) => db::set_feature_flag(pool, name, group, enabled).await?,
+            None => db::delete_feature_flag(pool, name, group).await?,
+        }
+
+        let key = (name.to_string(), group.to_string());
+        let mut values = self.values.write().await;
+        match enabled {
+            Some(enabled) => values.insert(key, enabled),
+            None => values.remove(&key),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::{
+    budget::Class,
+    clock::Interval,
+    config::{Aggregation, Config},
+    protocols::SensorMsg,
+    webhooks::WebhookEvent,
+    AppState,
+};
+
+// longest window readings are aggregated over before they are forwarded
+pub const MAX_WINDOW_SECS: u64 = 86400;
+
+// how the readings of the devices are forwarded as reading webhook events. readings of
+// pass-through devices always go out as they came
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardingMode {
+    // only the averages leave the server
+    #[default]
+    Off,
+    // the aggregate of every window per device and channel, to save the uplink
+    Aggregate,
+    // every reading as it came while the raw share of the upstream budget lasts, the
+    // aggregates of the windows once it is used up
+    Passthrough,
+}
+
+impl FromStr for ForwardingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "aggregate" => Ok(Self::Aggregate),
+            "passthrough" => Ok(Self::Passthrough),
+            _ => Err(format!("Invalid forwarding mode: {}", s)),
+        }
+    }
+}
+
+// how readings are forwarded, read from the config on startup and changed at runtime through
+// the admin api. a changed window applies from the next one on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForwardingSettings {
+    pub mode: ForwardingMode,
+    pub window_secs: u64,
+    pub aggregation: Aggregation,
+}
+
+impl ForwardingSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            mode: config.forwarding_mode,
+            window_secs: config.forwarding_window_secs,
+            aggregation: config.forwarding_aggregation,
+        }
+    }
+
+    pub fn validate(&self) -> crate::Result<()> {
+        if !(1..=MAX_WINDOW_SECS).contains(&self.window_secs) {
+            return Err(crate::Error::Protocol(format!(
+                "window must be between 1 and {} seconds",
+                MAX_WINDOW_SECS
+            )));
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+// readings of the current window of a device and channel
+#[derive(Default)]
+struct Window {
+    values: Vec<f64>,
+    alarm: bool,
+}
+
+// the settings readings are forwarded with and the windows they are aggregated in
+pub struct Forwarding {
+    pub settings: watch::Sender<ForwardingSettings>,
+    windows: Mutex<BTreeMap<(String, String), Window>>,
+}
+
+impl Forwarding {
+    pub fn new(settings: ForwardingSettings) -> Self {
+        Self {
+            settings: watch::Sender::new(settings),
+            windows: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn take_windows(&self) -> BTreeMap<(String, String), Window> {
+        std::mem::take(&mut *self.windows.lock().unwrap())
+    }
+}
+
+// forwards a stored reading, or adds it to the window of its device and channel
+pub fn forward(state: &AppState, reading: &SensorMsg) {
+    let mode = state.forwarding.settings.borrow().mode;
+    let raw = match mode {
+        ForwardingMode::Off => return,
+        ForwardingMode::Aggregate => false,
+        ForwardingMode::Passthrough => state.budget.has_room_at(Class::Raw, state.clock.unix_now()),
+    };
+    if raw {
+        state.webhooks.publish(WebhookEvent::Reading {
+            uid: reading.uid.clone(),
+            timestamp: reading.timestamp,
+            data: reading.data,
+            channel: reading.channel.clone(),
+            alarm: reading.alarm,
+            count: None,
+        });
+        return;
+    }
+    let mut windows = state.forwarding.windows.lock().unwrap();
+    let window = windows
+        .entry((reading.uid.clone(), reading.channel.clone()))
+        .or_default();
+    window.values.push(reading.data);
+    window.alarm |= reading.alarm;
+}
+
+// forwards the aggregates of the windows as they end, timestamped with the end of the window
+pub async fn forwarding_service(state: Arc<AppState>) {
+    let mut updates = state.forwarding.settings.subscribe();
+    let mut settings = updates.borrow_and_update().clone();
+    let mut interval = Interval::delayed(state.clock.clone(), settings.window());
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = updates.changed() => {
+                let changed = updates.borrow_and_update().clone();
+                info!("Forwarding readings with {:?} from now on", changed);
+                if changed.window_secs != settings.window_secs {
+                    interval = Interval::delayed(state.clock.clone(), changed.window());
+                }
+                settings = changed;
+                continue;
+            }
+        }
+
+        let now = state.clock.unix_now();
+        for ((uid, channel), window) in state.forwarding.take_windows() {
+            let Some(data) = settings.aggregation.apply(&window.values) else {
+                continue;
+            };
+            state.webhooks.publish(WebhookEvent::Reading {
+                uid,
+                timestamp: now,
+                data,
+                channel,
+                alarm: window.alarm,
+                count: Some(window.values.len()),
+            });
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// mean radius of the earth in km, close enough for the distances on a map of devices
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// a rectangle of latitudes and longitudes in degrees. min_lon is greater than max_lon when the
+// box crosses the antimeridian
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        let lon_inside = if self.min_lon <= self.max_lon {
+            (self.min_lon..=self.max_lon).contains(&lon)
+        } else {
+            lon >= self.min_lon || lon <= self.max_lon
+        };
+        (self.min_lat..=self.max_lat).contains(&lat) && lon_inside
+    }
+}
+
+// the part of the map a geo query asks for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Area {
+    Box(BoundingBox),
+    // everything within radius_km of the center
+    Radius { lat: f64, lon: f64, radius_km: f64 },
+}
+
+impl Area {
+    // a box around the area, so the db only has to look at the devices close to it
+    pub fn bounds(&self) -> BoundingBox {
+        match *self {
+            Area::Box(bounds) => bounds,
+            Area::Radius {
+                lat,
+                lon,
+                radius_km,
+            } => {
+                let dlat = (radius_km / EARTH_RADIUS_KM).to_degrees();
+                let (min_lat, max_lat) = (lat - dlat, lat + dlat);
+                // at the poles every longitude is close
+                if min_lat <= -90.0 || max_lat >= 90.0 {
+                    return BoundingBox {
+                        min_lat: min_lat.max(-90.0),
+                        min_lon: -180.0,
+                        max_lat: max_lat.min(90.0),
+                        max_lon: 180.0,
+                    };
+                }
+                let dlon = (radius_km / (EARTH_RADIUS_KM * lat.to_radians().cos())).to_degrees();
+                if dlon >= 180.0 {
+                    return BoundingBox {
+                        min_lat,
+                        min_lon: -180.0,
+                        max_lat,
+                        max_lon: 180.0,
+                    };
+                }
+                BoundingBox {
+                    min_lat,
+                    min_lon: wrap_lon(lon - dlon),
+                    max_lat,
+                    max_lon: wrap_lon(lon + dlon),
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match *self {
+            Area::Box(bounds) => bounds.contains(lat, lon),
+            Area::Radius {
+                lat: center_lat,
+                lon: center_lon,
+                radius_km,
+            } => distance_km(center_lat, center_lon, lat, lon) <= radius_km,
+        }
+    }
+}
+
+fn wrap_lon(lon: f64) -> f64 {
+    if lon < -180.0 {
+        lon + 360.0
+    } else if lon > 180.0 {
+        lon - 360.0
+    } else {
+        lon
+    }
+}
+
+// great-circle distance between two points, haversine formula
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+pub fn is_valid_lat(lat: f64) -> bool {
+    (-90.0..=90.0).contains(&lat)
+}
+
+pub fn is_valid_lon(lon: f64) -> bool {
+    (-180.0..=180.0).contains(&lon)
+}
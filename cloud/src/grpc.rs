@@ -0,0 +1,206 @@
+use crate::protocols::{truncated, ParseError, SensorMsg, DEFAULT_CHANNEL};
+
+// parses a reading sent over gRPC like the SENSOR message it stands for, so both are
+// validated the same way. an empty channel is the default one
+pub fn parse_reading(
+    uid: &str,
+    timestamp: i64,
+    data: f64,
+    channel: &str,
+    alarm: bool,
+) -> Result<SensorMsg, ParseError> {
+    // the fields can't smuggle in fields of their own
+    if uid.contains('#') {
+        return Err(ParseError::InvalidUid(truncated(uid)));
+    }
+    if channel.contains('#') {
+        return Err(ParseError::InvalidChannel(truncated(channel)));
+    }
+    let channel = if channel.is_empty() {
+        DEFAULT_CHANNEL
+    } else {
+        channel
+    };
+    let mut msg = format!("SENSOR#{}#{}#{}#{}", uid, timestamp, data, channel);
+    if alarm {
+        msg.push_str("#alarm");
+    }
+    SensorMsg::from_msg(&msg)
+}
+
+#[cfg(feature = "grpc")]
+pub use service::{proto, serve};
+
+#[cfg(feature = "grpc")]
+mod service {
+    use futures_util::Stream;
+    use std::{collections::HashSet, pin::Pin, sync::Arc};
+    use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+    use tonic::{Request, Response, Status, Streaming};
+    use tracing::{error, info, warn};
+
+    use super::parse_reading;
+    use crate::{
+        admission::Priority, db, events::StreamEvent, handlers, protocols::parse_uid, sensors,
+        AppState,
+    };
+
+    pub mod proto {
+        tonic::include_proto!("fog");
+    }
+
+    use proto::{
+        ingest_server::{Ingest, IngestServer},
+        Aggregate, IngestSummary, SensorReading, SubscribeRequest,
+    };
+
+    struct IngestService {
+        state: Arc<AppState>,
+    }
+
+    #[tonic::async_trait]
+    impl Ingest for IngestService {
+        // readings are stored one after the other, so a client can't outrun the db
+        async fn stream_sensor_data(
+            &self,
+            request: Request<Streaming<SensorReading>>,
+        ) -> Result<Response<IngestSummary>, Status> {
+            let state = &self.state;
+            let mut readings = request.into_inner();
+            let mut registered = HashSet::new();
+            let mut summary = IngestSummary::default();
+
+            while let Some(reading) = readings.message().await? {
+                let sensor_data = match parse_reading(
+                    &reading.uid,
+                    reading.timestamp,
+                    reading.data,
+                    &reading.channel,
+                    reading.alarm,
+                )
+                .and_then(|mut sensor_data| {
+                    sensor_data.seq = reading.seq;
+                    sensor_data.via = reading.via.as_deref().map(parse_uid).transpose()?;
+                    handlers::check_timestamp(state, &mut sensor_data)?;
+                    Ok(sensor_data)
+                }) {
+                    Ok(sensor_data) => sensor_data,
+                    Err(e) => {
+                        warn!("Invalid gRPC reading of {:?}: {}", reading.uid, e);
+                        summary.rejected += 1;
+                        continue;
+                    }
+                };
+
+                // gRPC clients never send CONN, their devices are registered on the first reading.
+                // fog nodes only forward for the devices an admin registered behind them
+                if let Some(fog_uid) = &sensor_data.via {
+                    match db::may_forward(&state.pool, &sensor_data.uid, fog_uid).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!(
+                                "Rejected a gRPC reading of {} forwarded by {}, it isn't registered behind it",
+                                sensor_data.uid, fog_uid
+                            );
+                            summary.rejected += 1;
+                            continue;
+                        }
+                        Err(e) => {
+                            error!(
+                                "Error checking {} behind {}: {}",
+                                sensor_data.uid, fog_uid, e
+                            );
+                            return Err(Status::unavailable("try again later"));
+                        }
+                    }
+                } else if !registered.contains(&sensor_data.uid) {
+                    if let Err(e) = sensors::register(state, &sensor_data.uid).await {
+                        error!("Error registering {}: {}", sensor_data.uid, e);
+                        return Err(Status::unavailable("try again later"));
+                    }
+                    registered.insert(sensor_data.uid.clone());
+                }
+
+                let priority = if sensor_data.alarm {
+                    Priority::High
+                } else {
+                    Priority::Routine
+                };
+                let max_in_flight = state
+                    .latency
+                    .max_in_flight(state.config.ingest_max_in_flight);
+                let Some(_permit) = state.admission.try_admit(priority, max_in_flight) else {
+                    summary.shed += 1;
+                    continue;
+                };
+                handlers::ingest_sensor(state, sensor_data).await;
+                summary.accepted += 1;
+            }
+
+            info!(
+                "gRPC stream stored {} readings, rejected {} and shed {}",
+                summary.accepted, summary.rejected, summary.shed
+            );
+            Ok(Response::new(summary))
+        }
+
+        type SubscribeAggregatesStream =
+            Pin<Box<dyn Stream<Item = Result<Aggregate, Status>> + Send>>;
+
+        async fn subscribe_aggregates(
+            &self,
+            request: Request<SubscribeRequest>,
+        ) -> Result<Response<Self::SubscribeAggregatesStream>, Status> {
+            let request = request.into_inner();
+            let events = self.state.events.subscribe();
+
+            let aggregates =
+                futures_util::stream::unfold((events, request), |(mut events, request)| async {
+                    loop {
+                        match events.recv().await {
+                            Ok(StreamEvent::Avg {
+                                timestamp,
+                                data,
+                                channel,
+                                tenant,
+                            }) if (request.channels.is_empty()
+                                || request.channels.contains(&channel))
+                                && (request.tenant.is_empty()
+                                    || tenant.as_ref() == Some(&request.tenant)) =>
+                            {
+                                let aggregate = Aggregate {
+                                    timestamp,
+                                    data,
+                                    channel,
+                                    tenant: tenant.unwrap_or_default(),
+                                };
+                                return Some((Ok(aggregate), (events, request)));
+                            }
+                            Ok(_) => {}
+                            Err(RecvError::Lagged(skipped)) => {
+                                warn!("gRPC subscriber fell behind, skipped {} events", skipped)
+                            }
+                            Err(RecvError::Closed) => return None,
+                        }
+                    }
+                });
+            Ok(Response::new(Box::pin(aggregates)))
+        }
+    }
+
+    // serves the gRPC api on a port of its own, next to the websocket server
+    pub async fn serve(listener: TcpListener, state: Arc<AppState>) {
+        let connections = futures_util::stream::unfold(listener, |listener| async {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let service = IngestServer::new(IngestService { state });
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(connections)
+            .await
+        {
+            error!("gRPC server stopped: {}", e);
+        }
+    }
+}
@@ -1,38 +1,331 @@
-use crate::{db, protocols, AppState};
+use crate::{
+    admission::{Permit, Priority},
+    alerts,
+    auth,
+    budget,
+    cache,
+    clock::{Clock, Interval},
+    codec,
+    config::{Aggregation, AggregationTimestamp, RateLimitMode, StreamUrl},
+    conversions,
+    // schema types of the api are imported, so their schema names aren't qualified
+    db::{self, BandwidthSample, Connection, ConnectionStats, RollupPeriod},
+    device_configs,
+    envelope::Envelope,
+    events::StreamEvent,
+    flags,
+    forwarding,
+    geo,
+    latency::Stage,
+    pairing,
+    probes,
+    protocols::{self, AvgMsg, ErrorCode, ParseError},
+    send_queue::{QueuePolicy, SendQueue},
+    sessions::{Control, SessionHandle, Traffic, TrafficStats},
+    signing,
+    sinks,
+    timeline::{self, EventKind},
+    tls::ClientIdentity,
+    webhooks::WebhookEvent,
+    AppState,
+    Error,
+};
 use axum::{
+    body::StreamBody,
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{Message, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
     },
-    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use futures_util::{
-    sink::SinkExt,
-    stream::{SplitSink, SplitStream, StreamExt},
+    sink::{Sink, SinkExt},
+    stream::{self, SplitSink, SplitStream, Stream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{error, info};
+use tokio::{
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+    time::Instant as ClockInstant,
+};
+use tracing::{error, info, info_span, warn, Instrument};
+use utoipa::{IntoParams, ToSchema};
+
+// seconds after which a sent but unacknowledged message is delivered again
+const ACK_TIMEOUT_SECS: i64 = 30;
+
+// token bucket limiting the rate of SENSOR messages of a single device
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: ClockInstant,
+}
+
+impl TokenBucket {
+    fn new(refill_per_sec: f64, capacity: f64, now: ClockInstant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_sec,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: ClockInstant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn has_token(&mut self, now: ClockInstant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    // takes a token if one is available
+    fn try_take(&mut self, now: ClockInstant) -> bool {
+        if self.has_token(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // time until the next token is available
+    fn wait_time(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.refill_per_sec).max(0.0))
+    }
+
+    fn is_full(&mut self, now: ClockInstant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+// buckets a connection keeps before the ones of devices that went quiet are dropped
+const MAX_BUCKETS: usize = 1024;
+
+// token buckets of a connection by device. a fog node forwards the readings of all the
+// devices behind it over one connection, each of them is limited on its own and all of
+// them together by the total of the connection. the buckets refill on the state clock
+struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    per_sec: f64,
+    burst: f64,
+    buckets: HashMap<String, TokenBucket>,
+    // every message of the connection, only fog nodes have one
+    total: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(clock: Arc<dyn Clock>, per_sec: f64, burst: f64) -> Self {
+        Self {
+            clock,
+            per_sec,
+            burst,
+            buckets: HashMap::new(),
+            total: None,
+        }
+    }
+
+    fn with_total(mut self, per_sec: f64, burst: f64) -> Self {
+        self.total = Some(TokenBucket::new(per_sec, burst, self.clock.now()));
+        self
+    }
 
-pub async fn handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    // takes a token of the device and one of the connection, or neither
+    fn try_take(&mut self, uid: &str) -> bool {
+        let now = self.clock.now();
+        if self
+            .total
+            .as_mut()
+            .is_some_and(|total| !total.has_token(now))
+        {
+            return false;
+        }
+        if !self.bucket(uid, now).try_take(now) {
+            return false;
+        }
+        if let Some(total) = &mut self.total {
+            total.try_take(now);
+        }
+        true
+    }
+
+    // time until both the device and the connection have a token again
+    fn wait_time(&mut self, uid: &str) -> Duration {
+        let now = self.clock.now();
+        let wait = self.bucket(uid, now).wait_time();
+        match &self.total {
+            Some(total) => wait.max(total.wait_time()),
+            None => wait,
+        }
+    }
+
+    fn bucket(&mut self, uid: &str, now: ClockInstant) -> &mut TokenBucket {
+        if !self.buckets.contains_key(uid) && self.buckets.len() >= MAX_BUCKETS {
+            // a full bucket is no different from a new one
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let (per_sec, burst) = (self.per_sec, self.burst);
+        self.buckets
+            .entry(uid.to_string())
+            .or_insert_with(|| TokenBucket::new(per_sec, burst, now))
+    }
+}
+
+// counts consecutive invalid messages of a connection, so a single malformed
+// message is skipped instead of closing an otherwise healthy connection
+pub struct ErrorPolicy {
+    max_consecutive: u32,
+    consecutive: u32,
+}
+
+impl ErrorPolicy {
+    pub fn new(max_consecutive: u32) -> Self {
+        Self {
+            max_consecutive,
+            consecutive: 0,
+        }
+    }
+
+    // records an invalid message, returns true if the connection has to be closed
+    pub fn record_failure(&mut self) -> bool {
+        self.consecutive += 1;
+        self.consecutive >= self.max_consecutive
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive = 0;
+    }
+}
+
+// frames this many times over MAX_FRAME_BYTES close the websocket
+const OVERSIZE_FACTOR: usize = 4;
+
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    identity: Option<Extension<ClientIdentity>>,
+) -> Response {
+    if state.sessions.is_closing() {
+        warn!("Rejected a websocket connection, the server shuts down");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
     info!("New websocket connection");
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    let identity = identity.map(|Extension(identity)| identity);
+    // frames over the limit are answered with an ERR, far longer ones aren't even read
+    let hard_limit = state.config.max_frame_bytes.saturating_mul(OVERSIZE_FACTOR);
+    ws.max_message_size(hard_limit)
+        .max_frame_size(hard_limit)
+        .on_upgrade(move |socket| handle_socket(socket, state, identity, false))
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
+// a connection to a device, a websocket or a connection speaking the line protocol
+pub trait DeviceSocket:
+    Stream<Item = Result<Message, axum::Error>>
+    + Sink<Message, Error = axum::Error>
+    + Send
+    + Unpin
+    + 'static
+{
+}
+
+impl<S> DeviceSocket for S where
+    S: Stream<Item = Result<Message, axum::Error>>
+        + Sink<Message, Error = axum::Error>
+        + Send
+        + Unpin
+        + 'static
+{
+}
+
+// runs the session of a device. text only sockets can't carry binary frames,
+// so compression and the binary encodings are rejected in CONN
+pub(crate) async fn handle_socket<S: DeviceSocket>(
+    mut socket: S,
+    state: Arc<AppState>,
+    identity: Option<ClientIdentity>,
+    text_only: bool,
+) {
     let uid: String;
+    let framing: codec::Framing;
+    let delivery_interval: Duration;
+    let wake: Option<protocols::WakeSchedule>;
+    let public_key: Option<String>;
+    let tenant: Option<String>;
+    let fog: bool;
+    let metadata: db::DeviceMetadata;
+    let traffic = Arc::new(Traffic::default());
 
-    //get initial message with id
+    //get initial message with id, which is never compressed
     if let Some(Ok(msg)) = socket.next().await {
-        let data = msg.into_text().unwrap();
-        info!("Received message: {:?}", data);
+        traffic.add_in(codec::frame_len(&msg), state.clock.unix_now());
+        traffic.add_raw_in(codec::frame_len(&msg));
+        if codec::frame_len(&msg) > state.config.max_frame_bytes {
+            warn!("Rejected a CONN of {} bytes", codec::frame_len(&msg));
+            reject(&mut socket, ErrorCode::TooLarge, "frame too large").await;
+            return;
+        }
+        let data = match msg.into_text() {
+            Ok(data) => data,
+            Err(_) => {
+                warn!("Rejected a CONN that is not text");
+                reject(&mut socket, ErrorCode::BadProtocol, "expected CONN message").await;
+                return;
+            }
+        };
+        info!("Received message: {:?}", protocols::loggable(&data));
+
+        // devices without a uid ask for one while pairing mode is open
+        if data == "PAIR" || data.starts_with("PAIR#") {
+            let reply = match pair(&state, &data).await {
+                Ok(paired) => paired.to_msg(),
+                Err(err) => err.to_msg(),
+            };
+            let _ = socket.send(Message::Text(reply)).await;
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
 
-        let parsed = protocols::ConnMsg::from_msg(&data);
+        let parsed = protocols::ConnMsg::from_msg(&data).ok();
         match parsed {
-            Ok(msg) => {
+            Some(msg) => {
                 uid = msg.uid;
+                framing = codec::Framing {
+                    compression: msg.compression,
+                    encoding: msg.encoding,
+                    timestamps: msg.timestamps,
+                    max_batch_bytes: Some(state.config.max_frame_bytes),
+                };
+                delivery_interval = Duration::from_secs(msg.delivery_interval_secs);
+                wake = msg.wake;
+                public_key = msg.public_key;
+                tenant = msg.tenant;
+                fog = msg.fog;
+                metadata = msg.metadata;
             }
-            Err(_) => {
+            None => {
+                reject(&mut socket, ErrorCode::BadProtocol, "expected CONN message").await;
                 return;
             }
         }
@@ -41,203 +334,4409 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
         return;
     }
 
+    if text_only
+        && (framing.compression != protocols::Compression::None
+            || framing.encoding != protocols::Encoding::Text)
+    {
+        warn!("CONN as {} rejected, binary frames are not supported", uid);
+        reject(
+            &mut socket,
+            ErrorCode::BadProtocol,
+            "only uncompressed text messages are supported",
+        )
+        .await;
+        return;
+    }
+
+    // on mutual TLS connections devices may only claim the uid of their certificate
+    if let Some(ClientIdentity(cert_uid)) = identity {
+        if cert_uid.as_deref() != Some(uid.as_str()) {
+            warn!(
+                "CONN as {} rejected, the client certificate belongs to {:?}",
+                uid, cert_uid
+            );
+            reject(
+                &mut socket,
+                ErrorCode::AuthFailed,
+                "uid does not match the client certificate",
+            )
+            .await;
+            return;
+        }
+    }
+
+    // only fog nodes registered through the admin api forward the readings of other devices
+    if fog {
+        let rejection = match db::is_fog_node(&state.pool, &uid).await {
+            Ok(true) => None,
+            Ok(false) => Some((ErrorCode::AuthFailed, "not a registered fog node")),
+            Err(e) => {
+                error!("Error getting the fog node {}: {}", uid, e);
+                Some((ErrorCode::Overloaded, "try again later"))
+            }
+        };
+        if let Some((code, reason)) = rejection {
+            warn!("CONN as {} with role=fog rejected: {}", uid, reason);
+            reject(&mut socket, code, reason).await;
+            return;
+        }
+    }
+
+    // devices can only join tenants added through the admin api
+    if let Some(tenant) = &tenant {
+        let rejection = match db::get_tenant(&state.pool, tenant).await {
+            Ok(_) => None,
+            Err(Error::NotFound) => Some((ErrorCode::AuthFailed, "unknown tenant")),
+            Err(e) => {
+                error!("Error getting tenant {}: {}", tenant, e);
+                Some((ErrorCode::Overloaded, "try again later"))
+            }
+        };
+        if let Some((code, reason)) = rejection {
+            warn!("CONN as {} rejected, tenant {}: {}", uid, tenant, reason);
+            reject(&mut socket, code, reason).await;
+            return;
+        }
+    }
+
+    // a device whose pairing was rejected lost its uid
+    let rejection = match db::is_pairing_rejected(&state.pool, &uid).await {
+        Ok(false) => None,
+        Ok(true) => Some((ErrorCode::AuthFailed, "pairing was rejected")),
+        Err(e) => {
+            error!("Error getting the pairing of {}: {}", uid, e);
+            Some((ErrorCode::Overloaded, "try again later"))
+        }
+    };
+    if let Some((code, reason)) = rejection {
+        warn!("CONN as {} rejected: {}", uid, reason);
+        reject(&mut socket, code, reason).await;
+        return;
+    }
+
+    // with reject-new a device that is still connected, or connecting over another socket,
+    // is turned away before anything is stored
+    let Some(claim) = state
+        .sessions
+        .claim(&uid, state.config.duplicate_sessions)
+        .await
+    else {
+        warn!("CONN as {} rejected, the device is already connected", uid);
+        reject(
+            &mut socket,
+            ErrorCode::SessionExists,
+            "the device is already connected",
+        )
+        .await;
+        return;
+    };
+
     // Create a new connection in the database if it doesn't exist
-    if db::get_connection(&state.pool, &uid).await.is_err() {
-        if db::add_connection(&state.pool, &uid).await.is_err() {
-            error!("Error adding new connection to database");
+    match db::upsert_connection(&state.pool, &uid, state.clock.unix_now()).await {
+        Ok(connection) => {
+            // a device can't move its readings to another tenant
+            if let (Some(current), Some(requested)) = (&connection.tenant, &tenant) {
+                if current != requested {
+                    warn!(
+                        "CONN as {} rejected, the device belongs to tenant {}",
+                        uid, current
+                    );
+                    reject(
+                        &mut socket,
+                        ErrorCode::AuthFailed,
+                        "device belongs to another tenant",
+                    )
+                    .await;
+                    return;
+                }
+            }
+
+            // a drained device stays away while it is serviced
+            if connection.maintenance {
+                warn!("CONN as {} rejected, the device is in maintenance", uid);
+                reject(
+                    &mut socket,
+                    ErrorCode::Maintenance,
+                    "the device is in maintenance",
+                )
+                .await;
+                return;
+            }
+
+            // a device coming back after DISCONN keeps its history
+            if connection.disconnected_at.is_some()
+                && db::reconnect_connection(&state.pool, &uid).await.is_err()
+            {
+                error!("Error reconnecting {}", uid);
+            }
+        }
+        Err(e) => {
+            error!("Error storing connection {}: {}", uid, e);
+            // let the device retry later instead of treating it as unknown
+            if e.is_transient() {
+                reject(&mut socket, ErrorCode::Overloaded, "try again later").await;
+            } else {
+                let _ = socket.send(Message::Close(None)).await;
+            }
+            return;
+        }
+    }
+
+    if let Some(public_key) = public_key {
+        if let Err(e) = db::set_public_key(&state.pool, &uid, &public_key).await {
+            error!("Error storing the public key of {}: {}", uid, e);
+        }
+    }
+
+    // devices that leave the options out keep what they registered before
+    if !metadata.is_empty() {
+        if let Err(e) = db::set_device_metadata(&state.pool, &uid, &metadata).await {
+            error!("Error storing the metadata of {}: {}", uid, e);
+        }
+    }
+
+    // devices that leave the option out stay in their tenant
+    if let Some(tenant) = &tenant {
+        if let Err(e) = db::set_connection_tenant(&state.pool, &uid, tenant).await {
+            error!("Error storing the tenant of {}: {}", uid, e);
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    }
+
+    // the device just woke up, its wake windows are counted from now
+    let woke_at = state.clock.unix_now();
+    if let Err(e) = db::set_wake_schedule(&state.pool, &uid, wake, woke_at).await {
+        error!("Error storing the wake schedule of {}: {}", uid, e);
+    }
+
+    // a new session ends the older ones of the device
+    let session_id = match db::start_session(&state.pool, &uid).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            error!("Error starting a session of {}: {}", uid, e);
+            let _ = socket.send(Message::Close(None)).await;
             return;
         }
+    };
+    let session = protocols::SessionMsg {
+        session_id: session_id.clone(),
+    };
+    traffic.add_out(session.to_msg().len());
+    traffic.add_raw_out(session.to_msg().len());
+    if socket.send(Message::Text(session.to_msg())).await.is_err() {
+        error!("Error sending the session id to {}", uid);
+        let _ = db::end_session(&state.pool, &session_id).await;
+        return;
     }
 
+    // everything logged by the tasks of the session carries the uid and the session id
+    let span = info_span!("session", uid = %uid, session_id = %session_id);
+
     // split socket into sender and receiver
     let (sender, receiver) = socket.split();
 
-    // create a connection state mutex
-    let is_active = Arc::new(Mutex::new(true));
+    // cleared by the reader on DISCONN, the writer then closes the websocket
+    let is_active = Arc::new(AtomicBool::new(true));
+
+    // channel for notices the reader sends back to the client through the writer
+    let (notice_tx, notice_rx) = mpsc::unbounded_channel();
+
+    // rules changed while the device was away are sent on every CONN until it acknowledges them
+    if let Some(rules) = alerts::pending_rules(&state, &uid).await {
+        let _ = notice_tx.send(rules);
+    }
+    // so are configs, a fog node passes on the ones of the devices behind it
+    for config in device_configs::pending_configs(&state, &uid, fog).await {
+        let _ = notice_tx.send(config);
+    }
+
+    // control channel of the session, so it can be controlled from the REST api
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
 
-    let j_writer = tokio::spawn(ws_writer(
+    // persist the traffic and the counters of the connection periodically
+    let j_sampler = tokio::spawn(
+        traffic_sampler(
+            state.clone(),
+            uid.clone(),
+            session_id.clone(),
+            traffic.clone(),
+        )
+        .instrument(span.clone()),
+    );
+
+    let outgoing = Outgoing {
         sender,
-        state.clone(),
-        uid.clone(),
-        is_active.clone(),
+        framing,
+        traffic: traffic.clone(),
+    };
+    // messages waiting to be sent, so a slow device can't hold up more than its own queue
+    let queue = Arc::new(SendQueue::new(
+        state.config.send_queue_capacity,
+        state.config.send_queue_policy,
     ));
-    let j_receiver = tokio::spawn(ws_reader(receiver, state, uid, is_active));
+    // the sender empties the queue onto the websocket, the writer only fills it
+    let j_sender =
+        tokio::spawn(ws_sender(outgoing, queue.clone(), uid.clone()).instrument(span.clone()));
+    let j_writer = tokio::spawn(
+        ws_writer(
+            queue.clone(),
+            state.clone(),
+            uid.clone(),
+            Cadence {
+                interval: delivery_interval,
+                wake,
+                woke_at,
+            },
+            is_active.clone(),
+            notice_rx,
+            control_rx,
+        )
+        .instrument(span.clone()),
+    );
+    let j_receiver = tokio::spawn(
+        ws_reader(
+            receiver,
+            state.clone(),
+            Peer {
+                uid: uid.clone(),
+                session_id: session_id.clone(),
+                fog,
+            },
+            framing,
+            traffic.clone(),
+            is_active,
+            notice_tx,
+        )
+        .instrument(span),
+    );
+
+    // register the session, so it can be listed and closed
+    let connected_at = state.clock.unix_now();
+    state
+        .sessions
+        .register(
+            claim,
+            SessionHandle {
+                control: control_tx.clone(),
+                session_id: session_id.clone(),
+                connected_at,
+                timestamps: framing.timestamps,
+                tasks: vec![j_writer.abort_handle(), j_receiver.abort_handle()],
+                queue: queue.clone(),
+                traffic: traffic.clone(),
+            },
+        )
+        .await;
+
+    // wait for the tasks to finish, they are only cancelled when the server shuts down
+    let _ = j_writer.await;
+    let _ = j_receiver.await;
+    let _ = j_sender.await;
+
+    state.sessions.unregister(&uid, &control_tx).await;
+    if let Err(e) = db::end_session(&state.pool, &session_id).await {
+        error!("Error ending session {} of {}: {}", session_id, uid, e);
+    }
+    state
+        .replay
+        .forget_stale(state.config.replay_window_secs)
+        .await;
+
+    // store the traffic since the last sample and the final counters
+    j_sampler.abort();
+    save_traffic(&state, &uid, &session_id, &traffic).await;
+}
+
+// tells the device why its CONN was turned away and closes the socket
+async fn reject<S: DeviceSocket>(socket: &mut S, code: ErrorCode, reason: &str) {
+    let err = protocols::ErrMsg {
+        code,
+        reason: reason.to_string(),
+    };
+    let _ = socket.send(Message::Text(err.to_msg())).await;
+    let _ = socket.send(Message::Close(None)).await;
+}
 
-    // wait for both threads to finish
-    j_writer.await.unwrap();
-    j_receiver.await.unwrap();
+// provisions a device that sent PAIR with a new uid and its signing key. the pairing is
+// recorded as pending until an admin approves or rejects it
+async fn pair(state: &AppState, data: &str) -> Result<protocols::PairedMsg, protocols::ErrMsg> {
+    let reject = |code, reason: &str| protocols::ErrMsg {
+        code,
+        reason: reason.to_string(),
+    };
+    let msg = protocols::PairMsg::from_msg(data)
+        .map_err(|e| reject(ErrorCode::BadProtocol, &e.to_string()))?;
+    // keys are derived from the signing secret, pairing mode can't be opened without one
+    let secret = match &state.config.signing_secret {
+        Some(secret) if state.pairing.is_open() => secret,
+        _ => {
+            warn!("PAIR rejected, pairing mode is closed");
+            return Err(reject(ErrorCode::AuthFailed, "pairing mode is closed"));
+        }
+    };
 
-    return;
+    let uid = uuid::Uuid::new_v4().to_string();
+    if let Err(e) = db::add_pairing(&state.pool, &uid, msg.name.as_deref()).await {
+        error!("Error recording the pairing of {}: {}", uid, e);
+        return Err(reject(ErrorCode::Overloaded, "try again later"));
+    }
+    warn!(
+        "AUDIT: device {} paired as {}, waiting for approval",
+        msg.name.as_deref().unwrap_or("without a name"),
+        uid
+    );
+    Ok(protocols::PairedMsg {
+        key: hex::encode(signing::device_key(secret.expose(), &uid)),
+        uid,
+    })
 }
 
-async fn ws_reader(
-    mut receiver: SplitStream<WebSocket>,
+async fn traffic_sampler(
     state: Arc<AppState>,
     uid: String,
-    is_active: Arc<Mutex<bool>>,
+    session_id: String,
+    traffic: Arc<Traffic>,
 ) {
-    while let Some(Ok(msg)) = receiver.next().await {
-        let data = msg.into_text().unwrap();
-        info!("Received message: {:?}", data);
-
-        let p = protocols::get_protocol(&data).unwrap_or(protocols::Protocol::INVALID);
-        match p {
-            // add sensor data to database
-            protocols::Protocol::SENSOR => {
-                let sensor_data_result = protocols::SensorMsg::from_msg(&data);
-
-                match sensor_data_result {
-                    Ok(sensor_data) => {
-                        //make sure the connection uid matches the sensor data uid
-                        if sensor_data.uid != uid {
-                            error!("Sensor data uid doesn't match connection uid");
-                            return;
-                        }
+    let mut interval = Interval::delayed(
+        state.clock.clone(),
+        Duration::from_secs(state.config.bandwidth_sample_secs),
+    );
 
-                        //process message in a separate thread, so that the connection is not blocked
-                        let new_state = state.clone();
-                        tokio::spawn(async move {
-                            //add message to database
-                            if db::add_received_message(&new_state.pool, &sensor_data)
-                                .await
-                                .is_err()
-                            {
-                                error!("Error adding sensor data to the db");
-                            }
-                            //update last seen timestamp
-                            if db::update_connection(&new_state.pool, &sensor_data.uid)
-                                .await
-                                .is_err()
-                            {
-                                error!("Error updating last seen timestamp");
-                            }
-                        });
-                    }
-                    Err(_) => {
-                        error!("Invalid protocol: {:?}", data.to_string());
-                        return;
-                    }
-                }
-            }
-            protocols::Protocol::DISCONN => {
-                let disconn_res = protocols::DisconnMsg::from_msg(&data);
-                match disconn_res {
-                    Ok(disconn_data) => {
-                        //make sure the connection uid matches the sensor data uid
-                        if disconn_data.uid != uid {
-                            error!("Sensor data uid doesn't match connection uid");
-                            return;
-                        }
+    loop {
+        interval.tick().await;
+        save_traffic(&state, &uid, &session_id, &traffic).await;
+    }
+}
 
-                        let new_state = state.clone();
-                        let new_is_active = is_active.clone();
-                        tokio::spawn(async move {
-                            //remove connection from database and all its messages
-                            if db::delete_connection(&new_state.pool, &disconn_data.uid)
-                                .await
-                                .is_err()
-                            {
-                                error!("Error removing connection from database");
-                            }
+// stores a bandwidth sample and a snapshot of the counters, unless nothing was sent or
+// received since the last one
+async fn save_traffic(state: &AppState, uid: &str, session_id: &str, traffic: &Traffic) {
+    let (bytes_in, bytes_out) = traffic.take();
+    if bytes_in == 0 && bytes_out == 0 {
+        return;
+    }
+    if db::add_bandwidth_sample(
+        &state.pool,
+        uid,
+        bytes_in as i64,
+        bytes_out as i64,
+        state.clock.unix_now(),
+    )
+    .await
+    .is_err()
+    {
+        error!("Error adding bandwidth sample of {} to the db", uid);
+    }
+    let stats = connection_stats(uid, session_id, traffic.stats(), false);
+    if let Err(e) = db::save_connection_stats(&state.pool, &stats).await {
+        error!("Error saving the connection stats of {}: {}", uid, e);
+    }
+}
 
-                            //notify sender thread to close the websocket
-                            let mut locked_is_active = new_is_active.lock().await;
-                            *locked_is_active = false;
+fn connection_stats(
+    uid: &str,
+    session_id: &str,
+    stats: TrafficStats,
+    live: bool,
+) -> ConnectionStats {
+    ConnectionStats {
+        uid: uid.to_string(),
+        session_id: session_id.to_string(),
+        frames_in: stats.frames_in as i64,
+        frames_out: stats.frames_out as i64,
+        bytes_in: stats.bytes_in as i64,
+        bytes_out: stats.bytes_out as i64,
+        raw_bytes_in: stats.raw_bytes_in as i64,
+        raw_bytes_out: stats.raw_bytes_out as i64,
+        parse_errors: stats.parse_errors as i64,
+        last_activity: stats.last_activity,
+        updated_at: db::unix_now(),
+        live,
+    }
+}
 
-                            info!("Websocket receiver with id {} closed", uid);
-                        });
-                        return;
-                    }
-                    Err(_) => {
-                        error!("Invalid protocol: {:?}", data.to_string());
-                        return;
-                    }
-                }
+// reports an invalid message to the client,
+// returns true if there were too many in a row and the reader has to stop
+fn reject_invalid(
+    notices: &UnboundedSender<String>,
+    traffic: &Traffic,
+    errors: &mut ErrorPolicy,
+    reason: &str,
+) -> bool {
+    traffic.add_parse_error();
+    send_error(notices, ErrorCode::BadProtocol, reason) | errors.record_failure()
+}
+
+// like reject_invalid, with the code telling what was wrong with the message
+fn reject_unparsable(
+    notices: &UnboundedSender<String>,
+    traffic: &Traffic,
+    errors: &mut ErrorPolicy,
+    e: &ParseError,
+) -> bool {
+    traffic.add_parse_error();
+    send_error(notices, e.code(), &e.to_string()) | errors.record_failure()
+}
+
+// sends an ERR message to the client through the writer,
+// returns true if the error is fatal and the reader has to stop
+fn send_error(notices: &UnboundedSender<String>, code: ErrorCode, reason: &str) -> bool {
+    let err = protocols::ErrMsg {
+        code,
+        reason: reason.to_string(),
+    };
+    let _ = notices.send(err.to_msg());
+    code.is_fatal()
+}
+
+// applies the timestamp policy to a reading received right now
+pub(crate) fn check_timestamp(
+    state: &AppState,
+    sensor_data: &mut protocols::SensorMsg,
+) -> Result<(), ParseError> {
+    sensor_data.check_timestamp(
+        state.clock.unix_now(),
+        state.config.max_timestamp_skew_secs,
+        state.config.timestamp_policy,
+    )
+}
+
+// checks the timestamp of a reading the device may send and admits it to the ingest,
+// routine readings are shed while the ingest is saturated. readings off the websocket and
+// simulated ones go through here, the error is what the device is answered with
+async fn admit_sensor(
+    state: &AppState,
+    sensor_data: &mut protocols::SensorMsg,
+    critical: bool,
+) -> Result<Permit, protocols::ErrMsg> {
+    if let Err(e) = check_timestamp(state, sensor_data) {
+        warn!("Rejected reading of {}: {}", sensor_data.uid, e);
+        return Err(protocols::ErrMsg {
+            code: e.code(),
+            reason: e.to_string(),
+        });
+    }
+
+    let priority = if critical || sensor_data.alarm {
+        Priority::High
+    } else {
+        Priority::Routine
+    };
+    let max_in_flight = state
+        .latency
+        .max_in_flight(state.config.ingest_max_in_flight);
+    state
+        .admission
+        .try_admit(priority, max_in_flight)
+        .ok_or_else(|| {
+            warn!("Ingest saturated, shed a reading of {}", sensor_data.uid);
+            protocols::ErrMsg {
+                code: ErrorCode::Overloaded,
+                reason: "reading shed".to_string(),
             }
-            _ => {
-                error!("Invalid protocol: {:?}", data.to_string());
-                return;
+        })
+}
+
+// converts a reading to the unit set for its channel, returns the reading as it came if the
+// conversion keeps it
+async fn convert_reading(state: &AppState, sensor_data: &mut protocols::SensorMsg) -> Option<f64> {
+    let conversion =
+        match db::get_conversion(&state.pool, &sensor_data.uid, &sensor_data.channel).await {
+            Ok(Some(conversion)) => conversion,
+            Ok(None) => return None,
+            Err(e) => {
+                error!(
+                    "Error getting the conversion of {}/{}: {}",
+                    sensor_data.uid, sensor_data.channel, e
+                );
+                return None;
+            }
+        };
+    let Some(converted) = conversions::apply(&conversion, sensor_data.data) else {
+        warn!(
+            "Storing {} of {}/{} unconverted, {} gives no number",
+            sensor_data.data, sensor_data.uid, sensor_data.channel, conversion.kind
+        );
+        return None;
+    };
+    let raw = std::mem::replace(&mut sensor_data.data, converted);
+    conversion.keep_raw.then_some(raw)
+}
+
+// stores a reading and updates the connection it came from
+pub(crate) async fn ingest_sensor(state: &AppState, mut sensor_data: protocols::SensorMsg) {
+    //add message to database
+    let started = Instant::now();
+    let raw_data = convert_reading(state, &mut sensor_data).await;
+    let added =
+        db::add_converted_message(&state.pool, &sensor_data, raw_data, state.clock.unix_now())
+            .await;
+    state.latency.observe(Stage::Ingest, started.elapsed());
+    match added {
+        Ok(Some(stored)) => {
+            // readings of event-type sensors are meaningless as averages, they go out as they are
+            if stored.pass_through {
+                state.webhooks.publish(WebhookEvent::Reading {
+                    uid: sensor_data.uid.clone(),
+                    timestamp: sensor_data.timestamp,
+                    data: sensor_data.data,
+                    channel: sensor_data.channel.clone(),
+                    alarm: sensor_data.alarm,
+                    count: None,
+                });
+            } else {
+                forwarding::forward(state, &sensor_data);
             }
+            state.events.publish(StreamEvent::Sensor {
+                uid: sensor_data.uid.clone(),
+                timestamp: sensor_data.timestamp,
+                data: sensor_data.data,
+                channel: sensor_data.channel.clone(),
+                alarm: sensor_data.alarm,
+                attributes: stored.attributes,
+                tenant: stored.tenant,
+            });
+        }
+        // re-sent after a reconnect, it went out when it first came
+        Ok(None) => {
+            info!(
+                "Dropping reading {:?} of {}, it was stored before",
+                sensor_data.seq, sensor_data.uid
+            );
+            state.admission.duplicate();
+        }
+        Err(_) => error!("Error adding sensor data to the db"),
+    }
+    //update last seen timestamp
+    if db::update_connection(&state.pool, &sensor_data.uid, state.clock.unix_now())
+        .await
+        .is_err()
+    {
+        error!("Error updating last seen timestamp");
+    }
+
+    //remember how far the device clock is off
+    let skew = sensor_data.timestamp - state.clock.unix_now();
+    if db::update_clock_skew(&state.pool, &sensor_data.uid, skew)
+        .await
+        .is_err()
+    {
+        error!("Error updating clock skew");
+    }
+}
+
+// stores an end-to-end encrypted reading. the cloud can't read it, so it is neither
+// aggregated nor checked against alert rules
+async fn ingest_sealed(state: &AppState, sealed: protocols::SealedMsg) {
+    if let Err(e) = db::add_sealed_message(&state.pool, &sealed).await {
+        error!(
+            "Error adding sealed message of {} to the db: {}",
+            sealed.uid, e
+        );
+    }
+    if db::update_connection(&state.pool, &sealed.uid, state.clock.unix_now())
+        .await
+        .is_err()
+    {
+        error!("Error updating last seen timestamp");
+    }
+}
+
+// verifies a SIGNED message and returns the message it carries
+async fn unwrap_signed(
+    state: &AppState,
+    uid: &str,
+    key: &[u8],
+    data: &str,
+) -> Result<String, ErrorCode> {
+    let signed = match signing::SignedMsg::from_msg(data) {
+        Ok(signed) => signed,
+        Err(_) => {
+            warn!("Unsigned message from {}", uid);
+            return Err(ErrorCode::AuthFailed);
+        }
+    };
+    if !signed.verify(key) {
+        warn!("Invalid signature on a message from {}", uid);
+        return Err(ErrorCode::AuthFailed);
+    }
+
+    // only check the nonce of authentic messages, so forged ones can't burn nonces
+    match state
+        .replay
+        .check(uid, &signed, state.config.replay_window_secs)
+        .await
+    {
+        Ok(()) => Ok(signed.message),
+        Err(rejection) => {
+            warn!("Rejected signed message from {}: {:?}", uid, rejection);
+            Err(ErrorCode::Replayed)
         }
     }
 }
 
-async fn ws_writer(
-    mut sender: SplitSink<WebSocket, Message>,
-    state: Arc<AppState>,
+// whether the reader goes on after a message
+enum Flow {
+    Next,
+    Stop,
+}
+
+// the device on the other end of a websocket
+struct Peer {
     uid: String,
-    is_active: Arc<Mutex<bool>>,
-) {
-    // sending rate is 1 message per x seconds
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+    // id of the current session, sent to the device in the SESSION message
+    session_id: String,
+    // a fog node, see ConnMsg::fog
+    fog: bool,
+}
 
-    loop {
-        interval.tick().await;
+impl Peer {
+    // messages name the device by its uid or by the id of its current session
+    fn is(&self, claimed: &str) -> bool {
+        claimed == self.uid || claimed == self.session_id
+    }
 
-        // check if connection is still active, if not close the websocket
-        let locked_is_active = is_active.lock().await;
-        if !*locked_is_active {
-            if sender.send(Message::Close(None)).await.is_err() {
-                error!("Error closing websocket: could not send close message");
-            }
-            sender.close().await.unwrap();
-            info!("Websocket sender with id {} closed", uid);
-            return;
+    // the device a reading forwarded by a fog node came from
+    fn forwarded_for<'a>(&self, msg: &'a str) -> Option<&'a str> {
+        match msg.split('#').nth(1) {
+            Some(uid) if self.fog && msg.contains("#via=") => Some(uid),
+            _ => None,
         }
+    }
 
-        //retrieve all undelivered messages from the queue
-        let res = db::get_new_queued_messages(&state.pool).await;
-        if res.is_err() {
-            error!("Error getting connection from the db");
-            continue;
+    // whether the device may send the reading. fog nodes forward the readings of other
+    // devices, naming themselves in via
+    fn may_send(&self, reading: &protocols::SensorMsg) -> bool {
+        match &reading.via {
+            Some(via) => self.fog && self.is(via),
+            None => self.is(&reading.uid),
         }
-        let messages = res.unwrap();
+    }
+}
 
-        for msg in messages {
-            // send AVG message to the client
-            if sender
-                .send(Message::Text(msg.message.to_string()))
-                .await
-                .is_err()
+async fn ws_reader<S: DeviceSocket>(
+    mut receiver: SplitStream<S>,
+    state: Arc<AppState>,
+    peer: Peer,
+    framing: codec::Framing,
+    traffic: Arc<Traffic>,
+    is_active: Arc<AtomicBool>,
+    notices: UnboundedSender<String>,
+) {
+    let uid = peer.uid.clone();
+    let mut limiter = RateLimiter::new(
+        state.clock.clone(),
+        state.config.rate_limit_per_sec,
+        state.config.rate_limit_burst,
+    );
+    if peer.fog {
+        limiter = limiter.with_total(
+            state.config.fog_rate_limit_per_sec,
+            state.config.fog_rate_limit_burst,
+        );
+    }
+    // devices the db confirmed behind the fog node, only they get a bucket of their own
+    let mut origins: HashSet<String> = HashSet::new();
+    // whether the client was already notified about exceeding the rate limit
+    let mut is_limited = false;
+    let mut errors = ErrorPolicy::new(state.config.max_consecutive_errors);
+    // position of the message in the session, logged with everything it causes
+    let mut message_id: u64 = 0;
+
+    // group changes apply when the device reconnects
+    let critical = db::is_critical_device(&state.pool, &uid)
+        .await
+        .unwrap_or_else(|_| {
+            error!("Error getting the group policy of {}", uid);
+            false
+        });
+    let group = db::get_device_group(&state.pool, &uid)
+        .await
+        .unwrap_or_else(|_| {
+            error!("Error getting the group of {}", uid);
+            None
+        });
+
+    let signing_key = state
+        .config
+        .signing_secret
+        .as_ref()
+        .map(|secret| signing::device_key(secret.expose(), &uid));
+
+    while let Some(Ok(msg)) = receiver.next().await {
+        traffic.add_in(codec::frame_len(&msg), state.clock.unix_now());
+        if codec::frame_len(&msg) > state.config.max_frame_bytes {
+            warn!("Rejected a frame of {} bytes", codec::frame_len(&msg));
+            traffic.add_parse_error();
+            if send_error(&notices, ErrorCode::TooLarge, "frame too large")
+                | errors.record_failure()
             {
-                error!("Error sending message: {:?}", msg.message.to_string());
                 return;
             }
-            // add message to delivered messages
-            if db::add_delivered_message(&state.pool, &uid, &msg.id)
-                .await
-                .is_err()
-            {
-                error!("Error adding delivered message to the db");
-            }
-            info!("Sent message: {:?}", msg.message.to_string());
+            continue;
         }
-    }
-}
 
-pub async fn health_handler(State(state): State<Arc<AppState>>) -> Response {
-    // retrieve metrics from the database
-    let res = db::get_metrics(&state.pool).await;
-    match res {
-        Ok(metrics) => {
-            let res_text = format!(
-                r#"Status: OK
-Number of connections: {} 
-Number of received messages: {}
-Number of unique queued messages: {}
-Number of delivered messages: {}
-                "#,
-                metrics.connections.unwrap_or(-1),
-                metrics.received_messages.unwrap_or(-1),
-                metrics.queued_messages.unwrap_or(-1),
-                metrics.delivered_messages.unwrap_or(-1),
-            );
-            info!("Health check: ok");
-            return res_text.into_response();
+        // the client or the writer closed the websocket
+        if let Message::Close(_) = msg {
+            info!("Websocket receiver with id {} closed", uid);
+            return;
         }
-        Err(_) => {
-            error!("Error getting database metrics");
-            return "Status: Error".into_response();
+
+        // a single frame can carry a compressed batch or a binary message
+        let batch = match codec::decode(msg, framing, &uid) {
+            Ok(batch) => {
+                traffic.add_raw_in(batch.iter().map(String::len).sum());
+                batch
+            }
+            Err(e) if e.is::<codec::BatchTooLarge>() => {
+                warn!("Rejected a frame that decompressed past the limit");
+                traffic.add_parse_error();
+                if send_error(&notices, ErrorCode::TooLarge, "batch too large")
+                    | errors.record_failure()
+                {
+                    return;
+                }
+                continue;
+            }
+            Err(_) => {
+                error!("Error decoding websocket frame");
+                if reject_invalid(&notices, &traffic, &mut errors, "undecodable frame") {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        for data in batch {
+            info!("Received message: {:?}", protocols::loggable(&data));
+
+            // with a signing secret only signed messages are accepted
+            let data = match &signing_key {
+                Some(key) => match unwrap_signed(&state, &uid, key, &data).await {
+                    Ok(message) => message,
+                    Err(code) => {
+                        let fatal = match code {
+                            ErrorCode::Replayed => {
+                                send_error(&notices, code, "stale or replayed message")
+                                    | errors.record_failure()
+                            }
+                            _ => send_error(&notices, code, "invalid signature"),
+                        };
+                        if fatal {
+                            return;
+                        }
+                        continue;
+                    }
+                },
+                None => data,
+            };
+
+            let p = protocols::get_protocol(&data).unwrap_or(protocols::Protocol::INVALID);
+            message_id += 1;
+            let span = info_span!("message", protocol = ?p, message_id);
+            let flow = async {
+                match p {
+                    // add sensor data to database
+                    // sealed readings are rate limited like plain ones
+                    protocols::Protocol::SENSOR | protocols::Protocol::SEALED => {
+                        // forwarded readings count against the device they came from, the
+                        // ones of devices not registered behind the node against the node
+                        let limited_uid = match peer.forwarded_for(&data) {
+                            Some(origin) if origins.contains(origin) => origin,
+                            Some(origin)
+                                if origins.len() < MAX_BUCKETS
+                                    && db::may_forward(&state.pool, origin, &uid)
+                                        .await
+                                        .unwrap_or(false) =>
+                            {
+                                origins.insert(origin.to_string());
+                                origin
+                            }
+                            _ => uid.as_str(),
+                        };
+                        if !limiter.try_take(limited_uid) {
+                            if !is_limited {
+                                warn!("Device {} exceeded the rate limit", limited_uid);
+                                is_limited = true;
+                                let reason = format!(
+                                    "at most {} messages per second allowed",
+                                    state.config.rate_limit_per_sec
+                                );
+                                if send_error(&notices, ErrorCode::RateLimited, &reason) {
+                                    return Flow::Stop;
+                                }
+                            }
+                            match state.config.rate_limit_mode {
+                                RateLimitMode::Drop => {
+                                    warn!(
+                                        "Dropped rate limited message: {:?}",
+                                        protocols::loggable(&data)
+                                    );
+                                    return Flow::Next;
+                                }
+                                RateLimitMode::Throttle => {
+                                    // stop reading from the socket until a token is available
+                                    state.clock.sleep(limiter.wait_time(limited_uid)).await;
+                                    limiter.try_take(limited_uid);
+                                }
+                            }
+                        } else {
+                            is_limited = false;
+                        }
+
+                        if matches!(p, protocols::Protocol::SEALED) {
+                            match protocols::SealedMsg::parse(&data, framing.timestamps) {
+                                Ok(mut sealed) => {
+                                    errors.record_success();
+
+                                    if !peer.is(&sealed.uid) {
+                                        error!("Sealed message uid doesn't match connection uid");
+                                        if send_error(
+                                            &notices,
+                                            ErrorCode::UidMismatch,
+                                            "uid mismatch",
+                                        ) {
+                                            return Flow::Stop;
+                                        }
+                                        return Flow::Next;
+                                    }
+                                    sealed.uid = uid.clone();
+
+                                    if !state
+                                        .flags
+                                        .is_enabled_for(flags::SEALED_MESSAGES, group.as_deref())
+                                        .await
+                                    {
+                                        warn!("Sealed messages are disabled for {}", uid);
+                                        let reason = "sealed messages are disabled";
+                                        if send_error(&notices, ErrorCode::BadProtocol, reason) {
+                                            return Flow::Stop;
+                                        }
+                                        return Flow::Next;
+                                    }
+
+                                    let new_state = state.clone();
+                                    tokio::spawn(
+                                        async move {
+                                            ingest_sealed(&new_state, sealed).await;
+                                        }
+                                        .in_current_span(),
+                                    );
+                                }
+                                Err(e) => {
+                                    error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                                    if reject_invalid(
+                                        &notices,
+                                        &traffic,
+                                        &mut errors,
+                                        &e.to_string(),
+                                    ) {
+                                        return Flow::Stop;
+                                    }
+                                }
+                            }
+                            return Flow::Next;
+                        }
+
+                        let sensor_data_result =
+                            protocols::SensorMsg::parse(&data, framing.timestamps);
+
+                        match sensor_data_result {
+                            Ok(mut sensor_data) => {
+                                errors.record_success();
+
+                                //make sure the connection uid matches the sensor data uid
+                                if !peer.may_send(&sensor_data) {
+                                    error!("Sensor data uid doesn't match connection uid");
+                                    if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch")
+                                    {
+                                        return Flow::Stop;
+                                    }
+                                    return Flow::Next;
+                                }
+                                if sensor_data.via.is_some() {
+                                    sensor_data.via = Some(uid.clone());
+                                    // only for the devices an admin registered behind the node
+                                    let rejection = match db::may_forward(
+                                        &state.pool,
+                                        &sensor_data.uid,
+                                        &uid,
+                                    )
+                                    .await
+                                    {
+                                        Ok(true) => None,
+                                        Ok(false) => Some((
+                                            ErrorCode::AuthFailed,
+                                            "device is not registered behind the fog node",
+                                        )),
+                                        Err(e) => {
+                                            error!(
+                                                "Error checking {} behind {}: {}",
+                                                sensor_data.uid, uid, e
+                                            );
+                                            Some((ErrorCode::Overloaded, "try again later"))
+                                        }
+                                    };
+                                    if let Some((code, reason)) = rejection {
+                                        warn!(
+                                            "Rejected a reading of {} forwarded by {}: {}",
+                                            sensor_data.uid, uid, reason
+                                        );
+                                        if send_error(&notices, code, reason) {
+                                            return Flow::Stop;
+                                        }
+                                        return Flow::Next;
+                                    }
+                                } else {
+                                    sensor_data.uid = uid.clone();
+                                }
+
+                                let permit =
+                                    match admit_sensor(&state, &mut sensor_data, critical).await {
+                                        Ok(permit) => permit,
+                                        Err(err) => {
+                                            if send_error(&notices, err.code, &err.reason) {
+                                                return Flow::Stop;
+                                            }
+                                            return Flow::Next;
+                                        }
+                                    };
+
+                                //process message in a separate thread, so that the connection is not blocked
+                                let new_state = state.clone();
+                                tokio::spawn(
+                                    async move {
+                                        let _permit = permit;
+                                        ingest_sensor(&new_state, sensor_data).await;
+                                    }
+                                    .in_current_span(),
+                                );
+                            }
+                            Err(e) => {
+                                error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                                if reject_unparsable(&notices, &traffic, &mut errors, &e) {
+                                    return Flow::Stop;
+                                }
+                            }
+                        }
+                    }
+                    // mark a sent message or command as delivered
+                    protocols::Protocol::ACK | protocols::Protocol::CMD_ACK => {
+                        let ack_res = if matches!(p, protocols::Protocol::ACK) {
+                            protocols::AckMsg::from_msg(&data)
+                        } else {
+                            protocols::AckMsg::from_cmd_ack(&data)
+                        };
+                        match ack_res {
+                            Ok(mut ack_data) => {
+                                errors.record_success();
+
+                                //make sure the connection uid matches the ack uid
+                                if !peer.is(&ack_data.uid) {
+                                    error!("Ack uid doesn't match connection uid");
+                                    if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch")
+                                    {
+                                        return Flow::Stop;
+                                    }
+                                    return Flow::Next;
+                                }
+                                ack_data.uid = uid.clone();
+
+                                let new_state = state.clone();
+                                tokio::spawn(
+                                    async move {
+                                        match db::acknowledge_delivery(
+                                            &new_state.pool,
+                                            &ack_data.uid,
+                                            ack_data.queued_message_id,
+                                        )
+                                        .await
+                                        {
+                                            Ok(true) => info!(
+                                                "Message {} acknowledged by {}",
+                                                ack_data.queued_message_id, ack_data.uid
+                                            ),
+                                            Ok(false) => warn!(
+                                                "Received ACK for message {} which is not pending",
+                                                ack_data.queued_message_id
+                                            ),
+                                            Err(_) => {
+                                                error!("Error acknowledging delivery in the db")
+                                            }
+                                        }
+                                    }
+                                    .in_current_span(),
+                                );
+                            }
+                            Err(e) => {
+                                error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                                if reject_invalid(&notices, &traffic, &mut errors, &e.to_string()) {
+                                    return Flow::Stop;
+                                }
+                            }
+                        }
+                    }
+                    // the device applied a version of its alert rules
+                    protocols::Protocol::RULES_ACK => match protocols::RulesAckMsg::from_msg(&data)
+                    {
+                        Ok(ack) => {
+                            errors.record_success();
+
+                            if !peer.is(&ack.uid) {
+                                error!("RULES_ACK uid doesn't match connection uid");
+                                if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch") {
+                                    return Flow::Stop;
+                                }
+                                return Flow::Next;
+                            }
+
+                            let new_state = state.clone();
+                            let uid = uid.clone();
+                            tokio::spawn(
+                                async move {
+                                    match db::acknowledge_rule_version(
+                                        &new_state.pool,
+                                        &uid,
+                                        ack.version,
+                                    )
+                                    .await
+                                    {
+                                        Ok(true) => {
+                                            info!(
+                                                "{} applied version {} of its rules",
+                                                uid, ack.version
+                                            )
+                                        }
+                                        Ok(false) => warn!(
+                                        "Received RULES_ACK for version {} which is not pending",
+                                        ack.version
+                                    ),
+                                        Err(_) => {
+                                            error!("Error acknowledging the rules of {}", uid)
+                                        }
+                                    }
+                                }
+                                .in_current_span(),
+                            );
+                        }
+                        Err(e) => {
+                            error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                            if reject_invalid(&notices, &traffic, &mut errors, &e.to_string()) {
+                                return Flow::Stop;
+                            }
+                        }
+                    },
+                    // the device applied a version of its config, a fog node acknowledges the
+                    // configs of the devices behind it
+                    protocols::Protocol::CONFIG_ACK => match protocols::ConfigAckMsg::from_msg(
+                        &data,
+                    ) {
+                        Ok(ack) => {
+                            errors.record_success();
+
+                            let own = peer.is(&ack.uid);
+                            if !own && !peer.fog {
+                                error!("CONFIG_ACK uid doesn't match connection uid");
+                                if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch") {
+                                    return Flow::Stop;
+                                }
+                                return Flow::Next;
+                            }
+
+                            let new_state = state.clone();
+                            let (target, fog_uid) = if own {
+                                (uid.clone(), None)
+                            } else {
+                                (ack.uid, Some(uid.clone()))
+                            };
+                            tokio::spawn(
+                                async move {
+                                    match db::acknowledge_device_config(
+                                        &new_state.pool,
+                                        &target,
+                                        ack.version,
+                                        fog_uid.as_deref(),
+                                    )
+                                    .await
+                                    {
+                                        Ok(true) => info!(
+                                            "{} applied version {} of its config",
+                                            target, ack.version
+                                        ),
+                                        Ok(false) => warn!(
+                                            "Received CONFIG_ACK for version {} of {} which is not pending",
+                                            ack.version, target
+                                        ),
+                                        Err(_) => {
+                                            error!("Error acknowledging the config of {}", target)
+                                        }
+                                    }
+                                }
+                                .in_current_span(),
+                            );
+                        }
+                        Err(e) => {
+                            error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                            if reject_invalid(&notices, &traffic, &mut errors, &e.to_string()) {
+                                return Flow::Stop;
+                            }
+                        }
+                    },
+                    protocols::Protocol::DISCONN => {
+                        let disconn_res = protocols::DisconnMsg::from_msg(&data);
+                        match disconn_res {
+                            Ok(disconn_data) => {
+                                //make sure the connection uid matches the disconnect uid
+                                if !peer.is(&disconn_data.uid) {
+                                    error!("Disconnect uid doesn't match connection uid");
+                                    if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch")
+                                    {
+                                        return Flow::Stop;
+                                    }
+                                    return Flow::Next;
+                                }
+
+                                //mark the connection as disconnected before the websocket is
+                                //closed, its history is kept
+                                if db::disconnect_connection(&state.pool, &uid, state.clock.unix_now()).await.is_err() {
+                                    error!("Error disconnecting connection in database");
+                                }
+
+                                //notify sender thread to close the websocket
+                                is_active.store(false, Ordering::Relaxed);
+
+                                info!("Websocket receiver with id {} closed", uid);
+                                return Flow::Stop;
+                            }
+                            Err(e) => {
+                                error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                                if reject_invalid(&notices, &traffic, &mut errors, &e.to_string()) {
+                                    return Flow::Stop;
+                                }
+                            }
+                        }
+                    }
+                    // read a key of the device's key-value store
+                    protocols::Protocol::KVGET => match protocols::KvGetMsg::from_msg(&data) {
+                        Ok(mut get) => {
+                            errors.record_success();
+
+                            if !peer.is(&get.uid) {
+                                error!("KVGET uid doesn't match connection uid");
+                                if send_error(&notices, ErrorCode::UidMismatch, "uid mismatch") {
+                                    return Flow::Stop;
+                                }
+                                return Flow::Next;
+                            }
+                            get.uid = uid.clone();
+
+                            let new_state = state.clone();
+                            let new_notices = notices.clone();
+                            tokio::spawn(
+                                async move {
+                                    match db::get_device_value(&new_state.pool, &get.uid, &get.key)
+                                        .await
+                                    {
+                                        Ok(value) => {
+                                            let kv = protocols::KvMsg {
+                                                key: get.key,
+                                                value,
+                                            };
+                                            let _ = new_notices.send(kv.to_msg());
+                                        }
+                                        Err(_) => {
+                                            error!("Error reading key {} of {}", get.key, get.uid)
+                                        }
+                                    }
+                                }
+                                .in_current_span(),
+                            );
+                        }
+                        Err(e) => {
+                            error!("Invalid message {:?}: {}", protocols::loggable(&data), e);
+                            if reject_invalid(&notices, &traffic, &mut errors, &e.to_string()) {
+                                return Flow::Stop;
+                            }
+                        }
+                    },
+                    _ => {
+                        error!("Invalid protocol: {:?}", protocols::loggable(&data));
+                        if reject_invalid(&notices, &traffic, &mut errors, "unknown message type") {
+                            return Flow::Stop;
+                        }
+                    }
+                }
+                Flow::Next
+            }
+            .instrument(span)
+            .await;
+            if let Flow::Stop = flow {
+                return;
+            }
+        }
+    }
+}
+
+// sending half of a websocket, encodes messages with the negotiated compression
+struct Outgoing<S> {
+    sender: SplitSink<S, Message>,
+    framing: codec::Framing,
+    traffic: Arc<Traffic>,
+}
+
+impl<S: DeviceSocket> Outgoing<S> {
+    // encodes and sends messages to the client, returns false if the websocket is broken
+    async fn send(&mut self, msgs: &[String]) -> bool {
+        let frames = match codec::encode(msgs, self.framing) {
+            Ok(frames) => {
+                self.traffic.add_raw_out(msgs.iter().map(String::len).sum());
+                frames
+            }
+            Err(_) => {
+                error!("Error encoding messages: {:?}", msgs);
+                return true;
+            }
+        };
+        for frame in frames {
+            self.traffic.add_out(codec::frame_len(&frame));
+            if self.sender.send(frame).await.is_err() {
+                error!("Error sending messages: {:?}", msgs);
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn close(mut self, uid: &str) {
+        if self.sender.send(Message::Close(None)).await.is_err() {
+            error!("Error closing websocket: could not send close message");
+        }
+        if self.sender.close().await.is_err() {
+            error!("Error closing websocket");
+        }
+        info!("Websocket sender with id {} closed", uid);
+    }
+}
+
+// when the writer may send messages to the device
+struct Cadence {
+    interval: Duration,
+    wake: Option<protocols::WakeSchedule>,
+    // unix timestamp the wake windows are counted from
+    woke_at: i64,
+}
+
+impl Cadence {
+    fn is_awake(&self, now: i64) -> bool {
+        self.wake.is_none_or(|w| w.is_awake(self.woke_at, now))
+    }
+
+    // wakes the writer often enough to not sleep through a wake window
+    fn tick(&self) -> Duration {
+        match self.wake {
+            Some(w) => self.interval.min(Duration::from_secs(w.window_secs as u64)),
+            None => self.interval,
+        }
+    }
+}
+
+struct CloseOnDrop(Arc<SendQueue>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// sends the queued messages in batches until the queue is closed and emptied, then closes
+// the websocket
+async fn ws_sender<S: DeviceSocket>(mut out: Outgoing<S>, queue: Arc<SendQueue>, uid: String) {
+    while let Some(batch) = queue.pop(queue.capacity()).await {
+        if !out.send(&batch).await {
+            // unacknowledged deliveries among them are resent on the next connection
+            queue.discard();
+            return;
+        }
+    }
+    out.close(&uid).await;
+}
+
+async fn ws_writer(
+    queue: Arc<SendQueue>,
+    state: Arc<AppState>,
+    uid: String,
+    cadence: Cadence,
+    is_active: Arc<AtomicBool>,
+    mut notices: UnboundedReceiver<String>,
+    mut controls: UnboundedReceiver<Control>,
+) {
+    // an aborted writer still lets the sender close the websocket
+    let _closing = CloseOnDrop(queue.clone());
+    let stats = &state.send_queues;
+    // queued messages are delivered in batches at the interval the device asked for,
+    // notices and control messages are sent right away unless the device is asleep
+    let mut period = poll_period(&state, &cadence);
+    let mut interval = Interval::new(state.clock.clone(), period);
+    // messages queued since the last poll, the ones the delivery latency is measured with
+    let mut last_poll = state.clock.unix_now();
+    // control messages held back until the device wakes up
+    let mut held: Vec<String> = Vec::new();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            // notices are sent right away and are not acknowledged
+            notice = notices.recv() => match notice {
+                Some(notice) => {
+                    if !queue.push(std::slice::from_ref(&notice), stats).await {
+                        return;
+                    }
+                    info!("Sent notice: {:?}", notice);
+                    continue;
+                }
+                // the reader stopped, so the websocket is closed as well
+                None => {
+                    queue.close();
+                    return;
+                }
+            },
+            Some(control) = controls.recv() => match control {
+                Control::Drain => {
+                    drain(&queue, &state, &uid).await;
+                    return;
+                }
+                Control::Shutdown => {
+                    shut_down(&queue, &state, &uid).await;
+                    return;
+                }
+                Control::Close => {
+                    info!("Closing connection {} on request", uid);
+                    queue.discard();
+                    return;
+                }
+                Control::Send(msg) => {
+                    if !cadence.is_awake(state.clock.unix_now()) {
+                        info!("Holding message for sleeping device {}: {:?}", uid, msg);
+                        held.push(msg);
+                        continue;
+                    }
+                    if !queue.push(std::slice::from_ref(&msg), stats).await {
+                        return;
+                    }
+                    info!("Sent message: {:?}", msg);
+                    continue;
+                }
+                Control::Deliver => {}
+            },
+        }
+
+        // check if connection is still active, if not close the websocket
+        if !is_active.load(Ordering::Relaxed) {
+            queue.close();
+            return;
+        }
+
+        // sleeping devices get everything at once in their next wake window
+        if !cadence.is_awake(state.clock.unix_now()) {
+            continue;
+        }
+        if !held.is_empty() {
+            if !queue.push(&held, stats).await {
+                return;
+            }
+            info!("Sent {} held messages to {}", held.len(), uid);
+            held.clear();
+        }
+
+        //retrieve all undelivered messages from the queue, including timed out unacknowledged ones
+        let now = state.clock.unix_now();
+        if !deliver_queued_messages(
+            &queue,
+            &state,
+            &uid,
+            now - ACK_TIMEOUT_SECS,
+            Some(last_poll),
+        )
+        .await
+        {
+            return;
+        }
+        last_poll = now;
+
+        // follow the latency budget
+        let next = poll_period(&state, &cadence);
+        if next != period {
+            period = next;
+            interval = Interval::delayed(state.clock.clone(), period);
+        }
+    }
+}
+
+// how often the writer wakes up, in soft real-time mode awake devices get their
+// messages often enough to stay within the latency target
+fn poll_period(state: &AppState, cadence: &Cadence) -> Duration {
+    match (state.config.latency_target_ms, cadence.wake) {
+        (Some(target_ms), None) => state
+            .latency
+            .poll_interval(Duration::from_millis(target_ms), cadence.tick()),
+        _ => cadence.tick(),
+    }
+}
+
+// queues all undelivered queued messages, including unacknowledged ones sent before `resend_before`,
+// returns false if the websocket is broken. they are fetched a send queue at a time, with
+// backpressure the next batch waits until the device read the previous one. the time messages
+// queued since `fresh_since` waited for their delivery is observed for the latency budget
+async fn deliver_queued_messages(
+    queue: &SendQueue,
+    state: &AppState,
+    uid: &str,
+    mut resend_before: i64,
+    fresh_since: Option<i64>,
+) -> bool {
+    let started = state.clock.unix_now();
+    loop {
+        let messages = match db::get_new_queued_messages(
+            &state.pool,
+            uid,
+            resend_before,
+            queue.capacity() as i64,
+        )
+        .await
+        {
+            Ok(messages) => messages,
+            Err(_) => {
+                error!("Error getting queued messages from the db");
+                return true;
+            }
+        };
+
+        if messages.is_empty() {
+            return true;
+        }
+        if !queue_batch(queue, state, uid, &messages, fresh_since).await {
+            return false;
+        }
+
+        // with drop-oldest a larger batch would only push out this one, the rest waits for the next tick
+        if messages.len() < queue.capacity() || queue.policy() != QueuePolicy::Backpressure {
+            return true;
+        }
+        // the next batch leaves out the messages just queued
+        resend_before = resend_before.min(started - 1);
+    }
+}
+
+async fn queue_batch(
+    queue: &SendQueue,
+    state: &AppState,
+    uid: &str,
+    messages: &[db::QueuedMessage],
+    fresh_since: Option<i64>,
+) -> bool {
+    // queue all AVG messages with their ids, the sender passes them on as one batch
+    let outgoing: Vec<String> = messages
+        .iter()
+        .map(|msg| protocols::with_delivery_id(&msg.message, msg.id))
+        .collect();
+
+    // the deliveries are pending before they are queued, so an ACK the device sends right
+    // away finds them. the ones that are never sent are resent on the next connection
+    for msg in messages {
+        if db::add_pending_delivery(&state.pool, uid, msg.id, state.clock.unix_now())
+            .await
+            .is_err()
+        {
+            error!("Error adding pending delivery to the db");
+        }
+    }
+    if !queue.push(&outgoing, &state.send_queues).await {
+        return false;
+    }
+
+    if let Some(since) = fresh_since {
+        let now = state.clock.unix_now();
+        if let Some(waited) = messages
+            .iter()
+            .filter(|msg| msg.created_at >= since)
+            .map(|msg| now - msg.created_at)
+            .max()
+        {
+            let waited = Duration::from_secs(waited.max(0) as u64);
+            state.latency.observe(Stage::Deliver, waited);
+        }
+    }
+
+    for out in &outgoing {
+        info!("Sent message: {:?}", out);
+    }
+    true
+}
+
+// asks the device to finish sending, flushes all its pending deliveries and waits
+// for their ACKs before the device is put into maintenance
+async fn drain(queue: &SendQueue, state: &AppState, uid: &str) {
+    let timeout_secs = state.config.drain_timeout_secs;
+    info!("Draining connection {}", uid);
+
+    let notice = protocols::DrainMsg { timeout_secs }.to_msg();
+    if !flush_deliveries(queue, state, uid, notice, timeout_secs).await {
+        return;
+    }
+
+    if db::set_maintenance(&state.pool, uid, true).await.is_err() {
+        error!("Error putting {} into maintenance", uid);
+    }
+    info!("Connection {} drained", uid);
+}
+
+// tells the device the server shuts down, flushes all its pending deliveries and waits for
+// their ACKs. deliveries left unacknowledged are resent once the device is back
+async fn shut_down(queue: &SendQueue, state: &AppState, uid: &str) {
+    let grace_secs = state.config.shutdown_grace_secs;
+    info!("Shutting down connection {}", uid);
+
+    let notice = protocols::ShutdownMsg { grace_secs }.to_msg();
+    if flush_deliveries(queue, state, uid, notice, grace_secs).await {
+        info!("Connection {} shut down", uid);
+    }
+}
+
+// sends the notice and everything not acknowledged yet, then waits up to the timeout for the
+// ACKs. returns false if the websocket closed meanwhile
+async fn flush_deliveries(
+    queue: &SendQueue,
+    state: &AppState,
+    uid: &str,
+    notice: String,
+    timeout_secs: u64,
+) -> bool {
+    if !queue.push(&[notice], &state.send_queues).await {
+        return false;
+    }
+
+    // resend everything that is not acknowledged yet
+    if !deliver_queued_messages(queue, state, uid, i64::MAX, None).await {
+        return false;
+    }
+
+    // the reader keeps processing ACKs in the meantime
+    let deadline = state.clock.now() + Duration::from_secs(timeout_secs);
+    while state.clock.now() < deadline {
+        match db::count_pending_deliveries(&state.pool, uid).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => error!("Error counting pending deliveries of {}", uid),
+        }
+        state.clock.sleep(Duration::from_secs(1)).await;
+    }
+    true
+}
+
+// drains a connected device and puts it into maintenance
+#[utoipa::path(
+    post, path = "/api/v1/devices/{uid}/drain", tag = "commands", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 202, description = "the device gets its pending deliveries and is disconnected"),
+        (status = 404, description = "the device is not connected")
+    )
+)]
+pub async fn drain_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if state.sessions.send(&uid, Control::Drain).await {
+        info!("Drain of {} requested", uid);
+        (StatusCode::ACCEPTED, format!("Draining {}", uid)).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, format!("{} is not connected", uid)).into_response()
+    }
+}
+
+// takes a device out of maintenance, so it may connect again
+#[utoipa::path(
+    delete, path = "/api/v1/devices/{uid}/maintenance", tag = "commands", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 204, description = "the device may connect again"),
+        (status = 404, description = "the device is not known")
+    )
+)]
+pub async fn release_maintenance_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if let Err(e) = db::get_connection(&state.pool, &uid).await {
+        return error_status(&e).into_response();
+    }
+    match db::set_maintenance(&state.pool, &uid, false).await {
+        Ok(()) => {
+            info!("{} released from maintenance", uid);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error releasing {} from maintenance: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BandwidthQuery {
+    // only include samples taken at or after this unix timestamp
+    pub since: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BandwidthStats {
+    pub uid: String,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub samples: Vec<BandwidthSample>,
+}
+
+// bytes a device sent and received, as persisted by the bandwidth sampler
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/bandwidth", tag = "devices", params(("uid" = String, Path, description = "uid of the device"), BandwidthQuery),
+    responses((status = 200, body = BandwidthStats))
+)]
+pub async fn bandwidth_handler(
+    Path(uid): Path<String>,
+    Query(query): Query<BandwidthQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let res = db::get_bandwidth_samples(&state.pool, &uid, query.since.unwrap_or(0)).await;
+    match res {
+        Ok(samples) => {
+            let stats = BandwidthStats {
+                uid,
+                bytes_in: samples.iter().map(|s| s.bytes_in).sum(),
+                bytes_out: samples.iter().map(|s| s.bytes_out).sum(),
+                samples,
+            };
+            Json(stats).into_response()
+        }
+        Err(e) => {
+            error!("Error getting bandwidth samples of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// counters of the live connection of a device, or the last snapshot of its latest one
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/stats", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 200, body = ConnectionStats),
+        (status = 404, description = "the device never connected")
+    )
+)]
+pub async fn connection_stats_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if let Some((session_id, stats)) = state.sessions.stats(&uid).await {
+        return Json(connection_stats(&uid, &session_id, stats, true)).into_response();
+    }
+    match db::get_connection_stats(&state.pool, &uid).await {
+        Ok(Some(stats)) => Json(stats).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("{} never connected", uid)).into_response(),
+        Err(e) => {
+            error!("Error getting the connection stats of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RollupQuery {
+    pub period: RollupPeriod,
+    // only include this channel
+    pub channel: Option<String>,
+    // only include buckets starting at or after this unix timestamp
+    pub since: Option<i64>,
+    // only include buckets starting before this unix timestamp
+    pub until: Option<i64>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    // one json object per line
+    Ndjson,
+    // a json array of the rows, for tools that don't read ndjson
+    Json,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    pub channel: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DownloadQuery {
+    // uid of the device
+    pub uid: String,
+    pub channel: Option<String>,
+    // unix timestamps, the readings from `from` up to but excluding `to`
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// bytes of exported rows collected before they are sent as a chunk
+const EXPORT_CHUNK_LEN: usize = 64 * 1024;
+// chunks buffered ahead of a slow client, this bounds the memory of an export
+const EXPORT_CHUNKS_AHEAD: usize = 4;
+
+// raw readings of a device as a chunked csv or ndjson download. rows are read with a cursor
+// and sent as they come, so memory stays flat no matter how many rows are exported
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/export", tag = "messages", params(("uid" = String, Path, description = "uid of the device"), ExportQuery),
+    responses((status = 200, description = "raw readings as chunked csv, ndjson or json", content_type = "text/csv"))
+)]
+pub async fn export_handler(
+    Path(uid): Path<String>,
+    Query(query): Query<ExportQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    export(
+        state,
+        uid,
+        query.channel,
+        query.since.unwrap_or(0),
+        query.until.unwrap_or(i64::MAX),
+        query.format,
+    )
+}
+
+// the same export as a file download, for analysts pulling the history into their tools
+#[utoipa::path(
+    get, path = "/api/v1/export", tag = "messages", params(DownloadQuery),
+    responses((status = 200, description = "raw readings as a csv, ndjson or json file", content_type = "text/csv"))
+)]
+pub async fn download_handler(
+    Query(query): Query<DownloadQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    // the device is named in the query, so require_device_in_scope doesn't see it
+    if scope.is_tenant() {
+        match db::get_device_tenant(&state.pool, &query.uid).await {
+            Ok(tenant) if scope.allows(tenant.as_deref()) => {}
+            Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                error!("Error getting the tenant of {}: {}", query.uid, e);
+                return error_status(&e).into_response();
+            }
+        }
+    }
+    let extension = match query.format {
+        ExportFormat::Csv => "csv",
+        ExportFormat::Ndjson => "ndjson",
+        ExportFormat::Json => "json",
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", query.uid, extension);
+    let mut res = export(
+        state,
+        query.uid,
+        query.channel,
+        query.from.unwrap_or(0),
+        query.to.unwrap_or(i64::MAX),
+        query.format,
+    );
+    if let Ok(disposition) = header::HeaderValue::from_str(&disposition) {
+        res.headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    res
+}
+
+// quotes a field of a csv file if it has to be
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn export(
+    state: Arc<AppState>,
+    uid: String,
+    channel: Option<String>,
+    since: i64,
+    until: i64,
+    format: ExportFormat,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<Result<String, Error>>(EXPORT_CHUNKS_AHEAD);
+
+    tokio::spawn(async move {
+        let mut rows =
+            db::stream_received_messages(&state.pool, &uid, channel.as_deref(), since, until);
+        // a column per configured attribute
+        let attributes = state
+            .config
+            .enrich_attributes
+            .as_ref()
+            .map(|names| names.0.clone())
+            .unwrap_or_default();
+        let mut chunk = match format {
+            ExportFormat::Csv => format!(
+                "uid,channel,created_at,data,min,max,count{}\n",
+                attributes
+                    .iter()
+                    .map(|name| format!(",{}", name))
+                    .collect::<String>()
+            ),
+            ExportFormat::Ndjson => String::new(),
+            ExportFormat::Json => "[".to_string(),
+        };
+        let mut exported = 0;
+
+        while let Some(row) = rows.next().await {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    // aborts the response, so the client can tell the export is incomplete
+                    error!("Error exporting the readings of {}: {}", uid, e);
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            match format {
+                // a raw reading is a summary of itself
+                ExportFormat::Csv => {
+                    chunk.push_str(&format!(
+                        "{},{},{},{},{},{},{}",
+                        csv_field(&row.uid),
+                        csv_field(&row.channel),
+                        row.created_at,
+                        row.data,
+                        row.summary_min.unwrap_or(row.data),
+                        row.summary_max.unwrap_or(row.data),
+                        row.summary_count.unwrap_or(1)
+                    ));
+                    let values = db::parse_attributes(row.attributes.as_deref());
+                    for name in &attributes {
+                        chunk.push(',');
+                        chunk.push_str(&csv_field(values.get(name).map_or("", String::as_str)));
+                    }
+                    chunk.push('\n');
+                }
+                ExportFormat::Ndjson => {
+                    chunk.push_str(&serde_json::to_string(&row).unwrap_or_default());
+                    chunk.push('\n');
+                }
+                ExportFormat::Json => {
+                    if exported > 0 {
+                        chunk.push(',');
+                    }
+                    chunk.push_str(&serde_json::to_string(&row).unwrap_or_default());
+                }
+            }
+            exported += 1;
+
+            if chunk.len() >= EXPORT_CHUNK_LEN
+                && tx.send(Ok(std::mem::take(&mut chunk))).await.is_err()
+            {
+                info!("Export of {} cancelled after {} rows", uid, exported);
+                return;
+            }
+        }
+
+        if format == ExportFormat::Json {
+            chunk.push_str("]\n");
+        }
+        if !chunk.is_empty() {
+            let _ = tx.send(Ok(chunk)).await;
+        }
+        info!("Exported {} readings of {}", exported, uid);
+    });
+
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let content_type = match format {
+        ExportFormat::Csv => "text/csv",
+        ExportFormat::Ndjson => "application/x-ndjson",
+        ExportFormat::Json => "application/json",
+    };
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        StreamBody::new(chunks),
+    )
+        .into_response()
+}
+
+// hourly or daily aggregates of the readings of a device
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/rollups", tag = "messages", params(("uid" = String, Path, description = "uid of the device"), RollupQuery),
+    responses((status = 200, description = "hourly or daily aggregates of the readings", body = [Rollup]))
+)]
+pub async fn rollups_handler(
+    Path(uid): Path<String>,
+    Query(query): Query<RollupQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let res = db::get_rollups(
+        &state.pool,
+        query.period,
+        &uid,
+        query.channel.as_deref(),
+        query.since.unwrap_or(0),
+        query.until.unwrap_or(i64::MAX),
+    )
+    .await;
+    match res {
+        Ok(mut rollups) => {
+            for rollup in &mut rollups {
+                rollup.avg = protocols::round_value(rollup.avg, state.config.value_decimals);
+            }
+            Json(rollups).into_response()
+        }
+        Err(e) => {
+            error!(
+                "Error getting {} rollups of {}: {}",
+                query.period.name(),
+                uid,
+                e
+            );
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConnectionInfo {
+    #[serde(flatten)]
+    pub connection: Connection,
+    // whether the device has a live websocket session
+    pub live: bool,
+    // whether the device is between two wake windows of its schedule
+    pub asleep: bool,
+}
+
+async fn connection_info(state: &AppState, connection: Connection) -> ConnectionInfo {
+    let live = state.sessions.is_connected(&connection.uid).await;
+    let asleep = connection
+        .wake_schedule()
+        .is_some_and(|(wake, woke_at)| !wake.is_awake(woke_at, state.clock.unix_now()));
+    ConnectionInfo {
+        connection,
+        live,
+        asleep,
+    }
+}
+
+// pushes received readings and computed averages to a dashboard as server-sent events
+#[utoipa::path(
+    get, path = "/api/v1/stream", tag = "messages",
+    responses((status = 200, description = "readings (sensor) and averages (avg) as server-sent events", content_type = "text/event-stream"))
+)]
+pub async fn stream_handler(
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    info!("New event stream subscriber");
+    let receiver = state.events.subscribe();
+    let events = stream::unfold((receiver, scope), |(mut receiver, scope)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if !scope.allows(event.tenant()) => continue,
+                Ok(event) => {
+                    let sse = Event::default()
+                        .event(event.name())
+                        .json_data(&event)
+                        .unwrap_or_default();
+                    return Some((Ok(sse), (receiver, scope)));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event stream subscriber lagged, skipped {} events", skipped)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+// uids with a live websocket session
+#[utoipa::path(
+    get, path = "/api/v1/sessions", tag = "devices",
+    responses((status = 200, description = "live websocket sessions", body = [SessionInfo]))
+)]
+pub async fn list_sessions_handler(
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let mut sessions = state.sessions.list().await;
+    if let Some(tenant) = &scope.0 {
+        match db::get_connections(&state.pool, Some(tenant)).await {
+            Ok(connections) => {
+                let uids: HashSet<String> = connections.into_iter().map(|c| c.uid).collect();
+                sessions.retain(|session| uids.contains(&session.uid));
+            }
+            Err(e) => {
+                error!("Error getting the connections of tenant {}: {}", tenant, e);
+                return error_status(&e).into_response();
+            }
+        }
+    }
+    Json(sessions).into_response()
+}
+
+// live sessions of the devices in scope, -1 if they can't be told
+async fn live_sessions(state: &AppState, scope: &auth::Scope) -> i64 {
+    let Some(tenant) = &scope.0 else {
+        return state.sessions.count().await as i64;
+    };
+    match db::get_connections(&state.pool, Some(tenant)).await {
+        Ok(connections) => {
+            let mut live = 0;
+            for connection in connections {
+                if state.sessions.is_connected(&connection.uid).await {
+                    live += 1;
+                }
+            }
+            live
+        }
+        Err(_) => -1,
+    }
+}
+
+// sessions kept per device in the history
+const SESSION_HISTORY_LEN: i64 = 50;
+
+// recent sessions of a device, newest first, including the ended ones
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/sessions", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, description = "the latest sessions of the device, newest first", body = [DeviceSession]))
+)]
+pub async fn device_sessions_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_device_sessions(&state.pool, &uid, SESSION_HISTORY_LEN).await {
+        Ok(sessions) => Json(sessions).into_response(),
+        Err(e) => {
+            error!("Error getting the sessions of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TenantQuery {
+    // only the data of this tenant, all tenants if left out. the keys of a tenant always
+    // get the data of their own
+    pub tenant: Option<String>,
+}
+
+#[utoipa::path(
+    get, path = "/api/v1/connections", tag = "devices", params(TenantQuery),
+    responses((status = 200, description = "all known devices", body = [ConnectionInfo]))
+)]
+pub async fn list_connections_handler(
+    Query(query): Query<TenantQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let tenant = match scope.tenant(query.tenant) {
+        Ok(tenant) => tenant,
+        Err(status) => return status.into_response(),
+    };
+    match db::get_connections(&state.pool, tenant.as_deref()).await {
+        Ok(connections) => {
+            let mut infos = Vec::with_capacity(connections.len());
+            for connection in connections {
+                infos.push(connection_info(&state, connection).await);
+            }
+            Json(infos).into_response()
+        }
+        Err(e) => {
+            error!("Error getting connections from the db: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// status of a failed request, transient db errors are worth retrying
+fn error_status(e: &Error) -> StatusCode {
+    match e {
+        Error::NotFound => StatusCode::NOT_FOUND,
+        Error::Parse(_) | Error::Protocol(_) => StatusCode::BAD_REQUEST,
+        e if e.is_transient() => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[utoipa::path(
+    get, path = "/api/v1/connections/{uid}", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 200, body = ConnectionInfo),
+        (status = 404, description = "unknown device")
+    )
+)]
+pub async fn get_connection_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_connection(&state.pool, &uid).await {
+        Ok(connection) => Json(connection_info(&state, connection).await).into_response(),
+        Err(Error::NotFound) => {
+            (StatusCode::NOT_FOUND, format!("{} is not known", uid)).into_response()
+        }
+        Err(e) => {
+            error!("Error getting connection {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// closes the live session of a device and marks it as disconnected, like a DISCONN.
+// use the purge endpoint to delete its data
+#[utoipa::path(
+    delete, path = "/api/v1/connections/{uid}", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 204, description = "the device was disconnected, its history is kept"),
+        (status = 404, description = "unknown device")
+    )
+)]
+pub async fn delete_connection_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let was_live = state.sessions.send(&uid, Control::Close).await;
+    let was_known = match db::get_connection(&state.pool, &uid).await {
+        Ok(_) => true,
+        Err(Error::NotFound) => false,
+        Err(e) => {
+            error!("Error getting connection {}: {}", uid, e);
+            return error_status(&e).into_response();
+        }
+    };
+
+    if !was_live && !was_known {
+        return (StatusCode::NOT_FOUND, format!("{} is not known", uid)).into_response();
+    }
+
+    if was_known
+        && db::disconnect_connection(&state.pool, &uid, state.clock.unix_now())
+            .await
+            .is_err()
+    {
+        error!("Error disconnecting connection {} in database", uid);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    info!(
+        "Connection {} disconnected, live session closed: {}",
+        uid, was_live
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// devices needing attention, as of the last refresh of the attention service
+#[utoipa::path(
+    get, path = "/api/v1/attention", tag = "devices",
+    responses((status = 200, description = "devices needing attention", body = [AttentionItem]))
+)]
+pub async fn attention_handler(scope: auth::Scope, State(state): State<Arc<AppState>>) -> Response {
+    // the cache holds the devices of every tenant
+    if !scope.is_tenant() {
+        if let Some(cached) = state.cache.get(cache::ATTENTION_KEY).await {
+            return ([(header::CONTENT_TYPE, "application/json")], cached).into_response();
+        }
+    }
+
+    match db::get_attention(&state.pool, scope.0.as_deref()).await {
+        Ok(items) => {
+            if !scope.is_tenant() {
+                if let Ok(json) = serde_json::to_string(&items) {
+                    state.cache.set(cache::ATTENTION_KEY, &json).await;
+                }
+            }
+            Json(items).into_response()
+        }
+        Err(e) => {
+            error!("Error getting the devices needing attention: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// value of a key in the key-value store of a device
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/kv/{key}", tag = "devices",
+    params(("uid" = String, Path, description = "uid of the device"), ("key" = String, Path, description = "key of the value")),
+    responses(
+        (status = 200, description = "the stored value", body = String),
+        (status = 404, description = "no value stored")
+    )
+)]
+pub async fn get_kv_handler(
+    Path((uid, key)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let cache_key = cache::kv_key(&uid, &key);
+    if let Some(value) = state.cache.get(&cache_key).await {
+        return value.into_response();
+    }
+
+    match db::get_device_value(&state.pool, &uid, &key).await {
+        Ok(Some(value)) => {
+            state.cache.set(&cache_key, &value).await;
+            value.into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, format!("{} has no key {}", uid, key)).into_response(),
+        Err(e) => {
+            error!("Error reading key {} of {}: {}", key, uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CommandRequest {
+    pub command: String,
+    pub argument: Option<String>,
+}
+
+// queues a CMD message for a device, it is delivered like any other queued message
+// and acknowledged with CMD_ACK. returns the queued message id
+#[utoipa::path(
+    post, path = "/api/v1/devices/{uid}/commands", tag = "commands", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = CommandRequest,
+    responses(
+        (status = 201, description = "id of the queued command", body = i64),
+        (status = 400, description = "invalid command")
+    )
+)]
+pub async fn add_command_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CommandRequest>,
+) -> Response {
+    let cmd = protocols::CmdMsg {
+        command: request.command,
+        argument: request.argument,
+    };
+    if let Err(e) = cmd.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    match db::add_targeted_message(&state.pool, &uid, cmd.to_msg()).await {
+        Ok(id) => {
+            info!("Command {} queued for {} with id {}", cmd.command, uid, id);
+            (StatusCode::CREATED, Json(id)).into_response()
+        }
+        Err(e) => {
+            error!("Error queueing command {} for {}: {}", cmd.command, uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// commands queued for a device and whether they were acknowledged
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/commands", tag = "commands", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, description = "commands queued for the device and their state", body = [Command]))
+)]
+pub async fn list_commands_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_targeted_messages(&state.pool, &uid).await {
+        Ok(commands) => Json(commands).into_response(),
+        Err(e) => {
+            error!("Error getting the commands of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// sets a key in the key-value store of a device, the body is the value
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/kv/{key}", tag = "devices",
+    params(("uid" = String, Path, description = "uid of the device"), ("key" = String, Path, description = "key of the value")),
+    request_body(content = String, content_type = "text/plain"),
+    responses((status = 204), (status = 400, description = "invalid key or value"))
+)]
+pub async fn put_kv_handler(
+    Path((uid, key)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+    value: String,
+) -> Response {
+    if !protocols::is_valid_key(&key) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid key: {}", key)).into_response();
+    }
+    if value.len() > protocols::MAX_VALUE_LEN {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Values are limited to {} bytes", protocols::MAX_VALUE_LEN),
+        )
+            .into_response();
+    }
+
+    match db::set_device_value(&state.pool, &uid, &key, &value).await {
+        Ok(()) => {
+            state.cache.invalidate(&cache::kv_key(&uid, &key)).await;
+            info!("Key {} of {} set", key, uid);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error setting key {} of {}: {}", key, uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/alert-rules", tag = "alerts", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, body = [AlertRule]))
+)]
+pub async fn list_alert_rules_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_device_alert_rules(&state.pool, &uid).await {
+        Ok(rules) => Json(rules).into_response(),
+        Err(e) => {
+            error!("Error getting the alert rules of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// adds a threshold rule for the readings of a device, returns the rule id.
+// a rule with an action sends a CMD to the action device as soon as it fires
+#[utoipa::path(
+    post, path = "/api/v1/devices/{uid}/alert-rules", tag = "alerts", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = AlertRule,
+    responses((status = 201, description = "id of the rule", body = i64), (status = 400))
+)]
+pub async fn add_alert_rule_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(mut rule): Json<db::AlertRule>,
+) -> Response {
+    if rule.op != ">" && rule.op != "<" {
+        return (StatusCode::BAD_REQUEST, "op has to be > or <").into_response();
+    }
+    if rule.duration_secs < 0 {
+        return (StatusCode::BAD_REQUEST, "duration_secs can't be negative").into_response();
+    }
+    match (&rule.action_uid, &rule.action_command) {
+        (Some(_), Some(command)) => {
+            let cmd = protocols::CmdMsg {
+                command: command.clone(),
+                argument: rule.action_argument.clone(),
+            };
+            if let Err(e) = cmd.validate() {
+                return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+            }
+        }
+        (None, None) if rule.action_argument.is_none() => {}
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "an action needs both action_uid and action_command",
+            )
+                .into_response()
+        }
+    }
+    rule.uid = uid;
+
+    match db::add_alert_rule(&state.pool, &rule).await {
+        Ok(id) => {
+            alerts::rules_changed(&state, &rule.uid).await;
+            info!("Alert rule {} added for {}", id, rule.uid);
+            (StatusCode::CREATED, id.to_string()).into_response()
+        }
+        Err(e) => {
+            error!("Error adding an alert rule for {}: {}", rule.uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the version of the device's rules and the last one it applied, the device evaluates its
+// rules itself once both match
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/alert-rules/version", tag = "alerts", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, body = RuleVersion))
+)]
+pub async fn rule_version_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_rule_version(&state.pool, &uid).await {
+        Ok(version) => Json(version).into_response(),
+        Err(e) => {
+            error!("Error getting the rule version of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete, path = "/api/v1/alert-rules/{id}", tag = "alerts",
+    params(("id" = i64, Path, description = "id of the rule")),
+    responses((status = 204), (status = 404))
+)]
+pub async fn delete_alert_rule_handler(
+    Path(id): Path<i64>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    // the rule is named by its id, not by the uid of a device in the tenant
+    if scope.is_tenant() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match db::delete_alert_rule(&state.pool, id).await {
+        Ok(Some(uid)) => {
+            alerts::rules_changed(&state, &uid).await;
+            info!("Alert rule {} removed", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, format!("No alert rule {}", id)).into_response(),
+        Err(e) => {
+            error!("Error removing alert rule {}: {}", id, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get, path = "/api/v1/alerts", tag = "alerts",
+    responses((status = 200, description = "alerts that are not resolved", body = [Alert]))
+)]
+pub async fn active_alerts_handler(
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_active_alerts(&state.pool, scope.0.as_deref()).await {
+        Ok(alerts) => Json(alerts).into_response(),
+        Err(e) => {
+            error!("Error getting the active alerts: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeviceGroup {
+    pub group: String,
+}
+
+// assigns a device to a group
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/group", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = DeviceGroup,
+    responses((status = 204, description = "the group applies when the device reconnects"))
+)]
+pub async fn device_group_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<DeviceGroup>,
+) -> Response {
+    match db::set_device_group(&state.pool, &uid, &body.group).await {
+        Ok(()) => {
+            info!("Device {} assigned to group {}", uid, body.group);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error assigning {} to group {}: {}", uid, body.group, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PassThrough {
+    pub enabled: bool,
+}
+
+// marks a device as pass-through, its readings are forwarded to the subscribers and webhooks
+// as they come in and left out of the averages. applies to the readings from now on
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/pass-through", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = PassThrough,
+    responses((status = 204), (status = 404, description = "the device is not known"))
+)]
+pub async fn pass_through_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<PassThrough>,
+) -> Response {
+    match db::set_pass_through(&state.pool, &uid, body.enabled).await {
+        Ok(()) => {
+            info!("Pass-through of {} set to {}", uid, body.enabled);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error setting the pass-through of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DeviceAttributes {
+    pub attributes: BTreeMap<String, String>,
+}
+
+// the static attributes of a device in the registry
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/attributes", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, body = DeviceAttributes))
+)]
+pub async fn device_attributes_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_device_attributes(&state.pool, &uid).await {
+        Ok(attributes) => Json(DeviceAttributes { attributes }).into_response(),
+        Err(e) => {
+            error!("Error getting the attributes of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// replaces the static attributes of a device, like its site or asset tag. the attributes
+// configured in ENRICH_ATTRIBUTES are the only ones accepted, readings stored from now on
+// carry them to the exports and sinks
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/attributes", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = DeviceAttributes,
+    responses(
+        (status = 204),
+        (status = 400, description = "an attribute is not configured"),
+        (status = 404, description = "the device is not known")
+    )
+)]
+pub async fn set_device_attributes_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<DeviceAttributes>,
+) -> Response {
+    let configured = state.config.enrich_attributes.as_ref();
+    if let Some(name) = body
+        .attributes
+        .keys()
+        .find(|name| !configured.is_some_and(|names| names.contains(name)))
+    {
+        warn!(
+            "Rejected attribute {:?} of {}, it is not configured",
+            name, uid
+        );
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("attribute {} is not configured", name),
+        )
+            .into_response();
+    }
+
+    match db::set_device_attributes(&state.pool, &uid, &body.attributes).await {
+        Ok(()) => {
+            info!("Attributes of {} set to {:?}", uid, body.attributes);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error setting the attributes of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ConfigRequest {
+    #[schema(value_type = Object)]
+    pub params: db::ConfigParams,
+}
+
+// the parameters of a device and the last version it applied
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/config", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, body = DeviceConfig))
+)]
+pub async fn device_config_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_device_config(&state.pool, &uid).await {
+        Ok(config) => Json(config).into_response(),
+        Err(e) => {
+            error!("Error getting the config of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// replaces the parameters of a device, like its sampling rate or thresholds, as a new
+// version. it is pushed to the device, or to the fog node it is behind, right away and on
+// every CONN until the device acknowledges it with CONFIG_ACK
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/config", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = ConfigRequest,
+    responses(
+        (status = 200, body = DeviceConfig),
+        (status = 400, description = "a parameter is not a number, string or boolean"),
+        (status = 404, description = "the device is not known")
+    )
+)]
+pub async fn set_device_config_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<ConfigRequest>,
+) -> Response {
+    if let Some((name, _)) = body
+        .params
+        .iter()
+        .find(|(_, value)| value.is_null() || value.is_array() || value.is_object())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("parameter {} has to be a number, string or boolean", name),
+        )
+            .into_response();
+    }
+
+    match db::set_device_config(&state.pool, &uid, &body.params).await {
+        Ok(version) => {
+            info!("Config of {} is at version {}", uid, version);
+            device_configs::config_changed(&state, &uid).await;
+        }
+        Err(e) => {
+            error!("Error setting the config of {}: {}", uid, e);
+            return error_status(&e).into_response();
+        }
+    }
+    device_config_handler(Path(uid), State(state)).await
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DevicesQuery {
+    // part of the name, ignoring case
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub sensor_type: Option<String>,
+    pub firmware: Option<String>,
+}
+
+// the devices in the registry with their name, location, sensor type and firmware
+#[utoipa::path(
+    get, path = "/api/v1/devices", tag = "devices", params(DevicesQuery),
+    responses((status = 200, body = [Device]))
+)]
+pub async fn devices_handler(
+    Query(query): Query<DevicesQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let filter = db::DeviceMetadata {
+        name: query.name,
+        location: query.location,
+        sensor_type: query.sensor_type,
+        firmware: query.firmware,
+        ..Default::default()
+    };
+    match db::get_devices(&state.pool, scope.0.as_deref(), &filter).await {
+        Ok(devices) => Json(devices).into_response(),
+        Err(e) => {
+            error!("Error getting the devices: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses(
+        (status = 200, body = Device),
+        (status = 404, description = "the device is not registered")
+    )
+)]
+pub async fn device_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_device(&state.pool, &uid).await {
+        Ok(device) => Json(device).into_response(),
+        Err(e) => {
+            error!("Error getting the device {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// registers a device, also one that never connected yet, or updates the fields that are
+// set. devices update their metadata with the options of CONN
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = DeviceMetadata,
+    responses(
+        (status = 200, body = Device),
+        (status = 400, description = "the uid is not a uuid, a field is empty, longer than 64 bytes or has control characters, or the position is not valid")
+    )
+)]
+pub async fn register_device_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<db::DeviceMetadata>,
+) -> Response {
+    if uuid::Uuid::try_parse(&uid).is_err() {
+        return (StatusCode::BAD_REQUEST, "the uid has to be a uuid").into_response();
+    }
+    let fields = [
+        &body.name,
+        &body.location,
+        &body.sensor_type,
+        &body.firmware,
+    ];
+    if fields
+        .into_iter()
+        .flatten()
+        .any(|value| !protocols::is_valid_metadata(value))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "fields can't be empty, longer than 64 bytes or have control characters",
+        )
+            .into_response();
+    }
+    let position_valid = match (body.latitude, body.longitude) {
+        (Some(lat), Some(lon)) => geo::is_valid_lat(lat) && geo::is_valid_lon(lon),
+        (None, None) => true,
+        _ => false,
+    };
+    if !position_valid {
+        return (
+            StatusCode::BAD_REQUEST,
+            "latitude and longitude have to be given together and be in range",
+        )
+            .into_response();
+    }
+
+    match db::set_device_metadata(&state.pool, &uid, &body).await {
+        Ok(device) => {
+            info!("Device {} registered", uid);
+            Json(device).into_response()
+        }
+        Err(e) => {
+            error!("Error registering the device {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct GeoQuery {
+    // a bounding box, in degrees. min_lon is greater than max_lon for boxes that cross the
+    // antimeridian
+    pub min_lat: Option<f64>,
+    pub min_lon: Option<f64>,
+    pub max_lat: Option<f64>,
+    pub max_lon: Option<f64>,
+    // or a circle around a center, in degrees and km
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub radius_km: Option<f64>,
+    // only the fog nodes, or only the other devices
+    pub fog: Option<bool>,
+}
+
+// longest radius of a geo query, half the circumference of the earth
+const MAX_RADIUS_KM: f64 = 20_015.0;
+
+impl GeoQuery {
+    fn area(&self) -> Option<geo::Area> {
+        match *self {
+            GeoQuery {
+                min_lat: Some(min_lat),
+                min_lon: Some(min_lon),
+                max_lat: Some(max_lat),
+                max_lon: Some(max_lon),
+                lat: None,
+                lon: None,
+                radius_km: None,
+                ..
+            } if geo::is_valid_lat(min_lat)
+                && geo::is_valid_lat(max_lat)
+                && min_lat <= max_lat
+                && geo::is_valid_lon(min_lon)
+                && geo::is_valid_lon(max_lon) =>
+            {
+                Some(geo::Area::Box(geo::BoundingBox {
+                    min_lat,
+                    min_lon,
+                    max_lat,
+                    max_lon,
+                }))
+            }
+            GeoQuery {
+                min_lat: None,
+                min_lon: None,
+                max_lat: None,
+                max_lon: None,
+                lat: Some(lat),
+                lon: Some(lon),
+                radius_km: Some(radius_km),
+                ..
+            } if geo::is_valid_lat(lat)
+                && geo::is_valid_lon(lon)
+                && radius_km > 0.0
+                && radius_km <= MAX_RADIUS_KM =>
+            {
+                Some(geo::Area::Radius {
+                    lat,
+                    lon,
+                    radius_km,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+const INVALID_AREA: &str =
+    "give either min_lat, min_lon, max_lat and max_lon or lat, lon and radius_km, in range";
+
+// the registered devices with a position in the area
+async fn devices_in(
+    state: &AppState,
+    scope: &auth::Scope,
+    area: &geo::Area,
+    fog: Option<bool>,
+) -> crate::Result<Vec<db::Device>> {
+    let devices = db::get_devices_in(&state.pool, scope.0.as_deref(), &area.bounds(), fog).await?;
+    Ok(devices
+        .into_iter()
+        .filter(|device| match (device.latitude, device.longitude) {
+            (Some(lat), Some(lon)) => area.contains(lat, lon),
+            _ => false,
+        })
+        .collect())
+}
+
+// the devices in a bounding box or within a radius, for the map of the dashboard
+#[utoipa::path(
+    get, path = "/api/v1/geo/devices", tag = "devices", params(GeoQuery),
+    responses(
+        (status = 200, body = [Device]),
+        (status = 400, description = "neither a bounding box nor a radius, or coordinates out of range")
+    )
+)]
+pub async fn geo_devices_handler(
+    Query(query): Query<GeoQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let Some(area) = query.area() else {
+        return (StatusCode::BAD_REQUEST, INVALID_AREA).into_response();
+    };
+    match devices_in(&state, &scope, &area, query.fog).await {
+        Ok(devices) => Json(devices).into_response(),
+        Err(e) => {
+            error!("Error getting the devices in {:?}: {}", area, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the last reading of every channel of the devices in a bounding box or within a radius
+#[utoipa::path(
+    get, path = "/api/v1/geo/readings/latest", tag = "messages", params(GeoQuery),
+    responses(
+        (status = 200, body = [ReceivedMessage]),
+        (status = 400, description = "neither a bounding box nor a radius, or coordinates out of range")
+    )
+)]
+pub async fn geo_latest_readings_handler(
+    Query(query): Query<GeoQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let Some(area) = query.area() else {
+        return (StatusCode::BAD_REQUEST, INVALID_AREA).into_response();
+    };
+    let devices = match devices_in(&state, &scope, &area, query.fog).await {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Error getting the devices in {:?}: {}", area, e);
+            return error_status(&e).into_response();
+        }
+    };
+    // the db only narrows the readings down to the box around the area
+    let uids: HashSet<String> = devices.into_iter().map(|device| device.uid).collect();
+    let res =
+        db::get_latest_readings_in(&state.pool, scope.0.as_deref(), &area.bounds(), query.fog)
+            .await;
+    match res {
+        Ok(readings) => Json(
+            readings
+                .into_iter()
+                .filter(|reading| uids.contains(&reading.uid))
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => {
+            error!("Error getting the latest readings: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the conversions of the channels of a device
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/conversions", tag = "devices", params(("uid" = String, Path, description = "uid of the device")),
+    responses((status = 200, body = [Conversion]))
+)]
+pub async fn list_conversions_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_conversions(&state.pool, &uid).await {
+        Ok(conversions) => Json(conversions).into_response(),
+        Err(e) => {
+            error!("Error getting the conversions of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// converts the readings of a channel before they are stored, like raw ADC counts to °C with
+// a linear conversion or °F to °C. readings stored before keep their unit
+#[utoipa::path(
+    put, path = "/api/v1/devices/{uid}/conversions/{channel}", tag = "devices",
+    params(("uid" = String, Path, description = "uid of the device"), ("channel" = String, Path, description = "channel of the readings")),
+    request_body = Conversion,
+    responses(
+        (status = 200, body = Conversion),
+        (status = 400, description = "unknown kind, scale or offset not finite, or invalid channel or unit")
+    )
+)]
+pub async fn set_conversion_handler(
+    Path((uid, channel)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+    Json(mut conversion): Json<db::Conversion>,
+) -> Response {
+    if !conversions::is_valid_kind(&conversion.kind) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("kind has to be one of {}", conversions::KINDS.join(", ")),
+        )
+            .into_response();
+    }
+    if !conversion.scale.is_finite() || !conversion.offset.is_finite() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "scale and offset have to be numbers",
+        )
+            .into_response();
+    }
+    if !protocols::is_valid_channel(&channel) {
+        return (StatusCode::BAD_REQUEST, "invalid channel").into_response();
+    }
+    if let Some(unit) = &conversion.unit {
+        if !protocols::is_valid_metadata(unit) {
+            return (StatusCode::BAD_REQUEST, "invalid unit").into_response();
+        }
+    }
+    conversion.uid = uid;
+    conversion.channel = channel;
+
+    match db::set_conversion(&state.pool, &conversion).await {
+        Ok(conversion) => {
+            info!(
+                "Readings of {}/{} are converted with {}",
+                conversion.uid, conversion.channel, conversion.kind
+            );
+            Json(conversion).into_response()
+        }
+        Err(e) => {
+            error!(
+                "Error setting the conversion of {}/{}: {}",
+                conversion.uid, conversion.channel, e
+            );
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// stores the readings of the channel as they come again
+#[utoipa::path(
+    delete, path = "/api/v1/devices/{uid}/conversions/{channel}", tag = "devices",
+    params(("uid" = String, Path, description = "uid of the device"), ("channel" = String, Path, description = "channel of the readings")),
+    responses((status = 204), (status = 404, description = "the channel has no conversion"))
+)]
+pub async fn delete_conversion_handler(
+    Path((uid, channel)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::delete_conversion(&state.pool, &uid, &channel).await {
+        Ok(true) => {
+            info!("Conversion of {}/{} removed", uid, channel);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            format!("No conversion of {}/{}", uid, channel),
+        )
+            .into_response(),
+        Err(e) => {
+            error!(
+                "Error removing the conversion of {}/{}: {}",
+                uid, channel, e
+            );
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the fog nodes devices forwarded readings through, with how many devices are behind each
+#[utoipa::path(
+    get, path = "/api/v1/fog-nodes", tag = "devices",
+    responses((status = 200, body = [FogNode]))
+)]
+pub async fn fog_nodes_handler(scope: auth::Scope, State(state): State<Arc<AppState>>) -> Response {
+    match db::get_fog_nodes(&state.pool, scope.0.as_deref()).await {
+        Ok(nodes) => Json(nodes).into_response(),
+        Err(e) => {
+            error!("Error getting the fog nodes: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the devices whose readings came through the fog node last
+#[utoipa::path(
+    get, path = "/api/v1/fog-nodes/{uid}/devices", tag = "devices", params(("uid" = String, Path, description = "uid of the fog node")),
+    responses((status = 200, body = [Connection]))
+)]
+pub async fn fog_devices_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_fog_devices(&state.pool, &uid).await {
+        Ok(devices) => Json(devices).into_response(),
+        Err(e) => {
+            error!("Error getting the devices behind {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the last reading of every device and channel behind the fog node
+#[utoipa::path(
+    get, path = "/api/v1/fog-nodes/{uid}/readings/latest", tag = "messages", params(("uid" = String, Path, description = "uid of the fog node")),
+    responses((status = 200, body = [ReceivedMessage]))
+)]
+pub async fn fog_latest_readings_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::get_latest_fog_readings(&state.pool, &uid).await {
+        Ok(readings) => Json(readings).into_response(),
+        Err(e) => {
+            error!("Error getting the latest readings behind {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct GroupPolicy {
+    pub critical: bool,
+}
+
+// configures whether readings of a group are admitted when the ingest is saturated
+#[utoipa::path(
+    put, path = "/api/v1/groups/{name}", tag = "devices",
+    params(("name" = String, Path, description = "name of the group")),
+    request_body = GroupPolicy,
+    responses((status = 204))
+)]
+pub async fn group_policy_handler(
+    Path(name): Path<String>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<GroupPolicy>,
+) -> Response {
+    if scope.is_tenant() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match db::set_group_policy(&state.pool, &name, body.critical).await {
+        Ok(()) => {
+            info!("Group {} critical: {}", name, body.critical);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error setting the policy of group {}: {}", name, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FlagInfo {
+    pub name: &'static str,
+    // whether the flag is enabled for the deployment
+    pub enabled: bool,
+    pub default: bool,
+    // settings of device groups that differ from the deployment
+    pub groups: HashMap<String, bool>,
+}
+
+// known feature flags with their settings
+#[utoipa::path(
+    get, path = "/api/v1/flags", tag = "flags",
+    responses((status = 200, body = [FlagInfo]))
+)]
+pub async fn list_flags_handler(
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if scope.is_tenant() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let stored = match db::get_feature_flags(&state.pool).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Error getting the feature flags: {}", e);
+            return error_status(&e).into_response();
+        }
+    };
+
+    let mut flags = Vec::new();
+    for (name, default) in flags::KNOWN {
+        let groups = stored
+            .iter()
+            .filter(|f| f.name == name && !f.group_name.is_empty())
+            .map(|f| (f.group_name.clone(), f.enabled))
+            .collect();
+        flags.push(FlagInfo {
+            name,
+            enabled: state.flags.is_enabled(name).await,
+            default,
+            groups,
+        });
+    }
+    Json(flags).into_response()
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FlagRequest {
+    // null goes back to the default
+    pub enabled: Option<bool>,
+    // device group to set the flag for, the whole deployment if missing
+    pub group: Option<String>,
+}
+
+// enables or disables a feature flag at runtime
+#[utoipa::path(
+    put, path = "/api/v1/flags/{name}", tag = "flags",
+    params(("name" = String, Path, description = "name of the flag")),
+    request_body = FlagRequest,
+    responses((status = 204), (status = 404, description = "unknown flag"))
+)]
+pub async fn set_flag_handler(
+    Path(name): Path<String>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<FlagRequest>,
+) -> Response {
+    if scope.is_tenant() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if !flags::is_known(&name) {
+        return (StatusCode::NOT_FOUND, format!("unknown flag {}", name)).into_response();
+    }
+
+    let group = request.group.as_deref().filter(|g| !g.is_empty());
+    match state
+        .flags
+        .set(&state.pool, &name, group, request.enabled)
+        .await
+    {
+        Ok(()) => {
+            info!(
+                "Flag {} set to {:?} for {}",
+                name,
+                request.enabled,
+                group.unwrap_or("the deployment")
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Error setting flag {}: {}", name, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the process answers, for liveness probes. doesn't touch the db, so a slow db doesn't get
+// the server restarted
+pub async fn liveness_handler() -> Response {
+    "OK".into_response()
+}
+
+// whether the server can take traffic, for readiness probes
+pub async fn readiness_handler(State(state): State<Arc<AppState>>) -> Response {
+    let problems = probes::readiness(&state).await;
+    if problems.is_empty() {
+        return "OK".into_response();
+    }
+    warn!("Not ready: {}", problems.join(", "));
+    (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n")).into_response()
+}
+
+// the counts of the devices and messages are the ones of the tenant for its keys
+pub async fn health_handler(scope: auth::Scope, State(state): State<Arc<AppState>>) -> Response {
+    // retrieve metrics from the database
+    let res = db::get_metrics(&state.pool, scope.0.as_deref()).await;
+    let live_sessions = live_sessions(&state, &scope).await;
+    match res {
+        Ok(metrics) => {
+            let res_text = format!(
+                r#"Status: OK
+Number of connections: {} 
+Number of received messages: {}
+Number of unique queued messages: {}
+Number of delivered messages: {}
+Number of unacknowledged messages: {}
+Number of devices in maintenance: {}
+Number of live sessions: {}
+Number of readings being stored: {}
+Number of shed readings: {}
+Number of duplicate readings: {}
+Number of pruned received messages: {}
+Number of pruned queued messages: {}
+Number of pruned delivered messages: {}
+Number of summarized received messages: {}
+End-to-end latency in milliseconds: {}
+Number of latency budget violations: {}
+Latency shed level: {}
+Number of messages dropped from send queues: {}
+Number of send queue stalls: {}
+Age of the newest reading of the last AVG message in seconds: {}
+Number of stale AVG messages: {}
+Number of background services down: {}
+Number of background service restarts: {}
+                "#,
+                metrics.connections.unwrap_or(-1),
+                metrics.received_messages.unwrap_or(-1),
+                metrics.queued_messages.unwrap_or(-1),
+                metrics.delivered_messages.unwrap_or(-1),
+                metrics.pending_deliveries.unwrap_or(-1),
+                metrics.maintenance.unwrap_or(-1),
+                live_sessions,
+                state.admission.in_flight(),
+                state.admission.shed(),
+                state.admission.duplicates(),
+                state.pruned.get(db::PrunableTable::Received),
+                state.pruned.get(db::PrunableTable::Queued),
+                state.pruned.get(db::PrunableTable::Delivered),
+                state.pruned.summarized(),
+                state.latency.end_to_end().as_millis(),
+                state.latency.violations(),
+                state.latency.shed_level(),
+                state.send_queues.dropped(),
+                state.send_queues.stalls(),
+                state.staleness.last(),
+                state.staleness.stale(),
+                state.services.down().len(),
+                state.services.restarts(),
+            );
+            info!("Health check: ok");
+            res_text.into_response()
+        }
+        Err(_) => {
+            error!("Error getting database metrics");
+            "Status: Error".into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SimulateRequest {
+    pub uid: String,
+    pub message: String,
+    // why the message is injected, kept in the audit log
+    pub note: Option<String>,
+}
+
+// injects a SENSOR message as if the device had sent it, so parsing and aggregation
+// issues can be reproduced without hardware. it is parsed with the timestamp format of the
+// live session of the device and validated like the readings off its websocket. every
+// injection is audit logged
+#[utoipa::path(
+    post, path = "/admin/simulate-message", tag = "admin",
+    request_body = SimulateRequest,
+    responses(
+        (status = 202, description = "the reading is stored"),
+        (status = 400, description = "only SENSOR messages of the uid can be simulated, the other messages of devices belong to their websocket session"),
+        (status = 422, description = "the ERR message the device would get", body = String)
+    )
+)]
+pub async fn simulate_message_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SimulateRequest>,
+) -> Response {
+    warn!(
+        "AUDIT: simulating message {:?} from {} ({})",
+        request.message,
+        request.uid,
+        request.note.as_deref().unwrap_or("no note")
+    );
+    if db::add_admin_audit(
+        &state.pool,
+        "simulate-message",
+        &request.uid,
+        &request.message,
+        request.note.as_deref(),
+    )
+    .await
+    .is_err()
+    {
+        // don't inject messages that can't be traced back
+        error!("Error writing the audit log, message not simulated");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    match protocols::get_protocol(&request.message) {
+        Ok(protocols::Protocol::SENSOR) => {}
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Only SENSOR messages can be simulated",
+            )
+                .into_response()
+        }
+    }
+    let timestamps = state
+        .sessions
+        .timestamps(&request.uid)
+        .await
+        .unwrap_or_default();
+    let mut sensor_data = match protocols::SensorMsg::parse(&request.message, timestamps) {
+        Ok(sensor_data) => sensor_data,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid message: {}", e)).into_response()
+        }
+    };
+    if sensor_data.uid != request.uid || sensor_data.via.is_some() {
+        return (StatusCode::BAD_REQUEST, "Message uid doesn't match uid").into_response();
+    }
+
+    let critical = db::is_critical_device(&state.pool, &request.uid)
+        .await
+        .unwrap_or_else(|_| {
+            error!("Error getting the group policy of {}", request.uid);
+            false
+        });
+    match admit_sensor(&state, &mut sensor_data, critical).await {
+        Ok(_permit) => {
+            ingest_sensor(&state, sensor_data).await;
+            StatusCode::ACCEPTED.into_response()
+        }
+        Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_msg()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PurgeRequest {
+    pub uid: String,
+    // why the data is deleted, kept in the audit log
+    pub note: Option<String>,
+}
+
+// deletes a device with its readings, deliveries, sessions and everything else it left,
+// closing its live session first. every purge is audit logged
+pub async fn purge_device_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PurgeRequest>,
+) -> Response {
+    warn!(
+        "AUDIT: purging {} ({})",
+        request.uid,
+        request.note.as_deref().unwrap_or("no note")
+    );
+    if db::add_admin_audit(
+        &state.pool,
+        "purge-device",
+        &request.uid,
+        "",
+        request.note.as_deref(),
+    )
+    .await
+    .is_err()
+    {
+        error!("Error writing the audit log, device not purged");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    state.sessions.send(&request.uid, Control::Close).await;
+    match db::purge_connection(&state.pool, &request.uid).await {
+        Ok(deleted) => {
+            info!("Purged {}, deleted {} rows", request.uid, deleted);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(Error::NotFound) => (
+            StatusCode::NOT_FOUND,
+            format!("{} is not known", request.uid),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Error purging {}: {}", request.uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SealedQuery {
+    pub since: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// most sealed messages returned at once
+const MAX_SEALED_MESSAGES: i64 = 1000;
+
+// sealed readings of a device, as the envelopes it sent
+#[utoipa::path(
+    get, path = "/api/v1/devices/{uid}/sealed", tag = "messages", params(("uid" = String, Path, description = "uid of the device"), SealedQuery),
+    responses((status = 200, description = "end-to-end encrypted readings as sent", body = [SealedMessage]))
+)]
+pub async fn list_sealed_handler(
+    Path(uid): Path<String>,
+    Query(query): Query<SealedQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let limit = query
+        .limit
+        .unwrap_or(MAX_SEALED_MESSAGES)
+        .clamp(1, MAX_SEALED_MESSAGES);
+    match db::get_sealed_messages(&state.pool, &uid, query.since.unwrap_or(0), limit).await {
+        Ok(messages) => Json(messages).into_response(),
+        Err(e) => {
+            error!("Error getting the sealed messages of {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SealedRequest {
+    // hex envelope sealed to the public key of the device
+    pub envelope: String,
+}
+
+// queues a sealed message for a device, delivered and acknowledged like other queued messages
+#[utoipa::path(
+    post, path = "/api/v1/devices/{uid}/sealed", tag = "commands", params(("uid" = String, Path, description = "uid of the device")),
+    request_body = SealedRequest,
+    responses(
+        (status = 201, description = "id of the queued message", body = i64),
+        (status = 400, description = "invalid envelope")
+    )
+)]
+pub async fn add_sealed_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SealedRequest>,
+) -> Response {
+    let envelope = match Envelope::from_hex(&request.envelope) {
+        Ok(envelope) => envelope,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let msg = protocols::SealedMsg::downlink(&envelope);
+    match db::add_targeted_message(&state.pool, &uid, msg).await {
+        Ok(id) => {
+            info!("Sealed message queued for {} with id {}", uid, id);
+            (StatusCode::CREATED, Json(id)).into_response()
+        }
+        Err(e) => {
+            error!("Error queueing a sealed message for {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// the embedded dashboard, showing connections, the last readings and the AVG history
+pub async fn dashboard_handler() -> Html<&'static str> {
+    Html(include_str!("../static/dashboard.html"))
+}
+
+// the last reading of every device and channel
+#[utoipa::path(
+    get, path = "/api/v1/readings/latest", tag = "messages", params(TenantQuery),
+    responses((status = 200, description = "the last reading of every device and channel", body = [ReceivedMessage]))
+)]
+pub async fn latest_readings_handler(
+    Query(query): Query<TenantQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let tenant = match scope.tenant(query.tenant) {
+        Ok(tenant) => tenant,
+        Err(status) => return status.into_response(),
+    };
+    match db::get_latest_readings(&state.pool, tenant.as_deref()).await {
+        Ok(readings) => Json(readings).into_response(),
+        Err(e) => {
+            error!("Error getting the latest readings: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AveragesQuery {
+    pub limit: Option<i64>,
+    // only the averages of this tenant, all tenants if left out
+    pub tenant: Option<String>,
+}
+
+// most AVG messages returned at once
+const MAX_AVERAGES: i64 = 500;
+
+// the most recent AVG messages, oldest first
+#[utoipa::path(
+    get, path = "/api/v1/averages", tag = "messages", params(AveragesQuery),
+    responses((status = 200, description = "the most recent AVG messages, oldest first", body = [AvgMsg]))
+)]
+pub async fn averages_handler(
+    Query(query): Query<AveragesQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let tenant = match scope.tenant(query.tenant) {
+        Ok(tenant) => tenant,
+        Err(status) => return status.into_response(),
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_AVERAGES);
+    match db::get_recent_averages(&state.pool, limit, tenant.as_deref()).await {
+        Ok(messages) => {
+            let averages: Vec<AvgMsg> = messages
+                .iter()
+                .rev()
+                .filter_map(|m| protocols::AvgMsg::from_msg(&m.message).ok())
+                .collect();
+            Json(averages).into_response()
+        }
+        Err(e) => {
+            error!("Error getting the recent averages: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AggregatesQuery {
+    // only include this channel
+    pub channel: Option<String>,
+    // only the aggregates of this tenant, all tenants if left out
+    pub tenant: Option<String>,
+    // only include windows ending at or after this unix timestamp
+    pub since: Option<i64>,
+    // only include windows starting before this unix timestamp
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// most aggregates returned at once
+const MAX_AGGREGATES: i64 = 10000;
+
+// the averages the AVG service computed with their windows, oldest first
+#[utoipa::path(
+    get, path = "/api/v1/aggregates", tag = "messages", params(AggregatesQuery),
+    responses((status = 200, description = "computed averages with their windows, oldest first", body = [Aggregate]))
+)]
+pub async fn aggregates_handler(
+    Query(query): Query<AggregatesQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let tenant = match scope.tenant(query.tenant) {
+        Ok(tenant) => tenant,
+        Err(status) => return status.into_response(),
+    };
+    let res = db::get_aggregates(
+        &state.pool,
+        tenant.as_deref(),
+        query.channel.as_deref(),
+        query.since.unwrap_or(0),
+        query.until.unwrap_or(i64::MAX),
+        query.limit.unwrap_or(1000).clamp(1, MAX_AGGREGATES),
+    )
+    .await;
+    match res {
+        Ok(aggregates) => Json(aggregates).into_response(),
+        Err(e) => {
+            error!("Error getting the aggregates: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    // only include events at or after this unix timestamp
+    pub from: Option<i64>,
+    // only include events before this unix timestamp
+    pub to: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// most system events returned at once
+const MAX_SYSTEM_EVENTS: i64 = 10000;
+
+// the timeline of the server, like service restarts, config reloads, migrations and
+// shedding episodes, oldest first
+#[utoipa::path(
+    get, path = "/api/v1/events", tag = "system", params(EventsQuery),
+    responses((status = 200, description = "system events, oldest first", body = [SystemEvent]))
+)]
+pub async fn system_events_handler(
+    Query(query): Query<EventsQuery>,
+    scope: auth::Scope,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if scope.is_tenant() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let res = db::get_system_events(
+        &state.pool,
+        query.from.unwrap_or(0),
+        query.to.unwrap_or(i64::MAX),
+        query.limit.unwrap_or(1000).clamp(1, MAX_SYSTEM_EVENTS),
+    )
+    .await;
+    match res {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => {
+            error!("Error getting the system events: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// slow queries reported at once
+const TOP_SLOW_QUERIES: usize = 20;
+
+// the db queries that spent the most time being slower than SLOW_QUERY_MS
+pub async fn slow_queries_handler() -> Response {
+    Json(db::SLOW_QUERIES.top(TOP_SLOW_QUERIES)).into_response()
+}
+
+// the settings the AVG service currently aggregates with
+pub async fn avg_settings_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.avg_settings.borrow().clone()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AvgSettingsRequest {
+    pub window_size: Option<i64>,
+    pub interval_secs: Option<u64>,
+    pub aggregation: Option<Aggregation>,
+    pub timestamp: Option<AggregationTimestamp>,
+    // why the settings are changed, kept in the audit log
+    pub note: Option<String>,
+}
+
+// changes the settings of the AVG service without a restart, left out settings are kept.
+// every change is audit logged
+pub async fn update_avg_settings_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<AvgSettingsRequest>,
+) -> Response {
+    let mut settings = state.avg_settings.borrow().clone();
+    if let Some(window_size) = request.window_size {
+        settings.window_size = window_size;
+    }
+    if let Some(interval_secs) = request.interval_secs {
+        settings.interval_secs = interval_secs;
+    }
+    if let Some(aggregation) = request.aggregation {
+        settings.aggregation = aggregation;
+    }
+    if let Some(timestamp) = request.timestamp {
+        settings.timestamp = timestamp;
+    }
+    if let Err(e) = settings.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    let message = serde_json::to_string(&settings).unwrap_or_default();
+    warn!(
+        "AUDIT: AVG settings changed to {} ({})",
+        message,
+        request.note.as_deref().unwrap_or("no note")
+    );
+    if db::add_admin_audit(
+        &state.pool,
+        "avg-settings",
+        "",
+        &message,
+        request.note.as_deref(),
+    )
+    .await
+    .is_err()
+    {
+        error!("Error writing the audit log, AVG settings not changed");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    state.avg_settings.send_replace(settings.clone());
+    timeline::record(
+        &state,
+        EventKind::ConfigReload,
+        &format!("AVG settings changed to {}", message),
+    )
+    .await;
+    Json(settings).into_response()
+}
+
+pub async fn forwarding_settings_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.forwarding.settings.borrow().clone()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ForwardingSettingsRequest {
+    pub mode: Option<forwarding::ForwardingMode>,
+    pub window_secs: Option<u64>,
+    pub aggregation: Option<Aggregation>,
+    // why the settings are changed, kept in the audit log
+    pub note: Option<String>,
+}
+
+// switches how readings are forwarded to the webhooks without a restart, left out settings
+// are kept. every change is audit logged
+pub async fn update_forwarding_settings_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ForwardingSettingsRequest>,
+) -> Response {
+    let mut settings = state.forwarding.settings.borrow().clone();
+    if let Some(mode) = request.mode {
+        settings.mode = mode;
+    }
+    if let Some(window_secs) = request.window_secs {
+        settings.window_secs = window_secs;
+    }
+    if let Some(aggregation) = request.aggregation {
+        settings.aggregation = aggregation;
+    }
+    if let Err(e) = settings.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    let message = serde_json::to_string(&settings).unwrap_or_default();
+    warn!(
+        "AUDIT: forwarding settings changed to {} ({})",
+        message,
+        request.note.as_deref().unwrap_or("no note")
+    );
+    if db::add_admin_audit(
+        &state.pool,
+        "forwarding",
+        "",
+        &message,
+        request.note.as_deref(),
+    )
+    .await
+    .is_err()
+    {
+        error!("Error writing the audit log, forwarding settings not changed");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    state.forwarding.settings.send_replace(settings.clone());
+    timeline::record(
+        &state,
+        EventKind::ConfigReload,
+        &format!("Forwarding settings changed to {}", message),
+    )
+    .await;
+    Json(settings).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct WebhookDeliveriesQuery {
+    pub limit: Option<i64>,
+}
+
+// most webhook deliveries returned at once
+const MAX_WEBHOOK_DELIVERIES: i64 = 1000;
+
+// the most recent deliveries of events to the webhooks, newest first
+pub async fn webhook_deliveries_handler(
+    Query(query): Query<WebhookDeliveriesQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_WEBHOOK_DELIVERIES);
+    match db::get_webhook_deliveries(&state.pool, limit).await {
+        Ok(deliveries) => Json(deliveries).into_response(),
+        Err(e) => {
+            error!("Error getting the webhook deliveries: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// readings included in the diagnostics
+const DIAGNOSTICS_READINGS: i64 = 20;
+
+#[derive(Serialize)]
+pub struct DeviceDiagnostics {
+    pub uid: String,
+    pub last_seen: i64,
+    pub live: bool,
+    pub maintenance: bool,
+    pub disconnected: bool,
+}
+
+// rows waiting in the db, -1 if they could not be counted
+#[derive(Serialize)]
+pub struct BufferDiagnostics {
+    pub received_messages: i32,
+    pub received_max_rows: Option<i64>,
+    pub queued_messages: i32,
+    pub unacknowledged_messages: i32,
+    pub summarized_messages: u64,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamDiagnostics {
+    // nats or kafka, the url may carry credentials
+    pub stream: Option<&'static str>,
+    pub stream_connected: bool,
+    pub stream_published: u64,
+    pub stream_failed: u64,
+    // readings held back for the upstream budget, and the ones dropped meanwhile
+    pub stream_backlog: usize,
+    pub stream_dropped: u64,
+    // what was sent upstream in the current hour
+    pub budget: budget::BudgetReport,
+    // webhook deliveries by status
+    pub webhooks: HashMap<String, i64>,
+    // how exporting goes, by the url of the sink
+    pub sinks: BTreeMap<String, sinks::SinkStats>,
+}
+
+#[derive(Serialize)]
+pub struct Diagnostics {
+    pub devices: Vec<DeviceDiagnostics>,
+    pub buffer: BufferDiagnostics,
+    pub upstream: UpstreamDiagnostics,
+    pub recent_readings: Vec<db::ReceivedMessage>,
+}
+
+// archives the completed days not archived yet right away, instead of waiting for the schedule
+pub async fn archive_handler(State(state): State<Arc<AppState>>) -> Response {
+    if state.config.archive_url.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match state.archive.run(&state).await {
+        Some(Ok(report)) => {
+            info!(
+                "Archived {} rows of {} days on request",
+                report.rows,
+                report.days.len()
+            );
+            Json(report).into_response()
+        }
+        Some(Err(e)) => {
+            error!("Error archiving: {}", e);
+            error_status(&e).into_response()
+        }
+        None => (StatusCode::CONFLICT, "an archive run is already going").into_response(),
+    }
+}
+
+// how long a flush waits for the readings being written
+const INGEST_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the readings being written to the db and the last flush, to check nothing is left behind
+// before a node is taken down
+pub async fn ingest_status_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.admission.status()).into_response()
+}
+
+// waits until the readings admitted so far are written to the db. answers with 503 if some
+// were still being written when it gave up
+pub async fn ingest_flush_handler(State(state): State<Arc<AppState>>) -> Response {
+    let report = state
+        .admission
+        .flush(&*state.clock, INGEST_FLUSH_TIMEOUT)
+        .await;
+    if report.drained {
+        info!("Ingest flushed after {}ms", report.waited_ms);
+        Json(report).into_response()
+    } else {
+        warn!(
+            "Ingest flush gave up with {} readings still being written",
+            report.remaining
+        );
+        (StatusCode::SERVICE_UNAVAILABLE, Json(report)).into_response()
+    }
+}
+
+// everything a technician on site needs at a glance: the devices seen, how much is buffered,
+// whether the upstream takes the readings and what came in last
+pub async fn diagnostics_handler(State(state): State<Arc<AppState>>) -> Response {
+    let (connections, metrics, webhooks, recent_readings) = match tokio::try_join!(
+        db::get_connections(&state.pool, None),
+        db::get_metrics(&state.pool, None),
+        db::count_webhook_deliveries(&state.pool),
+        db::get_recent_readings(&state.pool, DIAGNOSTICS_READINGS),
+    ) {
+        Ok(results) => results,
+        Err(e) => {
+            error!("Error collecting the diagnostics: {}", e);
+            return error_status(&e).into_response();
+        }
+    };
+
+    let mut devices = Vec::with_capacity(connections.len());
+    for connection in connections {
+        devices.push(DeviceDiagnostics {
+            live: state.sessions.is_connected(&connection.uid).await,
+            uid: connection.uid,
+            last_seen: connection.last_seen,
+            maintenance: connection.maintenance,
+            disconnected: connection.disconnected_at.is_some(),
+        });
+    }
+    devices.sort_by_key(|device| std::cmp::Reverse(device.last_seen));
+
+    Json(Diagnostics {
+        devices,
+        buffer: BufferDiagnostics {
+            received_messages: metrics.received_messages.unwrap_or(-1),
+            received_max_rows: state.config.received_retention.max_rows,
+            queued_messages: metrics.queued_messages.unwrap_or(-1),
+            unacknowledged_messages: metrics.pending_deliveries.unwrap_or(-1),
+            summarized_messages: state.pruned.summarized(),
+        },
+        upstream: UpstreamDiagnostics {
+            stream: state.config.stream_url.as_ref().map(|url| match url {
+                StreamUrl::Nats(_) => "nats",
+                StreamUrl::Kafka(_) => "kafka",
+            }),
+            stream_connected: state.upstream.is_connected(),
+            stream_published: state.upstream.published(),
+            stream_failed: state.upstream.failed(),
+            stream_backlog: state.upstream.backlog(),
+            stream_dropped: state.upstream.dropped(),
+            budget: state.budget.report_at(state.clock.unix_now()),
+            webhooks: webhooks.into_iter().collect(),
+            sinks: state.sinks.stats(),
+        },
+        recent_readings,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct TenantRequest {
+    pub id: String,
+    pub name: String,
+}
+
+// adds a tenant, its devices join it with the tenant CONN option. audit logged
+pub async fn add_tenant_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<TenantRequest>,
+) -> Response {
+    if !protocols::is_valid_tenant(&request.id) {
+        return (StatusCode::BAD_REQUEST, "invalid tenant id").into_response();
+    }
+
+    match db::add_tenant(&state.pool, &request.id, &request.name).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::CONFLICT.into_response(),
+        Err(e) => {
+            error!("Error adding tenant {}: {}", request.id, e);
+            return error_status(&e).into_response();
+        }
+    }
+    warn!("AUDIT: tenant {} ({}) added", request.id, request.name);
+    if db::add_admin_audit(&state.pool, "add-tenant", "", &request.id, None)
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of tenant {}", request.id);
+    }
+
+    match db::get_tenant(&state.pool, &request.id).await {
+        Ok(tenant) => (StatusCode::CREATED, Json(tenant)).into_response(),
+        Err(e) => error_status(&e).into_response(),
+    }
+}
+
+// all tenants with the number of their devices and stored readings
+pub async fn list_tenants_handler(State(state): State<Arc<AppState>>) -> Response {
+    match db::get_tenants(&state.pool).await {
+        Ok(tenants) => Json(tenants).into_response(),
+        Err(e) => {
+            error!("Error getting the tenants: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    pub role: auth::Role,
+    // the tenant whose data the key reads, every tenant if left out
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub api_key: db::ApiKey,
+    // only returned here, the server keeps its digest
+    pub key: String,
+}
+
+// hands out a new API key of the REST api. audit logged
+pub async fn add_api_key_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ApiKeyRequest>,
+) -> Response {
+    if let Some(tenant) = &request.tenant {
+        match db::get_tenant(&state.pool, tenant).await {
+            Ok(_) => {}
+            Err(Error::NotFound) => {
+                return (StatusCode::BAD_REQUEST, "unknown tenant").into_response()
+            }
+            Err(e) => {
+                error!("Error getting tenant {}: {}", tenant, e);
+                return error_status(&e).into_response();
+            }
+        }
+    }
+    let key = auth::generate_key();
+    let role = request.role.as_str();
+    let res = db::add_api_key(
+        &state.pool,
+        &request.name,
+        &auth::hash_key(&key),
+        role,
+        request.tenant.as_deref(),
+    )
+    .await;
+    let api_key = match res {
+        Ok(api_key) => api_key,
+        Err(e) => {
+            error!("Error adding API key {}: {}", request.name, e);
+            return error_status(&e).into_response();
+        }
+    };
+    warn!(
+        "AUDIT: {} API key {} ({}) of tenant {:?} added",
+        role, api_key.id, api_key.name, api_key.tenant
+    );
+    if db::add_admin_audit(&state.pool, "add-api-key", "", &api_key.name, Some(role))
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of API key {}", api_key.id);
+    }
+
+    (StatusCode::CREATED, Json(NewApiKey { api_key, key })).into_response()
+}
+
+// all API keys, including the revoked ones
+pub async fn list_api_keys_handler(State(state): State<Arc<AppState>>) -> Response {
+    match db::get_api_keys(&state.pool).await {
+        Ok(keys) => Json(keys).into_response(),
+        Err(e) => {
+            error!("Error getting the API keys: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// revoked keys are rejected right away. audit logged
+pub async fn revoke_api_key_handler(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::revoke_api_key(&state.pool, id).await {
+        Ok(true) => {
+            warn!("AUDIT: API key {} revoked", id);
+            if db::add_admin_audit(&state.pool, "revoke-api-key", "", &id.to_string(), None)
+                .await
+                .is_err()
+            {
+                error!("Error writing the audit log of API key {}", id);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error revoking API key {}: {}", id, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// registers a fog node, it may then CONN with role=fog. audit logged
+pub async fn add_fog_node_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if protocols::parse_uid(&uid).is_err() {
+        return (StatusCode::BAD_REQUEST, "invalid uid").into_response();
+    }
+    match db::add_fog_node(&state.pool, &uid).await {
+        Ok(true) => {
+            warn!("AUDIT: fog node {} registered", uid);
+            if db::add_admin_audit(&state.pool, "add-fog-node", &uid, "", None)
+                .await
+                .is_err()
+            {
+                error!("Error writing the audit log of fog node {}", uid);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            error!("Error registering fog node {}: {}", uid, e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+// takes the fog role from a node and disconnects it. audit logged
+pub async fn delete_fog_node_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match db::delete_fog_node(&state.pool, &uid).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error removing fog node {}: {}", uid, e);
+            return error_status(&e).into_response();
+        }
+    }
+    warn!("AUDIT: fog node {} removed", uid);
+    if db::add_admin_audit(&state.pool, "delete-fog-node", &uid, "", None)
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of fog node {}", uid);
+    }
+
+    if state.sessions.send(&uid, Control::Close).await {
+        info!("Disconnected {}, it is no longer a fog node", uid);
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// registers a device behind a fog node, moving it from the one it was behind before. the
+// device joins the tenant of the fog node and may not be in another one. audit logged
+pub async fn add_fog_device_handler(
+    Path((uid, device)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if protocols::parse_uid(&device).is_err() {
+        return (StatusCode::BAD_REQUEST, "invalid uid").into_response();
+    }
+    match db::is_fog_node(&state.pool, &uid).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error getting the fog node {}: {}", uid, e);
+            return error_status(&e).into_response();
+        }
+    }
+    match db::add_fog_device(&state.pool, &device, &uid).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::CONFLICT, "device belongs to another tenant").into_response()
+        }
+        Err(e) => {
+            error!("Error registering {} behind {}: {}", device, uid, e);
+            return error_status(&e).into_response();
+        }
+    }
+    warn!("AUDIT: {} registered behind fog node {}", device, uid);
+    if db::add_admin_audit(&state.pool, "add-fog-device", &device, &uid, None)
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of {} behind {}", device, uid);
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PairingRequest {
+    // how long pairing mode stays open, at most an hour
+    pub secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct PairingStatus {
+    // unix timestamp pairing mode closes at, None while it is closed
+    pub open_until: Option<i64>,
+}
+
+pub async fn pairing_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(PairingStatus {
+        open_until: state.pairing.open_until(),
+    })
+    .into_response()
+}
+
+// opens pairing mode, so new devices can be provisioned without configuring their uid
+// and key by hand. audit logged
+pub async fn open_pairing_handler(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PairingRequest>,
+) -> Response {
+    if state.config.signing_secret.is_none() {
+        return (
+            StatusCode::CONFLICT,
+            "pairing needs SIGNING_SECRET to derive the keys of the devices",
+        )
+            .into_response();
+    }
+    if request.secs == 0 || request.secs > pairing::MAX_PAIRING_SECS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "pairing mode can be opened for 1 to {} seconds",
+                pairing::MAX_PAIRING_SECS
+            ),
+        )
+            .into_response();
+    }
+
+    let open_until = state.pairing.open(request.secs);
+    warn!("AUDIT: pairing mode opened for {} seconds", request.secs);
+    if db::add_admin_audit(
+        &state.pool,
+        "open-pairing",
+        "",
+        &request.secs.to_string(),
+        None,
+    )
+    .await
+    .is_err()
+    {
+        error!("Error writing the audit log of opening pairing mode");
+    }
+
+    Json(PairingStatus {
+        open_until: Some(open_until),
+    })
+    .into_response()
+}
+
+// closes pairing mode before its time is up. audit logged
+pub async fn close_pairing_handler(State(state): State<Arc<AppState>>) -> Response {
+    state.pairing.close();
+    warn!("AUDIT: pairing mode closed");
+    if db::add_admin_audit(&state.pool, "close-pairing", "", "", None)
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of closing pairing mode");
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PairingsQuery {
+    // pending, approved or rejected, unset lists all pairings
+    pub status: Option<String>,
+}
+
+// the devices provisioned in pairing mode, the newest first
+pub async fn list_pairings_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PairingsQuery>,
+) -> Response {
+    match db::get_pairings(&state.pool, query.status.as_deref()).await {
+        Ok(pairings) => Json(pairings).into_response(),
+        Err(e) => {
+            error!("Error getting the pairings: {}", e);
+            error_status(&e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingDecision {
+    Approved,
+    Rejected,
+}
+
+#[derive(Deserialize)]
+pub struct PairingDecisionRequest {
+    pub status: PairingDecision,
+}
+
+// approves or rejects a paired device, rejected devices are disconnected and can't connect
+// anymore. audit logged
+pub async fn decide_pairing_handler(
+    Path(uid): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PairingDecisionRequest>,
+) -> Response {
+    let (status, action) = match request.status {
+        PairingDecision::Approved => ("approved", "approve-pairing"),
+        PairingDecision::Rejected => ("rejected", "reject-pairing"),
+    };
+    match db::decide_pairing(&state.pool, &uid, status).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Error deciding the pairing of {}: {}", uid, e);
+            return error_status(&e).into_response();
         }
     }
+    warn!("AUDIT: pairing of {} {}", uid, status);
+    if db::add_admin_audit(&state.pool, action, &uid, "", None)
+        .await
+        .is_err()
+    {
+        error!("Error writing the audit log of the pairing of {}", uid);
+    }
+
+    if request.status == PairingDecision::Rejected
+        && state.sessions.send(&uid, Control::Close).await
+    {
+        info!("Disconnected {}, its pairing was rejected", uid);
+    }
+    StatusCode::NO_CONTENT.into_response()
 }
@@ -0,0 +1,157 @@
+use crate::{clock::Interval, AppState};
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+// seconds between checks of the latency budget, one AVG window
+const CHECK_INTERVAL_SECS: u64 = 10;
+// every level halves the readings admitted at the same time
+const MAX_SHED_LEVEL: u32 = 4;
+// shortest delivery interval, so a tight budget can't turn the writers into busy loops
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    // storing a reading
+    Ingest,
+    // averaging the readings of a window and queueing the AVG messages
+    Aggregate,
+    // waiting in the queue until the message is sent to the device, in whole seconds
+    // like the queue timestamps
+    Deliver,
+}
+
+const STAGES: [Stage; 3] = [Stage::Ingest, Stage::Aggregate, Stage::Deliver];
+
+#[derive(Default)]
+struct StageLatency {
+    // slowest observation since the last check, in microseconds
+    window_us: AtomicU64,
+    // latency of the stage as of the last check
+    current_us: AtomicU64,
+}
+
+// soft real-time mode. the latency of readings from ingest to the delivery of their AVG
+// is checked against a target, when it is exceeded routine telemetry is shed until the
+// latency is back within the budget. the averaging window itself is not counted
+#[derive(Default)]
+pub struct Latency {
+    ingest: StageLatency,
+    aggregate: StageLatency,
+    deliver: StageLatency,
+    violations: AtomicU64,
+    shed_level: AtomicU32,
+}
+
+impl Latency {
+    fn stage(&self, stage: Stage) -> &StageLatency {
+        match stage {
+            Stage::Ingest => &self.ingest,
+            Stage::Aggregate => &self.aggregate,
+            Stage::Deliver => &self.deliver,
+        }
+    }
+
+    pub fn observe(&self, stage: Stage, elapsed: Duration) {
+        self.stage(stage)
+            .window_us
+            .fetch_max(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // latency of a stage as of the last check
+    pub fn get(&self, stage: Stage) -> Duration {
+        Duration::from_micros(self.stage(stage).current_us.load(Ordering::Relaxed))
+    }
+
+    pub fn end_to_end(&self) -> Duration {
+        STAGES.iter().map(|&stage| self.get(stage)).sum()
+    }
+
+    // checks since the server started in which the end-to-end latency exceeded the target
+    pub fn violations(&self) -> u64 {
+        self.violations.load(Ordering::Relaxed)
+    }
+
+    pub fn shed_level(&self) -> u32 {
+        self.shed_level.load(Ordering::Relaxed)
+    }
+
+    // takes the slowest observations of every stage since the last check, stages without
+    // observations decay. sheds more load while the target is exceeded and less once the
+    // latency is well within it, returns whether the target was exceeded
+    pub fn check(&self, target: Duration) -> bool {
+        for stage in STAGES {
+            let latency = self.stage(stage);
+            let observed = latency.window_us.swap(0, Ordering::Relaxed);
+            let current = match observed {
+                0 => latency.current_us.load(Ordering::Relaxed) / 2,
+                observed => observed,
+            };
+            latency.current_us.store(current, Ordering::Relaxed);
+        }
+
+        let end_to_end = self.end_to_end();
+        let level = self.shed_level();
+        if end_to_end > target {
+            self.violations.fetch_add(1, Ordering::Relaxed);
+            self.shed_level
+                .store((level + 1).min(MAX_SHED_LEVEL), Ordering::Relaxed);
+            true
+        } else {
+            if end_to_end < target * 3 / 4 {
+                self.shed_level
+                    .store(level.saturating_sub(1), Ordering::Relaxed);
+            }
+            false
+        }
+    }
+
+    // readings admitted at the same time at the current shed level
+    pub fn max_in_flight(&self, configured: usize) -> usize {
+        (configured >> self.shed_level()).max(1)
+    }
+
+    // delivery interval of a device, shortened to the part of the target
+    // ingest and aggregation leave for the delivery
+    pub fn poll_interval(&self, target: Duration, requested: Duration) -> Duration {
+        let left = target
+            .saturating_sub(self.get(Stage::Ingest))
+            .saturating_sub(self.get(Stage::Aggregate));
+        requested.min(left).max(MIN_POLL_INTERVAL)
+    }
+}
+
+// periodically checks the latency budget, if soft real-time mode is enabled
+pub async fn latency_service(state: Arc<AppState>) {
+    let target = match state.config.latency_target_ms {
+        Some(target_ms) => Duration::from_millis(target_ms),
+        None => return,
+    };
+    info!("Soft real-time mode with a latency target of {:?}", target);
+
+    let mut interval = Interval::new(
+        state.clock.clone(),
+        Duration::from_secs(CHECK_INTERVAL_SECS),
+    );
+    loop {
+        interval.tick().await;
+
+        let latency = &state.latency;
+        if latency.check(target) {
+            warn!(
+                "Latency budget exceeded: {:?} of {:?} (ingest {:?}, aggregate {:?}, deliver {:?}), shed level {}",
+                latency.end_to_end(),
+                target,
+                latency.get(Stage::Ingest),
+                latency.get(Stage::Aggregate),
+                latency.get(Stage::Deliver),
+                latency.shed_level()
+            );
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use sqlx::{Pool, Sqlite};
+use std::sync::Arc;
+
+pub use error::{Error, Result};
+
+pub mod admission;
+pub mod alerts;
+pub mod archive;
+pub mod attention;
+pub mod auth;
+pub mod budget;
+pub mod cache;
+pub mod clock;
+pub mod coap;
+pub mod codec;
+pub mod config;
+pub mod conversions;
+pub mod db;
+pub mod device_configs;
+pub mod envelope;
+pub mod error;
+pub mod events;
+pub mod flags;
+pub mod forwarding;
+pub mod geo;
+pub mod grpc;
+pub mod handlers;
+pub mod latency;
+pub mod lines;
+pub mod mdns;
+pub mod openapi;
+pub mod pairing;
+pub mod probes;
+pub mod protocols;
+pub mod publisher;
+pub mod retention;
+pub mod rollups;
+pub mod routes;
+pub mod send_queue;
+pub mod sensors;
+pub mod serial_reader;
+pub mod sessions;
+pub mod signing;
+pub mod sinks;
+pub mod slow_queries;
+pub mod sniffer;
+pub mod timeline;
+pub mod tls;
+pub mod versioning;
+pub mod webhooks;
+
+pub struct AppState {
+    pub pool: Pool<Sqlite>,
+    pub config: config::Config,
+    pub sessions: sessions::Sessions,
+    pub admission: admission::Admission,
+    pub cache: cache::Cache,
+    pub events: events::Events,
+    pub pruned: retention::Pruned,
+    pub replay: signing::ReplayGuard,
+    pub flags: flags::Flags,
+    pub latency: latency::Latency,
+    // settings of the AVG service, changed through the admin api
+    pub avg_settings: tokio::sync::watch::Sender<protocols::AvgSettings>,
+    // how readings are forwarded to the webhooks, also changed through the admin api
+    pub forwarding: forwarding::Forwarding,
+    // wakes the alert service up to reload the rules
+    pub alert_rules_changed: tokio::sync::Notify,
+    pub webhooks: webhooks::Webhooks,
+    pub upstream: publisher::Upstream,
+    pub sinks: sinks::Sinks,
+    pub send_queues: send_queue::QueueStats,
+    pub pairing: pairing::PairingWindow,
+    pub budget: budget::UpstreamBudget,
+    pub archive: archive::Archive,
+    // how old the readings of the AVG messages were
+    pub staleness: protocols::Staleness,
+    // the supervised background services, the server isn't ready while one is down
+    pub services: probes::Services,
+    // the time the background services and the websocket writers run on
+    pub clock: Arc<dyn clock::Clock>,
+}
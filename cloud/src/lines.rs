@@ -0,0 +1,88 @@
+use crate::{handlers, AppState};
+use axum::extract::ws::Message;
+use futures_util::{Sink, Stream};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{error, info};
+
+// longest line, enough for the largest sealed message
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+// the line protocol: the messages of the websocket protocol over a plain TCP connection,
+// one per line. for devices whose network stacks can't do websockets.
+// lines may end with \r\n, messages sent to the device end with \n
+pub struct LineSocket {
+    framed: Framed<TcpStream, LinesCodec>,
+}
+
+impl LineSocket {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            framed: Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LEN)),
+        }
+    }
+}
+
+impl Stream for LineSocket {
+    type Item = Result<Message, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.framed).poll_next(cx).map(|line| {
+            line.map(|line| match line {
+                Ok(line) => Ok(Message::Text(line.trim_end_matches('\r').to_string())),
+                Err(e) => Err(axum::Error::new(e)),
+            })
+        })
+    }
+}
+
+impl Sink<Message> for LineSocket {
+    type Error = axum::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<String>::poll_ready(Pin::new(&mut self.framed), cx).map_err(axum::Error::new)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<(), Self::Error> {
+        match msg {
+            Message::Text(text) => Pin::new(&mut self.framed)
+                .start_send(text)
+                .map_err(axum::Error::new),
+            Message::Binary(_) => Err(axum::Error::new("binary messages can't be sent as lines")),
+            // there are no control frames, closing the sink closes the connection
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => Ok(()),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<String>::poll_flush(Pin::new(&mut self.framed), cx).map_err(axum::Error::new)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<String>::poll_close(Pin::new(&mut self.framed), cx).map_err(axum::Error::new)
+    }
+}
+
+// runs the session of a device connected with the line protocol
+pub async fn serve(stream: TcpStream, state: Arc<AppState>) {
+    info!("New line protocol connection");
+    handlers::handle_socket(LineSocket::new(stream), state, None, true).await;
+}
+
+// accepts devices speaking the line protocol on a port of their own
+pub async fn listen(listener: TcpListener, state: Arc<AppState>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                info!("Line protocol connection from {}", peer);
+                tokio::spawn(serve(stream, state.clone()));
+            }
+            Err(_) => error!("Error accepting a line protocol connection"),
+        }
+    }
+}
@@ -1,17 +1,22 @@
-use axum::{routing::get, Router};
+use cloud::{
+    admission, alerts, archive, attention, budget, cache, clock, coap, config, db, events, flags,
+    forwarding, latency, lines, mdns, pairing, probes, protocols, publisher, retention, rollups,
+    routes, send_queue, sensors, sessions, signing, sinks, timeline, webhooks, AppState,
+};
 use dotenvy::dotenv;
-use sqlx::{Pool, Sqlite};
-use std::sync::Arc;
-use tokio::signal;
-use tracing::{info, warn};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    signal,
+};
+use tracing::{error, info, warn};
 
-mod db;
-mod handlers;
-mod protocols;
-
-pub struct AppState {
-    pub pool: Pool<Sqlite>,
-}
+// seconds live sessions get to close on shutdown before they are aborted
+const SESSION_CLOSE_TIMEOUT_SECS: u64 = 5;
 
 #[tokio::main]
 async fn main() {
@@ -20,31 +25,405 @@ async fn main() {
         warn!("No .env file found");
     }
 
+    // load configuration
+    let config = config::Config::from_env();
+
     // initialize tracing
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
+    let logs = tracing_subscriber::fmt().with_max_level(tracing::Level::INFO);
+    match config.log_format {
+        config::LogFormat::Text => logs.init(),
+        config::LogFormat::Json => logs.json().init(),
+    }
+    info!("Using configuration: {:?}", config);
 
     // initialize database
-    let pool = db::initialize_db().await;
-    let shared_state = Arc::new(AppState { pool });
+    db::SLOW_QUERIES.set_threshold(Duration::from_millis(config.slow_query_ms));
+    let pool = db::initialize_db(&config).await;
+
+    // reschedule deliveries that were in flight when the server stopped
+    match db::recover_pending_deliveries(&pool).await {
+        Ok(recovered) => info!("Rescheduled {} unacknowledged deliveries", recovered),
+        Err(_) => error!("Could not reschedule unacknowledged deliveries"),
+    }
+
+    // load the runtime feature flags
+    let flags = flags::Flags::default();
+    if let Err(e) = flags.load(&pool).await {
+        error!(
+            "Could not load the feature flags, using the defaults: {}",
+            e
+        );
+    }
+
+    // connect to the optional read cache
+    let cache = cache::Cache::connect(config.redis_url.as_deref(), config.cache_ttl_secs).await;
+
+    if config.api_auth && config.admin_token.is_none() {
+        panic!("API_AUTH is set without an ADMIN_TOKEN to manage the API keys");
+    }
+    if !config.api_auth {
+        warn!(
+            "API_AUTH is off, anyone reaching the REST api may also change and delete through it"
+        );
+    }
+
+    if config.webhook_urls.is_some() && config.webhook_secret.is_none() {
+        panic!("Webhooks are configured without a WEBHOOK_SECRET to sign their payloads");
+    }
+    if config.webhook_health_ms == 0 {
+        panic!("WEBHOOK_HEALTH_MS has to be positive");
+    }
+
+    if let Some(percent) = config.received_summarize_percent {
+        if config.received_retention.max_rows.is_none() || !(1..=100).contains(&percent) {
+            panic!("RETENTION_RECEIVED_SUMMARIZE_PERCENT has to be 1 to 100 of RETENTION_RECEIVED_MAX_ROWS");
+        }
+    }
 
+    let avg_settings = protocols::AvgSettings::from_config(&config);
+    if let Err(e) = avg_settings.validate() {
+        panic!("Invalid AVG settings: {}", e);
+    }
+    let forwarding_settings = forwarding::ForwardingSettings::from_config(&config);
+    if let Err(e) = forwarding_settings.validate() {
+        panic!("Invalid forwarding settings: {}", e);
+    }
+    if config
+        .value_decimals
+        .is_some_and(|decimals| decimals > protocols::MAX_DECIMALS)
+    {
+        panic!("VALUE_DECIMALS can be at most {}", protocols::MAX_DECIMALS);
+    }
+    if !config.rate_limit_per_sec.is_finite() || config.rate_limit_per_sec <= 0.0 {
+        panic!("RATE_LIMIT_PER_SEC has to be positive");
+    }
+    if config.rate_limit_burst.is_nan() || config.rate_limit_burst < 1.0 {
+        panic!("RATE_LIMIT_BURST has to be at least 1");
+    }
+    if !config.fog_rate_limit_per_sec.is_finite() || config.fog_rate_limit_per_sec <= 0.0 {
+        panic!("FOG_RATE_LIMIT_PER_SEC has to be positive");
+    }
+    if config.fog_rate_limit_burst.is_nan() || config.fog_rate_limit_burst < 1.0 {
+        panic!("FOG_RATE_LIMIT_BURST has to be at least 1");
+    }
+
+    let budget = budget::UpstreamBudget::new(config.upstream_budget_bytes);
+
+    let shared_state = Arc::new(AppState {
+        pool,
+        config,
+        sessions: sessions::Sessions::default(),
+        admission: admission::Admission::default(),
+        cache,
+        events: events::Events::default(),
+        pruned: retention::Pruned::default(),
+        replay: signing::ReplayGuard::default(),
+        flags,
+        latency: latency::Latency::default(),
+        avg_settings: tokio::sync::watch::Sender::new(avg_settings),
+        forwarding: forwarding::Forwarding::new(forwarding_settings),
+        alert_rules_changed: tokio::sync::Notify::new(),
+        webhooks: webhooks::Webhooks::default(),
+        upstream: publisher::Upstream::default(),
+        sinks: sinks::Sinks::default(),
+        send_queues: send_queue::QueueStats::default(),
+        pairing: pairing::PairingWindow::default(),
+        budget,
+        archive: archive::Archive::default(),
+        staleness: protocols::Staleness::default(),
+        services: probes::Services::default(),
+        clock: Arc::new(clock::SystemClock),
+    });
+
+    // the background services are restarted if they panic, the server isn't ready meanwhile
     //initialize average message service
-    tokio::spawn(protocols::avg_msg_service(shared_state.clone()));
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "avg_msg_service",
+        protocols::avg_msg_service,
+    ));
+
+    //initialize the service forwarding the aggregates of the readings
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "forwarding_service",
+        forwarding::forwarding_service,
+    ));
+
+    //initialize the service pruning old messages
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "retention_service",
+        retention::retention_service,
+    ));
+
+    //initialize the service rolling up readings for long-term storage
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "rollup_service",
+        rollups::rollup_service,
+    ));
+
+    //initialize the service evaluating alert rules
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "alert_service",
+        alerts::alert_service,
+    ));
+
+    //initialize the service refreshing the devices needing attention
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "attention_service",
+        attention::attention_service,
+    ));
+
+    //initialize the service checking the latency budget
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "latency_service",
+        latency::latency_service,
+    ));
+
+    //initialize the service posting events to the webhooks
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "webhook_service",
+        webhooks::webhook_service,
+    ));
+
+    //initialize the service publishing readings to the streaming platform
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "publisher_service",
+        publisher::publisher_service,
+    ));
+
+    //initialize the service exporting readings and averages to the sinks
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "sink_service",
+        sinks::sink_service,
+    ));
+
+    //initialize the service reporting the upstream budget consumption
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "budget_service",
+        budget::budget_service,
+    ));
+
+    //initialize the service recording the shedding episodes of the ingest
+    tokio::spawn(probes::supervise(
+        shared_state.clone(),
+        "shedding_service",
+        timeline::shedding_service,
+    ));
 
     // initialize router
-    let app = Router::new()
-        .route("/", get(handlers::health_handler))
-        .route("/ws", get(handlers::handler))
-        .with_state(shared_state.clone());
+    let app = routes::router(shared_state.clone());
+
+    // devices speaking the line protocol get a port of their own
+    if let Some(port) = shared_state.config.line_port {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+            .await
+            .expect("Could not listen for the line protocol");
+        info!("Accepting the line protocol on port {}", port);
+        tokio::spawn(lines::listen(listener, shared_state.clone()));
+    }
+
+    // battery powered devices posting readings over CoAP
+    if let Some(port) = shared_state.config.coap_port {
+        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+            .await
+            .expect("Could not listen for CoAP");
+        info!("Accepting CoAP on UDP port {}", port);
+        tokio::spawn(coap::listen(socket, shared_state.clone()));
+    }
+
+    // local devices discover the server instead of having its address configured
+    if let Some(name) = &shared_state.config.mdns_name {
+        let address = shared_state
+            .config
+            .mdns_address
+            .or_else(mdns::local_address)
+            .expect("Could not find the address to advertise over mDNS, set MDNS_ADDRESS");
+        let advertisement = mdns::Advertisement::new(
+            name,
+            address,
+            shared_state.config.port,
+            shared_state.config.tls_enabled(),
+        )
+        .unwrap_or_else(|e| panic!("MDNS_NAME is not usable: {}", e));
+        // the socket of the first run, a restarted responder binds again
+        let socket = Mutex::new(Some(
+            mdns::bind().expect("Could not listen for mDNS queries"),
+        ));
+        tokio::spawn(probes::supervise(
+            shared_state.clone(),
+            "mdns_responder",
+            move |_| {
+                let socket =
+                    socket.lock().unwrap().take().unwrap_or_else(|| {
+                        mdns::bind().expect("Could not listen for mDNS queries")
+                    });
+                mdns::respond(socket, advertisement.clone())
+            },
+        ));
+    }
+
+    // readings and rollups archived as parquet files
+    if shared_state.config.archive_url.is_some() {
+        #[cfg(feature = "parquet")]
+        tokio::spawn(probes::supervise(
+            shared_state.clone(),
+            "archive_service",
+            archive::archive_service,
+        ));
+
+        #[cfg(not(feature = "parquet"))]
+        panic!("ARCHIVE_URL is set but the server was built without the parquet feature");
+    }
+
+    // clients preferring gRPC over the text protocol
+    if let Some(port) = shared_state.config.grpc_port {
+        #[cfg(feature = "grpc")]
+        {
+            let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+                .await
+                .expect("Could not listen for gRPC");
+            info!("Serving gRPC on port {}", port);
+            tokio::spawn(cloud::grpc::serve(listener, shared_state.clone()));
+        }
+
+        #[cfg(not(feature = "grpc"))]
+        panic!(
+            "GRPC_PORT is set to {} but the server was built without the grpc feature",
+            port
+        );
+    }
+
+    // local sensors plugged into the server
+    if let Some(ports) = &shared_state.config.serial_ports {
+        #[cfg(feature = "serial")]
+        for port in &ports.0 {
+            tokio::spawn(cloud::serial_reader::listen(
+                shared_state.clone(),
+                port.clone(),
+            ));
+        }
+
+        #[cfg(not(feature = "serial"))]
+        panic!(
+            "{} serial ports are configured but the server was built without the serial feature",
+            ports.0.len()
+        );
+    }
+
+    // sensors attached to the I2C bus of the server
+    if let Some(sensors) = &shared_state.config.local_sensors {
+        for sensor in &sensors.0 {
+            let open = {
+                let sensor = sensor.clone();
+                move || {
+                    sensors::open(&sensor).unwrap_or_else(|e| {
+                        panic!(
+                            "Could not open the {:?} at {:#x} on {}: {}",
+                            sensor.driver, sensor.address, sensor.bus, e
+                        )
+                    })
+                }
+            };
+            // the source of the first run, a restarted service opens the sensor again
+            let source = Mutex::new(Some(open()));
+            let uid = sensor.uid.clone();
+            let schedule = sensors::Schedule::for_sensor(sensor, &shared_state.config);
+            tokio::spawn(probes::supervise(
+                shared_state.clone(),
+                format!("sample_service {}", uid),
+                move |state| {
+                    let source = source.lock().unwrap().take().unwrap_or_else(&open);
+                    sensors::sample_service(state, uid.clone(), source, schedule.clone())
+                },
+            ));
+        }
+    }
 
     info!("Starting the cloud server...");
     // start server
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
+    let addr = SocketAddr::from(([0, 0, 0, 0], shared_state.config.port));
+    if shared_state.config.sniff_protocols {
+        if shared_state.config.tls_enabled() {
+            panic!("Protocol sniffing can't be combined with TLS");
+        }
+        cloud::sniffer::serve(
+            app,
+            shared_state.clone(),
+            addr,
+            drained(shared_state.clone()),
+        )
+        .await
+        .expect("Could not serve");
+    } else if shared_state.config.tls_enabled() {
+        #[cfg(feature = "tls")]
+        cloud::tls::serve(
+            app,
+            shared_state.clone(),
+            addr,
+            drained(shared_state.clone()),
+        )
         .await
-        .unwrap();
+        .expect("Could not serve TLS");
+
+        #[cfg(not(feature = "tls"))]
+        panic!("TLS is configured but the server was built without the tls feature");
+    } else {
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(drained(shared_state.clone()))
+            .await
+            .unwrap();
+    }
+
+    // websockets are not tracked by the server, close them explicitly
+    shared_state
+        .sessions
+        .close_all(Duration::from_secs(SESSION_CLOSE_TIMEOUT_SECS))
+        .await;
+    info!("All sessions closed");
+
+    // readings received until the end are written before the db is closed
+    let report = shared_state
+        .admission
+        .flush(
+            &*shared_state.clock,
+            Duration::from_secs(shared_state.config.shutdown_grace_secs),
+        )
+        .await;
+    if !report.drained {
+        warn!(
+            "{} readings were still being stored on shutdown",
+            report.remaining
+        );
+    }
+    shared_state.pool.close().await;
+    info!("Database closed");
+}
+
+// resolves once the server is drained after a shutdown signal. new websockets are turned
+// away and live sessions get the grace period to acknowledge their pending deliveries,
+// while the REST api keeps answering
+async fn drained(state: Arc<AppState>) {
+    shutdown_signal().await;
+    info!(
+        "Draining {} sessions before shutting down",
+        state.sessions.count().await
+    );
+    let grace = Duration::from_secs(state.config.shutdown_grace_secs);
+    state
+        .sessions
+        .shut_down(grace + Duration::from_secs(SESSION_CLOSE_TIMEOUT_SECS))
+        .await;
 }
 
 // Graceful shutdown
@@ -0,0 +1,506 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+use crate::{Error, Result};
+
+// the service devices browse for to find the websocket endpoint of the server
+pub const SERVICE: &str = "_foghw._tcp.local";
+// names the services of a network to browsers asking for all of them
+const SERVICES: &str = "_services._dns-sd._udp.local";
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+// seconds the records are cached, RFC 6762 recommends 120 for records naming hosts
+const TTL: u32 = 120;
+// largest datagram read, queries of constrained devices are far smaller
+const MAX_DATAGRAM_LEN: usize = 9000;
+// unsolicited announcements on startup, one second apart as RFC 6762 asks for
+const ANNOUNCEMENTS: usize = 2;
+
+// record types of RFC 1035 and RFC 2782
+pub mod rtype {
+    pub const A: u16 = 1;
+    pub const PTR: u16 = 12;
+    pub const TXT: u16 = 16;
+    pub const SRV: u16 = 33;
+    pub const ANY: u16 = 255;
+}
+const CLASS_IN: u16 = 1;
+// top bit of the class, asks for a unicast response in questions and flushes caches in answers
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Question {
+    pub name: String,
+    pub rtype: u16,
+    // the asker wants a unicast response
+    pub unicast: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    // types the responder does not need to understand
+    Other(u16),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Record {
+    pub name: String,
+    pub data: RecordData,
+}
+
+// the subset of DNS messages mDNS needs: no authority records, and names are
+// written without compression
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Packet {
+    pub id: u16,
+    pub is_response: bool,
+    pub questions: Vec<Question>,
+    // answers followed by the additional records
+    pub records: Vec<Record>,
+}
+
+impl Packet {
+    // a query for a name, like the one devices send to find the server
+    pub fn query(name: &str, rtype: u16) -> Self {
+        Self {
+            questions: vec![Question {
+                name: name.to_string(),
+                rtype,
+                unicast: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub fn parse(datagram: &[u8]) -> Result<Self> {
+        let mut reader = Reader { datagram, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authorities = reader.u16()?;
+        let additionals = reader.u16()?;
+
+        let mut packet = Packet {
+            id,
+            is_response: flags & FLAG_RESPONSE != 0,
+            ..Default::default()
+        };
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let class = reader.u16()?;
+            packet.questions.push(Question {
+                name,
+                rtype,
+                unicast: class & CLASS_FLAG != 0,
+            });
+        }
+        for i in 0..answers as usize + authorities as usize + additionals as usize {
+            let record = reader.record()?;
+            if i < answers as usize || i >= answers as usize + authorities as usize {
+                packet.records.push(record);
+            }
+        }
+        Ok(packet)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        let flags = if self.is_response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.records.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+
+        for question in &self.questions {
+            write_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.rtype.to_be_bytes());
+            let class = if question.unicast {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            buf.extend_from_slice(&class.to_be_bytes());
+        }
+        for record in &self.records {
+            write_record(&mut buf, record);
+        }
+        buf
+    }
+}
+
+struct Reader<'a> {
+    datagram: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .datagram
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| Error::Protocol("packet is truncated".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // a name at the current position, following compression pointers into the rest of the packet
+    fn name(&mut self) -> Result<String> {
+        let invalid = |reason: &str| Error::Protocol(reason.to_string());
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        // every pointer has to go back, so a packet can't make the reader loop
+        let mut limit = pos;
+
+        loop {
+            let len = *self
+                .datagram
+                .get(pos)
+                .ok_or_else(|| invalid("name is truncated"))? as usize;
+            match len {
+                0 => {
+                    end.get_or_insert(pos + 1);
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let low = *self
+                        .datagram
+                        .get(pos + 1)
+                        .ok_or_else(|| invalid("name is truncated"))?;
+                    let target = ((len & 0x3f) << 8) | low as usize;
+                    if target >= limit {
+                        return Err(invalid("compression pointer does not point back"));
+                    }
+                    end.get_or_insert(pos + 2);
+                    limit = target;
+                    pos = target;
+                }
+                len if len < 64 => {
+                    let label = self
+                        .datagram
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or_else(|| invalid("name is truncated"))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return Err(invalid("unsupported label type")),
+            }
+        }
+        self.pos = end.unwrap_or(pos);
+        Ok(labels.join("."))
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let _class = self.u16()?;
+        let _ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let data = match rtype {
+            rtype::A if len == 4 => {
+                let bytes = self.bytes(4)?;
+                RecordData::A(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+            }
+            rtype::PTR => RecordData::Ptr(self.name()?),
+            rtype::SRV => {
+                let _priority = self.u16()?;
+                let _weight = self.u16()?;
+                let port = self.u16()?;
+                let target = self.name()?;
+                RecordData::Srv { port, target }
+            }
+            rtype::TXT => {
+                let mut entries = Vec::new();
+                while self.pos < start + len {
+                    let entry_len = self.bytes(1)?[0] as usize;
+                    let entry = self.bytes(entry_len)?;
+                    entries.push(String::from_utf8_lossy(entry).into_owned());
+                }
+                RecordData::Txt(entries)
+            }
+            rtype => RecordData::Other(rtype),
+        };
+        // the data of unknown types is skipped
+        self.pos = start + len;
+        if self.pos > self.datagram.len() {
+            return Err(Error::Protocol("record is truncated".to_string()));
+        }
+        Ok(Record { name, data })
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &Record) {
+    write_name(buf, &record.name);
+    let mut data = Vec::new();
+    let (rtype, shared) = match &record.data {
+        RecordData::A(address) => {
+            data.extend_from_slice(&address.octets());
+            (rtype::A, false)
+        }
+        RecordData::Ptr(target) => {
+            write_name(&mut data, target);
+            (rtype::PTR, true)
+        }
+        RecordData::Txt(entries) => {
+            for entry in entries {
+                data.push(entry.len() as u8);
+                data.extend_from_slice(entry.as_bytes());
+            }
+            (rtype::TXT, false)
+        }
+        RecordData::Srv { port, target } => {
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(&port.to_be_bytes());
+            write_name(&mut data, target);
+            (rtype::SRV, false)
+        }
+        RecordData::Other(rtype) => (*rtype, true),
+    };
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    // records only this server answers for flush stale copies from the caches
+    let class = if shared {
+        CLASS_IN
+    } else {
+        CLASS_IN | CLASS_FLAG
+    };
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&data);
+}
+
+// the websocket endpoint of the server as DNS-SD records: <name>._foghw._tcp.local
+// served by <name>.local, with the path and scheme in the TXT record
+#[derive(Clone, PartialEq, Debug)]
+pub struct Advertisement {
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+    pub tls: bool,
+}
+
+impl Advertisement {
+    // the name is a single DNS label, so it is also the host name of the server
+    pub fn new(name: &str, address: Ipv4Addr, port: u16, tls: bool) -> Result<Self> {
+        if name.is_empty()
+            || name.len() > 63
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(Error::Protocol(format!("invalid mDNS name: {:?}", name)));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            address,
+            port,
+            tls,
+        })
+    }
+
+    pub fn instance(&self) -> String {
+        format!("{}.{}", self.name, SERVICE)
+    }
+
+    pub fn host(&self) -> String {
+        format!("{}.local", self.name)
+    }
+
+    fn ptr(&self) -> Record {
+        Record {
+            name: SERVICE.to_string(),
+            data: RecordData::Ptr(self.instance()),
+        }
+    }
+
+    fn srv(&self) -> Record {
+        Record {
+            name: self.instance(),
+            data: RecordData::Srv {
+                port: self.port,
+                target: self.host(),
+            },
+        }
+    }
+
+    fn txt(&self) -> Record {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        Record {
+            name: self.instance(),
+            data: RecordData::Txt(vec!["path=/ws".to_string(), format!("scheme={}", scheme)]),
+        }
+    }
+
+    fn a(&self) -> Record {
+        Record {
+            name: self.host(),
+            data: RecordData::A(self.address),
+        }
+    }
+
+    // every record, sent unsolicited when the server starts
+    pub fn announcement(&self) -> Packet {
+        Packet {
+            is_response: true,
+            records: vec![self.ptr(), self.srv(), self.txt(), self.a()],
+            ..Default::default()
+        }
+    }
+
+    // the response to a query, None if it asks for nothing this server answers for.
+    // legacy queries from ports other than 5353 get their id and questions back, RFC 6762 6.7
+    pub fn respond(&self, query: &Packet, legacy: bool) -> Option<Packet> {
+        if query.is_response {
+            return None;
+        }
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        let is = |name: &str, other: &str| name.eq_ignore_ascii_case(other);
+        let asks = |rtype: u16, wanted: u16| rtype == wanted || rtype == rtype::ANY;
+
+        for question in &query.questions {
+            if is(&question.name, SERVICES) && asks(question.rtype, rtype::PTR) {
+                answers.push(Record {
+                    name: SERVICES.to_string(),
+                    data: RecordData::Ptr(SERVICE.to_string()),
+                });
+            } else if is(&question.name, SERVICE) && asks(question.rtype, rtype::PTR) {
+                // the device needs the endpoint as well, so it doesn't have to ask again
+                answers.push(self.ptr());
+                additionals.extend([self.srv(), self.txt(), self.a()]);
+            } else if is(&question.name, &self.instance()) {
+                if asks(question.rtype, rtype::SRV) {
+                    answers.push(self.srv());
+                    additionals.push(self.a());
+                }
+                if asks(question.rtype, rtype::TXT) {
+                    answers.push(self.txt());
+                }
+            } else if is(&question.name, &self.host()) && asks(question.rtype, rtype::A) {
+                answers.push(self.a());
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        additionals.retain(|record| !answers.contains(record));
+        additionals.dedup();
+        answers.extend(additionals);
+        Some(Packet {
+            id: if legacy { query.id } else { 0 },
+            is_response: true,
+            questions: if legacy {
+                query.questions.clone()
+            } else {
+                Vec::new()
+            },
+            records: answers,
+        })
+    }
+}
+
+// a socket on the mDNS port in the mDNS group, shared with other responders of the host
+pub fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from(([0, 0, 0, 0], MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// the address of the interface multicast leaves from, what devices on the local network reach
+pub fn local_address() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_ADDR, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(addr) if !addr.ip().is_unspecified() => Some(*addr.ip()),
+        _ => None,
+    }
+}
+
+// announces the server and answers queries for it until the server stops
+pub async fn respond(socket: UdpSocket, advertisement: Advertisement) {
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+    info!(
+        "Advertising {} at {}:{} over mDNS",
+        advertisement.instance(),
+        advertisement.address,
+        advertisement.port
+    );
+    let announcement = advertisement.announcement().encode();
+    for i in 0..ANNOUNCEMENTS {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if let Err(e) = socket.send_to(&announcement, group).await {
+            error!("Error announcing the server over mDNS: {}", e);
+        }
+    }
+
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Error receiving an mDNS query: {}", e);
+                continue;
+            }
+        };
+        let query = match Packet::parse(&buf[..len]) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("Invalid mDNS packet from {}: {}", peer, e);
+                continue;
+            }
+        };
+        let legacy = peer.port() != MDNS_PORT;
+        let Some(response) = advertisement.respond(&query, legacy) else {
+            continue;
+        };
+        let unicast = legacy || query.questions.iter().any(|q| q.unicast);
+        let to = if unicast { peer } else { group };
+        if let Err(e) = socket.send_to(&response.encode(), to).await {
+            error!("Error answering the mDNS query of {}: {}", peer, e);
+        }
+    }
+}
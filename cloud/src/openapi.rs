@@ -0,0 +1,119 @@
+use axum::{response::Html, Json};
+use utoipa::OpenApi;
+
+use crate::{db, handlers, protocols, sessions};
+
+// OpenAPI document of the REST api, generated from the annotated handlers
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "fog-hw cloud", description = "REST api of the fog-hw cloud server"),
+    paths(
+        handlers::list_connections_handler,
+        handlers::get_connection_handler,
+        handlers::delete_connection_handler,
+        handlers::list_sessions_handler,
+        handlers::device_sessions_handler,
+        handlers::bandwidth_handler,
+        handlers::connection_stats_handler,
+        handlers::device_group_handler,
+        handlers::group_policy_handler,
+        handlers::device_attributes_handler,
+        handlers::set_device_attributes_handler,
+        handlers::devices_handler,
+        handlers::device_handler,
+        handlers::register_device_handler,
+        handlers::geo_devices_handler,
+        handlers::geo_latest_readings_handler,
+        handlers::list_conversions_handler,
+        handlers::set_conversion_handler,
+        handlers::delete_conversion_handler,
+        handlers::device_config_handler,
+        handlers::set_device_config_handler,
+        handlers::pass_through_handler,
+        handlers::fog_nodes_handler,
+        handlers::fog_devices_handler,
+        handlers::fog_latest_readings_handler,
+        handlers::attention_handler,
+        handlers::get_kv_handler,
+        handlers::put_kv_handler,
+        handlers::latest_readings_handler,
+        handlers::averages_handler,
+        handlers::aggregates_handler,
+        handlers::system_events_handler,
+        handlers::export_handler,
+        handlers::download_handler,
+        handlers::rollups_handler,
+        handlers::list_sealed_handler,
+        handlers::stream_handler,
+        handlers::drain_handler,
+        handlers::release_maintenance_handler,
+        handlers::add_command_handler,
+        handlers::list_commands_handler,
+        handlers::add_sealed_handler,
+        handlers::list_alert_rules_handler,
+        handlers::add_alert_rule_handler,
+        handlers::rule_version_handler,
+        handlers::delete_alert_rule_handler,
+        handlers::active_alerts_handler,
+        handlers::list_flags_handler,
+        handlers::set_flag_handler,
+        handlers::simulate_message_handler,
+    ),
+    components(schemas(
+        db::Connection,
+        db::FogNode,
+        db::Device,
+        db::DeviceMetadata,
+        db::Conversion,
+        db::ReceivedMessage,
+        db::AttentionItem,
+        db::BandwidthSample,
+        db::ConnectionStats,
+        db::SealedMessage,
+        db::Command,
+        db::RollupPeriod,
+        db::Rollup,
+        db::Aggregate,
+        db::SystemEvent,
+        db::AlertRule,
+        db::RuleVersion,
+        db::DeviceConfig,
+        db::Alert,
+        db::DeviceSession,
+        sessions::SessionInfo,
+        sessions::TrafficStats,
+        protocols::AvgMsg,
+        handlers::ConnectionInfo,
+        handlers::BandwidthStats,
+        handlers::ExportFormat,
+        handlers::CommandRequest,
+        handlers::DeviceGroup,
+        handlers::GroupPolicy,
+        handlers::DeviceAttributes,
+        handlers::ConfigRequest,
+        handlers::PassThrough,
+        handlers::FlagInfo,
+        handlers::FlagRequest,
+        handlers::SealedRequest,
+        handlers::SimulateRequest,
+    )),
+    tags(
+        (name = "devices", description = "registered devices, their sessions and settings"),
+        (name = "messages", description = "readings, averages and their history"),
+        (name = "commands", description = "messages for devices"),
+        (name = "alerts", description = "alert rules and fired alerts"),
+        (name = "flags", description = "runtime feature flags"),
+        (name = "system", description = "what happened to the server"),
+        (name = "admin", description = "operator endpoints, they need the ADMIN_TOKEN"),
+    )
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Swagger UI for the document, loaded from a CDN so the server doesn't have to bundle it
+pub async fn docs_handler() -> Html<&'static str> {
+    Html(include_str!("../static/docs.html"))
+}
@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use crate::db;
+
+// longest time pairing mode stays open, so a forgotten window doesn't let devices in for days
+pub const MAX_PAIRING_SECS: u64 = 3600;
+
+// pairing mode of the server. while it is open devices without a uid may send PAIR
+// to be provisioned a uid and signing key, recorded for later approval
+#[derive(Default)]
+pub struct PairingWindow {
+    // unix timestamp the window closes at, 0 while it is closed
+    open_until: AtomicI64,
+}
+
+impl PairingWindow {
+    // opens the window for secs seconds from now, returns when it closes
+    pub fn open(&self, secs: u64) -> i64 {
+        let until = db::unix_now() + secs.min(MAX_PAIRING_SECS) as i64;
+        self.open_until.store(until, Ordering::Relaxed);
+        until
+    }
+
+    pub fn close(&self) {
+        self.open_until.store(0, Ordering::Relaxed);
+    }
+
+    // None while the window is closed
+    pub fn open_until(&self) -> Option<i64> {
+        let until = self.open_until.load(Ordering::Relaxed);
+        (until > db::unix_now()).then_some(until)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until().is_some()
+    }
+}
@@ -0,0 +1,152 @@
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::{
+    db,
+    timeline::{self, EventKind},
+    AppState,
+};
+
+// waits after a panic before the service is started again, doubling up to the maximum
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ServiceStatus {
+    // false while the service waits to be restarted after a panic
+    pub running: bool,
+    pub restarts: u32,
+    // unix timestamp of the last panic
+    pub last_panic: Option<i64>,
+}
+
+// the name of a supervised service, services running once per device have the uid in it
+pub type ServiceName = Cow<'static, str>;
+
+// the supervised background services. the server isn't ready while one of them is down
+#[derive(Default)]
+pub struct Services {
+    services: Mutex<BTreeMap<ServiceName, ServiceStatus>>,
+}
+
+impl Services {
+    fn started(&self, name: &ServiceName) {
+        self.services
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .running = true;
+    }
+
+    fn panicked(&self, name: &ServiceName, at: i64) {
+        let mut services = self.services.lock().unwrap();
+        let status = services.entry(name.clone()).or_default();
+        status.running = false;
+        status.last_panic = Some(at);
+    }
+
+    fn restarting(&self, name: &ServiceName) {
+        self.services
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .restarts += 1;
+    }
+
+    // services that returned are done and not supervised anymore
+    fn finished(&self, name: &ServiceName) {
+        self.services.lock().unwrap().remove(name);
+    }
+
+    pub fn list(&self) -> BTreeMap<ServiceName, ServiceStatus> {
+        self.services.lock().unwrap().clone()
+    }
+
+    pub fn down(&self) -> Vec<ServiceName> {
+        self.services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, status)| !status.running)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.services
+            .lock()
+            .unwrap()
+            .values()
+            .map(|status| status.restarts as u64)
+            .sum()
+    }
+}
+
+// runs a background service and starts it again with a backoff whenever it panics. a service
+// that returns is done, one aborted on shutdown didn't fail
+pub async fn supervise<S, F>(state: Arc<AppState>, name: impl Into<ServiceName>, service: S)
+where
+    S: Fn(Arc<AppState>) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let name = name.into();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        state.services.started(&name);
+        let started = state.clock.now();
+        match tokio::spawn(service(state.clone())).await {
+            Ok(()) => {
+                info!("Background service {} finished", name);
+                state.services.finished(&name);
+                return;
+            }
+            Err(e) if e.is_cancelled() => return,
+            Err(_) => {}
+        }
+
+        // a service that ran a while before it panicked starts over with the shortest backoff
+        if state.clock.now() - started > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        error!(
+            "Background service {} panicked, restarting it in {:?}",
+            name, backoff
+        );
+        state.services.panicked(&name, state.clock.unix_now());
+        timeline::record(
+            &state,
+            EventKind::ServiceRestart,
+            &format!("{} panicked, restarting it in {:?}", name, backoff),
+        )
+        .await;
+        state.clock.sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        state.services.restarting(&name);
+    }
+}
+
+// what keeps the server from taking traffic, nothing if it is ready
+pub async fn readiness(state: &AppState) -> Vec<String> {
+    let mut problems = Vec::new();
+    match db::pending_migrations(&state.pool).await {
+        Ok(0) => {}
+        Ok(pending) => problems.push(format!("{} migrations are not applied", pending)),
+        Err(e) => problems.push(format!("the db is not reachable: {}", e)),
+    }
+    for name in state.services.down() {
+        problems.push(format!("the {} service is down", name));
+    }
+    if state.sessions.is_closing() {
+        problems.push("the server shuts down".to_string());
+    }
+    problems
+}
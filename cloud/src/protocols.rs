@@ -1,211 +1,1601 @@
+use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     error::Error,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    fmt,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use tracing::{
     error,
     log::{info, warn},
 };
+use utoipa::ToSchema;
 
-use crate::db;
+use crate::{
+    clock::Interval,
+    config::{Aggregation, AggregationTimestamp, Config, Sample, TimestampPolicy},
+    db,
+    envelope::{self, Envelope},
+    events::StreamEvent,
+    geo,
+    latency::Stage,
+    webhooks::WebhookEvent,
+};
 
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Debug)]
 pub enum Protocol {
     CONN,
     SENSOR,
     AVG,
+    ACK,
+    ERR,
+    DRAIN,
+    SHUTDOWN,
     DISCONN,
+    KVGET,
+    KV,
+    ALERT,
+    CMD,
+    CMD_ACK,
+    SESSION,
+    SEALED,
+    RULES,
+    RULES_ACK,
+    CONFIG,
+    CONFIG_ACK,
     INVALID,
 }
 
-pub fn get_protocol(msg: &String) -> Result<Protocol, Box<dyn Error>> {
-    let parts: Vec<&str> = msg.split("#").collect();
+pub fn get_protocol(msg: &str) -> Result<Protocol, ParseError> {
+    if msg.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let header = msg.split_once('#').map_or(msg, |(header, _)| header);
 
-    match parts[0] {
+    match header {
         "CONN" => Ok(Protocol::CONN),
         "SENSOR" => Ok(Protocol::SENSOR),
         "AVG" => Ok(Protocol::AVG),
+        "ACK" => Ok(Protocol::ACK),
+        "ERR" => Ok(Protocol::ERR),
+        "DRAIN" => Ok(Protocol::DRAIN),
+        "SHUTDOWN" => Ok(Protocol::SHUTDOWN),
         "DISCONN" => Ok(Protocol::DISCONN),
-        _ => Err("Invalid protocol".into()),
+        "KVGET" => Ok(Protocol::KVGET),
+        "KV" => Ok(Protocol::KV),
+        "ALERT" => Ok(Protocol::ALERT),
+        "CMD" => Ok(Protocol::CMD),
+        "CMD_ACK" => Ok(Protocol::CMD_ACK),
+        "SESSION" => Ok(Protocol::SESSION),
+        "SEALED" => Ok(Protocol::SEALED),
+        "RULES" => Ok(Protocol::RULES),
+        "RULES_ACK" => Ok(Protocol::RULES_ACK),
+        "CONFIG" => Ok(Protocol::CONFIG),
+        "CONFIG_ACK" => Ok(Protocol::CONFIG_ACK),
+        _ => Err(ParseError::UnknownProtocol(truncated(header))),
+    }
+}
+
+// why a message from a device could not be parsed
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownProtocol(String),
+    WrongProtocol {
+        expected: &'static str,
+        found: String,
+    },
+    // number of fields after the protocol header
+    FieldCount {
+        protocol: &'static str,
+        min: usize,
+        max: usize,
+        found: usize,
+    },
+    // position of the field after the protocol header, starting at 1
+    EmptyField {
+        protocol: &'static str,
+        position: usize,
+    },
+    InvalidUid(String),
+    InvalidInteger {
+        field: &'static str,
+        value: String,
+    },
+    InvalidNumber {
+        field: &'static str,
+        value: String,
+    },
+    NotANumber {
+        field: &'static str,
+    },
+    // inf and infinity, spelled out
+    Infinite {
+        field: &'static str,
+    },
+    // numbers beyond the range of f64 like 1e999, which would parse as infinity
+    Overflow {
+        field: &'static str,
+        value: String,
+    },
+    // a comma as decimal separator like 21,5, written by devices with a european locale
+    DecimalComma {
+        field: &'static str,
+        value: String,
+    },
+    InvalidTimestamp(String),
+    // seconds the timestamp of a reading is off the server clock
+    ClockSkew(i64),
+    InvalidChannel(String),
+    InvalidFlag(String),
+    InvalidKey(String),
+    InvalidEnvelope,
+    InvalidOption(String),
+    InvalidName(String),
+    UnsupportedEncoding(String),
+    IncompatibleOptions(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty message"),
+            Self::UnknownProtocol(header) => write!(f, "unknown protocol {}", header),
+            Self::WrongProtocol { expected, found } => {
+                write!(f, "expected {} instead of {}", expected, found)
+            }
+            Self::FieldCount {
+                protocol,
+                min,
+                max,
+                found,
+            } if min == max => write!(f, "{} takes {} fields, not {}", protocol, min, found),
+            Self::FieldCount {
+                protocol,
+                min,
+                max,
+                found,
+            } => write!(
+                f,
+                "{} takes {} to {} fields, not {}",
+                protocol, min, max, found
+            ),
+            Self::EmptyField { protocol, position } => {
+                write!(f, "field {} of {} is empty", position, protocol)
+            }
+            Self::InvalidUid(uid) => write!(f, "invalid uid {}", uid),
+            Self::InvalidInteger { field, value } => write!(f, "invalid {} {}", field, value),
+            Self::InvalidNumber { field, value } => write!(f, "invalid {} {}", field, value),
+            Self::NotANumber { field } => write!(f, "{} is not a number", field),
+            Self::Infinite { field } => write!(f, "{} has to be finite", field),
+            Self::Overflow { field, value } => {
+                write!(
+                    f,
+                    "{} {} is out of range, at most {:e}",
+                    field,
+                    value,
+                    f64::MAX
+                )
+            }
+            Self::DecimalComma { field, value } => {
+                write!(f, "{} {} has to use . as decimal separator", field, value)
+            }
+            Self::InvalidTimestamp(value) => write!(f, "invalid timestamp {}", value),
+            Self::ClockSkew(skew) => {
+                write!(f, "timestamp is off the server clock by {} seconds", skew)
+            }
+            Self::InvalidChannel(channel) => write!(f, "invalid channel {}", channel),
+            Self::InvalidFlag(flag) => write!(f, "invalid flag {}", flag),
+            Self::InvalidKey(key) => write!(f, "invalid key {}", key),
+            Self::InvalidEnvelope => write!(f, "invalid envelope"),
+            Self::InvalidOption(option) => write!(f, "invalid option {}", option),
+            Self::InvalidName(name) => write!(f, "invalid device name {}", name),
+            Self::UnsupportedEncoding(encoding) => {
+                write!(f, "encoding {} is not supported", encoding)
+            }
+            Self::IncompatibleOptions(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+    // the code of the ERR message reporting the error to the device
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotANumber { .. } => ErrorCode::NotANumber,
+            Self::Infinite { .. } => ErrorCode::Infinite,
+            Self::Overflow { .. } => ErrorCode::Overflow,
+            Self::DecimalComma { .. } => ErrorCode::DecimalComma,
+            Self::ClockSkew(_) => ErrorCode::ClockSkew,
+            _ => ErrorCode::BadProtocol,
+        }
+    }
+}
+
+// longest part of invalid input that is echoed back in errors
+const MAX_ECHO_LEN: usize = 40;
+
+pub(crate) fn truncated(value: &str) -> String {
+    value.chars().take(MAX_ECHO_LEN).collect()
+}
+
+// longest part of a message from a device that is logged
+const MAX_LOGGED_LEN: usize = 256;
+
+// the message as it is logged, longer ones are cut off with the size of the rest
+pub fn loggable(msg: &str) -> Cow<'_, str> {
+    match msg.char_indices().nth(MAX_LOGGED_LEN) {
+        Some((end, _)) => Cow::Owned(format!(
+            "{}... ({} more bytes)",
+            &msg[..end],
+            msg.len() - end
+        )),
+        None => Cow::Borrowed(msg),
+    }
+}
+
+// splits a message into the fields after its header, which has to match the protocol.
+// messages with fewer than min or more than max fields, or with empty fields, are rejected
+fn fields<'a>(
+    msg: &'a str,
+    protocol: &'static str,
+    min: usize,
+    max: usize,
+) -> Result<Vec<&'a str>, ParseError> {
+    if msg.is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let mut parts = msg.split('#');
+    let header = parts.next().unwrap_or_default();
+    if header != protocol {
+        return Err(ParseError::WrongProtocol {
+            expected: protocol,
+            found: truncated(header),
+        });
+    }
+
+    let fields: Vec<&str> = parts.collect();
+    if fields.len() < min || fields.len() > max {
+        return Err(ParseError::FieldCount {
+            protocol,
+            min,
+            max,
+            found: fields.len(),
+        });
+    }
+    if let Some(i) = fields.iter().position(|field| field.is_empty()) {
+        return Err(ParseError::EmptyField {
+            protocol,
+            position: i + 1,
+        });
+    }
+
+    Ok(fields)
+}
+
+// uids are hyphenated UUIDs, kept as the device wrote them
+pub(crate) fn parse_uid(value: &str) -> Result<String, ParseError> {
+    // the hyphenated form is the only one with 36 characters
+    if value.len() != 36 || uuid::Uuid::try_parse(value).is_err() {
+        return Err(ParseError::InvalidUid(truncated(value)));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_integer(field: &'static str, value: &str) -> Result<i64, ParseError> {
+    value.parse().map_err(|_| ParseError::InvalidInteger {
+        field,
+        value: truncated(value),
+    })
+}
+
+// finite decimal numbers with a . as decimal separator, whatever the locale of the device.
+// NaN and infinity would poison the averages, so would numbers too large for f64
+fn parse_number(field: &'static str, value: &str) -> Result<f64, ParseError> {
+    let invalid = || ParseError::InvalidNumber {
+        field,
+        value: truncated(value),
+    };
+    let Ok(number) = value.parse::<f64>() else {
+        if value.contains(',') && value.replacen(',', ".", 1).parse::<f64>().is_ok() {
+            return Err(ParseError::DecimalComma {
+                field,
+                value: truncated(value),
+            });
+        }
+        return Err(invalid());
+    };
+    if number.is_nan() {
+        return Err(ParseError::NotANumber { field });
+    }
+    if number.is_infinite() {
+        // anything but inf and infinity spelled out is a number that overflowed
+        let spelled = value.trim_start_matches(['+', '-']).to_ascii_lowercase();
+        if spelled == "inf" || spelled == "infinity" {
+            return Err(ParseError::Infinite { field });
+        }
+        return Err(ParseError::Overflow {
+            field,
+            value: truncated(value),
+        });
+    }
+    Ok(number)
+}
+
+// compression of batched payloads, negotiated by the client in the CONN message
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    // zstd compressed batch in a binary frame
+    Zstd,
+    // zstd compressed batch, base64 encoded in a text frame
+    ZstdBase64,
+}
+
+impl Compression {
+    fn from_option(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "zstd-base64" => Ok(Self::ZstdBase64),
+            _ => Err(ParseError::InvalidOption(format!(
+                "compression={}",
+                truncated(value)
+            ))),
+        }
+    }
+}
+
+// encoding of SENSOR, AVG, ACK and DISCONN messages, negotiated by the client in the CONN message.
+// all encodings but text are cargo features, see the codec module.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Text,
+    // compact fixed layout in binary frames
+    Binary,
+    // JSON objects in text frames
+    Json,
+    // CBOR maps in binary frames
+    Cbor,
+    // protobuf messages in binary frames
+    Protobuf,
+}
+
+impl Encoding {
+    fn from_option(value: &str) -> Result<Self, ParseError> {
+        let encoding = match value {
+            "text" => Self::Text,
+            "binary" => Self::Binary,
+            "json" => Self::Json,
+            "cbor" => Self::Cbor,
+            "protobuf" => Self::Protobuf,
+            _ => {
+                return Err(ParseError::InvalidOption(format!(
+                    "encoding={}",
+                    truncated(value)
+                )))
+            }
+        };
+
+        // not included in this build
+        if encoding != Self::Text && crate::codec::codec_for(encoding).is_none() {
+            return Err(ParseError::UnsupportedEncoding(value.to_string()));
+        }
+
+        Ok(encoding)
+    }
+}
+
+// how devices write the timestamps of SENSOR messages, negotiated in the CONN message.
+// timestamps are stored as unix seconds whatever the device sends
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TimestampFormat {
+    // unix seconds or milliseconds, told apart by magnitude, or ISO-8601
+    #[default]
+    Auto,
+    Seconds,
+    Millis,
+    // RFC 3339 timestamps with an offset, like 2023-07-22T04:26:40Z
+    Iso8601,
+}
+
+// larger unix timestamps are milliseconds, as seconds they would be after the year 5000
+const MIN_MILLIS_TIMESTAMP: i64 = 100_000_000_000;
+
+impl TimestampFormat {
+    fn from_option(value: &str) -> Result<Self, ParseError> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "s" => Ok(Self::Seconds),
+            "ms" => Ok(Self::Millis),
+            "iso8601" => Ok(Self::Iso8601),
+            _ => Err(ParseError::InvalidOption(format!(
+                "timestamps={}",
+                truncated(value)
+            ))),
+        }
+    }
+
+    // returns the timestamp in unix seconds
+    pub fn parse(&self, value: &str) -> Result<i64, ParseError> {
+        let invalid = || ParseError::InvalidTimestamp(truncated(value));
+        match self {
+            Self::Seconds => value.parse::<i64>().map_err(|_| invalid()),
+            Self::Millis => value
+                .parse::<i64>()
+                .map(|millis| millis.div_euclid(1000))
+                .map_err(|_| invalid()),
+            Self::Iso8601 => {
+                time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339)
+                    .map(|datetime| datetime.unix_timestamp())
+                    .map_err(|_| invalid())
+            }
+            Self::Auto => match value.parse::<i64>() {
+                Ok(timestamp) if timestamp.abs() >= MIN_MILLIS_TIMESTAMP => {
+                    Ok(timestamp.div_euclid(1000))
+                }
+                Ok(timestamp) => Ok(timestamp),
+                Err(_) => Self::Iso8601.parse(value),
+            },
+        }
+    }
+}
+
+// seconds between deliveries of queued messages, unless the device asks for another interval
+pub const DEFAULT_DELIVERY_INTERVAL_SECS: u64 = 5;
+// longest interval a device may ask for, so unacknowledged messages are still resent
+const MAX_DELIVERY_INTERVAL_SECS: u64 = 3600;
+
+// longest sleep a device may declare
+const MAX_WAKE_PERIOD_SECS: i64 = 86400;
+
+// a device waking up every period for window seconds, counted from its CONN.
+// sleeping devices are not reported offline and only receive messages while awake
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WakeSchedule {
+    pub period_secs: i64,
+    pub window_secs: i64,
+}
+
+impl WakeSchedule {
+    // <period>/<window>, e.g. 900/30 to wake every 15 minutes for 30 seconds
+    fn from_option(value: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError::InvalidOption(format!("wake={}", truncated(value)));
+        let (period, window) = value.split_once('/').ok_or_else(invalid)?;
+        let schedule = Self {
+            period_secs: period.parse().map_err(|_| invalid())?,
+            window_secs: window.parse().map_err(|_| invalid())?,
+        };
+
+        if schedule.window_secs < 1
+            || schedule.window_secs >= schedule.period_secs
+            || schedule.period_secs > MAX_WAKE_PERIOD_SECS
+        {
+            return Err(invalid());
+        }
+        Ok(schedule)
+    }
+
+    pub fn is_awake(&self, woke_at: i64, now: i64) -> bool {
+        (now - woke_at).rem_euclid(self.period_secs) < self.window_secs
     }
 }
 
 pub struct ConnMsg {
     pub uid: String,
+    pub compression: Compression,
+    pub encoding: Encoding,
+    pub timestamps: TimestampFormat,
+    // sleepy devices ask for fewer deliveries to save battery
+    pub delivery_interval_secs: u64,
+    pub wake: Option<WakeSchedule>,
+    // hex X25519 key the device opens sealed messages with, see envelope
+    pub public_key: Option<String>,
+    // tenant the device belongs to, see db::get_tenant
+    pub tenant: Option<String>,
+    // the device is a fog node forwarding the readings of the devices behind it
+    pub fog: bool,
+    // what the device tells about itself, stored in the device registry
+    pub metadata: db::DeviceMetadata,
 }
 
 impl ConnMsg {
-    pub fn from_msg(msg: &String) -> Result<Self, Box<dyn Error>> {
-        let parts: Vec<&str> = msg.split("#").collect();
+    // CONN#<uid>[#<key>=<value>,<key>=<value>,...]
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "CONN", 1, 2)?;
 
-        if parts.len() != 2 {
-            error!(
-                "Invalid CONN message length: {:?} instead of 2",
-                parts.len()
-            );
-            return Err("Invalid message".into());
+        let mut conn = Self {
+            uid: parse_uid(fields[0])?,
+            compression: Compression::default(),
+            encoding: Encoding::default(),
+            timestamps: TimestampFormat::default(),
+            delivery_interval_secs: DEFAULT_DELIVERY_INTERVAL_SECS,
+            wake: None,
+            public_key: None,
+            tenant: None,
+            fog: false,
+            metadata: db::DeviceMetadata::default(),
+        };
+
+        // optional connection options
+        if let Some(options) = fields.get(1) {
+            for option in options.split(',') {
+                let (key, value) = option
+                    .split_once('=')
+                    .ok_or_else(|| ParseError::InvalidOption(truncated(option)))?;
+                match key {
+                    "compression" => conn.compression = Compression::from_option(value)?,
+                    "encoding" => conn.encoding = Encoding::from_option(value)?,
+                    "timestamps" => conn.timestamps = TimestampFormat::from_option(value)?,
+                    "interval" => conn.delivery_interval_secs = parse_interval(value)?,
+                    "wake" => conn.wake = Some(WakeSchedule::from_option(value)?),
+                    "pubkey" => conn.public_key = Some(parse_public_key(value)?),
+                    "tenant" => conn.tenant = Some(parse_tenant(value)?),
+                    "role" => conn.fog = parse_role(value)?,
+                    "name" => conn.metadata.name = Some(parse_metadata(key, value)?),
+                    "location" => conn.metadata.location = Some(parse_metadata(key, value)?),
+                    "type" => conn.metadata.sensor_type = Some(parse_metadata(key, value)?),
+                    "firmware" => conn.metadata.firmware = Some(parse_metadata(key, value)?),
+                    "lat" => conn.metadata.latitude = Some(parse_coordinate(key, value)?),
+                    "lon" => conn.metadata.longitude = Some(parse_coordinate(key, value)?),
+                    _ => warn!("Ignoring unknown CONN option: {:?}", key),
+                }
+            }
         }
 
-        // protocol part
-        if parts[0] != "CONN" {
-            error!(
-                "Invalid CONN protocol header: {:?} instead of CONN",
-                parts[0]
-            );
-            return Err("Invalid protocol".into());
+        // only text messages are batched and compressed
+        if conn.compression != Compression::None && conn.encoding != Encoding::Text {
+            return Err(ParseError::IncompatibleOptions(
+                "compression can only be combined with text encoding",
+            ));
+        }
+
+        // the other encodings carry timestamps as numbers
+        if conn.timestamps == TimestampFormat::Iso8601 && conn.encoding != Encoding::Text {
+            return Err(ParseError::IncompatibleOptions(
+                "ISO-8601 timestamps can only be combined with text encoding",
+            ));
         }
 
-        let id = parts[1].parse::<String>()?;
-        if id.len() != 36 {
-            error!("Invalid uuid: {:?}", id);
-            return Err("Invalid id".into());
+        // a position needs both coordinates
+        if conn.metadata.latitude.is_some() != conn.metadata.longitude.is_some() {
+            return Err(ParseError::IncompatibleOptions(
+                "lat and lon have to be given together",
+            ));
         }
 
-        Ok(Self { uid: id })
+        Ok(conn)
     }
 }
 
+// latitude or longitude in degrees
+fn parse_coordinate(key: &str, value: &str) -> Result<f64, ParseError> {
+    let valid = match key {
+        "lat" => geo::is_valid_lat,
+        _ => geo::is_valid_lon,
+    };
+    match value.parse::<f64>() {
+        Ok(degrees) if valid(degrees) => Ok(degrees),
+        _ => Err(ParseError::InvalidOption(format!(
+            "{}={}",
+            key,
+            truncated(value)
+        ))),
+    }
+}
+
+// seconds between deliveries, between 1 and MAX_DELIVERY_INTERVAL_SECS
+fn parse_interval(value: &str) -> Result<u64, ParseError> {
+    match value.parse::<u64>() {
+        Ok(secs) if (1..=MAX_DELIVERY_INTERVAL_SECS).contains(&secs) => Ok(secs),
+        _ => Err(ParseError::InvalidOption(format!(
+            "interval={}",
+            truncated(value)
+        ))),
+    }
+}
+
+// hex X25519 public key, normalized to lowercase
+fn parse_public_key(value: &str) -> Result<String, ParseError> {
+    match hex::decode(value) {
+        Ok(key) if key.len() == envelope::KEY_LEN => Ok(hex::encode(key)),
+        _ => Err(ParseError::InvalidOption(format!(
+            "pubkey={}",
+            truncated(value)
+        ))),
+    }
+}
+
+// role=device or role=fog, true for fog nodes
+fn parse_role(value: &str) -> Result<bool, ParseError> {
+    match value {
+        "device" => Ok(false),
+        "fog" => Ok(true),
+        _ => Err(ParseError::InvalidOption(format!(
+            "role={}",
+            truncated(value)
+        ))),
+    }
+}
+
+fn parse_tenant(value: &str) -> Result<String, ParseError> {
+    if !is_valid_tenant(value) {
+        return Err(ParseError::InvalidOption(format!(
+            "tenant={}",
+            truncated(value)
+        )));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_metadata(key: &str, value: &str) -> Result<String, ParseError> {
+    if !is_valid_metadata(value) {
+        return Err(ParseError::InvalidOption(format!(
+            "{}={}",
+            key,
+            truncated(value)
+        )));
+    }
+    Ok(value.to_string())
+}
+
+const MAX_METADATA_LEN: usize = 64;
+
+// metadata of devices are short printable values like Greenhouse 2 or 1.4.0-rc1
+pub fn is_valid_metadata(value: &str) -> bool {
+    !value.trim().is_empty()
+        && value.len() <= MAX_METADATA_LEN
+        && !value.chars().any(char::is_control)
+}
+
+// tenant ids follow the rules of channel names, e.g. acme or greenhouse-2
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    is_valid_channel(tenant)
+}
+
+// channel of readings from devices that don't name their channels
+pub const DEFAULT_CHANNEL: &str = "default";
+const MAX_CHANNEL_LEN: usize = 32;
+
+// channel names are short lowercase identifiers like temperature or humidity
+pub fn is_valid_channel(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.len() <= MAX_CHANNEL_LEN
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// flag of SENSOR messages with alarm related readings
+const ALARM_FLAG: &str = "alarm";
+// SENSOR fields before the tagged ones
+const MAX_SENSOR_FIELDS: usize = 5;
+
 pub struct SensorMsg {
     pub uid: String,
     pub data: f64,
     pub timestamp: i64,
+    pub channel: String,
+    pub alarm: bool,
+    // sequence number the device gave the reading, a reading re-sent after a reconnect
+    // keeps it and is stored only once
+    pub seq: Option<i64>,
+    // fog node the reading was forwarded by, uid is then the device that took it
+    pub via: Option<String>,
 }
 
 impl SensorMsg {
-    pub fn from_msg(msg: &String) -> Result<Self, Box<dyn Error>> {
-        let parts: Vec<&str> = msg.split("#").collect();
+    // SENSOR#<uid>#<timestamp>#<data>[#<channel>[#alarm]][#via=<fog uid>][#seq=<n>], with auto
+    // detected timestamps
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        Self::parse(msg, TimestampFormat::Auto)
+    }
 
-        if parts.len() != 4 {
-            error!(
-                "Invalid SENSOR message length: {:?} instead of 4",
-                parts.len()
-            );
-            return Err("Invalid message".into());
+    pub fn parse(msg: &str, timestamps: TimestampFormat) -> Result<Self, ParseError> {
+        let mut fields = fields(msg, "SENSOR", 3, MAX_SENSOR_FIELDS + 2)?;
+
+        // tagged fields come last in any order, channels and flags have no =
+        let mut seq = None;
+        let mut via = None;
+        while fields.len() > 3 {
+            let tagged = fields[fields.len() - 1];
+            let Some((key, value)) = tagged.split_once('=') else {
+                break;
+            };
+            match key {
+                "seq" => seq = Some(parse_integer("seq", value)?),
+                "via" => via = Some(parse_uid(value)?),
+                _ => return Err(ParseError::InvalidOption(truncated(tagged))),
+            }
+            fields.pop();
         }
+        if fields.len() > MAX_SENSOR_FIELDS {
+            return Err(ParseError::FieldCount {
+                protocol: "SENSOR",
+                min: 3,
+                max: MAX_SENSOR_FIELDS,
+                found: fields.len(),
+            });
+        }
+
+        let uid = parse_uid(fields[0])?;
+        let timestamp = timestamps.parse(fields[1])?;
+        let data = parse_number("data", fields[2])?;
 
-        // protocol part
-        if parts[0] != "SENSOR" {
-            error!("Invalid SENSOR header: {:?} instead od SENSOR", parts[0]);
-            return Err("Invalid protocol".into());
+        let channel = fields.get(3).copied().unwrap_or(DEFAULT_CHANNEL);
+        if !is_valid_channel(channel) {
+            return Err(ParseError::InvalidChannel(truncated(channel)));
         }
 
-        let id = parts[1].parse::<String>()?;
-        if id.len() != 36 {
-            error!("Invalid uuid: {:?}", id);
-            return Err("Invalid id".into());
+        let alarm = match fields.get(4) {
+            None => false,
+            Some(&ALARM_FLAG) => true,
+            Some(flag) => return Err(ParseError::InvalidFlag(truncated(flag))),
+        };
+
+        Ok(Self {
+            uid,
+            data,
+            timestamp,
+            channel: channel.to_string(),
+            alarm,
+            seq,
+            via,
+        })
+    }
+
+    // applies the timestamp policy to a reading received at `now`, readings within the allowed
+    // skew are left as they are
+    pub fn check_timestamp(
+        &mut self,
+        now: i64,
+        max_skew: i64,
+        policy: TimestampPolicy,
+    ) -> Result<(), ParseError> {
+        let skew = self.timestamp - now;
+        if skew.abs() <= max_skew {
+            return Ok(());
+        }
+        match policy {
+            TimestampPolicy::Accept => Ok(()),
+            TimestampPolicy::Clamp => {
+                self.timestamp = now;
+                Ok(())
+            }
+            TimestampPolicy::Reject => Err(ParseError::ClockSkew(skew)),
         }
+    }
+}
 
-        let timestamp = parts[2].parse::<i64>()?;
+// SEALED#<uid>#<timestamp>#<channel>#<hex envelope>, an end-to-end encrypted reading
+// the cloud stores without reading it
+pub struct SealedMsg {
+    pub uid: String,
+    pub timestamp: i64,
+    pub channel: String,
+    pub envelope: Envelope,
+}
 
-        let data = parts[3].parse::<f64>()?;
+impl SealedMsg {
+    pub fn parse(msg: &str, timestamps: TimestampFormat) -> Result<Self, ParseError> {
+        let fields = fields(msg, "SEALED", 4, 4)?;
+
+        let uid = parse_uid(fields[0])?;
+        let timestamp = timestamps.parse(fields[1])?;
+        let channel = fields[2];
+        if !is_valid_channel(channel) {
+            return Err(ParseError::InvalidChannel(truncated(channel)));
+        }
 
         Ok(Self {
-            uid: id,
-            data,
+            uid,
             timestamp,
+            channel: channel.to_string(),
+            envelope: Envelope::from_hex(fields[3])?,
         })
     }
+
+    // SEALED#<hex envelope>, a sealed message for the device
+    pub fn downlink(envelope: &Envelope) -> String {
+        format!("SEALED#{}", envelope.to_hex())
+    }
+}
+
+// most decimal places values are rounded to, a double doesn't hold more
+pub const MAX_DECIMALS: u32 = 15;
+
+// rounds a computed value to the decimal places, so it is written as 0.15 instead of
+// 0.15000000000000002, which some parsers of microcontrollers choke on
+pub fn round_value(value: f64, decimals: Option<u32>) -> f64 {
+    let Some(decimals) = decimals else {
+        return value;
+    };
+    let scale = 10f64.powi(decimals.min(MAX_DECIMALS) as i32);
+    let rounded = (value * scale).round() / scale;
+    // values too large to scale have no decimals left anyway
+    if rounded.is_finite() {
+        rounded
+    } else {
+        value
+    }
 }
 
+#[derive(Serialize, ToSchema)]
 pub struct AvgMsg {
     pub data: f64,
     pub timestamp: i64,
+    pub channel: String,
 }
 
 impl AvgMsg {
     pub fn to_msg(&self) -> String {
-        format!("AVG#{}#{}", self.timestamp, self.data)
+        format!("AVG#{}#{}#{}", self.timestamp, self.data, self.channel)
+    }
+
+    // AVG#<timestamp>#<data>#<channel>, as queued before the delivery id is appended
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "AVG", 3, 3)?;
+
+        Ok(Self {
+            timestamp: parse_integer("timestamp", fields[0])?,
+            data: parse_number("data", fields[1])?,
+            channel: fields[2].to_string(),
+        })
+    }
+}
+
+// appends the queued message id to an outgoing message, so the client can acknowledge it
+pub fn with_delivery_id(msg: &str, queued_message_id: i64) -> String {
+    format!("{}#{}", msg, queued_message_id)
+}
+
+pub struct AckMsg {
+    pub uid: String,
+    pub queued_message_id: i64,
+}
+
+impl AckMsg {
+    // ACK#<uid>#<queued message id>
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        Self::from_msg_with_header(msg, "ACK")
+    }
+
+    // CMD_ACK#<uid>#<queued message id>, acknowledges a CMD message
+    pub fn from_cmd_ack(msg: &str) -> Result<Self, ParseError> {
+        Self::from_msg_with_header(msg, "CMD_ACK")
+    }
+
+    fn from_msg_with_header(msg: &str, header: &'static str) -> Result<Self, ParseError> {
+        let fields = fields(msg, header, 2, 2)?;
+
+        Ok(Self {
+            uid: parse_uid(fields[0])?,
+            queued_message_id: parse_integer("message id", fields[1])?,
+        })
+    }
+}
+
+// state the AVG service keeps per channel between ticks
+#[derive(Default)]
+struct ChannelState {
+    last_id: i64,
+    // value and time of the last queued AVG message, used to suppress duplicates
+    last_emitted: Option<(f64, i64)>,
+}
+
+// how old the readings the AVG service averages are, the last AVG messages of a window
+// can be hours old when a channel went quiet
+#[derive(Default)]
+pub struct Staleness {
+    // seconds the newest reading of the last AVG message was old
+    last: AtomicI64,
+    // AVG messages computed from readings older than AVG_STALE_SECS since the server started
+    stale: AtomicU64,
+}
+
+impl Staleness {
+    pub fn last(&self) -> i64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    pub fn stale(&self) -> u64 {
+        self.stale.load(Ordering::Relaxed)
+    }
+}
+
+// largest AVG window and longest interval between ticks
+const MAX_AVG_WINDOW_SIZE: i64 = 1000;
+const MAX_AVG_INTERVAL_SECS: u64 = 3600;
+
+// how the AVG service aggregates, read from the config on startup and changed at runtime
+// through the admin api. changes apply from the next tick on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AvgSettings {
+    pub window_size: i64,
+    pub interval_secs: u64,
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub timestamp: AggregationTimestamp,
+}
+
+impl AvgSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            window_size: config.avg_window_size,
+            interval_secs: config.avg_interval_secs,
+            aggregation: config.avg_aggregation,
+            timestamp: config.avg_timestamp,
+        }
+    }
+
+    pub fn validate(&self) -> crate::Result<()> {
+        if !(1..=MAX_AVG_WINDOW_SIZE).contains(&self.window_size) {
+            return Err(crate::Error::Protocol(format!(
+                "window size must be between 1 and {}",
+                MAX_AVG_WINDOW_SIZE
+            )));
+        }
+        if !(1..=MAX_AVG_INTERVAL_SECS).contains(&self.interval_secs) {
+            return Err(crate::Error::Protocol(format!(
+                "interval must be between 1 and {} seconds",
+                MAX_AVG_INTERVAL_SECS
+            )));
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
     }
 }
 
 pub async fn avg_msg_service(state: Arc<crate::AppState>) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+    let mut updates = state.avg_settings.subscribe();
+    let mut settings = updates.borrow_and_update().clone();
+
+    // fill the hole a downtime left in the AVG history before resuming
+    backfill_missed_windows(&state, &settings).await;
+
+    let mut interval = Interval::new(state.clock.clone(), settings.interval());
 
     let mut ticks = 0;
-    let mut last_id = -1;
+    let mut channels: HashMap<(Option<String>, String), ChannelState> = HashMap::new();
 
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = updates.changed() => {
+                settings = updates.borrow_and_update().clone();
+                info!("AVG service: Using {:?} from now on", settings);
+                let period = settings.interval();
+                interval = Interval::delayed(state.clock.clone(), period);
+                continue;
+            }
+        }
         ticks += 1;
+        let started = Instant::now();
 
-        let messages = db::get_last_received_messages(&state.pool, 5)
-            .await
-            .unwrap_or(Vec::new());
-
-        let size = messages.len();
-        if size == 0 || messages[0].id == last_id {
+        let names = db::get_channels(&state.pool).await.unwrap_or_default();
+        if names.is_empty() {
             warn!(
-                "AVG service tick {}: No new messages to process, skipping tick",
+                "AVG service tick {}: No messages to process, skipping tick",
                 ticks
             );
             continue;
         }
-        last_id = messages[0].id;
 
-        let mut avg: f64 = 0.0;
-        for msg in messages {
-            avg += msg.data;
+        // every channel of every tenant is averaged separately
+        for (tenant, name) in names {
+            let channel = channels.entry((tenant.clone(), name.clone())).or_default();
+            average_channel(&state, &settings, ticks, tenant.as_deref(), &name, channel).await;
         }
-        avg /= size as f64;
-
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as i64;
-
-        let avg_msg = AvgMsg {
-            data: avg,
-            timestamp: now,
-        };
+        state.latency.observe(Stage::Aggregate, started.elapsed());
 
-        if db::add_queued_message(&state.pool, avg_msg.to_msg())
+        let now = state.clock.unix_now();
+        if db::set_last_aggregation_tick(&state.pool, now)
             .await
             .is_err()
         {
-            error!(
-                "AVG service tick {}: Failed to add message to the queue",
-                ticks
+            error!("AVG service tick {}: Failed to store the tick time", ticks);
+        }
+    }
+}
+
+// queues an AVG message for every window between the last tick before the
+// server stopped and now, averaging the readings received in that window
+async fn backfill_missed_windows(state: &crate::AppState, settings: &AvgSettings) {
+    let last_tick = match db::get_last_aggregation_tick(&state.pool).await {
+        Ok(Some(last_tick)) => last_tick,
+        Ok(None) => return,
+        Err(_) => {
+            error!("AVG service: Failed to read the last tick, not backfilling");
+            return;
+        }
+    };
+
+    let now = state.clock.unix_now();
+    let window = settings.interval_secs as i64;
+    if now - last_tick <= window {
+        return;
+    }
+
+    let start = last_tick.max(now - state.config.avg_backfill_max_secs);
+    if start > last_tick {
+        warn!(
+            "AVG service: Downtime of {} seconds exceeds the backfill limit, backfilling the last {} seconds only",
+            now - last_tick,
+            state.config.avg_backfill_max_secs
+        );
+    }
+
+    let names = db::get_channels(&state.pool).await.unwrap_or_default();
+    let mut backfilled = 0;
+    let mut window_start = start;
+    while window_start + window <= now {
+        let window_end = window_start + window;
+
+        for (tenant, name) in &names {
+            let label = channel_label(tenant.as_deref(), name);
+            let data = match db::get_window_data(
+                &state.pool,
+                tenant.as_deref(),
+                name,
+                settings.timestamp,
+                window_start,
+                window_end,
+            )
+            .await
+            {
+                Ok(data) => data,
+                Err(_) => {
+                    error!(
+                        "AVG service: Failed to average channel {} for backfill",
+                        label
+                    );
+                    continue;
+                }
+            };
+            let avg = match settings.aggregation.apply_samples(&data) {
+                Some(avg) => round_value(avg, state.config.value_decimals),
+                None => continue,
+            };
+
+            let avg_msg = AvgMsg {
+                data: avg,
+                timestamp: window_end,
+                channel: name.clone(),
+            };
+            if db::add_queued_message(&state.pool, tenant.as_deref(), avg_msg.to_msg())
+                .await
+                .is_err()
+            {
+                error!(
+                    "AVG service: Failed to queue backfilled message of channel {}",
+                    label
+                );
+                continue;
+            }
+            let aggregate = db::Aggregate {
+                id: 0,
+                tenant: tenant.clone(),
+                channel: name.clone(),
+                window_start,
+                window_end,
+                value: avg,
+                count: data.iter().map(|sample| sample.count).sum(),
+                staleness: None,
+            };
+            if db::add_aggregate(&state.pool, &aggregate).await.is_err() {
+                error!(
+                    "AVG service: Failed to store backfilled aggregate of channel {}",
+                    label
+                );
+            }
+            backfilled += 1;
+        }
+
+        window_start = window_end;
+    }
+
+    info!(
+        "AVG service: Backfilled {} messages for the {} seconds since the last tick",
+        backfilled,
+        now - last_tick
+    );
+}
+
+// a channel in the logs, with its tenant if it has one
+fn channel_label(tenant: Option<&str>, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{} of tenant {}", name, tenant),
+        None => name.to_string(),
+    }
+}
+
+async fn average_channel(
+    state: &crate::AppState,
+    settings: &AvgSettings,
+    ticks: i32,
+    tenant: Option<&str>,
+    name: &str,
+    channel: &mut ChannelState,
+) {
+    let label = channel_label(tenant, name);
+    let messages = db::get_last_received_messages(
+        &state.pool,
+        tenant,
+        name,
+        settings.timestamp,
+        settings.window_size,
+    )
+    .await
+    .unwrap_or(Vec::new());
+
+    let size = messages.len();
+    if size == 0 || messages[0].id == channel.last_id {
+        warn!(
+            "AVG service tick {}: No new messages in channel {} to process, skipping channel",
+            ticks, label
+        );
+        return;
+    }
+    channel.last_id = messages[0].id;
+
+    let data: Vec<Sample> = messages.iter().map(db::ReceivedMessage::sample).collect();
+    let avg = match settings.aggregation.apply_samples(&data) {
+        Some(avg) => round_value(avg, state.config.value_decimals),
+        None => return,
+    };
+
+    let now = state.clock.unix_now();
+
+    // skip values that did not change, unless the heartbeat interval has passed
+    if let (Some(epsilon), Some((last_avg, last_time))) =
+        (state.config.avg_epsilon, channel.last_emitted)
+    {
+        if (avg - last_avg).abs() <= epsilon && now - last_time < state.config.avg_heartbeat_secs {
+            info!(
+                "AVG service tick {}: avg {} of channel {} unchanged since last emission, skipping channel",
+                ticks, avg, label
             );
+            return;
         }
+    }
+
+    let avg_msg = AvgMsg {
+        data: avg,
+        timestamp: now,
+        channel: name.to_string(),
+    };
+
+    if db::add_queued_message(&state.pool, tenant, avg_msg.to_msg())
+        .await
+        .is_err()
+    {
+        error!(
+            "AVG service tick {}: Failed to add message of channel {} to the queue",
+            ticks, label
+        );
+        return;
+    }
+    channel.last_emitted = Some((avg, now));
+
+    // the messages are newest first
+    let staleness = (now - messages[0].timestamp(settings.timestamp)).max(0);
+    state.staleness.last.store(staleness, Ordering::Relaxed);
+    if staleness > state.config.avg_stale_secs {
+        state.staleness.stale.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "AVG service tick {}: avg of channel {} is stale, its newest reading is {} seconds old",
+            ticks, label, staleness
+        );
+        state.webhooks.publish(WebhookEvent::Stale {
+            timestamp: now,
+            channel: name.to_string(),
+            tenant: tenant.map(str::to_string),
+            staleness,
+        });
+    }
 
-        info!(
-            "AVG service tick {}: Processed the last {} messages, avg: {}",
-            ticks, size, avg
-        )
+    let aggregate = db::Aggregate {
+        id: 0,
+        tenant: tenant.map(str::to_string),
+        channel: name.to_string(),
+        window_start: messages[size - 1].timestamp(settings.timestamp),
+        window_end: messages[0].timestamp(settings.timestamp),
+        value: avg,
+        count: data.iter().map(|sample| sample.count).sum(),
+        staleness: Some(staleness),
+    };
+    if db::add_aggregate(&state.pool, &aggregate).await.is_err() {
+        error!(
+            "AVG service tick {}: Failed to store the aggregate of channel {}",
+            ticks, label
+        );
     }
+
+    state.webhooks.publish(WebhookEvent::Avg {
+        timestamp: avg_msg.timestamp,
+        data: avg_msg.data,
+        channel: avg_msg.channel.clone(),
+        tenant: tenant.map(str::to_string),
+    });
+    state.events.publish(StreamEvent::Avg {
+        timestamp: avg_msg.timestamp,
+        data: avg_msg.data,
+        channel: avg_msg.channel,
+        tenant: tenant.map(str::to_string),
+    });
+
+    info!(
+        "AVG service tick {}: Processed the last {} messages of channel {}, avg: {}",
+        ticks, size, label, avg
+    )
 }
 
-pub struct DisconnMsg {
+const MAX_KEY_LEN: usize = 64;
+// longest value the key-value store of a device accepts, in bytes
+pub const MAX_VALUE_LEN: usize = 4096;
+
+// keys follow the rules of channel names, but may be longer
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// a device reading one of its own keys
+pub struct KvGetMsg {
     pub uid: String,
+    pub key: String,
 }
 
-impl DisconnMsg {
-    pub fn from_msg(msg: &String) -> Result<Self, Box<dyn Error>> {
-        let parts: Vec<&str> = msg.split("#").collect();
+impl KvGetMsg {
+    // KVGET#<uid>#<key>
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "KVGET", 2, 2)?;
 
-        if parts.len() != 2 {
-            error!(
-                "Invalid DISCONN message length: {:?} instead of 2",
-                parts.len()
-            );
-            return Err("Invalid message".into());
+        let uid = parse_uid(fields[0])?;
+        let key = fields[1];
+        if !is_valid_key(key) {
+            return Err(ParseError::InvalidKey(truncated(key)));
         }
 
-        // protocol part
-        if parts[0] != "DISCONN" {
-            error!(
-                "Invalid DISCONN protocol header: {:?} instead of DISCONN",
-                parts[0]
-            );
-            return Err("Invalid protocol".into());
+        Ok(Self {
+            uid,
+            key: key.to_string(),
+        })
+    }
+}
+
+// answer to KVGET, the value is left out if the key is not set
+pub struct KvMsg {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl KvMsg {
+    // KV#<key>[#<value>], the value may itself contain '#'
+    pub fn to_msg(&self) -> String {
+        match &self.value {
+            Some(value) => format!("KV#{}#{}", self.key, value),
+            None => format!("KV#{}", self.key),
+        }
+    }
+}
+
+const MAX_ARGUMENT_LEN: usize = 256;
+
+// a command for a single device, like changing its sampling interval or toggling an actuator
+pub struct CmdMsg {
+    pub command: String,
+    pub argument: Option<String>,
+}
+
+impl CmdMsg {
+    // commands are named like keys, arguments are short and may not contain '#' or newlines
+    pub fn validate(&self) -> crate::Result<()> {
+        if !is_valid_key(&self.command) {
+            return Err(crate::Error::Protocol(format!(
+                "invalid command {}",
+                truncated(&self.command)
+            )));
+        }
+        if let Some(argument) = &self.argument {
+            if argument.len() > MAX_ARGUMENT_LEN || argument.contains(['#', '\n']) {
+                return Err(crate::Error::Protocol("invalid argument".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // CMD#<command>[#<argument>], the queued message id is appended on delivery
+    pub fn to_msg(&self) -> String {
+        match &self.argument {
+            Some(argument) => format!("CMD#{}#{}", self.command, argument),
+            None => format!("CMD#{}", self.command),
+        }
+    }
+}
+
+// tells a device that one of its alert rules fired
+pub struct AlertMsg {
+    pub rule_id: i64,
+    pub channel: String,
+    pub data: f64,
+}
+
+impl AlertMsg {
+    pub fn to_msg(&self) -> String {
+        format!("ALERT#{}#{}#{}", self.rule_id, self.channel, self.data)
+    }
+}
+
+// an alert rule as a device evaluates it itself. the action is only included if it
+// targets the device, actions on other devices are still sent by the server
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EdgeRule {
+    pub id: i64,
+    pub channel: Option<String>,
+    pub op: String,
+    pub threshold: f64,
+    pub duration_secs: i64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub action_command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub action_argument: Option<String>,
+}
+
+impl EdgeRule {
+    pub fn from_rule(rule: &db::AlertRule) -> Self {
+        let local = rule.action_uid.as_deref() == Some(rule.uid.as_str());
+        Self {
+            id: rule.id,
+            channel: rule.channel.clone(),
+            op: rule.op.clone(),
+            threshold: rule.threshold,
+            duration_secs: rule.duration_secs,
+            action_command: rule.action_command.clone().filter(|_| local),
+            action_argument: rule.action_argument.clone().filter(|_| local),
+        }
+    }
+}
+
+// the complete alert rules of a device, replacing the ones it has. the device answers with
+// RULES_ACK once it applied them
+pub struct RulesMsg {
+    pub version: i64,
+    pub rules: Vec<EdgeRule>,
+}
+
+impl RulesMsg {
+    // RULES#<version>#<JSON array of rules>, the JSON may contain '#'
+    pub fn to_msg(&self) -> String {
+        let rules = serde_json::to_string(&self.rules).unwrap_or_else(|_| "[]".to_string());
+        format!("RULES#{}#{}", self.version, rules)
+    }
+}
+
+pub struct RulesAckMsg {
+    pub uid: String,
+    pub version: i64,
+}
+
+impl RulesAckMsg {
+    // RULES_ACK#<uid>#<version>
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "RULES_ACK", 2, 2)?;
+
+        Ok(Self {
+            uid: parse_uid(fields[0])?,
+            version: parse_integer("version", fields[1])?,
+        })
+    }
+}
+
+// the complete parameters of a device, replacing the ones it has. sent to the device or to
+// the fog node it is behind, which passes it on. the device answers with CONFIG_ACK once it
+// applied them
+pub struct ConfigMsg {
+    pub uid: String,
+    pub version: i64,
+    pub params: db::ConfigParams,
+}
+
+impl ConfigMsg {
+    // CONFIG#<uid>#<version>#<JSON object of parameters>, the JSON may contain '#'
+    pub fn to_msg(&self) -> String {
+        let params = serde_json::to_string(&self.params).unwrap_or_else(|_| "{}".to_string());
+        format!("CONFIG#{}#{}#{}", self.uid, self.version, params)
+    }
+}
+
+pub struct ConfigAckMsg {
+    pub uid: String,
+    pub version: i64,
+}
+
+impl ConfigAckMsg {
+    // CONFIG_ACK#<uid>#<version>, sent by a fog node for the devices behind it
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "CONFIG_ACK", 2, 2)?;
+
+        Ok(Self {
+            uid: parse_uid(fields[0])?,
+            version: parse_integer("version", fields[1])?,
+        })
+    }
+}
+
+// error codes reported to the client in ERR messages
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorCode {
+    BadProtocol,
+    UidMismatch,
+    RateLimited,
+    Overloaded,
+    // the device may not use the uid it claimed
+    AuthFailed,
+    // a signed message was too old or already received
+    Replayed,
+    // the uid already has a live session and new ones are rejected
+    SessionExists,
+    // numbers of readings that were rejected, see ParseError
+    NotANumber,
+    Infinite,
+    Overflow,
+    DecimalComma,
+    // the timestamp of a reading was too far off the server clock, see TimestampPolicy
+    ClockSkew,
+    // the frame or the batch it carried was longer than MAX_FRAME_BYTES
+    TooLarge,
+    // the device was drained for servicing and may not connect until an admin releases it
+    Maintenance,
+}
+
+impl ErrorCode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadProtocol => "BAD_PROTOCOL",
+            ErrorCode::UidMismatch => "UID_MISMATCH",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::Overloaded => "OVERLOADED",
+            ErrorCode::AuthFailed => "AUTH_FAILED",
+            ErrorCode::Replayed => "REPLAYED",
+            ErrorCode::SessionExists => "SESSION_EXISTS",
+            ErrorCode::NotANumber => "NOT_A_NUMBER",
+            ErrorCode::Infinite => "INFINITE",
+            ErrorCode::Overflow => "OVERFLOW",
+            ErrorCode::DecimalComma => "DECIMAL_COMMA",
+            ErrorCode::ClockSkew => "CLOCK_SKEW",
+            ErrorCode::TooLarge => "TOO_LARGE",
+            ErrorCode::Maintenance => "MAINTENANCE",
         }
+    }
 
-        let id = parts[1].parse::<String>()?;
-        if id.len() != 36 {
-            error!("Invalid uuid: {:?}", id);
-            return Err("Invalid id".into());
+    // fatal errors close the connection after the ERR message was sent,
+    // the client can keep using the connection after recoverable ones
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ErrorCode::UidMismatch
+            | ErrorCode::AuthFailed
+            | ErrorCode::SessionExists
+            | ErrorCode::Maintenance => true,
+            ErrorCode::BadProtocol
+            | ErrorCode::RateLimited
+            | ErrorCode::Overloaded
+            | ErrorCode::Replayed
+            | ErrorCode::NotANumber
+            | ErrorCode::Infinite
+            | ErrorCode::Overflow
+            | ErrorCode::DecimalComma
+            | ErrorCode::ClockSkew
+            | ErrorCode::TooLarge => false,
         }
+    }
+}
+
+pub struct ErrMsg {
+    pub code: ErrorCode,
+    pub reason: String,
+}
 
-        Ok(Self { uid: id })
+impl ErrMsg {
+    pub fn to_msg(&self) -> String {
+        format!("ERR#{}#{}", self.code.as_str(), self.reason)
+    }
+}
+
+// answer to CONN, devices may name themselves by the session id instead of their uid
+pub struct SessionMsg {
+    pub session_id: String,
+}
+
+impl SessionMsg {
+    pub fn to_msg(&self) -> String {
+        format!("SESSION#{}", self.session_id)
+    }
+}
+
+// asks a device to finish sending, the server closes the connection after the timeout
+pub struct DrainMsg {
+    pub timeout_secs: u64,
+}
+
+impl DrainMsg {
+    pub fn to_msg(&self) -> String {
+        format!("DRAIN#{}", self.timeout_secs)
+    }
+}
+
+// tells a device the server shuts down, it closes the connection after the grace period.
+// the device reconnects once the server is back
+pub struct ShutdownMsg {
+    pub grace_secs: u64,
+}
+
+impl ShutdownMsg {
+    pub fn to_msg(&self) -> String {
+        format!("SHUTDOWN#{}", self.grace_secs)
+    }
+}
+
+// first message of a device without a uid while pairing mode is open, PAIR or PAIR#<name>.
+// the name follows the rules of channel names, e.g. bme280 or kitchen-window
+pub struct PairMsg {
+    pub name: Option<String>,
+}
+
+impl PairMsg {
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "PAIR", 0, 1)?;
+        let name = match fields.first() {
+            Some(name) if !is_valid_channel(name) => {
+                return Err(ParseError::InvalidName(truncated(name)))
+            }
+            name => name.map(|name| name.to_string()),
+        };
+
+        Ok(Self { name })
+    }
+}
+
+// answer to PAIR, the device connects with the uid and signs its messages with the hex key
+pub struct PairedMsg {
+    pub uid: String,
+    pub key: String,
+}
+
+impl PairedMsg {
+    pub fn to_msg(&self) -> String {
+        format!("PAIRED#{}#{}", self.uid, self.key)
+    }
+}
+
+pub struct DisconnMsg {
+    pub uid: String,
+}
+
+impl DisconnMsg {
+    // DISCONN#<uid>
+    pub fn from_msg(msg: &str) -> Result<Self, ParseError> {
+        let fields = fields(msg, "DISCONN", 1, 1)?;
+
+        Ok(Self {
+            uid: parse_uid(fields[0])?,
+        })
     }
 }
@@ -0,0 +1,234 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+#[cfg(not(any(feature = "nats", feature = "kafka")))]
+use tracing::warn;
+
+use crate::AppState;
+
+// how publishing to the streaming platform goes, for diagnostics
+#[derive(Default)]
+pub struct Upstream {
+    connected: AtomicBool,
+    published: AtomicU64,
+    failed: AtomicU64,
+    // readings waiting for the upstream budget, and the ones dropped when too many waited
+    backlog: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl Upstream {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    // readings handed to the client since the server started
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "nats", feature = "kafka"))]
+    fn record(&self, published: bool) {
+        let counter = if published {
+            &self.published
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// publishes every stored reading to NATS or Kafka, so the server can be the ingestion
+// frontend of a streaming platform. readings are JSON like the sensor events of the dashboard
+// stream, published to <topic>.<uid> on NATS and to <topic> keyed by the uid on Kafka.
+// readings exceeding their share of the upstream budget are held back until the next hour
+pub async fn publisher_service(state: Arc<AppState>) {
+    let Some(url) = state.config.stream_url.clone() else {
+        return;
+    };
+
+    #[cfg(any(feature = "nats", feature = "kafka"))]
+    client::run(state, url).await;
+
+    #[cfg(not(any(feature = "nats", feature = "kafka")))]
+    warn!(
+        "STREAM_URL is set to {:?} but the server was built without the nats and kafka features",
+        url
+    );
+}
+
+#[cfg(any(feature = "nats", feature = "kafka"))]
+mod client {
+    use std::{
+        collections::VecDeque,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+    use tokio::sync::broadcast::error::RecvError;
+    use tracing::{error, info, warn};
+
+    use crate::{budget::Class, clock::Interval, config::StreamUrl, events::StreamEvent, AppState};
+
+    // readings held back for the upstream budget, the oldest are dropped beyond it
+    const MAX_BACKLOG: usize = 10_000;
+    // seconds between attempts to publish the held back readings
+    const FLUSH_INTERVAL_SECS: u64 = 10;
+
+    // a connection to the streaming platform
+    enum Publisher {
+        #[cfg(feature = "nats")]
+        Nats(async_nats::Client),
+        #[cfg(feature = "kafka")]
+        Kafka(rdkafka::producer::FutureProducer),
+    }
+
+    impl Publisher {
+        // None if the platform can't be reached or this build does not include its client
+        async fn connect(url: &StreamUrl) -> Option<Self> {
+            match url {
+                #[cfg(feature = "nats")]
+                StreamUrl::Nats(url) => match async_nats::connect(url.as_str()).await {
+                    Ok(client) => Some(Self::Nats(client)),
+                    Err(e) => {
+                        error!("Could not connect to NATS at {}: {}", url, e);
+                        None
+                    }
+                },
+                #[cfg(feature = "kafka")]
+                StreamUrl::Kafka(brokers) => match rdkafka::ClientConfig::new()
+                    .set("bootstrap.servers", brokers)
+                    .create()
+                {
+                    Ok(producer) => Some(Self::Kafka(producer)),
+                    Err(e) => {
+                        error!("Could not create a Kafka producer for {}: {}", brokers, e);
+                        None
+                    }
+                },
+                #[allow(unreachable_patterns)]
+                url => {
+                    warn!(
+                        "STREAM_URL is set to {:?} but the server was built without its client",
+                        url
+                    );
+                    None
+                }
+            }
+        }
+
+        // only hands the reading to the client, which sends it in the background
+        async fn publish(&self, topic: &str, uid: &str, payload: Vec<u8>) -> Result<(), String> {
+            match self {
+                #[cfg(feature = "nats")]
+                Publisher::Nats(client) => client
+                    .publish(format!("{}.{}", topic, uid), payload.into())
+                    .await
+                    .map_err(|e| e.to_string()),
+                #[cfg(feature = "kafka")]
+                Publisher::Kafka(producer) => {
+                    let record = rdkafka::producer::FutureRecord::to(topic)
+                        .key(uid)
+                        .payload(&payload);
+                    let delivery = producer
+                        .send_result(record)
+                        .map_err(|(e, _)| e.to_string())?;
+                    let uid = uid.to_string();
+                    tokio::spawn(async move {
+                        if let Ok(Err((e, _))) = delivery.await {
+                            error!("Kafka did not take a reading of {}: {}", uid, e);
+                        }
+                    });
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub async fn run(state: Arc<AppState>, url: StreamUrl) {
+        let Some(publisher) = Publisher::connect(&url).await else {
+            return;
+        };
+        state.upstream.connected.store(true, Ordering::Relaxed);
+        info!(
+            "Publishing readings to {:?} under {}",
+            url, state.config.stream_topic
+        );
+
+        let mut events = state.events.subscribe();
+        // readings by uid in the order they were stored
+        let mut backlog: VecDeque<(String, Vec<u8>)> = VecDeque::new();
+        let mut flush = Interval::new(
+            state.clock.clone(),
+            Duration::from_secs(FLUSH_INTERVAL_SECS),
+        );
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Publisher fell behind, skipped {} readings", skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return,
+                    };
+                    let StreamEvent::Sensor { uid, .. } = &event else {
+                        continue;
+                    };
+                    let Ok(payload) = serde_json::to_vec(&event) else {
+                        continue;
+                    };
+                    // readings keep their order, new ones wait behind the held back ones
+                    let now = state.clock.unix_now();
+                    if backlog.is_empty() && state.budget.try_spend_at(Class::Raw, payload.len(), now) {
+                        publish(&publisher, &state, uid, payload).await;
+                        continue;
+                    }
+                    state.budget.defer_at(now);
+                    if backlog.len() == MAX_BACKLOG {
+                        backlog.pop_front();
+                        state.upstream.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    backlog.push_back((uid.clone(), payload));
+                }
+                _ = flush.tick(), if !backlog.is_empty() => {
+                    while let Some((_, payload)) = backlog.front() {
+                        if !state.budget.try_spend_at(Class::Raw, payload.len(), state.clock.unix_now()) {
+                            break;
+                        }
+                        if let Some((uid, payload)) = backlog.pop_front() {
+                            publish(&publisher, &state, &uid, payload).await;
+                        }
+                    }
+                }
+            }
+            state
+                .upstream
+                .backlog
+                .store(backlog.len(), Ordering::Relaxed);
+        }
+    }
+
+    async fn publish(publisher: &Publisher, state: &AppState, uid: &str, payload: Vec<u8>) {
+        let published = publisher
+            .publish(&state.config.stream_topic, uid, payload)
+            .await;
+        if let Err(e) = &published {
+            error!("Error publishing a reading of {}: {}", uid, e);
+        }
+        state.upstream.record(published.is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info};
+
+use crate::{clock::Interval, config::RetentionPolicy, db, AppState};
+
+// rows deleted per statement, so the ingest is not blocked for long
+const PRUNE_BATCH: i64 = 1000;
+
+// rows pruned per table since the server started
+#[derive(Default)]
+pub struct Pruned {
+    received: AtomicU64,
+    queued: AtomicU64,
+    delivered: AtomicU64,
+    // raw readings replaced by per-minute summaries
+    summarized: AtomicU64,
+}
+
+impl Pruned {
+    fn counter(&self, table: db::PrunableTable) -> &AtomicU64 {
+        match table {
+            db::PrunableTable::Received => &self.received,
+            db::PrunableTable::Queued => &self.queued,
+            db::PrunableTable::Delivered => &self.delivered,
+        }
+    }
+
+    pub fn get(&self, table: db::PrunableTable) -> u64 {
+        self.counter(table).load(Ordering::Relaxed)
+    }
+
+    pub fn summarized(&self) -> u64 {
+        self.summarized.load(Ordering::Relaxed)
+    }
+}
+
+// periodically prunes messages according to the configured retention policies
+pub async fn retention_service(state: Arc<AppState>) {
+    let config = &state.config;
+    let policies = [
+        (db::PrunableTable::Received, &config.received_retention),
+        (db::PrunableTable::Queued, &config.queued_retention),
+        (db::PrunableTable::Delivered, &config.delivered_retention),
+    ];
+    if policies.iter().all(|(_, policy)| policy.is_unlimited()) {
+        info!("Retention service: No retention policies configured, keeping all messages");
+        return;
+    }
+
+    let mut interval = Interval::new(
+        state.clock.clone(),
+        Duration::from_secs(config.retention_interval_secs),
+    );
+
+    loop {
+        interval.tick().await;
+
+        if let Some(percent) = config.received_summarize_percent {
+            match summarize(&state, percent).await {
+                Ok(0) => {}
+                Ok(summarized) => {
+                    state
+                        .pruned
+                        .summarized
+                        .fetch_add(summarized, Ordering::Relaxed);
+                    info!(
+                        "Retention service: Summarized {} readings per minute",
+                        summarized
+                    );
+                }
+                Err(e) => error!("Retention service: Failed to summarize readings: {}", e),
+            }
+        }
+
+        let mut total = 0;
+        for (table, policy) in &policies {
+            match prune(&state, *table, policy).await {
+                Ok(pruned) => {
+                    state
+                        .pruned
+                        .counter(*table)
+                        .fetch_add(pruned, Ordering::Relaxed);
+                    total += pruned;
+                }
+                Err(e) => error!("Retention service: Failed to prune {}: {}", table.name(), e),
+            }
+        }
+
+        if total == 0 {
+            continue;
+        }
+        info!("Retention service: Pruned {} rows", total);
+
+        // hand the freed pages back to the file system
+        if db::reclaim_space(&state.pool).await.is_err() {
+            error!("Retention service: Failed to vacuum the db");
+        }
+    }
+}
+
+async fn prune(
+    state: &AppState,
+    table: db::PrunableTable,
+    policy: &RetentionPolicy,
+) -> crate::Result<u64> {
+    let mut pruned = 0;
+
+    if let Some(max_age_secs) = policy.max_age_secs {
+        let now = state.clock.unix_now();
+        loop {
+            let deleted =
+                db::prune_older_than(&state.pool, table, now - max_age_secs, PRUNE_BATCH).await?;
+            pruned += deleted;
+            if deleted < PRUNE_BATCH as u64 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    if let Some(max_rows) = policy.max_rows {
+        loop {
+            let deleted = db::prune_excess_rows(&state.pool, table, max_rows, PRUNE_BATCH).await?;
+            pruned += deleted;
+            if deleted < PRUNE_BATCH as u64 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    Ok(pruned)
+}
+
+// summarizes the oldest raw readings once received_messages holds the percent of its row cap,
+// until it is below again or only summaries are left. the cap itself is still enforced by
+// pruning, summaries only make it last longer
+async fn summarize(state: &AppState, percent: u8) -> crate::Result<u64> {
+    let Some(max_rows) = state.config.received_retention.max_rows else {
+        return Ok(0);
+    };
+    let threshold = max_rows * percent as i64 / 100;
+    let mut summarized = 0;
+
+    while db::count_received_messages(&state.pool).await? >= threshold {
+        let batch = db::summarize_oldest_readings(&state.pool, PRUNE_BATCH).await?;
+        if batch == 0 {
+            break;
+        }
+        summarized += batch;
+        tokio::task::yield_now().await;
+    }
+
+    Ok(summarized)
+}
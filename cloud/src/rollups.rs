@@ -0,0 +1,47 @@
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info};
+
+use crate::{clock::Interval, db, flags, AppState};
+
+// periodically rolls completed hours and days of received readings up
+pub async fn rollup_service(state: Arc<AppState>) {
+    let mut interval = Interval::new(
+        state.clock.clone(),
+        Duration::from_secs(state.config.rollup_interval_secs),
+    );
+
+    loop {
+        interval.tick().await;
+
+        if !state.flags.is_enabled(flags::ROLLUPS).await {
+            continue;
+        }
+
+        // days are rolled up from the hours, so the hours go first
+        for period in [db::RollupPeriod::Hour, db::RollupPeriod::Day] {
+            match compact(&state, period).await {
+                Ok(0) => {}
+                Ok(rows) => info!("Rollup service: Wrote {} {} rollups", rows, period.name()),
+                Err(e) => error!(
+                    "Rollup service: Failed to roll up the {}s: {}",
+                    period.name(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+// rolls up all completed buckets of the period that were not rolled up yet
+async fn compact(state: &AppState, period: db::RollupPeriod) -> crate::Result<u64> {
+    let current_bucket = period.bucket(state.clock.unix_now());
+
+    let next_bucket = db::get_next_rollup_bucket(&state.pool, period)
+        .await?
+        .unwrap_or(0);
+    if next_bucket >= current_bucket {
+        return Ok(0);
+    }
+
+    db::roll_up(&state.pool, period, next_bucket, current_bucket).await
+}
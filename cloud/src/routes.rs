@@ -0,0 +1,195 @@
+use crate::{auth, handlers, openapi, versioning, AppState};
+use axum::{
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
+};
+use std::sync::Arc;
+
+// the websocket endpoint of the devices and the REST api, guarded by API keys with API_AUTH
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(handlers::health_handler))
+        .route("/healthz", get(handlers::liveness_handler))
+        .route("/readyz", get(handlers::readiness_handler))
+        .route("/ws", get(handlers::handler))
+        .route("/dashboard", get(handlers::dashboard_handler))
+        .route("/api/docs", get(openapi::docs_handler))
+        .route("/api/docs/openapi.json", get(openapi::openapi_handler))
+        .nest(
+            &format!("/api/v{}", versioning::CURRENT),
+            api(state.clone()),
+        )
+        .nest("/api", api(state.clone()))
+        .nest("/admin", admin(state.clone()))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            versioning::negotiate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_key,
+        ))
+        .with_state(state)
+}
+
+// the REST api, served under both /api/v1 and the deprecated /api, see versioning. the keys
+// of a tenant only reach its devices
+fn api(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/readings/latest", get(handlers::latest_readings_handler))
+        .route("/averages", get(handlers::averages_handler))
+        .route("/aggregates", get(handlers::aggregates_handler))
+        .route("/events", get(handlers::system_events_handler))
+        .route("/connections", get(handlers::list_connections_handler))
+        .route(
+            "/connections/:uid",
+            get(handlers::get_connection_handler).delete(handlers::delete_connection_handler),
+        )
+        .route("/stream", get(handlers::stream_handler))
+        .route("/sessions", get(handlers::list_sessions_handler))
+        .route("/alerts", get(handlers::active_alerts_handler))
+        .route(
+            "/alert-rules/:id",
+            delete(handlers::delete_alert_rule_handler),
+        )
+        .route(
+            "/devices/:uid/alert-rules",
+            get(handlers::list_alert_rules_handler).post(handlers::add_alert_rule_handler),
+        )
+        .route(
+            "/devices/:uid/alert-rules/version",
+            get(handlers::rule_version_handler),
+        )
+        .route("/attention", get(handlers::attention_handler))
+        .route("/devices", get(handlers::devices_handler))
+        .route("/geo/devices", get(handlers::geo_devices_handler))
+        .route(
+            "/geo/readings/latest",
+            get(handlers::geo_latest_readings_handler),
+        )
+        .route(
+            "/devices/:uid",
+            get(handlers::device_handler).put(handlers::register_device_handler),
+        )
+        .route(
+            "/devices/:uid/conversions",
+            get(handlers::list_conversions_handler),
+        )
+        .route(
+            "/devices/:uid/conversions/:channel",
+            put(handlers::set_conversion_handler).delete(handlers::delete_conversion_handler),
+        )
+        .route("/devices/:uid/drain", post(handlers::drain_handler))
+        .route(
+            "/devices/:uid/maintenance",
+            delete(handlers::release_maintenance_handler),
+        )
+        .route(
+            "/devices/:uid/sessions",
+            get(handlers::device_sessions_handler),
+        )
+        .route(
+            "/devices/:uid/commands",
+            get(handlers::list_commands_handler).post(handlers::add_command_handler),
+        )
+        .route(
+            "/devices/:uid/sealed",
+            get(handlers::list_sealed_handler).post(handlers::add_sealed_handler),
+        )
+        .route(
+            "/devices/:uid/kv/:key",
+            get(handlers::get_kv_handler).put(handlers::put_kv_handler),
+        )
+        .route("/devices/:uid/rollups", get(handlers::rollups_handler))
+        .route("/devices/:uid/export", get(handlers::export_handler))
+        .route("/export", get(handlers::download_handler))
+        .route("/devices/:uid/bandwidth", get(handlers::bandwidth_handler))
+        .route(
+            "/devices/:uid/stats",
+            get(handlers::connection_stats_handler),
+        )
+        .route("/devices/:uid/group", put(handlers::device_group_handler))
+        .route(
+            "/devices/:uid/attributes",
+            get(handlers::device_attributes_handler).put(handlers::set_device_attributes_handler),
+        )
+        .route(
+            "/devices/:uid/config",
+            get(handlers::device_config_handler).put(handlers::set_device_config_handler),
+        )
+        .route(
+            "/devices/:uid/pass-through",
+            put(handlers::pass_through_handler),
+        )
+        .route("/fog-nodes", get(handlers::fog_nodes_handler))
+        .route(
+            "/fog-nodes/:uid/devices",
+            get(handlers::fog_devices_handler),
+        )
+        .route(
+            "/fog-nodes/:uid/readings/latest",
+            get(handlers::fog_latest_readings_handler),
+        )
+        .route("/groups/:name", put(handlers::group_policy_handler))
+        .route("/flags", get(handlers::list_flags_handler))
+        .route("/flags/:name", put(handlers::set_flag_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state,
+            auth::require_device_in_scope,
+        ))
+}
+
+// the admin endpoints, only for the holder of the ADMIN_TOKEN
+fn admin(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/simulate-message",
+            post(handlers::simulate_message_handler),
+        )
+        .route("/purge-device", post(handlers::purge_device_handler))
+        .route("/slow-queries", get(handlers::slow_queries_handler))
+        .route(
+            "/avg-settings",
+            get(handlers::avg_settings_handler).put(handlers::update_avg_settings_handler),
+        )
+        .route(
+            "/forwarding",
+            get(handlers::forwarding_settings_handler)
+                .put(handlers::update_forwarding_settings_handler),
+        )
+        .route(
+            "/webhook-deliveries",
+            get(handlers::webhook_deliveries_handler),
+        )
+        .route("/diagnostics", get(handlers::diagnostics_handler))
+        .route("/archive", post(handlers::archive_handler))
+        .route("/ingest/status", get(handlers::ingest_status_handler))
+        .route("/ingest/flush", post(handlers::ingest_flush_handler))
+        .route(
+            "/tenants",
+            get(handlers::list_tenants_handler).post(handlers::add_tenant_handler),
+        )
+        .route(
+            "/api-keys",
+            get(handlers::list_api_keys_handler).post(handlers::add_api_key_handler),
+        )
+        .route("/api-keys/:id", delete(handlers::revoke_api_key_handler))
+        .route(
+            "/fog-nodes/:uid",
+            put(handlers::add_fog_node_handler).delete(handlers::delete_fog_node_handler),
+        )
+        .route(
+            "/fog-nodes/:uid/devices/:device",
+            put(handlers::add_fog_device_handler),
+        )
+        .route(
+            "/pairing",
+            get(handlers::pairing_handler)
+                .put(handlers::open_pairing_handler)
+                .delete(handlers::close_pairing_handler),
+        )
+        .route("/pairings", get(handlers::list_pairings_handler))
+        .route("/pairings/:uid", put(handlers::decide_pairing_handler))
+        .route_layer(middleware::from_fn_with_state(state, auth::require_admin))
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+// what the writer of a session does when its device reads slower than messages are queued
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    // the writer waits for room, so it stops fetching deliveries from the db
+    Backpressure,
+    // the oldest queued messages make room, unacknowledged deliveries among them are resent
+    DropOldest,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backpressure" => Ok(Self::Backpressure),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => Err(format!("Invalid send queue policy: {}", s)),
+        }
+    }
+}
+
+// counters over the send queues of all sessions
+#[derive(Default)]
+pub struct QueueStats {
+    dropped: AtomicU64,
+    stalls: AtomicU64,
+}
+
+impl QueueStats {
+    // messages the drop-oldest policy dropped since the server started
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // times a writer had to wait for room since the server started
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+}
+
+// bounded queue of the messages of a session, filled by its writer and emptied by the task
+// sending them on the socket. both sides are single tasks, so a stored notify permit
+// can't wake the wrong one
+pub struct SendQueue {
+    messages: Mutex<VecDeque<String>>,
+    capacity: usize,
+    policy: QueuePolicy,
+    // messages were queued or the queue was closed
+    pushed: Notify,
+    // messages were taken off the queue
+    popped: Notify,
+    closed: AtomicBool,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            policy,
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> QueuePolicy {
+        self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // queues the messages in order, returns false once the queue is closed
+    pub async fn push(&self, msgs: &[String], stats: &QueueStats) -> bool {
+        for msg in msgs {
+            loop {
+                if self.closed.load(Ordering::SeqCst) {
+                    return false;
+                }
+                {
+                    let mut messages = self.messages.lock().unwrap();
+                    if messages.len() < self.capacity {
+                        messages.push_back(msg.clone());
+                        break;
+                    }
+                    if self.policy == QueuePolicy::DropOldest {
+                        messages.pop_front();
+                        messages.push_back(msg.clone());
+                        stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                }
+                stats.stalls.fetch_add(1, Ordering::Relaxed);
+                self.popped.notified().await;
+            }
+            self.pushed.notify_one();
+        }
+        true
+    }
+
+    // waits for messages and takes up to max of them, None once closed and emptied
+    pub async fn pop(&self, max: usize) -> Option<Vec<String>> {
+        loop {
+            {
+                let mut messages = self.messages.lock().unwrap();
+                if !messages.is_empty() {
+                    let count = messages.len().min(max.max(1));
+                    let batch = messages.drain(..count).collect();
+                    drop(messages);
+                    self.popped.notify_one();
+                    return Some(batch);
+                }
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            self.pushed.notified().await;
+        }
+    }
+
+    // no more messages are queued, the queued ones are still sent
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pushed.notify_one();
+        self.popped.notify_one();
+    }
+
+    // closes the queue and drops the messages that weren't sent yet
+    pub fn discard(&self) {
+        self.messages.lock().unwrap().clear();
+        self.close();
+    }
+}
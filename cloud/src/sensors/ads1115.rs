@@ -0,0 +1,77 @@
+use i2cdev::{core::I2CDevice, linux::LinuxI2CDevice};
+use std::{thread, time::Duration};
+
+use super::{Sample, SensorError, SensorSource};
+
+const REG_CONVERSION: u8 = 0x00;
+const REG_CONFIG: u8 = 0x01;
+
+// starts a single conversion when written, reads as set once the conversion is done
+const CONFIG_OS: u16 = 0x8000;
+// single-ended input of AIN0, the input of AINx is this plus x
+const CONFIG_MUX_AIN0: u16 = 0x4000;
+// full scale range of ±4.096V, enough for sensors running on 3.3V
+const CONFIG_PGA: u16 = 0x0200;
+const FULL_SCALE_VOLTS: f64 = 4.096;
+// single-shot mode, 128 samples per second, comparator disabled
+const CONFIG_MODE: u16 = 0x0100 | 0x0080 | 0x0003;
+
+const INPUTS: u16 = 4;
+// a conversion at 128 samples per second takes 7.8ms
+const CONVERSION_TIME: Duration = Duration::from_millis(8);
+const MAX_POLLS: usize = 10;
+
+// volts of a conversion result
+pub fn to_volts(raw: i16) -> f64 {
+    raw as f64 * FULL_SCALE_VOLTS / 32768.0
+}
+
+// TI ADS1115 analog to digital converter, its four single-ended inputs are the channels
+// ain0 to ain3 in volts
+pub struct Ads1115 {
+    device: LinuxI2CDevice,
+}
+
+impl Ads1115 {
+    pub fn open(bus: &str, address: u16) -> Result<Self, SensorError> {
+        let mut device = LinuxI2CDevice::new(bus, address)?;
+        // the config register has a known value after reset, reading it checks the chip answers
+        device.write(&[REG_CONFIG])?;
+        let mut config = [0; 2];
+        device.read(&mut config)?;
+        Ok(Self { device })
+    }
+
+    fn convert(&mut self, input: u16) -> Result<i16, SensorError> {
+        let config = CONFIG_OS | (CONFIG_MUX_AIN0 + (input << 12)) | CONFIG_PGA | CONFIG_MODE;
+        let [hi, lo] = config.to_be_bytes();
+        self.device.write(&[REG_CONFIG, hi, lo])?;
+
+        let mut status = [0; 2];
+        for _ in 0..MAX_POLLS {
+            thread::sleep(CONVERSION_TIME);
+            self.device.write(&[REG_CONFIG])?;
+            self.device.read(&mut status)?;
+            if u16::from_be_bytes(status) & CONFIG_OS != 0 {
+                let mut result = [0; 2];
+                self.device.write(&[REG_CONVERSION])?;
+                self.device.read(&mut result)?;
+                return Ok(i16::from_be_bytes(result));
+            }
+        }
+        Err(format!("conversion of ain{} timed out", input).into())
+    }
+}
+
+impl SensorSource for Ads1115 {
+    fn sample(&mut self) -> Result<Vec<Sample>, SensorError> {
+        (0..INPUTS)
+            .map(|input| {
+                Ok(Sample {
+                    channel: format!("ain{}", input),
+                    value: to_volts(self.convert(input)?),
+                })
+            })
+            .collect()
+    }
+}
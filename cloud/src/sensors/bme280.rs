@@ -0,0 +1,173 @@
+use i2cdev::{core::I2CDevice, linux::LinuxI2CDevice};
+
+use super::{Sample, SensorError, SensorSource};
+
+const CHIP_ID: u8 = 0x60;
+
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_CALIB_TP: u8 = 0x88;
+const REG_CALIB_H2: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_CONFIG: u8 = 0xF5;
+const REG_DATA: u8 = 0xF7;
+
+// oversampling x1 for every measurement, normal mode measuring every second
+const CTRL_HUM: u8 = 0x01;
+const CTRL_MEAS: u8 = 0x27;
+const CONFIG: u8 = 0xA0;
+
+// trimming parameters burned into the chip, see section 4.2.2 of the datasheet
+#[derive(Clone, Debug, Default)]
+pub struct Calibration {
+    pub t1: u16,
+    pub t2: i16,
+    pub t3: i16,
+    pub p1: u16,
+    pub p2: i16,
+    pub p3: i16,
+    pub p4: i16,
+    pub p5: i16,
+    pub p6: i16,
+    pub p7: i16,
+    pub p8: i16,
+    pub p9: i16,
+    pub h1: u8,
+    pub h2: i16,
+    pub h3: u8,
+    pub h4: i16,
+    pub h5: i16,
+    pub h6: i8,
+}
+
+impl Calibration {
+    // from the 26 bytes at 0x88, the last of which is h1, and the 7 bytes at 0xE1
+    pub fn from_registers(tp: &[u8; 26], h: &[u8; 7]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]);
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            h1: tp[25],
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            // h4 and h5 share the nibbles of 0xE5
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        }
+    }
+
+    // degrees celsius, pascal and percent relative humidity from the raw adc values,
+    // with the floating point formulas of section 8.1 of the datasheet
+    pub fn compensate(&self, adc_t: i32, adc_p: i32, adc_h: i32) -> (f64, f64, f64) {
+        let (adc_t, adc_p, adc_h) = (adc_t as f64, adc_p as f64, adc_h as f64);
+
+        let var1 = (adc_t / 16384.0 - self.t1 as f64 / 1024.0) * self.t2 as f64;
+        let var2 = (adc_t / 131072.0 - self.t1 as f64 / 8192.0).powi(2) * self.t3 as f64;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * self.p6 as f64 / 32768.0;
+        var2 += var1 * self.p5 as f64 * 2.0;
+        var2 = var2 / 4.0 + self.p4 as f64 * 65536.0;
+        var1 = (self.p3 as f64 * var1 * var1 / 524288.0 + self.p2 as f64 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * self.p1 as f64;
+        // avoids a division by zero on a chip without calibration
+        let pressure = if var1 == 0.0 {
+            0.0
+        } else {
+            let mut p = 1048576.0 - adc_p;
+            p = (p - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = self.p9 as f64 * p * p / 2147483648.0;
+            let var2 = p * self.p8 as f64 / 32768.0;
+            p + (var1 + var2 + self.p7 as f64) / 16.0
+        };
+
+        let mut h = t_fine - 76800.0;
+        h = (adc_h - (self.h4 as f64 * 64.0 + self.h5 as f64 / 16384.0 * h))
+            * (self.h2 as f64 / 65536.0
+                * (1.0
+                    + self.h6 as f64 / 67108864.0 * h * (1.0 + self.h3 as f64 / 67108864.0 * h)));
+        h *= 1.0 - self.h1 as f64 * h / 524288.0;
+        let humidity = h.clamp(0.0, 100.0);
+
+        (temperature, pressure, humidity)
+    }
+}
+
+// Bosch BME280 temperature, pressure and humidity sensor
+pub struct Bme280 {
+    device: LinuxI2CDevice,
+    calibration: Calibration,
+}
+
+impl Bme280 {
+    pub fn open(bus: &str, address: u16) -> Result<Self, SensorError> {
+        let mut device = LinuxI2CDevice::new(bus, address)?;
+        let chip_id = device.smbus_read_byte_data(REG_CHIP_ID)?;
+        if chip_id != CHIP_ID {
+            return Err(format!("no BME280 at {:#x}, chip id {:#x}", address, chip_id).into());
+        }
+
+        let tp = read_block::<26>(&mut device, REG_CALIB_TP)?;
+        let h = read_block::<7>(&mut device, REG_CALIB_H2)?;
+
+        // the humidity setting only applies after ctrl_meas is written
+        device.smbus_write_byte_data(REG_CTRL_HUM, CTRL_HUM)?;
+        device.smbus_write_byte_data(REG_CONFIG, CONFIG)?;
+        device.smbus_write_byte_data(REG_CTRL_MEAS, CTRL_MEAS)?;
+
+        Ok(Self {
+            device,
+            calibration: Calibration::from_registers(&tp, &h),
+        })
+    }
+}
+
+impl SensorSource for Bme280 {
+    fn sample(&mut self) -> Result<Vec<Sample>, SensorError> {
+        let data = read_block::<8>(&mut self.device, REG_DATA)?;
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | (data[2] as i32 >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | (data[5] as i32 >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        let (temperature, pressure, humidity) = self.calibration.compensate(adc_t, adc_p, adc_h);
+        Ok(vec![
+            Sample {
+                channel: "temperature".to_string(),
+                value: temperature,
+            },
+            Sample {
+                channel: "pressure".to_string(),
+                // hPa, like weather stations report it
+                value: pressure / 100.0,
+            },
+            Sample {
+                channel: "humidity".to_string(),
+                value: humidity,
+            },
+        ])
+    }
+}
+
+// reads N consecutive registers, a short read is an error
+fn read_block<const N: usize>(
+    device: &mut LinuxI2CDevice,
+    register: u8,
+) -> Result<[u8; N], SensorError> {
+    let data = device.smbus_read_i2c_block_data(register, N as u8)?;
+    data.try_into()
+        .map_err(|_| format!("short read at register {:#x}", register).into())
+}
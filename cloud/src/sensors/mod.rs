@@ -0,0 +1,166 @@
+use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{ChannelCalibration, Config, LocalSensor},
+    db, handlers, protocols, AppState,
+};
+
+#[cfg(feature = "ads1115")]
+pub mod ads1115;
+#[cfg(feature = "bme280")]
+pub mod bme280;
+
+pub type SensorError = Box<dyn Error + Send + Sync>;
+
+// the value of one channel of a sample
+#[derive(Clone, PartialEq, Debug)]
+pub struct Sample {
+    pub channel: String,
+    pub value: f64,
+}
+
+// a sensor attached to the server itself, so it originates readings instead of only relaying
+// them. sampling blocks, the scheduler runs it on the blocking pool
+pub trait SensorSource: Send + 'static {
+    // reads every channel of the sensor once
+    fn sample(&mut self) -> Result<Vec<Sample>, SensorError>;
+}
+
+// opens the driver of a configured sensor, fails for drivers this build does not include
+pub fn open(sensor: &LocalSensor) -> Result<Box<dyn SensorSource>, SensorError> {
+    match sensor.driver {
+        #[cfg(feature = "bme280")]
+        crate::config::SensorDriver::Bme280 => {
+            Ok(Box::new(bme280::Bme280::open(&sensor.bus, sensor.address)?))
+        }
+        #[cfg(feature = "ads1115")]
+        crate::config::SensorDriver::Ads1115 => Ok(Box::new(ads1115::Ads1115::open(
+            &sensor.bus,
+            sensor.address,
+        )?)),
+        #[allow(unreachable_patterns)]
+        driver => Err(format!("the server was built without the {:?} driver", driver).into()),
+    }
+}
+
+// when a local sensor is sampled and how its readings are corrected
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    pub period: Duration,
+    // longest random delay of each sample
+    pub jitter: Duration,
+    // channels without calibration are stored as read
+    pub channels: HashMap<String, ChannelCalibration>,
+}
+
+impl Schedule {
+    pub fn for_sensor(sensor: &LocalSensor, config: &Config) -> Self {
+        let channels = config
+            .local_sensor_channels
+            .iter()
+            .flat_map(|channels| &channels.0)
+            .filter(|channel| channel.uid == sensor.uid)
+            .map(|channel| (channel.channel.clone(), channel.calibration))
+            .collect();
+        Self {
+            period: Duration::from_secs(
+                sensor
+                    .interval_secs
+                    .unwrap_or(config.local_sensor_interval_secs),
+            ),
+            jitter: Duration::from_millis(config.local_sensor_jitter_ms),
+            channels,
+        }
+    }
+
+    // a random delay up to the jitter, never more than half the period
+    fn delay(&self) -> Duration {
+        let max = self.jitter.min(self.period / 2).as_millis();
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        // v4 uuids are random enough to spread samples
+        Duration::from_millis((uuid::Uuid::new_v4().as_u128() % (max + 1)) as u64)
+    }
+}
+
+// samples a sensor on its schedule and stores the calibrated readings like those of
+// networked sensors, stamped with the server clock
+pub async fn sample_service(
+    state: Arc<AppState>,
+    uid: String,
+    mut source: Box<dyn SensorSource>,
+    schedule: Schedule,
+) {
+    if let Err(e) = register(&state, &uid).await {
+        error!("Error registering {}: {}", uid, e);
+        return;
+    }
+    info!("Sampling {} every {:?}", uid, schedule.period);
+
+    let mut interval = tokio::time::interval(schedule.period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        tokio::time::sleep(schedule.delay()).await;
+
+        let sampled = tokio::task::spawn_blocking(move || {
+            let samples = source.sample();
+            (source, samples)
+        })
+        .await;
+        let samples = match sampled {
+            Ok((returned, samples)) => {
+                source = returned;
+                samples
+            }
+            // the source went down with the sampling, the supervisor opens the sensor again
+            Err(_) => panic!("Sampling {} panicked", uid),
+        };
+
+        let samples = match samples {
+            Ok(samples) => samples,
+            Err(e) => {
+                warn!("Could not sample {}: {}", uid, e);
+                continue;
+            }
+        };
+        let timestamp = db::unix_now();
+        for sample in samples {
+            let calibration = schedule
+                .channels
+                .get(&sample.channel)
+                .copied()
+                .unwrap_or_default();
+            let Some(data) = calibration.apply(sample.value) else {
+                warn!(
+                    "Discarding {} of {} out of its valid range: {}",
+                    sample.channel, uid, sample.value
+                );
+                continue;
+            };
+            let sensor_data = protocols::SensorMsg {
+                uid: uid.clone(),
+                data,
+                timestamp,
+                channel: sample.channel,
+                alarm: false,
+                seq: None,
+                via: None,
+            };
+            handlers::ingest_sensor(&state, sensor_data).await;
+        }
+    }
+}
+
+// creates the connection of a sensor attached to the server, which never sends CONN.
+// a sensor that was disconnected keeps its history
+pub(crate) async fn register(state: &AppState, uid: &str) -> crate::Result<()> {
+    let connection = db::upsert_connection(&state.pool, uid, state.clock.unix_now()).await?;
+    if connection.disconnected_at.is_some() {
+        db::reconnect_connection(&state.pool, uid).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use crate::protocols::{ParseError, SensorMsg};
+
+// parses a reading of a local sensor, one per line as <data>[#<channel>[#alarm]].
+// serial sensors have no clock, readings are stamped with the time they were read
+pub fn parse_line(uid: &str, line: &str, now: i64) -> Result<SensorMsg, ParseError> {
+    SensorMsg::from_msg(&format!("SENSOR#{}#{}#{}", uid, now, line.trim()))
+}
+
+#[cfg(feature = "serial")]
+pub use reader::listen;
+
+#[cfg(feature = "serial")]
+mod reader {
+    use crate::{config::SerialPort, db, handlers, sensors, AppState};
+    use futures_util::StreamExt;
+    use std::{sync::Arc, time::Duration};
+    use tokio_serial::{SerialPortBuilderExt, SerialStream};
+    use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+    use tracing::{error, info, warn};
+
+    // longest line a sensor may send, longer ones are skipped
+    const MAX_LINE_LEN: usize = 256;
+    // seconds before a port that could not be opened or was closed is opened again
+    const REOPEN_DELAY_SECS: u64 = 5;
+
+    // reads the readings of a local sensor and stores them like those of networked sensors.
+    // the port is opened again when the sensor is unplugged
+    pub async fn listen(state: Arc<AppState>, port: SerialPort) {
+        loop {
+            match tokio_serial::new(&port.path, port.baud_rate).open_native_async() {
+                Ok(stream) => {
+                    info!("Reading {} from serial port {}", port.uid, port.path);
+                    read_port(&state, &port, stream).await;
+                    warn!("Serial port {} closed", port.path);
+                }
+                Err(e) => error!("Could not open serial port {}: {}", port.path, e),
+            }
+            tokio::time::sleep(Duration::from_secs(REOPEN_DELAY_SECS)).await;
+        }
+    }
+
+    async fn read_port(state: &AppState, port: &SerialPort, stream: SerialStream) {
+        // the sensor never sends CONN, the port counts as its connection
+        if let Err(e) = sensors::register(state, &port.uid).await {
+            error!("Error registering {}: {}", port.uid, e);
+            return;
+        }
+
+        let mut lines = FramedRead::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LEN));
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    warn!("Skipping a too long line from {}", port.path);
+                    continue;
+                }
+                Err(LinesCodecError::Io(e)) => {
+                    error!("Error reading serial port {}: {}", port.path, e);
+                    return;
+                }
+            };
+            // sensors often print blank lines or a banner when they start
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match super::parse_line(&port.uid, &line, db::unix_now()) {
+                Ok(sensor_data) => handlers::ingest_sensor(state, sensor_data).await,
+                Err(e) => warn!("Invalid reading from {}: {}", port.path, e),
+            }
+        }
+    }
+}
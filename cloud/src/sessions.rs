@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Mutex},
+    task::AbortHandle,
+};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{config::DuplicateSessions, protocols::TimestampFormat, send_queue::SendQueue};
+
+// instructions for a live websocket session
+#[derive(Debug)]
+pub enum Control {
+    // finish pending deliveries, close the websocket and put the device in maintenance
+    Drain,
+    // finish pending deliveries and close the websocket, the server shuts down
+    Shutdown,
+    // close the websocket right away
+    Close,
+    // send a protocol message to the device right away
+    Send(String),
+    // deliver the queued messages right away instead of on the next tick
+    Deliver,
+}
+
+pub struct SessionHandle {
+    pub control: UnboundedSender<Control>,
+    // id of the session in the db
+    pub session_id: String,
+    // unix timestamp of the CONN message
+    pub connected_at: i64,
+    // how the device writes the timestamps of its readings
+    pub timestamps: TimestampFormat,
+    // reader and writer tasks of the websocket
+    pub tasks: Vec<AbortHandle>,
+    // messages waiting to be sent to the device
+    pub queue: Arc<SendQueue>,
+    // counters of the connection, shared with the reader and the sender
+    pub traffic: Arc<Traffic>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct SessionInfo {
+    pub uid: String,
+    pub session_id: String,
+    pub connected_at: i64,
+    // messages waiting to be sent, the device reads slowly if it stays near the capacity
+    pub queued: usize,
+    pub stats: TrafficStats,
+}
+
+// live websocket sessions by uid
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, SessionHandle>>,
+    // uids of CONNs that passed the duplicate check and aren't registered yet
+    claimed: std::sync::Mutex<HashSet<String>>,
+    // set once the server shuts down, no new sessions are accepted from then on
+    closing: AtomicBool,
+}
+
+// the uid of a CONN that is being set up, given up when it is dropped before the session
+// was registered
+pub struct Claim<'a> {
+    sessions: &'a Sessions,
+    uid: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.sessions.claimed.lock().unwrap().remove(&self.uid);
+    }
+}
+
+impl Sessions {
+    // claims the uid for a new session, before anything of it is stored. with reject-new
+    // there is no claim while the uid has a live session or another CONN of it is set up
+    pub async fn claim(&self, uid: &str, policy: DuplicateSessions) -> Option<Claim<'_>> {
+        let sessions = self.sessions.lock().await;
+        let mut claimed = self.claimed.lock().unwrap();
+        if policy == DuplicateSessions::RejectNew
+            && (sessions.contains_key(uid) || claimed.contains(uid))
+        {
+            return None;
+        }
+        claimed.insert(uid.to_string());
+        Some(Claim {
+            sessions: self,
+            uid: uid.to_string(),
+        })
+    }
+
+    // registers the session of a claimed uid. a session replacing an older one of the same
+    // uid ends it right away: nothing more is read from or sent over the old socket, its
+    // unacknowledged deliveries go out over the new one
+    pub async fn register(&self, claim: Claim<'_>, handle: SessionHandle) {
+        let mut sessions = self.sessions.lock().await;
+        let replaced = sessions.insert(claim.uid.clone(), handle);
+        drop(sessions);
+        if let Some(replaced) = replaced {
+            warn!(
+                "Session {} of {} replaced by a new connection",
+                replaced.session_id, claim.uid
+            );
+            replaced.queue.discard();
+            for task in replaced.tasks {
+                task.abort();
+            }
+        }
+    }
+
+    // removes the session, unless it was already replaced by a newer one with the same uid
+    pub async fn unregister(&self, uid: &str, control: &UnboundedSender<Control>) {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(handle) = sessions.get(uid) {
+            if handle.control.same_channel(control) {
+                sessions.remove(uid);
+            }
+        }
+    }
+
+    pub async fn count(&self) -> usize {
+        self.sessions.lock().await.len()
+    }
+
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let mut infos: Vec<SessionInfo> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(uid, handle)| SessionInfo {
+                uid: uid.clone(),
+                session_id: handle.session_id.clone(),
+                connected_at: handle.connected_at,
+                queued: handle.queue.len(),
+                stats: handle.traffic.stats(),
+            })
+            .collect();
+        infos.sort_by(|a, b| a.uid.cmp(&b.uid));
+        infos
+    }
+
+    // session id and counters of a live session
+    pub async fn stats(&self, uid: &str) -> Option<(String, TrafficStats)> {
+        self.sessions
+            .lock()
+            .await
+            .get(uid)
+            .map(|handle| (handle.session_id.clone(), handle.traffic.stats()))
+    }
+
+    // the timestamp format the live session of the uid negotiated
+    pub async fn timestamps(&self, uid: &str) -> Option<TimestampFormat> {
+        self.sessions
+            .lock()
+            .await
+            .get(uid)
+            .map(|handle| handle.timestamps)
+    }
+
+    pub async fn is_connected(&self, uid: &str) -> bool {
+        self.sessions.lock().await.contains_key(uid)
+    }
+
+    // sends a control instruction to a live session, returns false if the uid is not connected
+    pub async fn send(&self, uid: &str, control: Control) -> bool {
+        match self.sessions.lock().await.get(uid) {
+            Some(handle) => handle.control.send(control).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    // stops accepting sessions and asks the live ones to finish their pending deliveries
+    // before they close. sessions still open after the timeout are aborted
+    pub async fn shut_down(&self, timeout: Duration) {
+        self.closing.store(true, Ordering::Relaxed);
+        self.close_with(|| Control::Shutdown, timeout).await;
+    }
+
+    // asks all live sessions to close, sessions still open after the timeout are aborted
+    pub async fn close_all(&self, timeout: Duration) {
+        self.close_with(|| Control::Close, timeout).await;
+    }
+
+    async fn close_with(&self, control: impl Fn() -> Control, timeout: Duration) {
+        for handle in self.sessions.lock().await.values() {
+            let _ = handle.control.send(control());
+        }
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.count().await > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let mut sessions = self.sessions.lock().await;
+        for (uid, handle) in sessions.drain() {
+            warn!("Session {} did not close in time, aborting it", uid);
+            for task in handle.tasks {
+                task.abort();
+            }
+        }
+    }
+}
+
+// counters of a session since it connected, kept in memory and persisted with the
+// bandwidth samples, so counting doesn't write to the db for every message
+#[derive(Default)]
+pub struct Traffic {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // bytes of the protocol messages before compression and encoding
+    raw_bytes_in: AtomicU64,
+    raw_bytes_out: AtomicU64,
+    parse_errors: AtomicU64,
+    // unix timestamp of the last frame received, 0 before the first one
+    last_activity: AtomicI64,
+    // bytes already taken for a bandwidth sample
+    sampled_in: AtomicU64,
+    sampled_out: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub struct TrafficStats {
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // size of the text protocol messages the frames carried, larger than the bytes on the wire
+    // with compression
+    pub raw_bytes_in: u64,
+    pub raw_bytes_out: u64,
+    // frames that were undecodable or not a valid message
+    pub parse_errors: u64,
+    pub last_activity: Option<i64>,
+}
+
+impl Traffic {
+    // a frame received at the unix timestamp
+    pub fn add_in(&self, bytes: usize, now: i64) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity.store(now, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // the protocol messages a frame carried, counted after it was decoded
+    pub fn add_raw_in(&self, bytes: usize) {
+        self.raw_bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // the protocol messages of frames, counted before they are encoded
+    pub fn add_raw_out(&self, bytes: usize) {
+        self.raw_bytes_out
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // returns the bytes in and out since the last call
+    pub fn take(&self) -> (u64, u64) {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.bytes_out.load(Ordering::Relaxed);
+        (
+            bytes_in - self.sampled_in.swap(bytes_in, Ordering::Relaxed),
+            bytes_out - self.sampled_out.swap(bytes_out, Ordering::Relaxed),
+        )
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        let last_activity = self.last_activity.load(Ordering::Relaxed);
+        TrafficStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            raw_bytes_in: self.raw_bytes_in.load(Ordering::Relaxed),
+            raw_bytes_out: self.raw_bytes_out.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+            last_activity: (last_activity > 0).then_some(last_activity),
+        }
+    }
+}
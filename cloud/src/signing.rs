@@ -0,0 +1,136 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// longest nonce a device may use
+const MAX_NONCE_LEN: usize = 64;
+
+// SIGNED#<timestamp>#<nonce>#<hex hmac>#<message>
+// the hmac is computed over "<timestamp>#<nonce>#<message>" with the key of the device
+pub struct SignedMsg {
+    pub timestamp: i64,
+    pub nonce: String,
+    pub mac: Vec<u8>,
+    pub message: String,
+}
+
+impl SignedMsg {
+    pub fn from_msg(msg: &str) -> Result<Self, Box<dyn Error>> {
+        // the signed message itself contains '#', so only split off the header fields
+        let parts: Vec<&str> = msg.splitn(5, '#').collect();
+
+        if parts.len() != 5 {
+            return Err("Invalid message".into());
+        }
+
+        if parts[0] != "SIGNED" {
+            return Err("Invalid protocol".into());
+        }
+
+        let nonce = parts[2];
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err("Invalid nonce".into());
+        }
+
+        Ok(Self {
+            timestamp: parts[1].parse()?,
+            nonce: nonce.to_string(),
+            mac: hex::decode(parts[3])?,
+            message: parts[4].to_string(),
+        })
+    }
+
+    fn signed_part(&self) -> String {
+        format!("{}#{}#{}", self.timestamp, self.nonce, self.message)
+    }
+
+    // checks the hmac in constant time
+    pub fn verify(&self, key: &[u8]) -> bool {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(self.signed_part().as_bytes());
+        mac.verify_slice(&self.mac).is_ok()
+    }
+}
+
+// signs a message the way a device does, mostly useful for testing clients
+pub fn sign(key: &[u8], timestamp: i64, nonce: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}#{}#{}", timestamp, nonce, message).as_bytes());
+    let mac = hex::encode(mac.finalize().into_bytes());
+    format!("SIGNED#{}#{}#{}#{}", timestamp, nonce, mac, message)
+}
+
+// every device signs with its own key, derived from the server secret and its uid,
+// so a leaked device key can't be used to sign messages of other devices
+pub fn device_key(secret: &str, uid: &str) -> Vec<u8> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(uid.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    // the timestamp is outside of the replay window
+    Stale,
+    // the nonce was already used by the device within the replay window
+    Replayed,
+}
+
+// nonces seen per device within the replay window.
+// older messages are rejected by their timestamp, so their nonces can be forgotten
+#[derive(Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl ReplayGuard {
+    // accepts a signed message at most once while its timestamp is within window_secs of now
+    pub async fn check(
+        &self,
+        uid: &str,
+        msg: &SignedMsg,
+        window_secs: i64,
+    ) -> Result<(), Rejection> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        if (msg.timestamp - now).abs() > window_secs {
+            return Err(Rejection::Stale);
+        }
+
+        let mut seen = self.seen.lock().await;
+        let nonces = seen.entry(uid.to_string()).or_default();
+        // slide the window forward
+        nonces.retain(|_, timestamp| (*timestamp - now).abs() <= window_secs);
+
+        if nonces.contains_key(&msg.nonce) {
+            return Err(Rejection::Replayed);
+        }
+        nonces.insert(msg.nonce.clone(), msg.timestamp);
+        Ok(())
+    }
+
+    // drops nonces that left the window, so devices that stopped sending don't keep memory
+    pub async fn forget_stale(&self, window_secs: i64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let mut seen = self.seen.lock().await;
+        for nonces in seen.values_mut() {
+            nonces.retain(|_, timestamp| (*timestamp - now).abs() <= window_secs);
+        }
+        seen.retain(|_, nonces| !nonces.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+use futures_util::future::BoxFuture;
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+use super::{Sink, SinkError};
+use crate::events::StreamEvent;
+
+// appends the events to a file as ndjson, like the dashboard stream sends them
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> Result<Self, SinkError> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn write<'a>(&'a self, events: &'a [StreamEvent]) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let mut lines = Vec::new();
+            for event in events {
+                serde_json::to_writer(&mut lines, event)?;
+                lines.push(b'\n');
+            }
+            let mut file = self.file.lock().await;
+            file.write_all(&lines).await?;
+            file.flush().await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use futures_util::future::BoxFuture;
+use hyper::{client::HttpConnector, header, Body, Client, Request};
+use std::time::Duration;
+
+use super::{Sink, SinkError};
+use crate::events::StreamEvent;
+
+// seconds InfluxDB gets to take a batch
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+// posts the events to the write endpoint of InfluxDB as line protocol. readings go to the
+// readings measurement tagged by uid, channel and the attributes of the device, averages to
+// averages tagged by channel and tenant
+pub struct InfluxSink {
+    url: String,
+    token: Option<String>,
+    client: Client<HttpConnector>,
+}
+
+impl InfluxSink {
+    pub fn new(url: &str, token: Option<String>) -> Self {
+        Self {
+            url: url.to_string(),
+            token,
+            client: Client::new(),
+        }
+    }
+}
+
+// commas, equal signs and spaces separate the parts of a line
+fn escape(tag: &str) -> String {
+    tag.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// the event as a line of the line protocol, timestamps in nanoseconds
+pub fn line(event: &StreamEvent) -> String {
+    match event {
+        StreamEvent::Sensor {
+            uid,
+            timestamp,
+            data,
+            channel,
+            alarm,
+            attributes,
+            ..
+        } => {
+            // empty tag values are not allowed
+            let attributes: String = attributes
+                .iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| format!(",{}={}", escape(name), escape(value)))
+                .collect();
+            format!(
+                "readings,uid={},channel={}{} value={},alarm={} {}",
+                escape(uid),
+                escape(channel),
+                attributes,
+                data,
+                alarm,
+                timestamp * 1_000_000_000
+            )
+        }
+        StreamEvent::Avg {
+            timestamp,
+            data,
+            channel,
+            tenant,
+        } => {
+            // empty tag values are not allowed
+            let tenant = tenant
+                .as_deref()
+                .filter(|t| !t.is_empty())
+                .map(|t| format!(",tenant={}", escape(t)))
+                .unwrap_or_default();
+            format!(
+                "averages,channel={}{} value={} {}",
+                escape(channel),
+                tenant,
+                data,
+                timestamp * 1_000_000_000
+            )
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn write<'a>(&'a self, events: &'a [StreamEvent]) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let body = events.iter().map(line).collect::<Vec<_>>().join("\n");
+            let mut req = Request::post(&self.url).header(header::CONTENT_TYPE, "text/plain");
+            if let Some(token) = &self.token {
+                req = req.header(header::AUTHORIZATION, format!("Token {}", token));
+            }
+            let req = req.body(Body::from(body))?;
+
+            let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+            let res = tokio::time::timeout(timeout, self.client.request(req))
+                .await
+                .map_err(|_| "timed out")??;
+            if !res.status().is_success() {
+                return Err(format!("InfluxDB answered {}", res.status()).into());
+            }
+            Ok(())
+        })
+    }
+}
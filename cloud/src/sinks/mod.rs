@@ -0,0 +1,152 @@
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{
+    broadcast::error::RecvError,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::{error, info, warn};
+
+use crate::{config::SinkUrl, events::StreamEvent, AppState};
+
+pub mod file;
+pub mod influx;
+
+// events written to a sink at once
+const MAX_BATCH: usize = 500;
+// pause before a failed batch is written again, doubled up to the maximum
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub type SinkError = Box<dyn Error + Send + Sync>;
+
+// a downstream system readings and averages are exported to. the dispatcher buffers and
+// retries, so a sink only writes
+pub trait Sink: Send + Sync + 'static {
+    // writes a batch of events, a batch that failed is written again as a whole
+    fn write<'a>(&'a self, events: &'a [StreamEvent]) -> BoxFuture<'a, Result<(), SinkError>>;
+}
+
+// opens the sink of a configured url
+pub fn open(url: &SinkUrl, state: &AppState) -> Result<Box<dyn Sink>, SinkError> {
+    match url {
+        SinkUrl::File(path) => Ok(Box::new(file::FileSink::open(path)?)),
+        SinkUrl::Influx(url) => Ok(Box::new(influx::InfluxSink::new(
+            url,
+            state
+                .config
+                .sink_influx_token
+                .as_ref()
+                .map(|token| token.expose().to_string()),
+        ))),
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SinkStats {
+    pub written: u64,
+    pub failed_writes: u64,
+    // events dropped because the buffer of the sink was full
+    pub dropped: u64,
+}
+
+// how exporting to the sinks goes, by the url of the sink
+#[derive(Default)]
+pub struct Sinks {
+    stats: Mutex<BTreeMap<String, SinkStats>>,
+}
+
+impl Sinks {
+    fn update(&self, name: &str, update: impl FnOnce(&mut SinkStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        update(stats.entry(name.to_string()).or_default());
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, SinkStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+// fans the readings and averages of the event bus out to the configured sinks. every sink
+// has a buffer of its own, so a sink that is down doesn't hold up the others
+pub async fn sink_service(state: Arc<AppState>) {
+    let Some(urls) = state.config.sink_urls.clone() else {
+        return;
+    };
+    let mut events = state.events.subscribe();
+
+    let mut buffers = Vec::new();
+    for url in urls.0 {
+        let name = url.to_string();
+        let sink = match open(&url, &state) {
+            Ok(sink) => sink,
+            Err(e) => {
+                error!("Could not open the sink {}: {}", name, e);
+                continue;
+            }
+        };
+        info!("Exporting readings and averages to {}", name);
+        let (sender, receiver) = mpsc::channel(state.config.sink_buffer.max(1));
+        tokio::spawn(drain(state.clone(), name.clone(), sink, receiver));
+        buffers.push((name, sender));
+    }
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Sink dispatcher fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        for (name, sender) in &buffers {
+            match sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => state.sinks.update(name, |s| s.dropped += 1),
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+    }
+}
+
+// writes the buffered events of a sink in batches, retrying a failed batch with a backoff
+async fn drain(
+    state: Arc<AppState>,
+    name: String,
+    sink: Box<dyn Sink>,
+    mut buffer: mpsc::Receiver<StreamEvent>,
+) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while buffer.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match sink.write(&batch).await {
+                Ok(()) => {
+                    state
+                        .sinks
+                        .update(&name, |s| s.written += batch.len() as u64);
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "Error writing {} events to {}, retrying in {:?}: {}",
+                        batch.len(),
+                        name,
+                        backoff,
+                        e
+                    );
+                    state.sinks.update(&name, |s| s.failed_writes += 1);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        batch.clear();
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+// queries of the db module taking at least the threshold, aggregated per query
+pub struct SlowQueries {
+    threshold_ms: AtomicU64,
+    stats: Mutex<BTreeMap<&'static str, SlowQuery>>,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SlowQuery {
+    pub query: &'static str,
+    // slow runs of the query and their durations in milliseconds
+    pub count: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    pub last_ms: u64,
+}
+
+impl SlowQueries {
+    pub const fn new(threshold_ms: u64) -> Self {
+        Self {
+            threshold_ms: AtomicU64::new(threshold_ms),
+            stats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_threshold(&self, threshold: Duration) {
+        self.threshold_ms
+            .store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // logs and counts the query if it was slow
+    pub fn record(&self, query: &'static str, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        if ms < self.threshold_ms.load(Ordering::Relaxed) {
+            return;
+        }
+        warn!("Slow query {} took {} ms", query, ms);
+
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = stats.entry(query).or_insert(SlowQuery {
+            query,
+            count: 0,
+            total_ms: 0,
+            max_ms: 0,
+            last_ms: 0,
+        });
+        entry.count += 1;
+        entry.total_ms += ms;
+        entry.max_ms = entry.max_ms.max(ms);
+        entry.last_ms = ms;
+    }
+
+    // the queries that took the most time being slow, slowest first
+    pub fn top(&self, limit: usize) -> Vec<SlowQuery> {
+        let stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let mut top: Vec<SlowQuery> = stats.values().cloned().collect();
+        top.sort_by_key(|q| std::cmp::Reverse(q.total_ms));
+        top.truncate(limit);
+        top
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{lines, protocols, AppState};
+use axum::Router;
+use hyper::server::conn::Http;
+use std::{error::Error, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+// bytes peeked at to tell the protocols apart, enough for the headers of a websocket upgrade
+const SNIFF_LEN: usize = 2048;
+// seconds a client gets to send its first bytes
+const SNIFF_TIMEOUT_SECS: u64 = 5;
+// pause between peeks while a client has sent too little to tell its protocol
+const SNIFF_RETRY: Duration = Duration::from_millis(10);
+
+const HTTP_METHODS: [&str; 7] = ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"];
+
+// protocols a client can speak on the sniffed port
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sniffed {
+    // a websocket upgrade, served by the http server like any other request
+    WebSocket,
+    // a call of the REST api
+    Http,
+    // the line protocol of devices that can't do websockets
+    Lines,
+    Unknown,
+}
+
+// tells the protocol of a client apart by the first bytes it sent, None if they don't tell yet
+pub fn sniff(prefix: &[u8]) -> Option<Sniffed> {
+    // http requests start with a method
+    for method in HTTP_METHODS {
+        let token = format!("{} ", method);
+        if prefix.starts_with(token.as_bytes()) {
+            let head = String::from_utf8_lossy(prefix).to_ascii_lowercase();
+            return match head.find("\r\n\r\n") {
+                Some(end) if head[..end].contains("upgrade: websocket") => Some(Sniffed::WebSocket),
+                Some(_) => Some(Sniffed::Http),
+                // the headers are too long to peek at, they are no websocket upgrade then
+                None if prefix.len() >= SNIFF_LEN => Some(Sniffed::Http),
+                None => None,
+            };
+        }
+        if !prefix.is_empty() && token.as_bytes().starts_with(prefix) {
+            return None;
+        }
+    }
+
+    // messages of the line protocol start with a header like CONN#
+    match prefix.iter().position(|&b| b == b'#' || b == b'\n') {
+        Some(end) if prefix[end] == b'#' => {
+            let header = String::from_utf8_lossy(&prefix[..end]);
+            match protocols::get_protocol(&header) {
+                Ok(_) => Some(Sniffed::Lines),
+                Err(_) => Some(Sniffed::Unknown),
+            }
+        }
+        Some(_) => Some(Sniffed::Unknown),
+        None if prefix.len() >= SNIFF_LEN => Some(Sniffed::Unknown),
+        None => None,
+    }
+}
+
+// peeks at the first bytes of a client until they tell its protocol
+async fn sniff_stream(stream: &TcpStream) -> Option<Sniffed> {
+    let mut buf = vec![0; SNIFF_LEN];
+    loop {
+        let n = stream.peek(&mut buf).await.ok()?;
+        // the client closed the connection
+        if n == 0 {
+            return None;
+        }
+        if let Some(sniffed) = sniff(&buf[..n]) {
+            return Some(sniffed);
+        }
+        tokio::time::sleep(SNIFF_RETRY).await;
+    }
+}
+
+// serves websockets, the REST api and the line protocol on a single port,
+// for sites whose firewalls only let a single port through
+pub async fn serve(
+    app: Router,
+    state: Arc<AppState>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(conn) => conn,
+                Err(_) => {
+                    error!("Error accepting a connection");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return Ok(()),
+        };
+
+        let app = app.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(SNIFF_TIMEOUT_SECS);
+            let sniffed = match tokio::time::timeout(timeout, sniff_stream(&stream)).await {
+                Ok(Some(sniffed)) => sniffed,
+                Ok(None) => return,
+                Err(_) => {
+                    warn!("{} sent nothing to tell its protocol by", peer);
+                    return;
+                }
+            };
+            info!("Sniffed {:?} connection from {}", sniffed, peer);
+
+            match sniffed {
+                Sniffed::WebSocket | Sniffed::Http => {
+                    if Http::new()
+                        .serve_connection(stream, app)
+                        .with_upgrades()
+                        .await
+                        .is_err()
+                    {
+                        info!("Connection with {} closed with an error", peer);
+                    }
+                }
+                Sniffed::Lines => lines::serve(stream, state).await,
+                Sniffed::Unknown => warn!("Closing connection with {}, unknown protocol", peer),
+            }
+        });
+    }
+}
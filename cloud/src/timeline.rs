@@ -0,0 +1,77 @@
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+use crate::{clock::Interval, db, AppState};
+
+// how often the shedding of the ingest is looked at
+const SHEDDING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// kinds of the events in the timeline of the server
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    // a background service panicked and is started again
+    ServiceRestart,
+    // settings were changed while the server runs
+    ConfigReload,
+    // migrations were applied to the db on startup
+    Migration,
+    // the ingest started shedding routine readings
+    SheddingStarted,
+    // the ingest admits every reading again
+    SheddingEnded,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::ServiceRestart => "service_restart",
+            EventKind::ConfigReload => "config_reload",
+            EventKind::Migration => "migration",
+            EventKind::SheddingStarted => "shedding_started",
+            EventKind::SheddingEnded => "shedding_ended",
+        }
+    }
+}
+
+// adds an event to the timeline, an event that can't be stored is only logged
+pub async fn record(state: &AppState, kind: EventKind, detail: &str) {
+    info!("Event {}: {}", kind.as_str(), detail);
+    if let Err(e) = db::add_system_event(&state.pool, kind.as_str(), detail).await {
+        warn!("Could not record the event {}: {}", kind.as_str(), e);
+    }
+}
+
+// records the episodes the ingest shed readings in. an episode ends once a check finds no
+// newly shed readings
+pub async fn shedding_service(state: Arc<AppState>) {
+    let mut interval = Interval::new(state.clock.clone(), SHEDDING_CHECK_INTERVAL);
+    let mut last = state.admission.shed();
+    // readings shed when the episode started
+    let mut episode: Option<u64> = None;
+    loop {
+        interval.tick().await;
+        let shed = state.admission.shed();
+        match episode {
+            None if shed > last => {
+                record(
+                    &state,
+                    EventKind::SheddingStarted,
+                    &format!("{} routine readings shed", shed - last),
+                )
+                .await;
+                episode = Some(last);
+            }
+            Some(start) if shed == last => {
+                record(
+                    &state,
+                    EventKind::SheddingEnded,
+                    &format!("{} routine readings shed in the episode", shed - start),
+                )
+                .await;
+                episode = None;
+            }
+            _ => {}
+        }
+        last = shed;
+    }
+}
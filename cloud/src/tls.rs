@@ -0,0 +1,238 @@
+// uid a device authenticated with its client certificate, None if the certificate names no uid.
+// only present on mutual TLS connections.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity(pub Option<String>);
+
+#[cfg(feature = "tls")]
+pub use server::serve;
+
+#[cfg(feature = "tls")]
+mod server {
+    use axum::Router;
+    use hyper::server::conn::Http;
+    use std::{
+        error::Error,
+        fs::File,
+        future::Future,
+        io::BufReader,
+        net::SocketAddr,
+        sync::{Arc, RwLock},
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            server::WantsServerCert, Certificate, ConfigBuilder, PrivateKey, ServerConfig,
+            WantsVerifier,
+        },
+        TlsAcceptor,
+    };
+    use tracing::{error, info, warn};
+
+    use crate::{
+        config::Config,
+        timeline::{self, EventKind},
+        AppState,
+    };
+
+    // serves the app over TLS. with a client CA, devices need certificates signed by it.
+    // certificates are read again on SIGHUP, connections made before keep the old ones
+    pub async fn serve(
+        app: Router,
+        state: Arc<AppState>,
+        addr: SocketAddr,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn Error>> {
+        let config = &state.config;
+        let current = Arc::new(RwLock::new(Arc::new(server_config(config)?)));
+        let mutual = config.mtls_enabled();
+        let listener = TcpListener::bind(addr).await?;
+        tokio::pin!(shutdown);
+
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        #[cfg(not(unix))]
+        let mut hangup = ();
+
+        loop {
+            let (stream, peer) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(conn) => conn,
+                    Err(_) => {
+                        error!("Error accepting a connection");
+                        continue;
+                    }
+                },
+                _ = reload(&mut hangup) => {
+                    match server_config(config) {
+                        Ok(reloaded) => {
+                            *current.write().unwrap() = Arc::new(reloaded);
+                            timeline::record(
+                                &state,
+                                EventKind::ConfigReload,
+                                "reloaded the TLS certificates",
+                            )
+                            .await;
+                        }
+                        Err(e) => error!("Could not reload the TLS certificates: {}", e),
+                    }
+                    continue;
+                }
+                _ = &mut shutdown => return Ok(()),
+            };
+
+            let acceptor = TlsAcceptor::from(current.read().unwrap().clone());
+            let app = app.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(_) => {
+                        warn!("TLS handshake with {} failed", peer);
+                        return;
+                    }
+                };
+
+                let service = if mutual {
+                    client_identity(app, &stream, peer)
+                } else {
+                    app
+                };
+                if Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await
+                    .is_err()
+                {
+                    info!("Connection with {} closed with an error", peer);
+                }
+            });
+        }
+    }
+
+    fn server_config(config: &Config) -> Result<ServerConfig, Box<dyn Error>> {
+        let path = |p: &Option<String>| p.clone().ok_or("TLS is not configured");
+
+        let certs: Vec<Certificate> = read_pem(&path(&config.tls_cert_path)?)?
+            .into_iter()
+            .filter_map(|item| match item {
+                rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+                _ => None,
+            })
+            .collect();
+        if certs.is_empty() {
+            return Err("No certificate found".into());
+        }
+        let key = read_pem(&path(&config.tls_key_path)?)?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::RSAKey(der)
+                | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+                _ => None,
+            })
+            .ok_or("No private key found")?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &config.tls_client_ca_path {
+            Some(ca_path) => client_verifier(builder, ca_path)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(builder.with_single_cert(certs, key)?)
+    }
+
+    // resolves when the process receives SIGHUP, never on other platforms
+    #[cfg(unix)]
+    async fn reload(hangup: &mut tokio::signal::unix::Signal) {
+        hangup.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    async fn reload(_: &mut ()) {
+        std::future::pending::<()>().await
+    }
+
+    #[cfg(feature = "mtls")]
+    fn client_verifier(
+        builder: ConfigBuilder<ServerConfig, WantsVerifier>,
+        ca_path: &str,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, Box<dyn Error>> {
+        use tokio_rustls::rustls::{server::AllowAnyAuthenticatedClient, RootCertStore};
+
+        let mut roots = RootCertStore::empty();
+        for item in read_pem(ca_path)? {
+            if let rustls_pemfile::Item::X509Certificate(der) = item {
+                roots.add(&Certificate(der))?;
+            }
+        }
+        Ok(builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed()))
+    }
+
+    #[cfg(not(feature = "mtls"))]
+    fn client_verifier(
+        _: ConfigBuilder<ServerConfig, WantsVerifier>,
+        _: &str,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, Box<dyn Error>> {
+        Err("A client CA is configured but the server was built without the mtls feature".into())
+    }
+
+    // hands the uid of the client certificate to the handlers
+    #[cfg(feature = "mtls")]
+    fn client_identity(
+        app: Router,
+        stream: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+        peer: SocketAddr,
+    ) -> Router {
+        let uid = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| super::mtls::uid_from_certificate(&cert.0));
+        if uid.is_none() {
+            warn!("Client certificate of {} names no uid", peer);
+        }
+        app.layer(axum::Extension(super::ClientIdentity(uid)))
+    }
+
+    #[cfg(not(feature = "mtls"))]
+    fn client_identity(
+        app: Router,
+        _: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+        _: SocketAddr,
+    ) -> Router {
+        app
+    }
+
+    fn read_pem(path: &str) -> Result<Vec<rustls_pemfile::Item>, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Ok(rustls_pemfile::read_all(&mut reader)?)
+    }
+}
+
+#[cfg(feature = "mtls")]
+mod mtls {
+    use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+    // the uid is the common name, or a DNS or urn:uuid: URI subject alternative name
+    pub fn uid_from_certificate(der: &[u8]) -> Option<String> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let is_uid = |s: &str| s.len() == 36;
+
+        let cn = cert
+            .subject()
+            .iter_common_name()
+            .find_map(|cn| cn.as_str().ok());
+        if let Some(cn) = cn.filter(|cn| is_uid(cn)) {
+            return Some(cn.to_string());
+        }
+
+        let san = cert.subject_alternative_name().ok()??;
+        san.value.general_names.iter().find_map(|name| match name {
+            GeneralName::DNSName(dns) if is_uid(dns) => Some(dns.to_string()),
+            GeneralName::URI(uri) => uri
+                .strip_prefix("urn:uuid:")
+                .filter(|uid| is_uid(uid))
+                .map(|uid| uid.to_string()),
+            _ => None,
+        })
+    }
+}
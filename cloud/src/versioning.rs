@@ -0,0 +1,95 @@
+use axum::{
+    extract::State,
+    http::{HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::AppState;
+
+// version of the REST api served under /api/v<n>
+pub const CURRENT: u32 = 1;
+
+// header a client names the version it was built against with, echoed on every response
+pub const API_VERSION: HeaderName = HeaderName::from_static("api-version");
+
+// when the unversioned /api routes were deprecated in favour of /api/v1, unix timestamp
+const UNVERSIONED_DEPRECATED_AT: i64 = 1792108800;
+
+// whether the path is one of the current version, /api/v1/connections but not /api/connections
+fn is_versioned(path: &str) -> bool {
+    let version = format!("v{}", CURRENT);
+    path.strip_prefix("/api/")
+        .and_then(|rest| rest.split('/').next())
+        == Some(version.as_str())
+}
+
+// the time as an HTTP-date, like Sun, 06 Nov 1994 08:49:37 GMT
+fn http_date(timestamp: i64) -> Option<String> {
+    let time = time::OffsetDateTime::from_unix_timestamp(timestamp).ok()?;
+    Some(format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        &time.weekday().to_string()[..3],
+        time.day(),
+        &time.month().to_string()[..3],
+        time.year(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    ))
+}
+
+// serves the REST api at /api/v<n> and, for the dashboards and scripts built before it was
+// versioned, at /api. answers to the unversioned paths carry Deprecation, Sunset with
+// API_SUNSET and a Link to the versioned path, unless the client asked for a version with the
+// Api-Version header. an unsupported version is rejected. the api docs aren't versioned
+pub async fn negotiate<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path();
+    if !path.starts_with("/api/") || path.starts_with("/api/docs") {
+        return next.run(request).await;
+    }
+
+    let requested = match request.headers().get(&API_VERSION) {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(|v| v.parse::<u32>().ok()) {
+            Some(CURRENT) => Some(CURRENT),
+            _ => {
+                warn!("Rejected {} of api version {:?}", path, value);
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("unsupported api version, supported: {}", CURRENT),
+                )
+                    .into_response();
+            }
+        },
+    };
+    let successor =
+        (!is_versioned(path)).then(|| format!("/api/v{}{}", CURRENT, &path["/api".len()..]));
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(API_VERSION, HeaderValue::from(CURRENT));
+    if let (Some(successor), None) = (successor, requested) {
+        headers.insert(
+            "deprecation",
+            HeaderValue::from_str(&format!("@{}", UNVERSIONED_DEPRECATED_AT)).unwrap(),
+        );
+        if let Some(sunset) = state.config.api_sunset.and_then(http_date) {
+            if let Ok(sunset) = HeaderValue::from_str(&sunset) {
+                headers.insert("sunset", sunset);
+            }
+        }
+        if let Ok(link) =
+            HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+        {
+            headers.insert("link", link);
+        }
+    }
+    response
+}
@@ -0,0 +1,410 @@
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Client, Request};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info, warn};
+
+use crate::{
+    budget::{self, BudgetReport},
+    clock::Interval,
+    db, AppState,
+};
+
+// events the service can fall behind by before it skips some
+const EVENT_BUFFER: usize = 1024;
+// seconds a webhook gets to answer a delivery
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+// longest pause between retries of a delivery
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+type HmacSha256 = Hmac<Sha256>;
+
+// events posted to the webhooks as JSON, named by their event field
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Avg {
+        timestamp: i64,
+        data: f64,
+        channel: String,
+        // averages of devices outside of any tenant leave it out
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+    Alert(db::Alert),
+    // a reading of a pass-through device, forwarded as it came in instead of being averaged,
+    // or one the forwarding mode sends upstream
+    Reading {
+        uid: String,
+        timestamp: i64,
+        data: f64,
+        channel: String,
+        alarm: bool,
+        // readings aggregated into it, left out for a reading as it came
+        #[serde(skip_serializing_if = "Option::is_none")]
+        count: Option<usize>,
+    },
+    // an AVG message computed from readings older than AVG_STALE_SECS
+    Stale {
+        timestamp: i64,
+        channel: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        // seconds the newest reading averaged was old
+        staleness: i64,
+    },
+    // a CMD a fired rule sent to its device
+    Action {
+        rule_id: i64,
+        uid: String,
+        command: String,
+        argument: Option<String>,
+        // id of the queued command, acknowledged with CMD_ACK
+        command_id: i64,
+        triggered_at: i64,
+    },
+    // bytes sent upstream in the last hour, only with an upstream budget
+    Budget(BudgetReport),
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Avg { .. } => "avg",
+            WebhookEvent::Alert(_) => "alert",
+            WebhookEvent::Reading { .. } => "reading",
+            WebhookEvent::Stale { .. } => "stale",
+            WebhookEvent::Action { .. } => "action",
+            WebhookEvent::Budget(_) => "budget",
+        }
+    }
+}
+
+// hands the events produced by the services to the webhook service
+pub struct Webhooks {
+    sender: broadcast::Sender<WebhookEvent>,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+}
+
+impl Webhooks {
+    pub fn publish(&self, event: WebhookEvent) {
+        // sending only fails if no webhooks are configured
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WebhookEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// hex HMAC-SHA256 of "<timestamp>.<body>", sent in the X-Webhook-Signature header.
+// signing the timestamp lets receivers reject replayed deliveries
+pub fn sign(secret: &[u8], timestamp: i64, body: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// the urls of a webhook, the primary first and then its fallbacks. deliveries go to the url
+// taken over last until the primary passes a health check again
+struct Failover {
+    urls: Vec<String>,
+    active: AtomicUsize,
+}
+
+impl Failover {
+    // a url of WEBHOOK_URLS with its fallbacks
+    fn new(webhook: &str) -> Self {
+        Self {
+            urls: webhook.split('|').map(str::to_string).collect(),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    fn primary(&self) -> &str {
+        &self.urls[0]
+    }
+
+    // the url deliveries are posted to and its index
+    fn active(&self) -> (usize, &str) {
+        let index = self.active.load(Ordering::Relaxed);
+        (index, &self.urls[index])
+    }
+
+    // hands over to the next url once the one at index could not be reached, unless
+    // another delivery did so already
+    fn failed(&self, index: usize) {
+        let next = (index + 1) % self.urls.len();
+        if next != index
+            && self
+                .active
+                .compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            warn!(
+                "Webhook {} can't be reached, failing over to {}",
+                self.urls[index], self.urls[next]
+            );
+        }
+    }
+}
+
+// posts every AVG, ALERT, rule action and budget event to the configured webhooks, each delivery is
+// recorded in webhook_deliveries and retried with exponential backoff
+pub async fn webhook_service(state: Arc<AppState>) {
+    let Some(urls) = state.config.webhook_urls.clone() else {
+        return;
+    };
+    let failovers: Vec<_> = urls
+        .0
+        .iter()
+        .map(|webhook| Arc::new(Failover::new(webhook)))
+        .collect();
+    for failover in failovers.iter().filter(|failover| failover.urls.len() > 1) {
+        tokio::spawn(health_checks(state.clone(), failover.clone()));
+    }
+    // subscribe before resuming, so no event is missed meanwhile
+    let mut events = state.webhooks.subscribe();
+
+    // deliveries still retried when the server stopped are picked up again
+    match db::get_pending_webhook_deliveries(&state.pool).await {
+        Ok(pending) => {
+            if !pending.is_empty() {
+                info!("Resuming {} webhook deliveries", pending.len());
+            }
+            for delivery in pending {
+                // deliveries are recorded with the primary url of their webhook
+                let failover = failovers
+                    .iter()
+                    .find(|failover| failover.primary() == delivery.url)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Failover::new(&delivery.url)));
+                tokio::spawn(deliver(
+                    state.clone(),
+                    delivery.id,
+                    failover,
+                    budget::Class::of_event(&delivery.event),
+                    delivery.payload,
+                    delivery.attempts,
+                ));
+            }
+        }
+        Err(_) => error!("Could not resume the pending webhook deliveries"),
+    }
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Webhook service fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(payload) = serde_json::to_string(&event) else {
+            continue;
+        };
+
+        for failover in &failovers {
+            let url = failover.primary();
+            match db::add_webhook_delivery(&state.pool, url, event.name(), &payload).await {
+                Ok(id) => {
+                    tokio::spawn(deliver(
+                        state.clone(),
+                        id,
+                        failover.clone(),
+                        budget::Class::of_event(event.name()),
+                        payload.clone(),
+                        0,
+                    ));
+                }
+                Err(_) => error!(
+                    "Error recording the delivery of a {} event to {}",
+                    event.name(),
+                    url
+                ),
+            }
+        }
+    }
+}
+
+// posts a payload until the webhook accepts it, rejects it or the attempts are used up.
+// attempts the active url can't answer move the webhook on to its next one.
+// aggregates wait for the next hour once the upstream budget is used up
+async fn deliver(
+    state: Arc<AppState>,
+    id: i64,
+    failover: Arc<Failover>,
+    class: budget::Class,
+    payload: String,
+    mut attempts: i64,
+) {
+    let Some(secret) = &state.config.webhook_secret else {
+        return;
+    };
+    let client = Client::new();
+    let max_attempts = state.config.webhook_max_attempts as i64;
+    let base = Duration::from_millis(state.config.webhook_backoff_ms);
+
+    loop {
+        if attempts >= max_attempts {
+            warn!(
+                "Giving up on webhook delivery {} to {}",
+                id,
+                failover.primary()
+            );
+            if db::update_webhook_delivery(&state.pool, id, "failed", attempts, None, None)
+                .await
+                .is_err()
+            {
+                error!("Error recording webhook delivery {}", id);
+            }
+            return;
+        }
+        if attempts > 0 {
+            let backoff = base
+                .saturating_mul(1 << (attempts - 1).min(16))
+                .min(MAX_BACKOFF);
+            state.clock.sleep(backoff).await;
+        }
+        if !state
+            .budget
+            .try_spend_at(class, payload.len(), state.clock.unix_now())
+        {
+            info!("Webhook delivery {} waits for the upstream budget", id);
+            state.budget.defer_at(state.clock.unix_now());
+            while !state
+                .budget
+                .try_spend_at(class, payload.len(), state.clock.unix_now())
+            {
+                let wait = state.budget.until_next_window(state.clock.unix_now());
+                state.clock.sleep(wait).await;
+            }
+        }
+        attempts += 1;
+
+        let (index, url) = failover.active();
+        let (status, outcome, error) =
+            match post(&client, secret.expose().as_bytes(), id, url, &payload).await {
+                Ok(status) if (200..300).contains(&status) => (Some(status), "delivered", None),
+                // the webhook won't take the payload however often it is retried
+                Ok(status) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                    (Some(status), "failed", None)
+                }
+                Ok(status) => (Some(status), "pending", None),
+                Err(e) => (None, "pending", Some(e)),
+            };
+        if error.is_some() || status.is_some_and(|status| status >= 500) {
+            failover.failed(index);
+        }
+        if db::update_webhook_delivery(
+            &state.pool,
+            id,
+            outcome,
+            attempts,
+            status.map(i64::from),
+            error.as_deref(),
+        )
+        .await
+        .is_err()
+        {
+            error!("Error recording webhook delivery {}", id);
+        }
+
+        match outcome {
+            "delivered" => {
+                info!("Webhook delivery {} posted to {}", id, url);
+                return;
+            }
+            "failed" => {
+                warn!(
+                    "Webhook {} rejected delivery {} with {}",
+                    url,
+                    id,
+                    status.unwrap_or_default()
+                );
+                return;
+            }
+            _ => warn!(
+                "Webhook delivery {} to {} failed, attempt {} of {}",
+                id, url, attempts, max_attempts
+            ),
+        }
+    }
+}
+
+// returns the deliveries of a webhook on a fallback to its primary url once that answers again
+async fn health_checks(state: Arc<AppState>, failover: Arc<Failover>) {
+    let client = Client::new();
+    let mut interval = Interval::new(
+        state.clock.clone(),
+        Duration::from_millis(state.config.webhook_health_ms),
+    );
+    loop {
+        interval.tick().await;
+        if failover.active().0 == 0 {
+            continue;
+        }
+        if is_up(&client, failover.primary()).await {
+            info!(
+                "Webhook {} answers again, its events go back to it",
+                failover.primary()
+            );
+            failover.active.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// whether the webhook answers a HEAD request without a server error
+async fn is_up(client: &Client<hyper::client::HttpConnector>, url: &str) -> bool {
+    let Ok(req) = Request::head(url).body(Body::empty()) else {
+        return false;
+    };
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(res)) => !res.status().is_server_error(),
+        _ => false,
+    }
+}
+
+// the http status the webhook answered with
+async fn post(
+    client: &Client<hyper::client::HttpConnector>,
+    secret: &[u8],
+    id: i64,
+    url: &str,
+    payload: &str,
+) -> Result<u16, String> {
+    let timestamp = db::unix_now();
+    let req = Request::post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Delivery", id)
+        .header("X-Webhook-Timestamp", timestamp)
+        .header(
+            "X-Webhook-Signature",
+            format!("sha256={}", sign(secret, timestamp, payload)),
+        )
+        .body(Body::from(payload.to_string()))
+        .map_err(|e| e.to_string())?;
+
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(res)) => Ok(res.status().as_u16()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
@@ -0,0 +1,288 @@
+use cloud::{
+    clock::MockClock,
+    config::{Aggregation, AggregationTimestamp, Sample},
+    db,
+    protocols::{self, AvgSettings, SensorMsg},
+    routes,
+    webhooks::WebhookEvent,
+};
+use std::{
+    net::TcpListener,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+mod common;
+
+#[test]
+fn windows_are_aggregated_with_the_configured_function() {
+    let window = [4.0, 1.0, 3.0, 2.0];
+    assert_eq!(Aggregation::Mean.apply(&window), Some(2.5));
+    assert_eq!(Aggregation::Median.apply(&window), Some(2.5));
+    assert_eq!(Aggregation::Median.apply(&window[..3]), Some(3.0));
+    assert_eq!(Aggregation::Min.apply(&window), Some(1.0));
+    assert_eq!(Aggregation::Max.apply(&window), Some(4.0));
+    assert_eq!(Aggregation::Mean.apply(&[]), None);
+
+    assert_eq!("median".parse(), Ok(Aggregation::Median));
+    assert!("sum".parse::<Aggregation>().is_err());
+}
+
+#[tokio::test]
+async fn summaries_weigh_as_much_as_the_readings_they_stand_in_for() {
+    let state = common::state().await;
+    let uid = "a3f1c9e2-5b7d-4e8a-9c6f-2d1e0b4a7c35";
+    for (timestamp, data) in [(1000, 20.0), (1010, 22.0)] {
+        let reading = SensorMsg {
+            uid: uid.to_string(),
+            data,
+            timestamp,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+    // a minute of 8 readings between 28 and 32
+    sqlx::query(
+        r#"INSERT INTO received_messages ( uid, data, created_at, channel, summary_min,
+            summary_max, summary_count )
+        VALUES ( ?1, 30.0, 940, 'temperature', 28.0, 32.0, 8 )"#,
+    )
+    .bind(uid)
+    .execute(&state.pool)
+    .await
+    .unwrap();
+
+    let window = db::get_window_data(
+        &state.pool,
+        None,
+        "temperature",
+        AggregationTimestamp::Device,
+        900,
+        1100,
+    )
+    .await
+    .unwrap();
+    let last = db::get_last_received_messages(
+        &state.pool,
+        None,
+        "temperature",
+        AggregationTimestamp::Device,
+        10,
+    )
+    .await
+    .unwrap();
+    let last: Vec<Sample> = last.iter().map(db::ReceivedMessage::sample).collect();
+    for samples in [window, last] {
+        // (20 + 22 + 8 * 30) / 10, not (20 + 22 + 30) / 3
+        assert_eq!(Aggregation::Mean.apply_samples(&samples), Some(28.2));
+        assert_eq!(Aggregation::Median.apply_samples(&samples), Some(30.0));
+        assert_eq!(Aggregation::Min.apply_samples(&samples), Some(20.0));
+        assert_eq!(Aggregation::Max.apply_samples(&samples), Some(32.0));
+    }
+}
+
+#[test]
+fn settings_are_validated() {
+    let settings = AvgSettings {
+        window_size: 5,
+        interval_secs: 10,
+        aggregation: Aggregation::Mean,
+        timestamp: AggregationTimestamp::Device,
+    };
+    assert!(settings.validate().is_ok());
+
+    let empty_window = AvgSettings {
+        window_size: 0,
+        ..settings.clone()
+    };
+    assert!(empty_window.validate().is_err());
+
+    let no_interval = AvgSettings {
+        interval_secs: 0,
+        ..settings
+    };
+    assert!(no_interval.validate().is_err());
+}
+
+#[tokio::test]
+async fn windows_can_use_the_receive_time_of_the_readings() {
+    let state = common::state().await;
+    // a device whose clock reset to 1970
+    let reading = SensorMsg {
+        uid: "a3f1c9e2-5b7d-4e8a-9c6f-2d1e0b4a7c35".to_string(),
+        data: 20.0,
+        timestamp: 1000,
+        channel: "temperature".to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    };
+    db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+        .await
+        .unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let window = |by| db::get_window_data(&state.pool, None, "temperature", by, now - 60, now + 60);
+    assert!(window(AggregationTimestamp::Device)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        window(AggregationTimestamp::Received).await.unwrap(),
+        vec![Sample::raw(20.0)]
+    );
+
+    let stored = db::get_recent_readings(&state.pool, 1).await.unwrap();
+    assert_eq!(stored[0].created_at, 1000);
+    assert!(stored[0].timestamp(AggregationTimestamp::Received) >= now);
+}
+
+#[tokio::test]
+async fn computed_averages_are_kept_with_their_windows() {
+    let state = common::state().await;
+    for (timestamp, data) in [(1000, 20.0), (1010, 22.0)] {
+        let reading = SensorMsg {
+            uid: "a3f1c9e2-5b7d-4e8a-9c6f-2d1e0b4a7c35".to_string(),
+            data,
+            timestamp,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+
+    // the first tick of the service averages right away
+    tokio::spawn(protocols::avg_msg_service(state.clone()));
+    let mut aggregates = Vec::new();
+    for _ in 0..50 {
+        aggregates = db::get_aggregates(&state.pool, None, None, 0, i64::MAX, 10)
+            .await
+            .unwrap();
+        if !aggregates.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(aggregates.len(), 1);
+    assert_eq!(aggregates[0].channel, "temperature");
+    assert_eq!(
+        (aggregates[0].window_start, aggregates[0].window_end),
+        (1000, 1010)
+    );
+    assert_eq!((aggregates[0].value, aggregates[0].count), (21.0, 2));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    let get = |query: &str| {
+        let uri = format!("http://{}/api/aggregates?{}", addr, query);
+        async move {
+            let res = hyper::Client::new()
+                .get(uri.parse().unwrap())
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap()
+        }
+    };
+
+    // windows overlapping the range are included
+    let aggregates = get("channel=temperature&since=1005&until=2000").await;
+    assert_eq!(aggregates.len(), 1);
+    assert_eq!(aggregates[0]["value"], 21.0);
+    assert_eq!(aggregates[0]["count"], 2);
+    assert!(get("since=1011").await.is_empty());
+    assert!(get("until=1000").await.is_empty());
+    assert!(get("channel=humidity").await.is_empty());
+}
+
+#[tokio::test]
+async fn averages_of_old_readings_are_reported_as_stale() {
+    const NOW: i64 = 1700000000;
+    let mut config = common::config();
+    config.avg_interval_secs = 3600;
+    config.avg_stale_secs = 300;
+    let clock = Arc::new(MockClock::new(NOW));
+    let state = common::state_with_clock(config, clock.clone()).await;
+    let add = |timestamp: i64| {
+        let reading = SensorMsg {
+            uid: "a3f1c9e2-5b7d-4e8a-9c6f-2d1e0b4a7c35".to_string(),
+            data: 20.0,
+            timestamp,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        let pool = state.pool.clone();
+        let received_at = state.clock.unix_now();
+        async move {
+            db::add_received_message(&pool, &reading, received_at)
+                .await
+                .unwrap()
+        }
+    };
+    let aggregates = |count: usize| {
+        let pool = state.pool.clone();
+        async move {
+            let mut aggregates = Vec::new();
+            for _ in 0..50 {
+                aggregates = db::get_aggregates(&pool, None, None, 0, i64::MAX, 10)
+                    .await
+                    .unwrap();
+                if aggregates.len() == count {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            aggregates
+        }
+    };
+    let mut webhooks = state.webhooks.subscribe();
+    add(NOW - 20).await;
+    add(NOW - 10).await;
+
+    // fresh readings are averaged without a fuss
+    tokio::spawn(protocols::avg_msg_service(state.clone()));
+    assert_eq!(aggregates(1).await[0].staleness, Some(10));
+    assert_eq!(state.staleness.last(), 10);
+    assert_eq!(state.staleness.stale(), 0);
+
+    // the next tick an hour later only finds a reading from back then
+    add(NOW).await;
+    clock.advance(Duration::from_secs(3600));
+    assert_eq!(aggregates(2).await[1].staleness, Some(3600));
+    assert_eq!(state.staleness.stale(), 1);
+    loop {
+        match webhooks.recv().await.unwrap() {
+            WebhookEvent::Stale {
+                timestamp,
+                channel,
+                staleness,
+                ..
+            } => {
+                assert_eq!(
+                    (timestamp, channel.as_str(), staleness),
+                    (NOW + 3600, "temperature", 3600)
+                );
+                break;
+            }
+            _ => continue,
+        }
+    }
+}
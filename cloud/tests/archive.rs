@@ -0,0 +1,113 @@
+use cloud::config::ArchiveUrl;
+
+mod common;
+
+#[test]
+fn archive_urls_name_a_directory_or_a_bucket() {
+    assert_eq!(
+        "/var/lib/fog/archive".parse(),
+        Ok(ArchiveUrl::Dir("/var/lib/fog/archive".to_string()))
+    );
+    assert_eq!(
+        "file://archive".parse(),
+        Ok(ArchiveUrl::Dir("archive".to_string()))
+    );
+    assert_eq!(
+        "s3://readings/site-4/".parse(),
+        Ok(ArchiveUrl::S3 {
+            bucket: "readings".to_string(),
+            prefix: "site-4/".to_string()
+        })
+    );
+    assert_eq!(
+        "s3://readings".parse(),
+        Ok(ArchiveUrl::S3 {
+            bucket: "readings".to_string(),
+            prefix: String::new()
+        })
+    );
+    assert!("s3:///site-4".parse::<ArchiveUrl>().is_err());
+    assert!("".parse::<ArchiveUrl>().is_err());
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn completed_days_are_archived_per_device_and_pruned() {
+    use cloud::{clock::MockClock, config::Config, db, protocols::SensorMsg};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::sync::Arc;
+
+    const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+    const B: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+    // midnight of 2023-11-14 in UTC
+    const DAY: i64 = 1699920000;
+
+    let dir = std::env::temp_dir().join(format!("archive-{}", uuid::Uuid::new_v4()));
+    let mut config = common::config();
+    config.archive_url = Some(ArchiveUrl::Dir(dir.display().to_string()));
+    config.archive_prune = true;
+    let clock = Arc::new(MockClock::new(DAY + 2 * 86400 + 3600));
+    let state = common::state_with_clock(config, clock).await;
+
+    for (uid, timestamp, data) in [
+        (A, DAY + 100, 20.0),
+        (A, DAY + 200, 22.0),
+        (B, DAY + 300, 5.0),
+        (A, DAY + 86400 + 100, 21.0),
+        // today is not over yet
+        (A, DAY + 2 * 86400 + 10, 23.0),
+    ] {
+        let reading = SensorMsg {
+            uid: uid.to_string(),
+            data,
+            timestamp,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+    for period in [db::RollupPeriod::Hour, db::RollupPeriod::Day] {
+        db::roll_up(&state.pool, period, DAY, DAY + 2 * 86400)
+            .await
+            .unwrap();
+    }
+
+    let report = state.archive.run(&state).await.unwrap().unwrap();
+    assert_eq!(report.days, ["2023-11-14", "2023-11-15"]);
+    // the readings and the hourly and daily rollups of every device of a day
+    assert_eq!((report.files, report.rows, report.pruned), (6, 10, 4));
+
+    let open = |path: String| {
+        let file = std::fs::File::open(dir.join(path)).unwrap();
+        SerializedFileReader::new(file).unwrap()
+    };
+    let raw = open(format!("raw/day=2023-11-14/uid={}/data.parquet", A));
+    assert_eq!(raw.metadata().file_metadata().num_rows(), 2);
+    let rows: Vec<String> = raw
+        .get_row_iter(None)
+        .unwrap()
+        .map(|row| row.unwrap().to_string())
+        .collect();
+    assert!(rows[0].contains("created_at: 1699920100"), "{}", rows[0]);
+    assert!(rows[1].contains("data: 22.0"), "{}", rows[1]);
+    assert!(rows[1].contains("summary_min: null"), "{}", rows[1]);
+    let rollups = open(format!("rollups/day=2023-11-14/uid={}/data.parquet", B));
+    assert_eq!(rollups.metadata().file_metadata().num_rows(), 2);
+    assert!(dir
+        .join(format!("raw/day=2023-11-15/uid={}/data.parquet", A))
+        .exists());
+    assert!(!dir
+        .join(format!("raw/day=2023-11-15/uid={}/data.parquet", B))
+        .exists());
+
+    // only the reading of today is left, and archived days are not archived again
+    assert_eq!(db::count_received_messages(&state.pool).await.unwrap(), 1);
+    let report = state.archive.run(&state).await.unwrap().unwrap();
+    assert!(report.days.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,298 @@
+use cloud::{db, routes, AppState};
+use hyper::{header, Body, Method, Request, StatusCode};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const B: &str = "3f9d1b7e-2c4a-4e6f-8b0d-5a1c9e3f7d24";
+const C: &str = "8a2e6c0f-4b1d-4f3a-9e7c-1d5b3f9a2c68";
+const TOKEN: &str = "operator";
+
+async fn start() -> (SocketAddr, Arc<AppState>) {
+    let mut config = common::config();
+    config.admin_token = Some(TOKEN.parse().unwrap());
+    config.api_auth = true;
+    let state = common::state_with(config).await;
+    db::add_connection(&state.pool, A).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+    (addr, state)
+}
+
+// the status and body of the response
+async fn request(
+    method: Method,
+    uri: String,
+    key: Option<&str>,
+    body: &str,
+) -> (StatusCode, serde_json::Value) {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    if let Some(key) = key {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {}", key));
+    }
+    let res = hyper::Client::new()
+        .request(req.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+async fn add_key(addr: SocketAddr, name: &str, role: &str) -> (i64, String) {
+    let (status, key) = request(
+        Method::POST,
+        format!("http://{}/admin/api-keys", addr),
+        Some(TOKEN),
+        &format!(r#"{{"name":"{}","role":"{}"}}"#, name, role),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(key["role"], role);
+    (
+        key["id"].as_i64().unwrap(),
+        key["key"].as_str().unwrap().to_string(),
+    )
+}
+
+#[tokio::test]
+async fn viewers_may_only_read_and_admins_may_change_devices() {
+    let (addr, _) = start().await;
+    let connections = format!("http://{}/api/connections", addr);
+    let connection = format!("http://{}/api/connections/{}", addr, A);
+    let (viewer_id, viewer) = add_key(addr, "dashboard", "viewer").await;
+    let (_, admin) = add_key(addr, "operations", "admin").await;
+
+    let (status, _) = request(Method::GET, connections.clone(), None, "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = request(Method::GET, connections.clone(), Some("guess"), "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // the api docs stay public
+    let (status, _) = request(
+        Method::GET,
+        format!("http://{}/api/docs/openapi.json", addr),
+        None,
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, devices) = request(Method::GET, connections.clone(), Some(&viewer), "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(devices[0]["uid"], A);
+
+    let (status, _) = request(Method::DELETE, connection.clone(), Some(&viewer), "").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = request(
+        Method::POST,
+        format!("http://{}/api/devices/{}/commands", addr, A),
+        Some(&viewer),
+        r#"{"command":"reboot"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = request(Method::DELETE, connection, Some(&admin), "").await;
+    assert!(status.is_success(), "{}", status);
+
+    // revoked keys are rejected right away
+    let (status, _) = request(
+        Method::DELETE,
+        format!("http://{}/admin/api-keys/{}", addr, viewer_id),
+        Some(TOKEN),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = request(Method::GET, connections, Some(&viewer), "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, keys) = request(
+        Method::GET,
+        format!("http://{}/admin/api-keys", addr),
+        Some(TOKEN),
+        "",
+    )
+    .await;
+    assert!(keys[0]["revoked_at"].is_i64());
+    assert!(keys[1]["revoked_at"].is_null());
+    assert!(keys[0].get("key").is_none());
+}
+
+#[tokio::test]
+async fn the_admin_endpoints_need_the_admin_token() {
+    let (addr, _) = start().await;
+    let (_, admin) = add_key(addr, "operations", "admin").await;
+
+    for (method, path) in [
+        (Method::GET, "/admin/slow-queries"),
+        (Method::GET, "/admin/avg-settings"),
+        (Method::POST, "/admin/purge-device"),
+        (Method::GET, "/admin/pairings"),
+    ] {
+        let uri = format!("http://{}{}", addr, path);
+        let (status, _) = request(method.clone(), uri.clone(), None, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+        // api keys only open the api, even admin ones
+        let (status, _) = request(method, uri, Some(&admin), "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+    }
+    let (status, _) = request(
+        Method::GET,
+        format!("http://{}/admin/slow-queries", addr),
+        Some(TOKEN),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // without an ADMIN_TOKEN there are no admin endpoints
+    let state = common::state().await;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    let (status, _) = request(
+        Method::GET,
+        format!("http://{}/admin/slow-queries", addr),
+        Some(TOKEN),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+// the body of a response that isn't json
+async fn text(uri: String, key: &str) -> (StatusCode, String) {
+    let req = Request::builder()
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", key));
+    let res = hyper::Client::new()
+        .request(req.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn the_keys_of_a_tenant_only_read_its_devices() {
+    let (addr, state) = start().await;
+    for (tenant, uid) in [("acme", B), ("globex", C)] {
+        db::add_tenant(&state.pool, tenant, tenant).await.unwrap();
+        db::add_connection(&state.pool, uid).await.unwrap();
+        db::set_connection_tenant(&state.pool, uid, tenant)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"INSERT INTO received_messages ( uid, data, created_at, channel, tenant )
+            VALUES ( ?1, 21.5, 1700000000, 'temperature', ?2 )"#,
+        )
+        .bind(uid)
+        .bind(tenant)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    }
+
+    let (status, _) = request(
+        Method::POST,
+        format!("http://{}/admin/api-keys", addr),
+        Some(TOKEN),
+        r#"{"name":"initech","role":"viewer","tenant":"initech"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, key) = request(
+        Method::POST,
+        format!("http://{}/admin/api-keys", addr),
+        Some(TOKEN),
+        r#"{"name":"acme","role":"admin","tenant":"acme"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(key["tenant"], "acme");
+    let key = key["key"].as_str().unwrap().to_string();
+
+    // the scope comes from the key, not from the query
+    let (status, devices) = request(
+        Method::GET,
+        format!("http://{}/api/connections", addr),
+        Some(&key),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(devices.as_array().unwrap().len(), 1);
+    assert_eq!(devices[0]["uid"], B);
+    let (status, _) = request(
+        Method::GET,
+        format!("http://{}/api/readings/latest?tenant=globex", addr),
+        Some(&key),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (_, readings) = request(
+        Method::GET,
+        format!("http://{}/api/v1/readings/latest", addr),
+        Some(&key),
+        "",
+    )
+    .await;
+    assert_eq!(readings.as_array().unwrap().len(), 1);
+    assert_eq!(readings[0]["uid"], B);
+
+    // devices of other tenants, and of none, are not found
+    for uid in [A, C] {
+        for path in [
+            format!("/api/connections/{}", uid),
+            format!("/api/v1/devices/{}/export", uid),
+            format!("/api/export?uid={}", uid),
+        ] {
+            let (status, _) = text(format!("http://{}{}", addr, path), &key).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+    let (status, csv) = text(format!("http://{}/api/export?uid={}", addr, B), &key).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(csv.lines().count(), 2, "{}", csv);
+    let (status, _) = request(
+        Method::DELETE,
+        format!("http://{}/api/connections/{}", addr, C),
+        Some(&key),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // the server-wide endpoints are not for tenants
+    let (status, _) = text(format!("http://{}/api/events", addr), &key).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // the health counts are the tenant's
+    let (_, health) = text(format!("http://{}/", addr), &key).await;
+    assert!(health.contains("Number of connections: 1 "), "{}", health);
+    assert!(
+        health.contains("Number of received messages: 1\n"),
+        "{}",
+        health
+    );
+    let (_, health) = text(format!("http://{}/", addr), TOKEN).await;
+    assert!(health.contains("Number of connections: 3 "), "{}", health);
+}
@@ -0,0 +1,44 @@
+use cloud::budget::{BudgetReport, Class, UpstreamBudget, WINDOW_SECS};
+
+// the start of an hour
+const HOUR: i64 = 1_700_002_800;
+
+#[test]
+fn raw_readings_leave_room_for_aggregates_and_alerts_always_go_out() {
+    let budget = UpstreamBudget::new(Some(1000));
+    // raw readings may use half of the budget
+    assert!(budget.try_spend_at(Class::Raw, 400, HOUR));
+    assert!(!budget.try_spend_at(Class::Raw, 200, HOUR + 1));
+    assert!(budget.try_spend_at(Class::Aggregate, 500, HOUR + 2));
+    assert!(!budget.try_spend_at(Class::Aggregate, 200, HOUR + 3));
+    assert!(budget.try_spend_at(Class::Alert, 300, HOUR + 4));
+    assert!(!budget.try_spend_at(Class::Raw, 1, HOUR + 5));
+
+    let report = budget.report_at(HOUR + 6);
+    assert_eq!(report.used_bytes, 1200);
+    assert_eq!(
+        (report.alert_bytes, report.aggregate_bytes, report.raw_bytes),
+        (300, 500, 400)
+    );
+
+    // the next hour renews the budget and reports the last one once
+    assert!(budget.try_spend_at(Class::Raw, 200, HOUR + WINDOW_SECS));
+    let finished = budget.take_finished_at(HOUR + WINDOW_SECS).unwrap();
+    assert_eq!(
+        finished,
+        BudgetReport {
+            period_start: HOUR,
+            budget_bytes: Some(1000),
+            ..report
+        }
+    );
+    assert_eq!(budget.take_finished_at(HOUR + WINDOW_SECS + 1), None);
+}
+
+#[test]
+fn without_a_budget_everything_is_only_counted() {
+    let budget = UpstreamBudget::new(None);
+    assert!(budget.try_spend_at(Class::Raw, 1 << 30, HOUR));
+    assert!(budget.try_spend_at(Class::Aggregate, 1 << 30, HOUR));
+    assert_eq!(budget.report_at(HOUR).used_bytes, 2 << 30);
+}
@@ -0,0 +1,102 @@
+use cloud::{
+    clock::{Clock, Interval, MockClock},
+    config::RetentionPolicy,
+    db,
+    protocols::SensorMsg,
+    retention,
+};
+use std::{sync::Arc, time::Duration};
+
+mod common;
+
+const A: &str = "7c2e9f4a-1b5d-4e3c-8a6f-0d9b2e5c1a74";
+const NOW: i64 = 1700000000;
+
+// waits for the service to leave the expected number of readings
+async fn expect_readings(state: &cloud::AppState, expected: i64) {
+    let mut count = 0;
+    for _ in 0..50 {
+        count = db::count_received_messages(&state.pool).await.unwrap();
+        if count == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(count, expected);
+}
+
+#[tokio::test]
+async fn intervals_tick_when_the_mock_clock_is_advanced() {
+    let clock = Arc::new(MockClock::new(NOW));
+    let mut interval = Interval::new(clock.clone(), Duration::from_secs(60));
+
+    // the first tick completes right away
+    let first = interval.tick().await;
+    assert_eq!(first, clock.now());
+
+    // the next one waits for the clock, however long it takes in real time
+    let ticking = tokio::spawn(async move { (interval.tick().await, interval) });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!ticking.is_finished());
+    clock.advance(Duration::from_secs(59));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!ticking.is_finished());
+    clock.advance(Duration::from_secs(1));
+    let (second, mut interval) = ticking.await.unwrap();
+    assert_eq!(second, first + Duration::from_secs(60));
+    assert_eq!(clock.unix_now(), NOW + 60);
+
+    // missed ticks are caught up on
+    clock.advance(Duration::from_secs(150));
+    assert_eq!(interval.tick().await, first + Duration::from_secs(120));
+    assert_eq!(interval.tick().await, first + Duration::from_secs(180));
+
+    let delayed = Interval::delayed(clock.clone(), Duration::from_secs(10));
+    let ticking = tokio::spawn(async move {
+        let mut delayed = delayed;
+        delayed.tick().await
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!ticking.is_finished());
+    clock.advance(Duration::from_secs(10));
+    ticking.await.unwrap();
+}
+
+#[tokio::test]
+async fn readings_expire_on_the_clock_of_the_retention_service() {
+    let mut config = common::config();
+    config.retention_interval_secs = 3600;
+    config.received_retention = RetentionPolicy {
+        max_age_secs: Some(24 * 3600),
+        max_rows: None,
+    };
+    let clock = Arc::new(MockClock::new(NOW));
+    let state = common::state_with_clock(config, clock.clone()).await;
+    for age in [25 * 3600, 23 * 3600 + 1800, 3600] {
+        let reading = SensorMsg {
+            uid: A.to_string(),
+            data: 21.0,
+            timestamp: NOW - age,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+
+    // the first run prunes right away, on the time of the clock
+    tokio::spawn(retention::retention_service(state.clone()));
+    expect_readings(&state, 2).await;
+
+    // the next run only comes with the next hour, a day of simulated time takes no time
+    clock.advance(Duration::from_secs(1800));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    expect_readings(&state, 2).await;
+    clock.advance(Duration::from_secs(1800));
+    expect_readings(&state, 1).await;
+    clock.advance(Duration::from_secs(23 * 3600));
+    expect_readings(&state, 0).await;
+}
@@ -0,0 +1,124 @@
+use cloud::{
+    coap::{self, code, CoapMessage, MessageType},
+    db,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const URI_PATH: u16 = 11;
+
+fn post(kind: MessageType, message_id: u16, payload: &str) -> CoapMessage {
+    CoapMessage {
+        kind,
+        code: code::POST,
+        message_id,
+        token: vec![0xca, 0xfe],
+        options: vec![(URI_PATH, b"sensor".to_vec())],
+        payload: payload.as_bytes().to_vec(),
+    }
+}
+
+async fn exchange(client: &UdpSocket, request: &CoapMessage) -> CoapMessage {
+    client.send(&request.encode()).await.unwrap();
+    let mut buf = [0; 1152];
+    let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .expect("no response from the server")
+        .unwrap();
+    CoapMessage::parse(&buf[..len]).unwrap()
+}
+
+#[test]
+fn messages_survive_encoding_and_parsing() {
+    let msg = CoapMessage {
+        kind: MessageType::Confirmable,
+        code: code::POST,
+        message_id: 0x1234,
+        token: vec![1, 2, 3, 4],
+        // deltas and lengths that need the one and two byte extensions
+        options: vec![
+            (URI_PATH, b"sensor".to_vec()),
+            (60, vec![7; 20]),
+            (2048, vec![9; 300]),
+        ],
+        payload: b"SENSOR#uid#1#2".to_vec(),
+    };
+    assert_eq!(CoapMessage::parse(&msg.encode()).unwrap(), msg);
+    assert_eq!(msg.path(), "sensor");
+
+    // a GET to coap://server/a/b from RFC 7252 style bytes
+    let get = [0x40, 0x01, 0x00, 0x07, 0xb1, b'a', 0x01, b'b'];
+    let get = CoapMessage::parse(&get).unwrap();
+    assert_eq!(get.kind, MessageType::Confirmable);
+    assert_eq!(get.message_id, 7);
+    assert_eq!(get.path(), "a/b");
+    assert!(get.payload.is_empty());
+
+    assert!(CoapMessage::parse(&[0x40, 0x01]).is_err());
+    // version 2
+    assert!(CoapMessage::parse(&[0x80, 0x01, 0x00, 0x01]).is_err());
+    // token longer than the message
+    assert!(CoapMessage::parse(&[0x44, 0x01, 0x00, 0x01, 0xaa]).is_err());
+    // payload marker without a payload
+    assert!(CoapMessage::parse(&[0x40, 0x02, 0x00, 0x01, 0xff]).is_err());
+}
+
+#[tokio::test]
+async fn readings_are_posted_and_answered_with_the_latest_average() {
+    let state = common::state().await;
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(coap::listen(server, state.clone()));
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(addr).await.unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let reading = format!("SENSOR#{}#{}#21.5#temperature", A, now);
+
+    // there is no average yet
+    let response = exchange(&client, &post(MessageType::Confirmable, 1, &reading)).await;
+    assert_eq!(response.kind, MessageType::Acknowledgement);
+    assert_eq!(response.code, code::CHANGED);
+    assert_eq!(response.message_id, 1);
+    assert_eq!(response.token, vec![0xca, 0xfe]);
+    assert!(response.payload.is_empty());
+
+    // a retransmission is answered again without storing the reading twice
+    let retransmitted = exchange(&client, &post(MessageType::Confirmable, 1, &reading)).await;
+    assert_eq!(retransmitted, response);
+    let readings: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM received_messages WHERE uid = ?")
+        .bind(A)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+    assert_eq!(readings, 1);
+    assert!(db::get_connection(&state.pool, A).await.is_ok());
+
+    db::add_queued_message(
+        &state.pool,
+        None,
+        "AVG#1700000000#20#temperature".to_string(),
+    )
+    .await
+    .unwrap();
+    db::add_queued_message(&state.pool, None, "AVG#1700000000#45#humidity".to_string())
+        .await
+        .unwrap();
+    let response = exchange(&client, &post(MessageType::NonConfirmable, 2, &reading)).await;
+    assert_eq!(response.kind, MessageType::NonConfirmable);
+    assert_eq!(response.code, code::CHANGED);
+    assert_eq!(response.payload, b"AVG#1700000000#20#temperature");
+
+    let invalid = exchange(&client, &post(MessageType::Confirmable, 3, "SENSOR#x")).await;
+    assert_eq!(invalid.code, code::BAD_REQUEST);
+
+    let mut elsewhere = post(MessageType::Confirmable, 4, &reading);
+    elsewhere.options = vec![(URI_PATH, b"avg".to_vec())];
+    assert_eq!(exchange(&client, &elsewhere).await.code, code::NOT_FOUND);
+}
@@ -0,0 +1,78 @@
+use cloud::{
+    codec::{self, Framing},
+    protocols::Encoding,
+};
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+// encodes each message as the server would and decodes it as if the client had sent it
+fn round_trip(encoding: Encoding, msgs: &[String]) {
+    let framing = Framing {
+        encoding,
+        ..Default::default()
+    };
+
+    for msg in msgs {
+        let frames = codec::encode(std::slice::from_ref(msg), framing).unwrap();
+        assert_eq!(frames.len(), 1);
+        let decoded = codec::decode(frames.into_iter().next().unwrap(), framing, UID).unwrap();
+        assert_eq!(&decoded, &vec![msg.clone()], "{:?}", encoding);
+    }
+}
+
+fn sample_messages() -> Vec<String> {
+    vec![
+        format!("SENSOR#{}#1690000000#21.5", UID),
+        format!("SENSOR#{}#1690000000#21.5#humidity", UID),
+        format!("SENSOR#{}#1690000000#-3#temperature#alarm", UID),
+        "AVG#1690000010#21.25#temperature#42".to_string(),
+        format!("ACK#{}#42", UID),
+        format!("DISCONN#{}", UID),
+    ]
+}
+
+#[test]
+fn text_round_trip() {
+    round_trip(Encoding::Text, &sample_messages());
+}
+
+#[cfg(feature = "binary")]
+#[test]
+fn binary_round_trip() {
+    round_trip(Encoding::Binary, &sample_messages());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_round_trip() {
+    round_trip(Encoding::Json, &sample_messages());
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn cbor_round_trip() {
+    round_trip(Encoding::Cbor, &sample_messages());
+}
+
+#[cfg(feature = "protobuf")]
+#[test]
+fn protobuf_round_trip() {
+    round_trip(Encoding::Protobuf, &sample_messages());
+}
+
+#[test]
+fn messages_without_structured_form_stay_text() {
+    for encoding in [
+        Encoding::Binary,
+        Encoding::Json,
+        Encoding::Cbor,
+        Encoding::Protobuf,
+    ] {
+        let framing = Framing {
+            encoding,
+            ..Default::default()
+        };
+        let frames = codec::encode(&["DRAIN#30".to_string()], framing).unwrap();
+        assert_eq!(frames[0].clone().into_text().unwrap(), "DRAIN#30");
+    }
+}
@@ -0,0 +1,155 @@
+use cloud::{
+    admission, archive, budget, cache, clock, config, db, events, flags, forwarding, latency,
+    pairing, probes, protocols, publisher, retention, send_queue, sessions, signing, sinks,
+    webhooks, AppState,
+};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// the default configuration, spelled out so the tests don't depend on the environment
+pub fn config() -> config::Config {
+    config::Config {
+        port: 3000,
+        sniff_protocols: false,
+        line_port: None,
+        grpc_port: None,
+        coap_port: None,
+        mdns_name: None,
+        mdns_address: None,
+        avg_window_size: 5,
+        avg_interval_secs: 10,
+        avg_aggregation: config::Aggregation::Mean,
+        avg_timestamp: config::AggregationTimestamp::Device,
+        avg_epsilon: None,
+        avg_heartbeat_secs: 60,
+        avg_backfill_max_secs: 3600,
+        value_decimals: None,
+        avg_stale_secs: 300,
+        forwarding_mode: forwarding::ForwardingMode::Off,
+        forwarding_window_secs: 60,
+        forwarding_aggregation: config::Aggregation::Mean,
+        rate_limit_per_sec: 5.0,
+        rate_limit_burst: 10.0,
+        fog_rate_limit_per_sec: 100.0,
+        fog_rate_limit_burst: 200.0,
+        rate_limit_mode: config::RateLimitMode::Drop,
+        duplicate_sessions: config::DuplicateSessions::KickOld,
+        drain_timeout_secs: 30,
+        max_frame_bytes: 64 * 1024,
+        shutdown_grace_secs: 10,
+        send_queue_capacity: 256,
+        send_queue_policy: send_queue::QueuePolicy::Backpressure,
+        max_consecutive_errors: 5,
+        bandwidth_sample_secs: 60,
+        ingest_max_in_flight: 256,
+        attention_refresh_secs: 60,
+        offline_after_secs: 300,
+        max_clock_skew_secs: 120,
+        timestamp_policy: config::TimestampPolicy::Accept,
+        max_timestamp_skew_secs: 86400,
+        bandwidth_quota_bytes: None,
+        stream_url: None,
+        stream_topic: "readings".to_string(),
+        sink_urls: None,
+        sink_influx_token: None,
+        sink_buffer: 10_000,
+        enrich_attributes: None,
+        upstream_budget_bytes: None,
+        redis_url: None,
+        cache_ttl_secs: 30,
+        retention_interval_secs: 3600,
+        received_retention: config::RetentionPolicy::default(),
+        received_summarize_percent: None,
+        queued_retention: config::RetentionPolicy::default(),
+        delivered_retention: config::RetentionPolicy::default(),
+        rollup_interval_secs: 300,
+        archive_url: None,
+        archive_interval_secs: None,
+        archive_prune: false,
+        alert_webhook_url: None,
+        webhook_urls: None,
+        webhook_secret: None,
+        webhook_max_attempts: 5,
+        webhook_backoff_ms: 1000,
+        webhook_health_ms: 30000,
+        tls_cert_path: None,
+        tls_key_path: None,
+        tls_client_ca_path: None,
+        signing_secret: None,
+        replay_window_secs: 300,
+        admin_token: None,
+        api_auth: false,
+        api_sunset: None,
+        db_max_connections: 10,
+        db_busy_timeout_ms: 5000,
+        db_journal_mode: sqlx::sqlite::SqliteJournalMode::Wal,
+        db_synchronous: sqlx::sqlite::SqliteSynchronous::Normal,
+        slow_query_ms: 100,
+        log_format: config::LogFormat::Text,
+        latency_target_ms: None,
+        serial_ports: None,
+        local_sensors: None,
+        local_sensor_interval_secs: 10,
+        local_sensor_jitter_ms: 500,
+        local_sensor_channels: None,
+    }
+}
+
+// the state of a server with the default configuration and an in-memory db
+#[allow(dead_code)]
+pub async fn state() -> Arc<AppState> {
+    state_with(config()).await
+}
+
+// the state of a server with the given configuration and an in-memory db
+pub async fn state_with(config: config::Config) -> Arc<AppState> {
+    state_on(config, "sqlite::memory:").await
+}
+
+// the state of a server with the given configuration and db
+pub async fn state_on(config: config::Config, db_url: &str) -> Arc<AppState> {
+    state_at(config, db_url, Arc::new(clock::SystemClock)).await
+}
+
+// the state of a server with the given configuration and an in-memory db, running on the clock
+#[allow(dead_code)]
+pub async fn state_with_clock(
+    config: config::Config,
+    clock: Arc<dyn clock::Clock>,
+) -> Arc<AppState> {
+    state_at(config, "sqlite::memory:", clock).await
+}
+
+async fn state_at(
+    config: config::Config,
+    db_url: &str,
+    clock: Arc<dyn clock::Clock>,
+) -> Arc<AppState> {
+    Arc::new(AppState {
+        avg_settings: watch::Sender::new(protocols::AvgSettings::from_config(&config)),
+        forwarding: forwarding::Forwarding::new(forwarding::ForwardingSettings::from_config(
+            &config,
+        )),
+        pool: db::open_db(db_url, &db::PoolSettings::from_config(&config)).await,
+        cache: cache::Cache::connect(None, config.cache_ttl_secs).await,
+        budget: budget::UpstreamBudget::new(config.upstream_budget_bytes),
+        config,
+        sessions: sessions::Sessions::default(),
+        admission: admission::Admission::default(),
+        events: events::Events::default(),
+        pruned: retention::Pruned::default(),
+        replay: signing::ReplayGuard::default(),
+        flags: flags::Flags::default(),
+        latency: latency::Latency::default(),
+        alert_rules_changed: tokio::sync::Notify::new(),
+        webhooks: webhooks::Webhooks::default(),
+        upstream: publisher::Upstream::default(),
+        sinks: sinks::Sinks::default(),
+        send_queues: send_queue::QueueStats::default(),
+        pairing: pairing::PairingWindow::default(),
+        archive: archive::Archive::default(),
+        staleness: protocols::Staleness::default(),
+        services: probes::Services::default(),
+        clock,
+    })
+}
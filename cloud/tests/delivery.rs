@@ -0,0 +1,98 @@
+use cloud::db;
+use sqlx::{Pool, Sqlite};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const B: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+const SENT_AT: i64 = 1690000000;
+
+// a fresh db with two connected clients
+async fn two_clients() -> Pool<Sqlite> {
+    let path = std::env::temp_dir().join(format!("delivery-{}.db", uuid::Uuid::new_v4()));
+    let pool = db::open_db(
+        &format!("sqlite://{}", path.display()),
+        &db::PoolSettings::from_config(&common::config()),
+    )
+    .await;
+    db::add_connection(&pool, A).await.unwrap();
+    db::add_connection(&pool, B).await.unwrap();
+    pool
+}
+
+async fn new_ids(pool: &Pool<Sqlite>, uid: &str) -> Vec<i64> {
+    db::get_new_queued_messages(pool, uid, 0, i64::MAX)
+        .await
+        .unwrap()
+        .iter()
+        .map(|m| m.id)
+        .collect()
+}
+
+// sends a message to a client and lets it acknowledge it
+async fn deliver(pool: &Pool<Sqlite>, uid: &str, id: i64) {
+    db::add_pending_delivery(pool, uid, id, SENT_AT)
+        .await
+        .unwrap();
+    assert!(db::acknowledge_delivery(pool, uid, id).await.unwrap());
+}
+
+#[tokio::test]
+async fn broadcasts_reach_every_client() {
+    let pool = two_clients().await;
+    db::add_queued_message(&pool, None, "AVG#1690000000#21.5#temp".to_string())
+        .await
+        .unwrap();
+    let ids = new_ids(&pool, A).await;
+    assert_eq!(ids.len(), 1);
+
+    // the first client acknowledging doesn't hide the message from the second
+    deliver(&pool, A, ids[0]).await;
+    assert!(new_ids(&pool, A).await.is_empty());
+    assert_eq!(new_ids(&pool, B).await, ids);
+
+    deliver(&pool, B, ids[0]).await;
+    assert!(new_ids(&pool, B).await.is_empty());
+}
+
+#[tokio::test]
+async fn pending_deliveries_are_tracked_per_client() {
+    let pool = two_clients().await;
+    db::add_queued_message(&pool, None, "AVG#1690000000#21.5#temp".to_string())
+        .await
+        .unwrap();
+    let ids = new_ids(&pool, A).await;
+
+    // sent to both, only the second one acknowledges
+    db::add_pending_delivery(&pool, A, ids[0], SENT_AT)
+        .await
+        .unwrap();
+    db::add_pending_delivery(&pool, B, ids[0], SENT_AT)
+        .await
+        .unwrap();
+    assert!(db::acknowledge_delivery(&pool, B, ids[0]).await.unwrap());
+
+    // the first client gets it again once the ack timed out
+    let resend_before = i64::MAX;
+    let resent = db::get_new_queued_messages(&pool, A, resend_before, i64::MAX)
+        .await
+        .unwrap();
+    assert_eq!(resent.len(), 1);
+    assert!(
+        db::get_new_queued_messages(&pool, B, resend_before, i64::MAX)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn targeted_messages_only_reach_their_client() {
+    let pool = two_clients().await;
+    let id = db::add_targeted_message(&pool, A, "CMD#reboot".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(new_ids(&pool, A).await, vec![id]);
+    assert!(new_ids(&pool, B).await.is_empty());
+}
@@ -0,0 +1,121 @@
+use cloud::{admission::Priority, db, protocols::SensorMsg, routes};
+use std::{net::TcpListener, time::Duration};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const TOKEN: &str = "technician";
+
+#[tokio::test]
+async fn diagnostics_show_devices_buffers_and_recent_readings() {
+    let mut config = common::config();
+    config.admin_token = Some(TOKEN.parse().unwrap());
+    config.received_retention.max_rows = Some(1000);
+    let state = common::state_with(config).await;
+
+    db::add_connection(&state.pool, A).await.unwrap();
+    let reading = SensorMsg {
+        uid: A.to_string(),
+        data: 21.5,
+        timestamp: 1700000000,
+        channel: "temperature".to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    };
+    db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+
+    let get = |token: Option<&str>| {
+        let mut req = hyper::Request::get(format!("http://{}/admin/diagnostics", addr));
+        if let Some(token) = token {
+            req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        hyper::Client::new().request(req.body(hyper::Body::empty()).unwrap())
+    };
+
+    let res = get(Some("guess")).await.unwrap();
+    assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+
+    let res = get(Some(TOKEN)).await.unwrap();
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let diagnostics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(diagnostics["devices"][0]["uid"], A);
+    assert_eq!(diagnostics["devices"][0]["live"], false);
+    assert_eq!(diagnostics["buffer"]["received_messages"], 1);
+    assert_eq!(diagnostics["buffer"]["received_max_rows"], 1000);
+    assert_eq!(diagnostics["upstream"]["stream"], serde_json::Value::Null);
+    assert_eq!(diagnostics["upstream"]["stream_connected"], false);
+    assert_eq!(diagnostics["recent_readings"][0]["data"], 21.5);
+    assert_eq!(diagnostics["recent_readings"][0]["channel"], "temperature");
+}
+
+#[tokio::test]
+async fn ingest_flush_waits_for_the_readings_being_written() {
+    let mut config = common::config();
+    config.admin_token = Some(TOKEN.parse().unwrap());
+    let state = common::state_with(config).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+
+    let request = |method: hyper::Method, path: &str| {
+        let req = hyper::Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", addr, path))
+            .header(hyper::header::AUTHORIZATION, format!("Bearer {}", TOKEN));
+        hyper::Client::new().request(req.body(hyper::Body::empty()).unwrap())
+    };
+    let json = |res: hyper::Response<hyper::Body>| async move {
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    // a reading being written while the flush is asked for
+    let permit = state.admission.try_admit(Priority::Routine, 10).unwrap();
+    let status = json(
+        request(hyper::Method::GET, "/admin/ingest/status")
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status["in_flight"], 1);
+    assert!(status["oldest_ms"].is_u64());
+    assert_eq!(status["last_flush"], serde_json::Value::Null);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(permit);
+    });
+    let res = request(hyper::Method::POST, "/admin/ingest/flush")
+        .await
+        .unwrap();
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let report = json(res).await;
+    assert_eq!(report["drained"], true);
+    assert!(report["waited_ms"].as_u64().unwrap() >= 100);
+
+    let status = json(
+        request(hyper::Method::GET, "/admin/ingest/status")
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status["in_flight"], 0);
+    assert_eq!(status["oldest_ms"], serde_json::Value::Null);
+    assert_eq!(status["last_flush"]["remaining"], 0);
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "e2e")]
+
+use cloud::envelope::{crypto, Envelope};
+use cloud::protocols::{ConnMsg, SealedMsg, TimestampFormat};
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+#[test]
+fn sealed_payloads_open_with_the_recipient_key() {
+    let (secret, public) = crypto::keypair();
+    let aad = format!("{}#temp", UID);
+    let envelope = crypto::seal(public.as_bytes(), b"21.5", aad.as_bytes());
+
+    // the envelope survives the trip through a SEALED message
+    let msg = format!("SEALED#{}#1690000000#temp#{}", UID, envelope.to_hex());
+    let sealed = SealedMsg::parse(&msg, TimestampFormat::Auto).unwrap();
+    assert_eq!(sealed.envelope, envelope);
+    assert_eq!(
+        crypto::open(&secret, &sealed.envelope, aad.as_bytes()),
+        Some(b"21.5".to_vec())
+    );
+}
+
+#[test]
+fn other_keys_associated_data_and_tampering_are_rejected() {
+    let (secret, public) = crypto::keypair();
+    let (other, _) = crypto::keypair();
+    let envelope = crypto::seal(public.as_bytes(), b"21.5", b"a");
+
+    assert_eq!(crypto::open(&other, &envelope, b"a"), None);
+    assert_eq!(crypto::open(&secret, &envelope, b"b"), None);
+
+    let mut tampered = envelope.clone();
+    tampered.ciphertext[0] ^= 1;
+    assert_eq!(crypto::open(&secret, &tampered, b"a"), None);
+}
+
+#[test]
+fn devices_announce_their_key_in_conn() {
+    let (_, public) = crypto::keypair();
+    let key = hex::encode(public.as_bytes());
+    let conn = ConnMsg::from_msg(&format!("CONN#{}#pubkey={}", UID, key.to_uppercase())).unwrap();
+    assert_eq!(conn.public_key, Some(key));
+
+    assert!(ConnMsg::from_msg(&format!("CONN#{}#pubkey=abcd", UID)).is_err());
+    assert!(Envelope::from_hex("00").is_err());
+}
@@ -0,0 +1,130 @@
+use axum::extract::ws::Message;
+use cloud::{
+    codec::{self, Framing},
+    handlers::ErrorPolicy,
+    protocols::{self, Compression},
+};
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+// mirrors the validation the websocket reader does for SENSOR messages
+fn is_valid_sensor(msg: &str) -> bool {
+    matches!(
+        protocols::get_protocol(msg),
+        Ok(protocols::Protocol::SENSOR)
+    ) && protocols::SensorMsg::from_msg(msg).is_ok()
+}
+
+// feeds messages through the policy, returns the index at which the connection would be closed
+fn first_close(policy: &mut ErrorPolicy, msgs: &[String]) -> Option<usize> {
+    for (i, msg) in msgs.iter().enumerate() {
+        if is_valid_sensor(msg) {
+            policy.record_success();
+        } else if policy.record_failure() {
+            return Some(i);
+        }
+    }
+    None
+}
+
+#[test]
+fn single_failures_do_not_close() {
+    let mut policy = ErrorPolicy::new(3);
+    assert!(!policy.record_failure());
+    assert!(!policy.record_failure());
+    policy.record_success();
+    assert!(!policy.record_failure());
+    assert!(!policy.record_failure());
+}
+
+#[test]
+fn consecutive_failures_close_at_threshold() {
+    let mut policy = ErrorPolicy::new(3);
+    assert!(!policy.record_failure());
+    assert!(!policy.record_failure());
+    assert!(policy.record_failure());
+}
+
+#[test]
+fn partial_garbage_is_skipped() {
+    let msgs = vec![
+        format!("SENSOR#{}#1690000000#21.5", UID),
+        "SENSOR#garbage".to_string(),
+        format!("SENSOR#{}#1690000001#", UID),
+        format!("SENSOR#{}#1690000002#21.7", UID),
+        String::new(),
+        "\u{0}\u{1}#####".to_string(),
+        format!("SENSOR#{}#1690000003#21.6", UID),
+    ];
+
+    let mut policy = ErrorPolicy::new(3);
+    assert_eq!(first_close(&mut policy, &msgs), None);
+}
+
+#[test]
+fn continuous_garbage_closes() {
+    let msgs = vec![
+        format!("SENSOR#{}#1690000000#21.5", UID),
+        "SENSOR#".to_string(),
+        "#".to_string(),
+        "not a message".to_string(),
+        format!("SENSOR#{}#1690000001#21.5", UID),
+    ];
+
+    let mut policy = ErrorPolicy::new(3);
+    assert_eq!(first_close(&mut policy, &msgs), Some(3));
+}
+
+#[test]
+fn garbage_inside_compressed_batch_is_split_per_message() {
+    let msgs = vec![
+        format!("SENSOR#{}#1690000000#21.5", UID),
+        "SENSOR#broken".to_string(),
+        format!("SENSOR#{}#1690000001#21.7", UID),
+    ];
+
+    let framing = Framing {
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let frames = codec::encode(&msgs, framing).unwrap();
+    assert_eq!(frames.len(), 1);
+
+    let decoded = codec::decode(frames.into_iter().next().unwrap(), framing, UID).unwrap();
+    assert_eq!(decoded, msgs);
+
+    let valid: Vec<bool> = decoded.iter().map(|m| is_valid_sensor(m)).collect();
+    assert_eq!(valid, vec![true, false, true]);
+}
+
+#[test]
+fn undecodable_frame_is_an_error() {
+    let framing = Framing {
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let frame = Message::Binary(vec![0xde, 0xad, 0xbe, 0xef]);
+    assert!(codec::decode(frame, framing, UID).is_err());
+}
+
+#[test]
+fn batches_decompressing_past_the_limit_are_rejected() {
+    let msgs = vec![format!("SENSOR#{}#1690000000#{}", UID, "1".repeat(2000))];
+    let framing = Framing {
+        compression: Compression::Zstd,
+        max_batch_bytes: Some(1000),
+        ..Default::default()
+    };
+    let frame = codec::encode(&msgs, framing).unwrap().remove(0);
+    // the frame itself is small
+    assert!(codec::frame_len(&frame) < 1000);
+    let e = codec::decode(frame, framing, UID).err().unwrap();
+    assert!(e.is::<codec::BatchTooLarge>());
+
+    let framing = Framing {
+        max_batch_bytes: Some(4000),
+        ..framing
+    };
+    let frame = codec::encode(&msgs, framing).unwrap().remove(0);
+    assert_eq!(codec::decode(frame, framing, UID).unwrap(), msgs);
+}
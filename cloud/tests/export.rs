@@ -0,0 +1,183 @@
+use cloud::{db, protocols::SensorMsg, routes};
+use hyper::{header, Body, Method, Request, StatusCode};
+use std::net::TcpListener;
+
+mod common;
+
+const A: &str = "5e8b1d3f-9a2c-4f7e-b6d0-3c1a8e5f2b94";
+const B: &str = "c4a7e2f9-6b1d-4e3a-8f5c-9d2b0e7a1c36";
+
+#[tokio::test]
+async fn the_history_of_a_device_is_downloaded_as_csv_or_json() {
+    let state = common::state().await;
+    for (uid, timestamp, data, channel) in [
+        (A, 1000, 20.5, "temperature"),
+        (A, 1010, 21.0, "temperature"),
+        (A, 1020, 21.5, "temperature"),
+        (B, 1010, 5.0, "temperature"),
+        (B, 1020, 6.0, "room \"a\", north"),
+    ] {
+        let reading = SensorMsg {
+            uid: uid.to_string(),
+            data,
+            timestamp,
+            channel: channel.to_string(),
+            alarm: false,
+            seq: None,
+            via: None,
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    let get = |query: String| {
+        let uri = format!("http://{}/api/export?{}", addr, query);
+        async move {
+            let res = hyper::Client::new()
+                .get(uri.parse().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            let headers = res.headers().clone();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            (headers, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    // the range includes its start and excludes its end
+    let (headers, csv) = get(format!("uid={}&from=1005&to=1030", A)).await;
+    assert_eq!(headers["content-type"], "text/csv");
+    assert_eq!(
+        headers["content-disposition"],
+        format!("attachment; filename=\"{}.csv\"", A).as_str()
+    );
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "uid,channel,created_at,data,min,max,count");
+    assert_eq!(
+        &lines[1..],
+        [
+            format!("{},temperature,1010,21,21,21,1", A),
+            format!("{},temperature,1020,21.5,21.5,21.5,1", A)
+        ]
+    );
+
+    // fields with commas or quotes are quoted
+    let (_, csv) = get(format!("uid={}&from=1020", B)).await;
+    assert_eq!(
+        csv.lines().nth(1).unwrap(),
+        format!("{},\"room \"\"a\"\", north\",1020,6,6,6,1", B)
+    );
+
+    let (headers, json) = get(format!("uid={}&format=json", A)).await;
+    assert_eq!(headers["content-type"], "application/json");
+    let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0]["data"], 20.5);
+    assert!(rows.iter().all(|row| row["uid"] == A));
+
+    // an empty range is still a valid document
+    let (_, json) = get(format!("uid={}&format=json&from=2000", B)).await;
+    assert_eq!(
+        serde_json::from_str::<Vec<serde_json::Value>>(&json).unwrap(),
+        Vec::<serde_json::Value>::new()
+    );
+}
+
+#[tokio::test]
+async fn readings_carry_the_attributes_of_their_device() {
+    let mut config = common::config();
+    config.enrich_attributes = Some("site,asset_tag".parse().unwrap());
+    let state = common::state_with(config).await;
+    db::upsert_connection(&state.pool, A, state.clock.unix_now())
+        .await
+        .unwrap();
+    let reading = |timestamp| SensorMsg {
+        uid: A.to_string(),
+        data: 20.5,
+        timestamp,
+        channel: "temperature".to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    };
+    db::add_received_message(&state.pool, &reading(1000), state.clock.unix_now())
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+    let put = |uid: &str, body: &'static str| {
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri(format!("http://{}/api/devices/{}/attributes", addr, uid))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        async move { hyper::Client::new().request(req).await.unwrap().status() }
+    };
+
+    assert_eq!(
+        put(A, r#"{"attributes":{"building":"b"}}"#).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        put(B, r#"{"attributes":{"site":"x"}}"#).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        put(
+            A,
+            r#"{"attributes":{"site":"plant 1, hall 3","asset_tag":"T-17"}}"#
+        )
+        .await,
+        StatusCode::NO_CONTENT
+    );
+
+    // readings stored before keep what their device had then
+    let stored = db::add_received_message(&state.pool, &reading(1010), state.clock.unix_now())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.attributes["asset_tag"], "T-17");
+
+    let get = |query: String| {
+        let uri = format!("http://{}/api/export?uid={}&{}", addr, A, query);
+        async move {
+            let res = hyper::Client::new()
+                .get(uri.parse().unwrap())
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+    let csv = get("format=csv".to_string()).await;
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "uid,channel,created_at,data,min,max,count,site,asset_tag".to_string(),
+            format!("{},temperature,1000,20.5,20.5,20.5,1,,", A),
+            format!(
+                "{},temperature,1010,20.5,20.5,20.5,1,\"plant 1, hall 3\",T-17",
+                A
+            ),
+        ]
+    );
+
+    let json = get("format=json".to_string()).await;
+    let rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    assert_eq!(rows[0]["attributes"], serde_json::Value::Null);
+    assert_eq!(rows[1]["attributes"]["site"], "plant 1, hall 3");
+}
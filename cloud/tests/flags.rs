@@ -0,0 +1,48 @@
+use cloud::{db, flags};
+use sqlx::{Pool, Sqlite};
+
+mod common;
+
+async fn fresh_db() -> Pool<Sqlite> {
+    let path = std::env::temp_dir().join(format!("flags-{}.db", uuid::Uuid::new_v4()));
+    db::open_db(
+        &format!("sqlite://{}", path.display()),
+        &db::PoolSettings::from_config(&common::config()),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn groups_override_the_deployment_which_overrides_the_default() {
+    let pool = fresh_db().await;
+    let flags = flags::Flags::default();
+    let sealed = flags::SEALED_MESSAGES;
+    assert!(flags.is_enabled_for(sealed, Some("tenant")).await);
+
+    flags.set(&pool, sealed, None, Some(false)).await.unwrap();
+    flags
+        .set(&pool, sealed, Some("tenant"), Some(true))
+        .await
+        .unwrap();
+    assert!(!flags.is_enabled(sealed).await);
+    assert!(!flags.is_enabled_for(sealed, Some("other")).await);
+    assert!(flags.is_enabled_for(sealed, Some("tenant")).await);
+
+    // clearing the deployment setting goes back to the default
+    flags.set(&pool, sealed, None, None).await.unwrap();
+    assert!(flags.is_enabled_for(sealed, Some("other")).await);
+}
+
+#[tokio::test]
+async fn flags_survive_a_restart() {
+    let pool = fresh_db().await;
+    flags::Flags::default()
+        .set(&pool, flags::ROLLUPS, None, Some(false))
+        .await
+        .unwrap();
+
+    let restarted = flags::Flags::default();
+    assert!(restarted.is_enabled(flags::ROLLUPS).await);
+    restarted.load(&pool).await.unwrap();
+    assert!(!restarted.is_enabled(flags::ROLLUPS).await);
+}
@@ -0,0 +1,140 @@
+use cloud::{
+    budget::Class,
+    clock::{Clock, MockClock},
+    config::Aggregation,
+    forwarding::{self, ForwardingMode},
+    protocols::SensorMsg,
+    routes,
+    webhooks::WebhookEvent,
+};
+use hyper::{header, Body, Method, Request, StatusCode};
+use std::{net::TcpListener, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const B: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+const TOKEN: &str = "operator";
+// the start of a budget window
+const NOW: i64 = 1_699_999_200;
+
+fn reading(uid: &str, data: f64, channel: &str) -> SensorMsg {
+    SensorMsg {
+        uid: uid.to_string(),
+        data,
+        timestamp: NOW,
+        channel: channel.to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    }
+}
+
+// the uid, data, channel and count of the next reading event
+async fn next_reading(
+    events: &mut broadcast::Receiver<WebhookEvent>,
+) -> (String, f64, String, Option<usize>) {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no reading forwarded")
+            .unwrap();
+        if let WebhookEvent::Reading {
+            uid,
+            data,
+            channel,
+            count,
+            ..
+        } = event
+        {
+            return (uid, data, channel, count);
+        }
+    }
+}
+
+#[tokio::test]
+async fn readings_are_forwarded_aggregated_per_window_or_raw_while_the_budget_lasts() {
+    let mut config = common::config();
+    config.admin_token = Some(TOKEN.parse().unwrap());
+    config.forwarding_mode = ForwardingMode::Aggregate;
+    config.forwarding_window_secs = 60;
+    config.forwarding_aggregation = Aggregation::Max;
+    config.upstream_budget_bytes = Some(1000);
+    let clock = Arc::new(MockClock::new(NOW));
+    let state = common::state_with_clock(config, clock.clone()).await;
+    let mut events = state.webhooks.subscribe();
+    tokio::spawn(forwarding::forwarding_service(state.clone()));
+
+    // only the aggregate of every device and channel leaves the server at the end of the window
+    for (uid, data, channel) in [
+        (A, 20.0, "temperature"),
+        (A, 22.0, "temperature"),
+        (A, 40.0, "humidity"),
+        (B, 19.0, "temperature"),
+    ] {
+        forwarding::forward(&state, &reading(uid, data, channel));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(events.try_recv().is_err());
+    clock.advance(Duration::from_secs(60));
+    let mut aggregates = Vec::new();
+    for _ in 0..3 {
+        aggregates.push(next_reading(&mut events).await);
+    }
+    aggregates.sort_by(|a, b| (&a.0, &a.2).cmp(&(&b.0, &b.2)));
+    assert_eq!(
+        aggregates,
+        vec![
+            (A.to_string(), 40.0, "humidity".to_string(), Some(1)),
+            (A.to_string(), 22.0, "temperature".to_string(), Some(2)),
+            (B.to_string(), 19.0, "temperature".to_string(), Some(1)),
+        ]
+    );
+
+    // admins switch to raw readings while the uplink has room for them
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+    let put = |body: &'static str| {
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri(format!("http://{}/admin/forwarding", addr))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::from(body))
+            .unwrap();
+        async move { hyper::Client::new().request(req).await.unwrap().status() }
+    };
+    assert_eq!(put(r#"{"window_secs": 0}"#).await, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        put(r#"{"mode": "passthrough", "note": "uplink upgraded"}"#).await,
+        StatusCode::OK
+    );
+    forwarding::forward(&state, &reading(A, 23.5, "temperature"));
+    assert_eq!(
+        next_reading(&mut events).await,
+        (A.to_string(), 23.5, "temperature".to_string(), None)
+    );
+
+    // once the raw share of the budget is used up the readings are aggregated again
+    assert!(state.budget.try_spend_at(Class::Raw, 500, clock.unix_now()));
+    forwarding::forward(&state, &reading(A, 24.0, "temperature"));
+    forwarding::forward(&state, &reading(A, 25.0, "temperature"));
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(
+        next_reading(&mut events).await,
+        (A.to_string(), 25.0, "temperature".to_string(), Some(2))
+    );
+
+    // until the next budget window
+    clock.advance(Duration::from_secs(3600));
+    forwarding::forward(&state, &reading(A, 26.0, "temperature"));
+    assert_eq!(
+        next_reading(&mut events).await,
+        (A.to_string(), 26.0, "temperature".to_string(), None)
+    );
+}
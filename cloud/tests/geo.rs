@@ -0,0 +1,165 @@
+use cloud::{
+    db,
+    geo::{self, Area, BoundingBox},
+    protocols::SensorMsg,
+    routes,
+};
+use hyper::{Body, Request, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+
+mod common;
+
+// berlin, potsdam and new york
+const BERLIN: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const POTSDAM: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+const NEW_YORK: &str = "7c2e9f4a-1b5d-4e3c-8a6f-0d9b2e5c1a74";
+
+#[test]
+fn distances_are_great_circles() {
+    // berlin to new york is about 6385 km
+    let distance = geo::distance_km(52.52, 13.405, 40.7128, -74.006);
+    assert!((distance - 6385.0).abs() < 10.0, "{}", distance);
+    assert_eq!(geo::distance_km(52.52, 13.405, 52.52, 13.405), 0.0);
+}
+
+#[test]
+fn areas_may_cross_the_antimeridian() {
+    let pacific = BoundingBox {
+        min_lat: -30.0,
+        min_lon: 170.0,
+        max_lat: 30.0,
+        max_lon: -170.0,
+    };
+    assert!(pacific.contains(0.0, 179.5));
+    assert!(pacific.contains(0.0, -175.0));
+    assert!(!pacific.contains(0.0, 0.0));
+
+    let fiji = Area::Radius {
+        lat: -17.7,
+        lon: 179.9,
+        radius_km: 100.0,
+    };
+    let bounds = fiji.bounds();
+    assert!(bounds.min_lon > bounds.max_lon, "{:?}", bounds);
+    assert!(fiji.contains(-17.7, -179.9));
+    assert!(!fiji.contains(-17.7, 170.0));
+
+    // around the pole every longitude is in the box
+    let pole = Area::Radius {
+        lat: 89.9,
+        lon: 0.0,
+        radius_km: 50.0,
+    };
+    assert_eq!(
+        (pole.bounds().min_lon, pole.bounds().max_lon),
+        (-180.0, 180.0)
+    );
+}
+
+async fn start() -> SocketAddr {
+    let state = common::state().await;
+    for (uid, lat, lon) in [
+        (BERLIN, 52.52, 13.405),
+        (POTSDAM, 52.3906, 13.0645),
+        (NEW_YORK, 40.7128, -74.006),
+    ] {
+        db::add_connection(&state.pool, uid).await.unwrap();
+        let metadata = db::DeviceMetadata {
+            latitude: Some(lat),
+            longitude: Some(lon),
+            ..Default::default()
+        };
+        db::set_device_metadata(&state.pool, uid, &metadata)
+            .await
+            .unwrap();
+        let reading = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#21.5", uid)).unwrap();
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+    // a device that never told where it is
+    db::set_device_metadata(&state.pool, "unplaced", &db::DeviceMetadata::default())
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    addr
+}
+
+// the status and the uids in the answer
+async fn get(addr: SocketAddr, path: &str) -> (StatusCode, Vec<String>) {
+    let req = Request::builder()
+        .uri(format!("http://{}/api/v1{}", addr, path))
+        .body(Body::empty())
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let uids = match serde_json::from_slice::<Vec<serde_json::Value>>(&body) {
+        Ok(items) => items
+            .iter()
+            .map(|item| item["uid"].as_str().unwrap().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    (status, uids)
+}
+
+#[tokio::test]
+async fn devices_and_readings_are_queried_by_area() {
+    let addr = start().await;
+
+    let (status, uids) = get(
+        addr,
+        "/geo/devices?min_lat=50&min_lon=10&max_lat=55&max_lon=15",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(uids, vec![BERLIN.to_string(), POTSDAM.to_string()]);
+
+    // potsdam is about 27 km from berlin
+    let (_, uids) = get(addr, "/geo/devices?lat=52.52&lon=13.405&radius_km=20").await;
+    assert_eq!(uids, vec![BERLIN.to_string()]);
+    let (_, uids) = get(addr, "/geo/devices?lat=52.52&lon=13.405&radius_km=7000").await;
+    assert_eq!(uids.len(), 3);
+
+    let (status, uids) = get(
+        addr,
+        "/geo/readings/latest?min_lat=30&min_lon=-80&max_lat=45&max_lon=-70",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(uids, vec![NEW_YORK.to_string()]);
+
+    // potsdam is in the box around the circle but not in the circle
+    let (_, uids) = get(
+        addr,
+        "/geo/readings/latest?lat=52.52&lon=13.405&radius_km=20",
+    )
+    .await;
+    assert_eq!(uids, vec![BERLIN.to_string()]);
+
+    // no device here is a fog node
+    let (_, uids) = get(
+        addr,
+        "/geo/devices?lat=52.52&lon=13.405&radius_km=7000&fog=true",
+    )
+    .await;
+    assert!(uids.is_empty());
+
+    for query in [
+        "",
+        "lat=52.52&lon=13.405",
+        "lat=52.52&lon=13.405&radius_km=-1",
+        "min_lat=55&min_lon=10&max_lat=50&max_lon=15",
+        "min_lat=50&min_lon=10&max_lat=55&max_lon=15&radius_km=5",
+    ] {
+        let (status, _) = get(addr, &format!("/geo/devices?{}", query)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
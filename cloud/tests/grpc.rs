@@ -0,0 +1,109 @@
+use cloud::grpc::parse_reading;
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+#[test]
+fn grpc_readings_are_validated_like_sensor_messages() {
+    let reading = parse_reading(A, 1700000000, 21.5, "", false).unwrap();
+    assert_eq!(reading.uid, A);
+    assert_eq!(reading.channel, "default");
+    assert!(!reading.alarm);
+
+    let reading = parse_reading(A, 1700000000000, 3.0, "smoke", true).unwrap();
+    assert_eq!(reading.timestamp, 1700000000);
+    assert_eq!(reading.channel, "smoke");
+    assert!(reading.alarm);
+
+    assert!(parse_reading("sensor", 1700000000, 1.0, "", false).is_err());
+    assert!(parse_reading(A, 1700000000, f64::NAN, "", false).is_err());
+    assert!(parse_reading(A, 1700000000, 1.0, "Temperature", false).is_err());
+    // a channel can't carry the alarm flag or other fields
+    assert!(parse_reading(A, 1700000000, 1.0, "smoke#alarm", false).is_err());
+    assert!(parse_reading(&format!("{}#1700000000#1", A), 1700000000, 1.0, "", false).is_err());
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn readings_are_streamed_in_and_aggregates_streamed_out() {
+    use cloud::{
+        db,
+        events::StreamEvent,
+        grpc::{
+            self,
+            proto::{ingest_client::IngestClient, SensorReading, SubscribeRequest},
+        },
+    };
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::net::TcpListener;
+
+    let state = common::state().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(grpc::serve(listener, state.clone()));
+
+    let mut client = IngestClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let reading = |data: f64, channel: &str| SensorReading {
+        uid: A.to_string(),
+        timestamp: now,
+        data,
+        channel: channel.to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    };
+    let readings = vec![
+        reading(21.5, "temperature"),
+        reading(f64::INFINITY, "temperature"),
+        reading(40.0, "humidity"),
+    ];
+    let summary = client
+        .stream_sensor_data(futures_util::stream::iter(readings))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(summary.accepted, 2);
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(summary.shed, 0);
+
+    // the device was registered and its readings stored like those sent over websockets
+    assert!(db::get_connection(&state.pool, A).await.is_ok());
+    let mut latest = db::get_latest_readings(&state.pool, None).await.unwrap();
+    latest.sort_by(|a, b| a.channel.cmp(&b.channel));
+    assert_eq!(latest.len(), 2);
+    assert_eq!(latest[0].data, 40.0);
+    assert_eq!(latest[1].data, 21.5);
+
+    let mut aggregates = client
+        .subscribe_aggregates(SubscribeRequest {
+            channels: vec!["temperature".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    for (data, channel) in [(40.0, "humidity"), (21.5, "temperature")] {
+        state.events.publish(StreamEvent::Avg {
+            timestamp: 1700000000,
+            data,
+            channel: channel.to_string(),
+            tenant: None,
+        });
+    }
+    // only the subscribed channel is streamed
+    let aggregate = tokio::time::timeout(Duration::from_secs(5), aggregates.message())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(aggregate.channel, "temperature");
+    assert_eq!(aggregate.data, 21.5);
+}
@@ -0,0 +1,52 @@
+use cloud::latency::{Latency, Stage};
+use std::time::Duration;
+
+const TARGET: Duration = Duration::from_secs(2);
+
+#[test]
+fn exceeding_the_target_sheds_load_until_the_latency_recovers() {
+    let latency = Latency::default();
+    latency.observe(Stage::Ingest, Duration::from_millis(100));
+    latency.observe(Stage::Aggregate, Duration::from_millis(400));
+    latency.observe(Stage::Deliver, Duration::from_secs(3));
+    assert!(latency.check(TARGET));
+    assert_eq!(latency.end_to_end(), Duration::from_millis(3500));
+    assert_eq!(latency.violations(), 1);
+    assert_eq!(latency.max_in_flight(256), 128);
+
+    // without new observations the stages decay, 1750 ms is within the target but not well within
+    assert!(!latency.check(TARGET));
+    assert_eq!(latency.shed_level(), 1);
+
+    assert!(!latency.check(TARGET));
+    assert_eq!(latency.shed_level(), 0);
+    assert_eq!(latency.max_in_flight(256), 256);
+}
+
+#[test]
+fn deliveries_get_what_ingest_and_aggregation_leave_of_the_target() {
+    let latency = Latency::default();
+    let requested = Duration::from_secs(5);
+    assert_eq!(latency.poll_interval(TARGET, requested), TARGET);
+
+    latency.observe(Stage::Ingest, Duration::from_millis(200));
+    latency.observe(Stage::Aggregate, Duration::from_millis(300));
+    latency.check(TARGET);
+    assert_eq!(
+        latency.poll_interval(TARGET, requested),
+        Duration::from_millis(1500)
+    );
+    // devices asking for faster deliveries keep their interval
+    assert_eq!(
+        latency.poll_interval(TARGET, Duration::from_secs(1)),
+        Duration::from_secs(1)
+    );
+
+    // a blown budget still leaves the minimum interval
+    latency.observe(Stage::Aggregate, Duration::from_secs(5));
+    latency.check(TARGET);
+    assert_eq!(
+        latency.poll_interval(TARGET, requested),
+        Duration::from_millis(250)
+    );
+}
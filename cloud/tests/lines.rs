@@ -0,0 +1,82 @@
+use cloud::{db, lines};
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+type Lines = tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>;
+
+async fn connect(addr: SocketAddr) -> (Lines, OwnedWriteHalf) {
+    let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+    (BufReader::new(read).lines(), write)
+}
+
+// the next line, None once the server closed the connection
+async fn recv(lines: &mut Lines) -> Option<String> {
+    tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+        .await
+        .expect("no line from the server")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn devices_speak_the_protocol_in_lines() {
+    let state = common::state().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(lines::listen(listener, state.clone()));
+
+    // binary frames can't be sent as lines
+    let (mut read, mut write) = connect(addr).await;
+    let conn = format!("CONN#{}#compression=zstd\r\n", A);
+    write.write_all(conn.as_bytes()).await.unwrap();
+    let err = recv(&mut read).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    assert_eq!(recv(&mut read).await, None);
+
+    let (mut read, mut write) = connect(addr).await;
+    write
+        .write_all(format!("CONN#{}\r\n", A).as_bytes())
+        .await
+        .unwrap();
+    assert!(recv(&mut read).await.unwrap().starts_with("SESSION#"));
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let sensor = format!("SENSOR#{}#{}#21.5#temperature\n", A, now.as_secs());
+    write.write_all(sensor.as_bytes()).await.unwrap();
+    write.write_all(b"HELLO\n").await.unwrap();
+    let err = recv(&mut read).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    let sensor = format!("SENSOR#{}#{}#21,5#temperature\n", A, now.as_secs());
+    write.write_all(sensor.as_bytes()).await.unwrap();
+    let err = recv(&mut read).await.unwrap();
+    assert!(err.starts_with("ERR#DECIMAL_COMMA#"), "{}", err);
+
+    // the reading is stored in the background
+    let mut latest = Vec::new();
+    for _ in 0..50 {
+        latest = db::get_latest_readings(&state.pool, None).await.unwrap();
+        if !latest.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].data, 21.5);
+
+    write
+        .write_all(format!("DISCONN#{}\n", A).as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut read).await, None);
+    let connection = db::get_connection(&state.pool, A).await.unwrap();
+    assert!(connection.disconnected_at.is_some());
+}
@@ -0,0 +1,102 @@
+use cloud::{config::LogFormat, lines};
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+// log lines written by the subscriber of the test
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Logs {
+    fn lines(&self) -> Vec<serde_json::Value> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+#[test]
+fn log_formats_are_text_or_json() {
+    assert_eq!("text".parse(), Ok(LogFormat::Text));
+    assert_eq!("json".parse(), Ok(LogFormat::Json));
+    assert!("logfmt".parse::<LogFormat>().is_err());
+}
+
+#[tokio::test]
+async fn json_logs_carry_the_session_and_the_message() {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+    // the test runs on a single thread, so the tasks of the session log to it as well
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let state = common::state().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(lines::listen(listener, state.clone()));
+
+    let (read, mut write) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut read = BufReader::new(read).lines();
+    write
+        .write_all(format!("CONN#{}\n", A).as_bytes())
+        .await
+        .unwrap();
+    let session = tokio::time::timeout(Duration::from_secs(5), read.next_line())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let session_id = session.strip_prefix("SESSION#").unwrap().to_string();
+    for msg in ["HELLO\n", "SENSOR#x#1#2\n"] {
+        write.write_all(msg.as_bytes()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), read.next_line())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    let lines = logs.lines();
+    let invalid: Vec<&serde_json::Value> = lines
+        .iter()
+        .filter(|line| {
+            line["fields"]["message"]
+                .as_str()
+                .is_some_and(|message| message.starts_with("Invalid"))
+        })
+        .collect();
+    assert_eq!(invalid.len(), 2, "{:?}", lines);
+    for (line, (protocol, message_id)) in invalid.iter().zip([("INVALID", 1), ("SENSOR", 2)]) {
+        assert_eq!(line["spans"][0]["name"], "session");
+        assert_eq!(line["spans"][0]["uid"], A);
+        assert_eq!(line["spans"][0]["session_id"], session_id.as_str());
+        assert_eq!(line["span"]["name"], "message");
+        assert_eq!(line["span"]["protocol"], protocol);
+        assert_eq!(line["span"]["message_id"], message_id);
+    }
+}
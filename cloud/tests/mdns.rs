@@ -0,0 +1,78 @@
+use cloud::mdns::{rtype, Advertisement, Packet, RecordData, SERVICE};
+use std::net::Ipv4Addr;
+
+fn advertisement() -> Advertisement {
+    Advertisement::new("gateway", Ipv4Addr::new(192, 168, 1, 20), 3000, false).unwrap()
+}
+
+fn exchange(query: &Packet, legacy: bool) -> Option<Packet> {
+    let query = Packet::parse(&query.encode()).unwrap();
+    let response = advertisement().respond(&query, legacy)?;
+    Some(Packet::parse(&response.encode()).unwrap())
+}
+
+#[test]
+fn browsing_devices_get_the_websocket_endpoint_in_one_response() {
+    let response = exchange(&Packet::query(SERVICE, rtype::PTR), false).unwrap();
+    assert!(response.is_response);
+    assert_eq!(response.id, 0);
+    assert!(response.questions.is_empty());
+
+    let data: Vec<_> = response.records.iter().map(|r| r.data.clone()).collect();
+    assert_eq!(
+        data,
+        vec![
+            RecordData::Ptr(format!("gateway.{}", SERVICE)),
+            RecordData::Srv {
+                port: 3000,
+                target: "gateway.local".to_string()
+            },
+            RecordData::Txt(vec!["path=/ws".to_string(), "scheme=ws".to_string()]),
+            RecordData::A(Ipv4Addr::new(192, 168, 1, 20)),
+        ]
+    );
+}
+
+#[test]
+fn only_queries_for_the_server_are_answered() {
+    // host lookups are answered, unicast for legacy resolvers with their id and question
+    let mut query = Packet::query("GATEWAY.local", rtype::A);
+    query.id = 0x1234;
+    let response = exchange(&query, true).unwrap();
+    assert_eq!(response.id, 0x1234);
+    assert_eq!(response.questions, query.questions);
+    assert_eq!(
+        response.records[0].data,
+        RecordData::A(Ipv4Addr::new(192, 168, 1, 20))
+    );
+
+    assert!(exchange(&Packet::query("_http._tcp.local", rtype::PTR), false).is_none());
+    assert!(exchange(&Packet::query("printer.local", rtype::A), false).is_none());
+    // other responders answering the same query are not answered in turn
+    let announcement = advertisement().announcement();
+    assert!(advertisement().respond(&announcement, false).is_none());
+
+    assert!(Advertisement::new("my gateway", Ipv4Addr::LOCALHOST, 3000, false).is_err());
+    assert!(Packet::parse(&[0, 1, 0, 0, 0, 1]).is_err());
+}
+
+#[test]
+fn compressed_names_are_followed_but_not_into_loops() {
+    // a query for _foghw._tcp.local and gateway._foghw._tcp.local, the second pointing at the first
+    let mut datagram = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+    for label in ["_foghw", "_tcp", "local"] {
+        datagram.push(label.len() as u8);
+        datagram.extend_from_slice(label.as_bytes());
+    }
+    datagram.extend_from_slice(&[0, 0, 12, 0, 1]);
+    datagram.push(7);
+    datagram.extend_from_slice(b"gateway");
+    datagram.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1]);
+    let query = Packet::parse(&datagram).unwrap();
+    assert_eq!(query.questions[1].name, format!("gateway.{}", SERVICE));
+    assert_eq!(query.questions[1].rtype, rtype::SRV);
+
+    // a name pointing at itself
+    let looping = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+    assert!(Packet::parse(&looping).is_err());
+}
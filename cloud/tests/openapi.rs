@@ -0,0 +1,33 @@
+use cloud::openapi::ApiDoc;
+use utoipa::OpenApi;
+
+#[test]
+fn document_covers_devices_messages_and_commands() {
+    let doc = ApiDoc::openapi();
+    for path in [
+        "/api/v1/connections",
+        "/api/v1/connections/{uid}",
+        "/api/v1/readings/latest",
+        "/api/v1/devices/{uid}/export",
+        "/api/v1/export",
+        "/api/v1/devices/{uid}/commands",
+        "/api/v1/devices/{uid}/sealed",
+    ] {
+        assert!(doc.paths.paths.contains_key(path), "{}", path);
+    }
+
+    let json = doc.to_json().unwrap();
+    assert!(json.contains("\"ConnectionInfo\""));
+}
+
+#[test]
+fn every_schema_reference_resolves() {
+    let doc = ApiDoc::openapi();
+    let schemas = &doc.components.as_ref().unwrap().schemas;
+    let json = doc.to_json().unwrap();
+
+    for reference in json.split("#/components/schemas/").skip(1) {
+        let name = &reference[..reference.find('"').unwrap()];
+        assert!(schemas.contains_key(name), "{}", name);
+    }
+}
@@ -0,0 +1,113 @@
+use cloud::{routes, signing};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{header, Body, Method, Request, StatusCode};
+use std::{
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+mod common;
+
+const TOKEN: &str = "operator";
+const SECRET: &str = "pairing-secret";
+
+async fn start() -> SocketAddr {
+    let mut config = common::config();
+    config.admin_token = Some(TOKEN.parse().unwrap());
+    config.signing_secret = Some(SECRET.parse().unwrap());
+    let state = common::state_with(config).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    addr
+}
+
+async fn request(method: Method, uri: String, body: &str) -> (StatusCode, serde_json::Value) {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+// sends the first message of a session and returns the answer of the server
+async fn first_answer(addr: SocketAddr, msg: &str) -> String {
+    let (mut ws, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    ws.send(Message::Text(msg.to_string())).await.unwrap();
+    let answer = tokio::time::timeout(Duration::from_secs(5), ws.next())
+        .await
+        .expect("no answer from the server");
+    match answer {
+        Some(Ok(Message::Text(text))) => text,
+        other => panic!("unexpected answer {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn devices_paired_while_pairing_is_open_wait_for_approval() {
+    let addr = start().await;
+
+    let err = first_answer(addr, "PAIR#kitchen").await;
+    assert!(err.starts_with("ERR#AUTH_FAILED#"), "{}", err);
+
+    let (status, _) = request(
+        Method::PUT,
+        format!("http://{}/admin/pairing", addr),
+        r#"{"secs":7200}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, window) = request(
+        Method::PUT,
+        format!("http://{}/admin/pairing", addr),
+        r#"{"secs":60}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(window["open_until"].is_i64());
+
+    // the key is the one the device signs its messages with
+    let paired = first_answer(addr, "PAIR#kitchen").await;
+    let fields: Vec<&str> = paired.split('#').collect();
+    assert_eq!(fields[0], "PAIRED", "{}", paired);
+    let uid = fields[1];
+    assert_eq!(fields[2], hex::encode(signing::device_key(SECRET, uid)));
+
+    let (_, pairings) = request(
+        Method::GET,
+        format!("http://{}/admin/pairings?status=pending", addr),
+        "",
+    )
+    .await;
+    assert_eq!(pairings[0]["uid"], uid);
+    assert_eq!(pairings[0]["name"], "kitchen");
+
+    // pending devices may connect, rejected ones can't anymore
+    let session = first_answer(addr, &format!("CONN#{}", uid)).await;
+    assert!(session.starts_with("SESSION#"), "{}", session);
+    let (status, _) = request(
+        Method::PUT,
+        format!("http://{}/admin/pairings/{}", addr, uid),
+        r#"{"status":"rejected"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let err = first_answer(addr, &format!("CONN#{}", uid)).await;
+    assert!(err.starts_with("ERR#AUTH_FAILED#"), "{}", err);
+
+    let (status, _) = request(Method::DELETE, format!("http://{}/admin/pairing", addr), "").await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let err = first_answer(addr, "PAIR").await;
+    assert!(err.starts_with("ERR#AUTH_FAILED#"), "{}", err);
+}
@@ -0,0 +1,362 @@
+use cloud::protocols::{
+    self, AckMsg, AvgMsg, ConnMsg, DisconnMsg, ErrorCode, KvGetMsg, ParseError, SensorMsg,
+};
+use proptest::prelude::*;
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+#[test]
+fn empty_messages_are_rejected() {
+    assert!(matches!(
+        protocols::get_protocol(""),
+        Err(ParseError::Empty)
+    ));
+    assert!(matches!(SensorMsg::from_msg(""), Err(ParseError::Empty)));
+    assert!(matches!(DisconnMsg::from_msg(""), Err(ParseError::Empty)));
+}
+
+#[test]
+fn field_counts_are_enforced() {
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000", UID)),
+        Err(ParseError::FieldCount { found: 2, .. })
+    ));
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#temp#alarm#x", UID)),
+        Err(ParseError::FieldCount { found: 6, .. })
+    ));
+    assert!(matches!(
+        AckMsg::from_msg(&format!("ACK#{}#1#2", UID)),
+        Err(ParseError::FieldCount { found: 3, .. })
+    ));
+}
+
+#[test]
+fn trailing_garbage_is_rejected() {
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#21.5#", UID)),
+        Err(ParseError::EmptyField { position: 4, .. })
+    ));
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#21.5abc", UID)),
+        Err(ParseError::InvalidNumber { .. })
+    ));
+    assert!(matches!(
+        AckMsg::from_msg(&format!("ACK#{}#42 ", UID)),
+        Err(ParseError::InvalidInteger { .. })
+    ));
+    assert!(matches!(
+        ConnMsg::from_msg(&format!("CONN#{}#", UID)),
+        Err(ParseError::EmptyField { position: 2, .. })
+    ));
+}
+
+#[test]
+fn uids_have_to_be_uuids() {
+    // 36 characters, but no UUID
+    let uid = "zzzzzzzz-zzzz-zzzz-zzzz-zzzzzzzzzzzz";
+    assert!(matches!(
+        DisconnMsg::from_msg(&format!("DISCONN#{}", uid)),
+        Err(ParseError::InvalidUid(_))
+    ));
+    // a UUID, but not hyphenated
+    assert!(matches!(
+        DisconnMsg::from_msg("DISCONN#0b7e2c1a5d3f4a8e9c6b2f1d0e4a7b93"),
+        Err(ParseError::InvalidUid(_))
+    ));
+    assert_eq!(
+        DisconnMsg::from_msg(&format!("DISCONN#{}", UID.to_uppercase()))
+            .unwrap()
+            .uid,
+        UID.to_uppercase()
+    );
+}
+
+#[test]
+fn readings_have_to_be_finite() {
+    let parse = |data: &str| SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{}", UID, data));
+    for data in ["NaN", "-nan"] {
+        assert!(
+            matches!(parse(data), Err(ParseError::NotANumber { .. })),
+            "{}",
+            data
+        );
+    }
+    for data in ["inf", "-infinity", "+Infinity"] {
+        assert!(
+            matches!(parse(data), Err(ParseError::Infinite { .. })),
+            "{}",
+            data
+        );
+    }
+    for data in ["1e999", "-1E309", &"9".repeat(400)] {
+        assert!(
+            matches!(parse(data), Err(ParseError::Overflow { .. })),
+            "{}",
+            data
+        );
+    }
+    assert_eq!(parse("1.7976931348623157e308").unwrap().data, f64::MAX);
+}
+
+#[test]
+fn readings_have_to_use_a_decimal_point() {
+    let parse = |data: &str| SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{}", UID, data));
+    for data in ["21,5", "-0,25", ",5"] {
+        let e = parse(data).err().unwrap();
+        assert!(matches!(e, ParseError::DecimalComma { .. }), "{}", data);
+        assert_eq!(e.code(), ErrorCode::DecimalComma);
+    }
+    // neither a decimal comma nor a number
+    for data in ["1,234.5", "21,5,1", "1 000"] {
+        assert!(
+            matches!(parse(data), Err(ParseError::InvalidNumber { .. })),
+            "{}",
+            data
+        );
+    }
+    assert_eq!(parse("NaN").err().unwrap().code(), ErrorCode::NotANumber);
+    assert_eq!(parse("abc").err().unwrap().code(), ErrorCode::BadProtocol);
+}
+
+proptest! {
+    // whatever a device writes, the readings that make it through are finite
+    #[test]
+    fn parsed_readings_are_finite(data in "\\PC{0,30}") {
+        prop_assume!(!data.contains('#') && !data.is_empty());
+        if let Ok(sensor) = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{}", UID, data)) {
+            prop_assert!(sensor.data.is_finite());
+        }
+    }
+
+    // finite numbers survive being written and parsed again
+    #[test]
+    fn finite_readings_round_trip(data in proptest::num::f64::NORMAL | proptest::num::f64::ZERO) {
+        let sensor = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{}", UID, data)).unwrap();
+        prop_assert_eq!(sensor.data, data);
+        let sensor = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{:e}", UID, data)).unwrap();
+        prop_assert_eq!(sensor.data, data);
+    }
+
+    // any reading written with a decimal comma is told apart from garbage
+    #[test]
+    fn decimal_commas_are_reported(whole in -1_000_000i64..1_000_000, fraction in 0u32..1000) {
+        let e = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{},{}", UID, whole, fraction))
+            .err().unwrap();
+        prop_assert_eq!(e.code(), ErrorCode::DecimalComma);
+    }
+
+    // exponents past the range of f64 overflow instead of turning into infinity
+    #[test]
+    fn huge_exponents_overflow(mantissa in 1u32..10, exponent in 309u32..100_000) {
+        let e = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#{}e{}", UID, mantissa, exponent))
+            .err().unwrap();
+        prop_assert_eq!(e.code(), ErrorCode::Overflow);
+    }
+}
+
+#[test]
+fn headers_have_to_match() {
+    assert!(matches!(
+        KvGetMsg::from_msg(&format!("KVSET#{}#key", UID)),
+        Err(ParseError::WrongProtocol { .. })
+    ));
+    assert!(matches!(
+        protocols::get_protocol("HELLO#world"),
+        Err(ParseError::UnknownProtocol(_))
+    ));
+}
+
+#[test]
+fn valid_messages_still_parse() {
+    let sensor =
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#-3.5#temp#alarm", UID)).unwrap();
+    assert_eq!(sensor.data, -3.5);
+    assert_eq!(sensor.channel, "temp");
+    assert!(sensor.alarm);
+
+    let conn = ConnMsg::from_msg(&format!("CONN#{}#compression=zstd,timestamps=ms", UID)).unwrap();
+    assert_eq!(conn.uid, UID);
+
+    let get = KvGetMsg::from_msg(&format!("KVGET#{}#interval", UID)).unwrap();
+    assert_eq!(get.key, "interval");
+}
+
+#[test]
+fn readings_can_end_with_a_sequence_number() {
+    let sensor = SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#seq=42", UID)).unwrap();
+    assert_eq!((sensor.channel.as_str(), sensor.seq), ("default", Some(42)));
+
+    let sensor =
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#temp#alarm#seq=7", UID)).unwrap();
+    assert_eq!((sensor.channel.as_str(), sensor.alarm), ("temp", true));
+    assert_eq!(sensor.seq, Some(7));
+
+    assert_eq!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1", UID))
+            .unwrap()
+            .seq,
+        None
+    );
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#seq=x", UID)),
+        Err(ParseError::InvalidInteger { field: "seq", .. })
+    ));
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#temp#alarm#x#seq=1", UID)),
+        Err(ParseError::FieldCount { .. })
+    ));
+}
+
+#[test]
+fn delivery_interval_is_a_bounded_conn_option() {
+    let conn = ConnMsg::from_msg(&format!("CONN#{}#interval=60", UID)).unwrap();
+    assert_eq!(conn.delivery_interval_secs, 60);
+
+    let conn = ConnMsg::from_msg(&format!("CONN#{}", UID)).unwrap();
+    assert_eq!(
+        conn.delivery_interval_secs,
+        protocols::DEFAULT_DELIVERY_INTERVAL_SECS
+    );
+
+    for interval in ["0", "-5", "86400", "soon"] {
+        assert!(matches!(
+            ConnMsg::from_msg(&format!("CONN#{}#interval={}", UID, interval)),
+            Err(ParseError::InvalidOption(_))
+        ));
+    }
+}
+
+#[test]
+fn wake_schedules_are_a_conn_option() {
+    let wake = ConnMsg::from_msg(&format!("CONN#{}#wake=900/30", UID))
+        .unwrap()
+        .wake
+        .unwrap();
+    assert_eq!((wake.period_secs, wake.window_secs), (900, 30));
+    // awake for the first 30 seconds of every 15 minutes after CONN
+    assert!(wake.is_awake(1000, 1029));
+    assert!(!wake.is_awake(1000, 1030));
+    assert!(wake.is_awake(1000, 1900));
+
+    assert!(ConnMsg::from_msg(&format!("CONN#{}", UID))
+        .unwrap()
+        .wake
+        .is_none());
+    for wake in ["900", "30/900", "900/0", "900/900", "172800/30", "x/y"] {
+        assert!(matches!(
+            ConnMsg::from_msg(&format!("CONN#{}#wake={}", UID, wake)),
+            Err(ParseError::InvalidOption(_))
+        ));
+    }
+}
+
+#[test]
+fn computed_values_are_written_with_the_configured_decimals() {
+    let mean = (0.1 + 0.2) / 2.0;
+    let avg = |decimals| AvgMsg {
+        data: protocols::round_value(mean, decimals),
+        timestamp: 1700000000,
+        channel: "temperature".to_string(),
+    };
+    assert_eq!(
+        avg(None).to_msg(),
+        "AVG#1700000000#0.15000000000000002#temperature"
+    );
+    assert_eq!(avg(Some(2)).to_msg(), "AVG#1700000000#0.15#temperature");
+    assert_eq!(avg(Some(0)).to_msg(), "AVG#1700000000#0#temperature");
+
+    assert_eq!(protocols::round_value(-2.345678, Some(3)), -2.346);
+    assert_eq!(protocols::round_value(21.0, Some(2)), 21.0);
+    assert_eq!(protocols::round_value(1e300, Some(15)), 1e300);
+}
+
+#[test]
+fn forwarded_readings_name_the_fog_node() {
+    const FOG: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+    let sensor = SensorMsg::from_msg(&format!(
+        "SENSOR#{}#1690000000#1#temp#via={}#seq=3",
+        UID, FOG
+    ))
+    .unwrap();
+    assert_eq!(sensor.via.as_deref(), Some(FOG));
+    assert_eq!((sensor.channel.as_str(), sensor.seq), ("temp", Some(3)));
+
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#via=fog", UID)),
+        Err(ParseError::InvalidUid(_))
+    ));
+    assert!(matches!(
+        SensorMsg::from_msg(&format!("SENSOR#{}#1690000000#1#hop=2", UID)),
+        Err(ParseError::InvalidOption(_))
+    ));
+
+    assert!(
+        ConnMsg::from_msg(&format!("CONN#{}#role=fog", UID))
+            .unwrap()
+            .fog
+    );
+    assert!(!ConnMsg::from_msg(&format!("CONN#{}", UID)).unwrap().fog);
+}
+
+#[test]
+fn conn_carries_the_metadata_of_the_device() {
+    let conn = ConnMsg::from_msg(&format!(
+        "CONN#{}#name=Greenhouse 2,location=north,type=bme280,firmware=1.4.0",
+        UID
+    ))
+    .unwrap();
+    assert_eq!(conn.metadata.name.as_deref(), Some("Greenhouse 2"));
+    assert_eq!(conn.metadata.location.as_deref(), Some("north"));
+    assert_eq!(conn.metadata.sensor_type.as_deref(), Some("bme280"));
+    assert_eq!(conn.metadata.firmware.as_deref(), Some("1.4.0"));
+    assert!(ConnMsg::from_msg(&format!("CONN#{}", UID))
+        .unwrap()
+        .metadata
+        .is_empty());
+
+    for option in ["name=", "location=\u{7}", "firmware= "] {
+        assert!(matches!(
+            ConnMsg::from_msg(&format!("CONN#{}#{}", UID, option)),
+            Err(ParseError::InvalidOption(_))
+        ));
+    }
+    assert!(matches!(
+        ConnMsg::from_msg(&format!("CONN#{}#name={}", UID, "x".repeat(65))),
+        Err(ParseError::InvalidOption(_))
+    ));
+}
+
+#[test]
+fn conn_carries_the_position_of_the_device() {
+    let conn = ConnMsg::from_msg(&format!("CONN#{}#lat=52.52,lon=-13.405", UID)).unwrap();
+    assert_eq!(
+        (conn.metadata.latitude, conn.metadata.longitude),
+        (Some(52.52), Some(-13.405))
+    );
+
+    for options in [
+        "lat=91,lon=0",
+        "lat=0,lon=180.5",
+        "lat=north,lon=0",
+        "lat=NaN,lon=0",
+    ] {
+        assert!(matches!(
+            ConnMsg::from_msg(&format!("CONN#{}#{}", UID, options)),
+            Err(ParseError::InvalidOption(_))
+        ));
+    }
+    assert!(matches!(
+        ConnMsg::from_msg(&format!("CONN#{}#lat=52.52", UID)),
+        Err(ParseError::IncompatibleOptions(_))
+    ));
+}
+
+#[test]
+fn long_messages_are_cut_off_in_the_logs() {
+    let msg = format!("SENSOR#{}#1690000000#{}", UID, "1".repeat(10_000));
+    let logged = protocols::loggable(&msg);
+    assert!(logged.len() < 300, "{}", logged);
+    assert!(logged.ends_with(&format!("({} more bytes)", msg.len() - 256)));
+    assert_eq!(protocols::loggable("SENSOR#x"), "SENSOR#x");
+}
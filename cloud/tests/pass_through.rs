@@ -0,0 +1,120 @@
+use cloud::{db, lines, protocols, routes, webhooks::WebhookEvent};
+use std::{
+    net::TcpListener,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+mod common;
+
+const SENSOR: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const DOOR: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+
+#[tokio::test]
+async fn readings_of_pass_through_devices_are_forwarded_instead_of_averaged() {
+    let state = common::state().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let lines_addr = listener.local_addr().unwrap();
+    tokio::spawn(lines::listen(listener, state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+
+    let mut devices = Vec::new();
+    for uid in [SENSOR, DOOR] {
+        let (read, mut write) = TcpStream::connect(lines_addr).await.unwrap().into_split();
+        let mut read = BufReader::new(read).lines();
+        write
+            .write_all(format!("CONN#{}\n", uid).as_bytes())
+            .await
+            .unwrap();
+        let session = tokio::time::timeout(Duration::from_secs(5), read.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(session.starts_with("SESSION#"), "{}", session);
+        devices.push((read, write));
+    }
+
+    let put = |uid: &str| {
+        let req = hyper::Request::put(format!("http://{}/api/devices/{}/pass-through", addr, uid))
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(r#"{"enabled":true}"#))
+            .unwrap();
+        async move { hyper::Client::new().request(req).await.unwrap().status() }
+    };
+    assert_eq!(put(DOOR).await, 204);
+    assert_eq!(put("f00ba4a0-0000-4000-8000-000000000000").await, 404);
+
+    let mut webhooks = state.webhooks.subscribe();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    for (device, reading) in [
+        (1, format!("SENSOR#{}#{}#1#door\n", DOOR, now)),
+        (0, format!("SENSOR#{}#{}#20#temperature\n", SENSOR, now)),
+        (1, format!("SENSOR#{}#{}#100#temperature\n", DOOR, now)),
+    ] {
+        devices[device]
+            .1
+            .write_all(reading.as_bytes())
+            .await
+            .unwrap();
+    }
+
+    // the readings of the door go out as they are
+    let mut forwarded = Vec::new();
+    while forwarded.len() < 2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), webhooks.recv())
+            .await
+            .expect("no reading forwarded")
+            .unwrap();
+        if let WebhookEvent::Reading {
+            uid, data, channel, ..
+        } = event
+        {
+            assert_eq!(uid, DOOR);
+            forwarded.push((channel, data));
+        }
+    }
+    forwarded.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        forwarded,
+        [
+            ("door".to_string(), 1.0),
+            ("temperature".to_string(), 100.0)
+        ]
+    );
+
+    // and are left out of the averages
+    for _ in 0..50 {
+        if db::count_received_messages(&state.pool).await.unwrap() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::spawn(protocols::avg_msg_service(state.clone()));
+    let mut aggregates = Vec::new();
+    for _ in 0..50 {
+        aggregates = db::get_aggregates(&state.pool, None, None, 0, i64::MAX, 10)
+            .await
+            .unwrap();
+        if !aggregates.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(aggregates.len(), 1);
+    assert_eq!(aggregates[0].channel, "temperature");
+    assert_eq!((aggregates[0].value, aggregates[0].count), (20.0, 1));
+    let connection = db::get_connection(&state.pool, DOOR).await.unwrap();
+    assert!(connection.pass_through);
+}
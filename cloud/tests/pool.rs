@@ -0,0 +1,103 @@
+use cloud::{db, protocols::SensorMsg};
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use std::time::Duration;
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const NOW: i64 = 1_700_000_000;
+
+#[tokio::test]
+async fn concurrent_writers_wait_for_each_other_in_wal_mode() {
+    let path = std::env::temp_dir().join(format!("pool-{}.db", uuid::Uuid::new_v4()));
+    let settings = db::PoolSettings {
+        max_connections: 8,
+        busy_timeout: Duration::from_secs(5),
+        journal_mode: SqliteJournalMode::Wal,
+        synchronous: SqliteSynchronous::Normal,
+    };
+    let pool = db::open_db(&format!("sqlite://{}", path.display()), &settings).await;
+    db::add_connection(&pool, A).await.unwrap();
+
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(journal_mode, "wal");
+
+    let writers = (0..32).map(|i| {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            for j in 0..10 {
+                let msg = SensorMsg {
+                    uid: A.to_string(),
+                    data: (i * 10 + j) as f64,
+                    timestamp: NOW,
+                    channel: "temperature".to_string(),
+                    alarm: false,
+                    seq: None,
+                    via: None,
+                };
+                db::add_received_message(&pool, &msg, NOW).await?;
+            }
+            cloud::Result::Ok(())
+        })
+    });
+    for writer in writers.collect::<Vec<_>>() {
+        writer.await.unwrap().unwrap();
+    }
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM received_messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 320);
+}
+
+#[test]
+fn pool_settings_default_to_wal() {
+    let settings = db::PoolSettings::from_config(&common::config());
+    assert!(matches!(settings.journal_mode, SqliteJournalMode::Wal));
+    assert!(matches!(settings.synchronous, SqliteSynchronous::Normal));
+    assert!(settings.max_connections > 1);
+}
+
+#[tokio::test]
+async fn connections_are_created_once_and_kept_up_to_date() {
+    let path = std::env::temp_dir().join(format!("pool-{}.db", uuid::Uuid::new_v4()));
+    let pool = db::open_db(
+        &format!("sqlite://{}", path.display()),
+        &db::PoolSettings::from_config(&common::config()),
+    )
+    .await;
+
+    // concurrent CONNs of a new device
+    let upserts = (0..8).map(|_| {
+        let pool = pool.clone();
+        tokio::spawn(async move { db::upsert_connection(&pool, A, NOW).await })
+    });
+    let mut ids = Vec::new();
+    for upsert in upserts.collect::<Vec<_>>() {
+        ids.push(upsert.await.unwrap().unwrap().id);
+    }
+    ids.dedup();
+    assert_eq!(ids.len(), 1);
+
+    // adding a known device returns its row instead of failing on the unique uid
+    let adds = (0..8).map(|_| {
+        let pool = pool.clone();
+        tokio::spawn(async move { db::add_connection(&pool, A).await })
+    });
+    for add in adds.collect::<Vec<_>>() {
+        assert_eq!(add.await.unwrap().unwrap().id, ids[0]);
+    }
+
+    sqlx::query("UPDATE connections SET last_seen = 0")
+        .execute(&pool)
+        .await
+        .unwrap();
+    db::update_connection(&pool, A, NOW).await.unwrap();
+    let connection = db::get_connection(&pool, A).await.unwrap();
+    assert!(connection.last_seen > 0);
+    assert_eq!(connection.id, ids[0]);
+}
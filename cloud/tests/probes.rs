@@ -0,0 +1,76 @@
+use cloud::{clock::MockClock, probes, routes, AppState};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+mod common;
+
+async fn status(addr: SocketAddr, path: &str) -> hyper::StatusCode {
+    let uri: hyper::Uri = format!("http://{}{}", addr, path).parse().unwrap();
+    hyper::Client::new().get(uri).await.unwrap().status()
+}
+
+static RUNS: AtomicU32 = AtomicU32::new(0);
+
+// panics the first time it runs, like a service losing the db
+async fn flaky_service(_state: Arc<AppState>) {
+    if RUNS.fetch_add(1, Ordering::SeqCst) == 0 {
+        panic!("the service broke");
+    }
+    std::future::pending::<()>().await;
+}
+
+#[tokio::test]
+async fn a_panicked_service_is_restarted_and_not_ready_meanwhile() {
+    let mut config = common::config();
+    // the probes stay reachable without a key
+    config.api_auth = true;
+    const NOW: i64 = 1700000000;
+    let clock = Arc::new(MockClock::new(NOW));
+    let state = common::state_with_clock(config, clock.clone()).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+
+    assert_eq!(status(addr, "/healthz").await, hyper::StatusCode::OK);
+    assert_eq!(status(addr, "/readyz").await, hyper::StatusCode::OK);
+
+    // a service that is done isn't supervised anymore
+    probes::supervise(state.clone(), "done", |_| async {}).await;
+    assert!(state.services.list().is_empty());
+
+    tokio::spawn(probes::supervise(state.clone(), "flaky", flaky_service));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(state.services.down(), vec!["flaky"]);
+    assert_eq!(
+        status(addr, "/readyz").await,
+        hyper::StatusCode::SERVICE_UNAVAILABLE
+    );
+    // the process is still alive
+    assert_eq!(status(addr, "/healthz").await, hyper::StatusCode::OK);
+
+    // down until the backoff passed on the clock
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    assert_eq!(state.services.down(), vec!["flaky"]);
+
+    clock.advance(Duration::from_secs(1));
+    while RUNS.load(Ordering::SeqCst) < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let flaky = &state.services.list()["flaky"];
+    assert!(flaky.running);
+    assert_eq!(flaky.restarts, 1);
+    assert_eq!(flaky.last_panic, Some(NOW));
+    assert_eq!(state.services.restarts(), 1);
+    assert_eq!(status(addr, "/readyz").await, hyper::StatusCode::OK);
+}
@@ -0,0 +1,17 @@
+use cloud::config::StreamUrl;
+
+#[test]
+fn stream_urls_name_the_platform_by_their_scheme() {
+    assert_eq!(
+        "nats://localhost:4222".parse::<StreamUrl>(),
+        Ok(StreamUrl::Nats("nats://localhost:4222".to_string()))
+    );
+    assert_eq!(
+        "kafka://broker-1:9092,broker-2:9092".parse::<StreamUrl>(),
+        Ok(StreamUrl::Kafka("broker-1:9092,broker-2:9092".to_string()))
+    );
+
+    assert!("kafka://".parse::<StreamUrl>().is_err());
+    assert!("amqp://localhost".parse::<StreamUrl>().is_err());
+    assert!("localhost:4222".parse::<StreamUrl>().is_err());
+}
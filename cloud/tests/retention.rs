@@ -0,0 +1,123 @@
+use cloud::{db, protocols::SensorMsg};
+use futures_util::TryStreamExt;
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+// the start of a minute and of an hour
+const HOUR: i64 = 1699999200;
+
+async fn add(state: &cloud::AppState, timestamp: i64, data: f64, channel: &str) {
+    let reading = SensorMsg {
+        uid: A.to_string(),
+        data,
+        timestamp,
+        channel: channel.to_string(),
+        alarm: false,
+        seq: None,
+        via: None,
+    };
+    db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn the_oldest_readings_are_summarized_per_minute() {
+    let state = common::state().await;
+    add(&state, HOUR, 10.0, "temperature").await;
+    add(&state, HOUR + 30, 20.0, "temperature").await;
+    add(&state, HOUR + 50, 30.0, "temperature").await;
+    add(&state, HOUR + 60, 40.0, "temperature").await;
+    add(&state, HOUR + 10, 50.0, "humidity").await;
+
+    // the two oldest readings fall into the first minute, which is summarized as a whole
+    let summarized = db::summarize_oldest_readings(&state.pool, 2).await.unwrap();
+    assert_eq!(summarized, 4);
+    assert_eq!(db::count_received_messages(&state.pool).await.unwrap(), 3);
+
+    let rows: Vec<db::ReceivedMessage> =
+        db::stream_received_messages(&state.pool, A, Some("temperature"), 0, i64::MAX)
+            .try_collect()
+            .await
+            .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].created_at, HOUR);
+    assert_eq!(rows[0].data, 20.0);
+    assert_eq!(rows[0].summary_min, Some(10.0));
+    assert_eq!(rows[0].summary_max, Some(30.0));
+    assert_eq!(rows[0].summary_count, Some(3));
+    // newer readings stay raw
+    assert_eq!(rows[1].data, 40.0);
+    assert_eq!(rows[1].summary_count, None);
+
+    // rollups weigh the summaries by the readings they stand for
+    db::roll_up(&state.pool, db::RollupPeriod::Hour, HOUR, HOUR + 3600)
+        .await
+        .unwrap();
+    let rollups = db::get_rollups(
+        &state.pool,
+        db::RollupPeriod::Hour,
+        A,
+        Some("temperature"),
+        HOUR,
+        HOUR + 3600,
+    )
+    .await
+    .unwrap();
+    assert_eq!(rollups.len(), 1);
+    assert_eq!(rollups[0].avg, 25.0);
+    assert_eq!(rollups[0].min, 10.0);
+    assert_eq!(rollups[0].max, 40.0);
+    assert_eq!(rollups[0].count, 4);
+
+    // summaries are never summarized again
+    db::summarize_oldest_readings(&state.pool, 10)
+        .await
+        .unwrap();
+    assert_eq!(
+        db::summarize_oldest_readings(&state.pool, 10)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(db::count_received_messages(&state.pool).await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn summaries_keep_the_fog_node_of_their_readings() {
+    let state = common::state().await;
+    for (offset, data, via) in [(0, 10.0, "fog-1"), (10, 20.0, "fog-1"), (20, 60.0, "fog-2")] {
+        let reading = SensorMsg {
+            uid: A.to_string(),
+            data,
+            timestamp: HOUR + offset,
+            channel: "temperature".to_string(),
+            alarm: false,
+            seq: None,
+            via: Some(via.to_string()),
+        };
+        db::add_received_message(&state.pool, &reading, state.clock.unix_now())
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        db::summarize_oldest_readings(&state.pool, 10)
+            .await
+            .unwrap(),
+        3
+    );
+    let rows: Vec<db::ReceivedMessage> =
+        db::stream_received_messages(&state.pool, A, None, 0, i64::MAX)
+            .try_collect()
+            .await
+            .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].fog_uid.as_deref(), Some("fog-1"));
+    assert_eq!(rows[0].data, 15.0);
+    assert_eq!(rows[0].summary_count, Some(2));
+    assert!(rows[0].received_at.is_some());
+    assert_eq!(rows[1].fog_uid.as_deref(), Some("fog-2"));
+    assert_eq!(rows[1].summary_count, Some(1));
+}
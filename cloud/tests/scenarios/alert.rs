@@ -0,0 +1,49 @@
+use crate::common;
+
+use crate::harness::{Cloud, Device};
+
+const SENSOR: &str = "4d7a1e9c-3f2b-4a8d-b1e6-7c5f9a2d3e8b";
+const ACTUATOR: &str = "8e3b6a2d-9c1f-4d7e-a4b8-2f6c1e9d5a3b";
+
+const READINGS: &str = "SELECT COUNT(*) FROM received_messages WHERE uid = ?";
+const ACTIVE: &str = "SELECT COUNT(*) FROM alerts WHERE uid = ? AND resolved_at IS NULL";
+const RESOLVED: &str = "SELECT COUNT(*) FROM alerts WHERE uid = ? AND resolved_at IS NOT NULL";
+
+#[tokio::test]
+async fn an_alert_reaches_an_offline_actuator_and_survives_an_outage() {
+    let cloud = Cloud::boot(common::config()).await;
+    cloud
+        .request(
+            "POST",
+            &format!("/api/devices/{}/alert-rules", SENSOR),
+            &format!(
+                r#"{{"channel": "temperature", "op": ">", "threshold": 30,
+                    "action_uid": "{}", "action_command": "fan", "action_argument": "on"}}"#,
+                ACTUATOR
+            ),
+        )
+        .await;
+
+    // the sensor gets its rules and is told about the alert it caused
+    let mut sensor = Device::connect(&cloud, SENSOR, "").await;
+    sensor.expect("RULES").await;
+    sensor.reading("temperature", 35.0).await;
+    assert!(sensor.expect("ALERT").await.ends_with("#temperature#35"));
+    cloud.eventually(ACTIVE, SENSOR, 1).await;
+
+    // the actuator was offline, its command waits for it
+    let mut actuator = Device::connect(&cloud, ACTUATOR, "interval=1").await;
+    let cmd = actuator.expect("CMD").await;
+    assert!(cmd.starts_with("CMD#fan#on#"), "{}", cmd);
+    actuator.ack(&cmd).await;
+
+    // the alert is still active after an outage and isn't fired twice
+    let cloud = cloud.restart(common::config()).await;
+    sensor.expect_closed().await;
+    let mut sensor = Device::connect(&cloud, SENSOR, "").await;
+    sensor.reading("temperature", 36.0).await;
+    cloud.eventually(READINGS, SENSOR, 2).await;
+    sensor.reading("temperature", 25.0).await;
+    cloud.eventually(RESOLVED, SENSOR, 1).await;
+    cloud.eventually(ACTIVE, SENSOR, 0).await;
+}
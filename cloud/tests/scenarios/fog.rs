@@ -0,0 +1,37 @@
+use crate::common;
+
+use crate::harness::{Cloud, FogNode};
+
+const FOG: &str = "9a4c2e7f-1d3b-4f8a-b5e6-0c7d2a9f4e1b";
+const A: &str = "2b6e9d1c-7a4f-4c3e-8d5b-1f9a3e7c6d20";
+const B: &str = "6f1a8c3e-2d9b-4e7a-a3c5-8b0e4d2f7a91";
+const OUTSIDER: &str = "c5e2a7f9-3b1d-4a6c-9e8f-4d7b1a2c5e36";
+
+const READINGS: &str = "SELECT COUNT(*) FROM received_messages WHERE uid = ?";
+const FORWARDED: &str = "SELECT COUNT(*) FROM received_messages WHERE fog_uid = ?";
+
+#[tokio::test]
+async fn a_fog_node_forwards_for_its_devices_across_an_outage() {
+    let cloud = Cloud::boot(common::config()).await;
+    let mut fog = FogNode::connect(&cloud, FOG, &[A, B]).await;
+
+    fog.forward(A, "temperature", 21.0).await;
+    fog.forward(B, "temperature", 22.0).await;
+    cloud.eventually(READINGS, A, 1).await;
+    cloud.eventually(READINGS, B, 1).await;
+    cloud.eventually(FORWARDED, FOG, 2).await;
+
+    // the devices stay registered behind the node through an outage
+    let cloud = cloud.restart(common::config()).await;
+    fog.expect_closed().await;
+    let mut fog = FogNode::reconnect(&cloud, FOG).await;
+    fog.forward(A, "temperature", 21.5).await;
+    cloud.eventually(READINGS, A, 2).await;
+    cloud.eventually(FORWARDED, FOG, 3).await;
+
+    // forwarding for a device that wasn't registered behind it disconnects the node
+    fog.forward(OUTSIDER, "temperature", 20.0).await;
+    assert!(fog.expect("ERR").await.starts_with("ERR#AUTH_FAILED"));
+    fog.expect_closed().await;
+    cloud.eventually(READINGS, OUTSIDER, 0).await;
+}
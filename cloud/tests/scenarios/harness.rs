@@ -0,0 +1,284 @@
+use cloud::{alerts, config::Config, db, protocols, routes, AppState};
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::TcpStream, task::JoinHandle};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::common;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// seconds a device waits for a message before the scenario fails
+const RECV_TIMEOUT_SECS: u64 = 5;
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// the id the server appended to a delivered message
+pub fn delivery_id(msg: &str) -> i64 {
+    let (_, id) = msg.rsplit_once('#').unwrap();
+    id.parse().unwrap()
+}
+
+// the db file of a scenario, deleted with its journal files once the scenario is over
+struct DbFile(PathBuf);
+
+impl DbFile {
+    fn url(&self) -> String {
+        format!("sqlite://{}", self.0.display())
+    }
+}
+
+impl Drop for DbFile {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm", "-journal"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// a cloud instance with its services, on a db file that outlives restarts
+pub struct Cloud {
+    pub addr: SocketAddr,
+    pub state: Arc<AppState>,
+    db: DbFile,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Cloud {
+    // boots an instance on a free port and a new db
+    pub async fn boot(config: Config) -> Self {
+        let path = std::env::temp_dir().join(format!("scenario-{}.db", uuid::Uuid::new_v4()));
+        Self::boot_on(config, DbFile(path), SocketAddr::from(([127, 0, 0, 1], 0))).await
+    }
+
+    async fn boot_on(config: Config, db: DbFile, addr: SocketAddr) -> Self {
+        let state = common::state_on(config, &db.url()).await;
+        // like main, deliveries in flight when the last instance stopped are due again
+        db::recover_pending_deliveries(&state.pool).await.unwrap();
+
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(routes::router(state.clone()).into_make_service());
+        let tasks = vec![
+            tokio::spawn(async move {
+                let _ = server.await;
+            }),
+            tokio::spawn(protocols::avg_msg_service(state.clone())),
+            tokio::spawn(alerts::alert_service(state.clone())),
+        ];
+        // the services subscribe to the readings before the first device connects
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        Self {
+            addr,
+            state,
+            db,
+            tasks,
+        }
+    }
+
+    // an outage: the instance stops without ending the sessions properly and comes back
+    // on the same port and db with the given configuration
+    pub async fn restart(self, config: Config) -> Self {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for task in self.tasks {
+            let _ = task.await;
+        }
+        self.state.sessions.close_all(Duration::from_secs(1)).await;
+        self.state.pool.close().await;
+        Self::boot_on(config, self.db, self.addr).await
+    }
+
+    // the answer of a REST endpoint, panics unless it succeeded
+    pub async fn request(&self, method: &str, path: &str, body: &str) -> String {
+        let req = hyper::Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body.to_string()))
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        assert!(
+            res.status().is_success(),
+            "{} {}: {}",
+            method,
+            path,
+            res.status()
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    // waits until a count of the device's rows in the db reaches the expected one,
+    // the server writes most of them in the background
+    pub async fn eventually(&self, query: &str, uid: &str, expected: i64) {
+        let mut count = 0;
+        for _ in 0..50 {
+            count = sqlx::query_scalar(query)
+                .bind(uid)
+                .fetch_one(&self.state.pool)
+                .await
+                .unwrap();
+            if count == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("{} for {} is {} instead of {}", query, uid, count, expected);
+    }
+}
+
+// a simulated device speaking the websocket protocol
+pub struct Device {
+    pub uid: String,
+    socket: Socket,
+}
+
+impl Device {
+    // connects with CONN and the given options, e.g. "interval=1", and waits for the session
+    pub async fn connect(cloud: &Cloud, uid: &str, options: &str) -> Self {
+        let (socket, _) = connect_async(format!("ws://{}/ws", cloud.addr))
+            .await
+            .unwrap();
+        let mut device = Self {
+            uid: uid.to_string(),
+            socket,
+        };
+        let conn = match options {
+            "" => format!("CONN#{}", uid),
+            options => format!("CONN#{}#{}", uid, options),
+        };
+        device.send(&conn).await;
+        let session = device.recv().await.expect("the connection was closed");
+        assert!(session.starts_with("SESSION#"), "{}", session);
+        device
+    }
+
+    pub async fn send(&mut self, msg: &str) {
+        self.socket
+            .send(Message::Text(msg.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // sends a reading of the channel taken now
+    pub async fn reading(&mut self, channel: &str, value: f64) {
+        let msg = format!("SENSOR#{}#{}#{}#{}", self.uid, unix_now(), value, channel);
+        self.send(&msg).await;
+    }
+
+    // the next text message, None once the server closed the connection
+    pub async fn recv(&mut self) -> Option<String> {
+        loop {
+            let msg =
+                tokio::time::timeout(Duration::from_secs(RECV_TIMEOUT_SECS), self.socket.next())
+                    .await
+                    .unwrap_or_else(|_| panic!("no message for {}", self.uid));
+            match msg {
+                Some(Ok(Message::Text(text))) => return Some(text),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    // the next message of the kind, skipping the others
+    pub async fn expect(&mut self, kind: &str) -> String {
+        let prefix = format!("{}#", kind);
+        loop {
+            match self.recv().await {
+                Some(msg) if msg.starts_with(&prefix) => return msg,
+                Some(_) => continue,
+                None => panic!("{} was disconnected while waiting for {}", self.uid, kind),
+            }
+        }
+    }
+
+    // waits until the server closed the connection
+    pub async fn expect_closed(&mut self) {
+        while self.recv().await.is_some() {}
+    }
+
+    // acknowledges an AVG or CMD by its delivery id and returns the id
+    pub async fn ack(&mut self, msg: &str) -> i64 {
+        let id = delivery_id(msg);
+        let ack = if msg.starts_with("CMD#") {
+            "CMD_ACK"
+        } else {
+            "ACK"
+        };
+        self.send(&format!("{}#{}#{}", ack, self.uid, id)).await;
+        id
+    }
+
+    // ends the session with DISCONN
+    pub async fn disconnect(mut self) {
+        let msg = format!("DISCONN#{}", self.uid);
+        self.send(&msg).await;
+        self.expect_closed().await;
+    }
+}
+
+// a simulated fog node, forwarding the readings of the devices behind it over its own
+// connection with via=
+pub struct FogNode {
+    node: Device,
+}
+
+impl FogNode {
+    // registers the node and the devices behind it in the db like an admin would, then
+    // connects with role=fog
+    pub async fn connect(cloud: &Cloud, uid: &str, devices: &[&str]) -> Self {
+        db::add_fog_node(&cloud.state.pool, uid).await.unwrap();
+        for device in devices {
+            assert!(db::add_fog_device(&cloud.state.pool, device, uid)
+                .await
+                .unwrap());
+        }
+        Self::reconnect(cloud, uid).await
+    }
+
+    // connects a node that is already registered, e.g. after an outage
+    pub async fn reconnect(cloud: &Cloud, uid: &str) -> Self {
+        Self {
+            node: Device::connect(cloud, uid, "role=fog").await,
+        }
+    }
+
+    // forwards a reading of the channel a device behind the node took now
+    pub async fn forward(&mut self, device: &str, channel: &str, value: f64) {
+        let msg = format!(
+            "SENSOR#{}#{}#{}#{}#via={}",
+            device,
+            unix_now(),
+            value,
+            channel,
+            self.node.uid
+        );
+        self.node.send(&msg).await;
+    }
+
+    pub async fn expect(&mut self, kind: &str) -> String {
+        self.node.expect(kind).await
+    }
+
+    pub async fn expect_closed(&mut self) {
+        self.node.expect_closed().await
+    }
+}
@@ -0,0 +1,11 @@
+// system-level scenarios: a cloud instance and simulated devices in one process, scripted
+// through outages, reconnects and alerts and checked against what the devices received
+// and what ended up in the db
+#[path = "../common/mod.rs"]
+mod common;
+mod harness;
+
+mod alert;
+mod fog;
+mod outage;
+mod reconnect;
@@ -0,0 +1,59 @@
+use cloud::{config::Config, protocols};
+
+use crate::{
+    common,
+    harness::{delivery_id, Cloud, Device},
+};
+
+const A: &str = "3f8a1c2e-6b4d-4e9a-8c7f-1d2e3a4b5c6d";
+const B: &str = "7c2e9f1a-4d3b-4a8e-b6c5-9e8d7f6a5b4c";
+
+const READINGS: &str = "SELECT COUNT(*) FROM received_messages WHERE uid = ?";
+const PENDING: &str = "SELECT COUNT(*) FROM pending_deliveries WHERE uid = ?";
+
+fn config() -> Config {
+    let mut config = common::config();
+    config.avg_interval_secs = 1;
+    config
+}
+
+#[tokio::test]
+async fn averages_unacknowledged_before_an_outage_are_delivered_after_it() {
+    let cloud = Cloud::boot(config()).await;
+    let mut a = Device::connect(&cloud, A, "interval=1").await;
+    let mut b = Device::connect(&cloud, B, "interval=1").await;
+
+    a.reading("temperature", 21.0).await;
+    cloud.eventually(READINGS, A, 1).await;
+
+    // both devices get the average, only one acknowledges it before the outage
+    let avg = a.expect("AVG").await;
+    let (msg, _) = avg.rsplit_once('#').unwrap();
+    assert_eq!(protocols::AvgMsg::from_msg(msg).unwrap().data, 21.0);
+    let acked = a.ack(&avg).await;
+    assert_eq!(b.expect("AVG").await, avg);
+    cloud.eventually(PENDING, A, 0).await;
+    cloud.eventually(PENDING, B, 1).await;
+
+    let cloud = cloud.restart(config()).await;
+    a.expect_closed().await;
+    b.expect_closed().await;
+    cloud.eventually(READINGS, A, 1).await;
+
+    // the unacknowledged average comes first, the acknowledged one isn't sent again. the
+    // new instance may average the stored readings once more
+    let mut a = Device::connect(&cloud, A, "interval=1").await;
+    let mut b = Device::connect(&cloud, B, "interval=1").await;
+    let resent = b.expect("AVG").await;
+    assert_eq!(resent, avg);
+    b.ack(&resent).await;
+    let next = a.expect("AVG").await;
+    assert!(delivery_id(&next) > acked, "{}", next);
+    cloud
+        .eventually(
+            &format!("{} AND queued_message_id = {}", PENDING, acked),
+            B,
+            0,
+        )
+        .await;
+}
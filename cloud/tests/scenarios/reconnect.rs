@@ -0,0 +1,55 @@
+use cloud::{config::Config, db};
+
+use crate::{
+    common,
+    harness::{Cloud, Device},
+};
+
+const SENSOR: &str = "9b1d4f7e-2c8a-4e3b-a5d6-0f1e2d3c4b5a";
+const DROPPED: &str = "1e5c8a3f-7d2b-4f9e-8a1c-6b5d4e3f2a1b";
+const LEFT: &str = "6a4f2d8c-1b9e-4c7a-9d3f-5e2b8a1c7f6d";
+
+const SESSIONS: &str = "SELECT COUNT(*) FROM device_sessions WHERE uid = ?";
+const ENDED: &str = "SELECT COUNT(*) FROM device_sessions WHERE uid = ? AND ended_at IS NOT NULL";
+
+fn config() -> Config {
+    let mut config = common::config();
+    config.avg_interval_secs = 1;
+    config
+}
+
+#[tokio::test]
+async fn devices_coming_back_get_what_they_missed() {
+    let cloud = Cloud::boot(config()).await;
+    let mut sensor = Device::connect(&cloud, SENSOR, "interval=1").await;
+    let dropped = Device::connect(&cloud, DROPPED, "interval=1").await;
+    let left = Device::connect(&cloud, LEFT, "interval=1").await;
+
+    // one device loses its network, the other one says goodbye
+    drop(dropped);
+    left.disconnect().await;
+    cloud.eventually(ENDED, DROPPED, 1).await;
+    let connection = db::get_connection(&cloud.state.pool, LEFT).await.unwrap();
+    assert!(connection.disconnected_at.is_some());
+    let connection = db::get_connection(&cloud.state.pool, DROPPED)
+        .await
+        .unwrap();
+    assert!(connection.disconnected_at.is_none());
+
+    // an average is computed while they are away
+    sensor.reading("temperature", 30.0).await;
+    let avg = sensor.expect("AVG").await;
+    sensor.ack(&avg).await;
+
+    let mut dropped = Device::connect(&cloud, DROPPED, "interval=1").await;
+    assert_eq!(dropped.expect("AVG").await, avg);
+    cloud.eventually(SESSIONS, DROPPED, 2).await;
+
+    // a device that left is connected again and catches up as well
+    let mut left = Device::connect(&cloud, LEFT, "interval=1").await;
+    let connection = db::get_connection(&cloud.state.pool, LEFT).await.unwrap();
+    assert!(connection.disconnected_at.is_none());
+    assert_eq!(left.expect("AVG").await, avg);
+    left.reading("humidity", 40.0).await;
+    assert!(left.expect("AVG").await.contains("#humidity#"));
+}
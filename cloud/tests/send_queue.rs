@@ -0,0 +1,55 @@
+use cloud::send_queue::{QueuePolicy, QueueStats, SendQueue};
+use std::{sync::Arc, time::Duration};
+
+fn msgs(range: std::ops::Range<u32>) -> Vec<String> {
+    range.map(|i| format!("AVG#{}", i)).collect()
+}
+
+#[tokio::test]
+async fn a_full_queue_drops_its_oldest_messages() {
+    let stats = QueueStats::default();
+    let queue = SendQueue::new(3, QueuePolicy::DropOldest);
+    assert!(queue.push(&msgs(0..5), &stats).await);
+    assert_eq!(stats.dropped(), 2);
+    assert_eq!(stats.stalls(), 0);
+    assert_eq!(queue.pop(10).await.unwrap(), msgs(2..5));
+}
+
+#[tokio::test]
+async fn a_full_queue_holds_the_writer_back_until_messages_are_sent() {
+    let stats = Arc::new(QueueStats::default());
+    let queue = Arc::new(SendQueue::new(2, QueuePolicy::Backpressure));
+    let writer = tokio::spawn({
+        let (queue, stats) = (queue.clone(), stats.clone());
+        async move { queue.push(&msgs(0..4), &stats).await }
+    });
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!writer.is_finished());
+    assert_eq!(queue.len(), 2);
+
+    let mut sent = Vec::new();
+    while sent.len() < 4 {
+        sent.extend(queue.pop(1).await.unwrap());
+    }
+    assert_eq!(sent, msgs(0..4));
+    assert!(writer.await.unwrap());
+    assert_eq!(stats.dropped(), 0);
+    assert!(stats.stalls() > 0);
+}
+
+#[tokio::test]
+async fn a_closed_queue_is_emptied_but_takes_no_more_messages() {
+    let stats = QueueStats::default();
+    let queue = SendQueue::new(4, QueuePolicy::Backpressure);
+    assert!(queue.push(&msgs(0..2), &stats).await);
+    queue.close();
+    assert!(!queue.push(&msgs(2..3), &stats).await);
+    assert_eq!(queue.pop(10).await.unwrap(), msgs(0..2));
+    assert_eq!(queue.pop(10).await, None);
+
+    let queue = SendQueue::new(4, QueuePolicy::Backpressure);
+    assert!(queue.push(&msgs(0..2), &stats).await);
+    queue.discard();
+    assert_eq!(queue.pop(10).await, None);
+}
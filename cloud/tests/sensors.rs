@@ -0,0 +1,234 @@
+use cloud::{
+    config::{ChannelCalibration, LocalChannels, LocalSensor, LocalSensors, SensorDriver},
+    db, probes,
+    sensors::{self, Sample, Schedule, SensorError, SensorSource},
+    AppState,
+};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+// a sensor reading the same values every time, failing every other sample
+struct FakeSensor {
+    samples: usize,
+}
+
+impl SensorSource for FakeSensor {
+    fn sample(&mut self) -> Result<Vec<Sample>, SensorError> {
+        self.samples += 1;
+        if self.samples.is_multiple_of(2) {
+            return Err("bus error".into());
+        }
+        Ok(vec![
+            Sample {
+                channel: "temperature".to_string(),
+                value: 21.5,
+            },
+            Sample {
+                channel: "humidity".to_string(),
+                value: 40.0,
+            },
+        ])
+    }
+}
+
+fn schedule(channels: HashMap<String, ChannelCalibration>) -> Schedule {
+    Schedule {
+        period: Duration::from_millis(50),
+        jitter: Duration::from_millis(10),
+        channels,
+    }
+}
+
+// waits until the sample service stored at least the expected readings
+async fn wait_for_readings(state: &AppState, expected: i64) {
+    for _ in 0..50 {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM received_messages WHERE uid = ?")
+            .bind(A)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        if count >= expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("expected {} readings", expected);
+}
+
+#[tokio::test]
+async fn samples_are_stored_as_readings_of_the_sensor() {
+    let state = common::state().await;
+    tokio::spawn(sensors::sample_service(
+        state.clone(),
+        A.to_string(),
+        Box::new(FakeSensor { samples: 0 }),
+        schedule(HashMap::new()),
+    ));
+
+    // failed samples are skipped, the sensor is sampled again on the next tick
+    wait_for_readings(&state, 4).await;
+
+    let connection = db::get_connection(&state.pool, A).await.unwrap();
+    assert!(connection.disconnected_at.is_none());
+    let mut latest = db::get_latest_readings(&state.pool, None).await.unwrap();
+    latest.sort_by(|a, b| a.channel.cmp(&b.channel));
+    assert_eq!(latest.len(), 2);
+    assert_eq!(latest[0].channel, "humidity");
+    assert_eq!(latest[0].data, 40.0);
+    assert_eq!(latest[1].channel, "temperature");
+    assert_eq!(latest[1].data, 21.5);
+}
+
+// a sensor whose driver panics on the first sample after it was opened the first time
+struct PanickingSensor {
+    opened: usize,
+}
+
+impl SensorSource for PanickingSensor {
+    fn sample(&mut self) -> Result<Vec<Sample>, SensorError> {
+        if self.opened == 1 {
+            panic!("driver bug");
+        }
+        Ok(vec![Sample {
+            channel: "temperature".to_string(),
+            value: 21.5,
+        }])
+    }
+}
+
+#[tokio::test]
+async fn a_sensor_whose_sampling_panicked_is_opened_again() {
+    static OPENED: AtomicUsize = AtomicUsize::new(0);
+    let state = common::state().await;
+    let name = format!("sample_service {}", A);
+    tokio::spawn(probes::supervise(state.clone(), name.clone(), |state| {
+        let opened = OPENED.fetch_add(1, Ordering::SeqCst) + 1;
+        sensors::sample_service(
+            state,
+            A.to_string(),
+            Box::new(PanickingSensor { opened }),
+            schedule(HashMap::new()),
+        )
+    }));
+
+    wait_for_readings(&state, 1).await;
+    assert_eq!(OPENED.load(Ordering::SeqCst), 2);
+    let service = &state.services.list()[name.as_str()];
+    assert!(service.running);
+    assert_eq!(service.restarts, 1);
+}
+
+#[tokio::test]
+async fn samples_are_calibrated_and_validated_before_they_are_stored() {
+    let state = common::state().await;
+    let channels: LocalChannels = format!(
+        "{}/temperature:scale=2,offset=1; {}/humidity:min=0,max=30",
+        A, A
+    )
+    .parse()
+    .unwrap();
+    let channels = channels
+        .0
+        .into_iter()
+        .map(|channel| (channel.channel, channel.calibration))
+        .collect();
+    tokio::spawn(sensors::sample_service(
+        state.clone(),
+        A.to_string(),
+        Box::new(FakeSensor { samples: 0 }),
+        schedule(channels),
+    ));
+    wait_for_readings(&state, 2).await;
+
+    // humidity of 40 is out of its range
+    let latest = db::get_latest_readings(&state.pool, None).await.unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].channel, "temperature");
+    assert_eq!(latest[0].data, 44.0);
+}
+
+#[test]
+fn local_sensors_name_a_driver_bus_and_address() {
+    let sensors: LocalSensors = format!(
+        "bme280:/dev/i2c-1:0x76={}, ads1115:/dev/i2c-1:72={}@60",
+        A, A
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(
+        sensors.0,
+        vec![
+            LocalSensor {
+                driver: SensorDriver::Bme280,
+                bus: "/dev/i2c-1".to_string(),
+                address: 0x76,
+                uid: A.to_string(),
+                interval_secs: None,
+            },
+            LocalSensor {
+                driver: SensorDriver::Ads1115,
+                bus: "/dev/i2c-1".to_string(),
+                address: 0x48,
+                uid: A.to_string(),
+                interval_secs: Some(60),
+            },
+        ]
+    );
+
+    assert!(format!("dht22:/dev/i2c-1:0x76={}", A)
+        .parse::<LocalSensors>()
+        .is_err());
+    assert!(format!("bme280:/dev/i2c-1={}", A)
+        .parse::<LocalSensors>()
+        .is_err());
+    assert!("bme280:/dev/i2c-1:0x76=sensor"
+        .parse::<LocalSensors>()
+        .is_err());
+    assert!(format!("bme280:/dev/i2c-1:0x76={}@0", A)
+        .parse::<LocalSensors>()
+        .is_err());
+}
+
+#[cfg(feature = "bme280")]
+#[test]
+fn bme280_readings_are_compensated_with_the_trimming_parameters() {
+    use cloud::sensors::bme280::Calibration;
+
+    // the worked example of the datasheet
+    let calibration = Calibration {
+        t1: 27504,
+        t2: 26435,
+        t3: -1000,
+        p1: 36477,
+        p2: -10685,
+        p3: 3024,
+        p4: 2855,
+        p5: 140,
+        p6: -7,
+        p7: 15500,
+        p8: -14600,
+        p9: 6000,
+        ..Default::default()
+    };
+    let (temperature, pressure, humidity) = calibration.compensate(519888, 415148, 0);
+    assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+    assert!((pressure - 100653.27).abs() < 1.0, "{}", pressure);
+    assert!((0.0..=100.0).contains(&humidity));
+}
+
+#[cfg(feature = "ads1115")]
+#[test]
+fn ads1115_conversions_are_scaled_to_the_full_scale_range() {
+    use cloud::sensors::ads1115::to_volts;
+
+    assert_eq!(to_volts(0), 0.0);
+    assert_eq!(to_volts(16384), 2.048);
+    assert_eq!(to_volts(-32768), -4.096);
+}
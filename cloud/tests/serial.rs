@@ -0,0 +1,50 @@
+use cloud::{
+    config::{SerialPort, SerialPorts},
+    serial_reader::parse_line,
+};
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const B: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+
+#[test]
+fn serial_ports_map_paths_to_uids() {
+    let ports: SerialPorts = format!("/dev/ttyUSB0={}, /dev/ttyACM0={}@115200", A, B)
+        .parse()
+        .unwrap();
+    assert_eq!(
+        ports.0,
+        vec![
+            SerialPort {
+                path: "/dev/ttyUSB0".to_string(),
+                uid: A.to_string(),
+                baud_rate: 9600,
+            },
+            SerialPort {
+                path: "/dev/ttyACM0".to_string(),
+                uid: B.to_string(),
+                baud_rate: 115200,
+            },
+        ]
+    );
+
+    assert!("/dev/ttyUSB0".parse::<SerialPorts>().is_err());
+    assert!("/dev/ttyUSB0=sensor".parse::<SerialPorts>().is_err());
+    assert!(format!("/dev/ttyUSB0={}@fast", A)
+        .parse::<SerialPorts>()
+        .is_err());
+}
+
+#[test]
+fn serial_lines_are_stamped_with_the_time_they_were_read() {
+    let reading = parse_line(A, "21.5#temperature\r", 1_700_000_000).unwrap();
+    assert_eq!(reading.uid, A);
+    assert_eq!(reading.timestamp, 1_700_000_000);
+    assert_eq!(reading.data, 21.5);
+    assert_eq!(reading.channel, "temperature");
+    assert!(!reading.alarm);
+
+    let reading = parse_line(A, "40#humidity#alarm", 1_700_000_000).unwrap();
+    assert!(reading.alarm);
+
+    assert!(parse_line(A, "warming up", 1_700_000_000).is_err());
+}
@@ -0,0 +1,65 @@
+use cloud::signing::{self, Rejection, ReplayGuard, SignedMsg};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const WINDOW_SECS: i64 = 300;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn sensor_msg(timestamp: i64) -> String {
+    format!("SENSOR#{}#{}#21.5#temperature", UID, timestamp)
+}
+
+#[test]
+fn signed_messages_verify_with_the_device_key() {
+    let key = signing::device_key("secret", UID);
+    let msg = signing::sign(&key, now(), "n1", &sensor_msg(now()));
+
+    let signed = SignedMsg::from_msg(&msg).unwrap();
+    assert!(signed.verify(&key));
+    assert_eq!(signed.message, sensor_msg(now()));
+}
+
+#[test]
+fn keys_of_other_devices_and_tampered_messages_are_rejected() {
+    let key = signing::device_key("secret", UID);
+    let other = signing::device_key("secret", "some-other-device");
+    let msg = signing::sign(&other, now(), "n1", &sensor_msg(now()));
+    assert!(!SignedMsg::from_msg(&msg).unwrap().verify(&key));
+
+    let msg = signing::sign(&key, now(), "n1", &sensor_msg(now())).replace("21.5", "99.9");
+    assert!(!SignedMsg::from_msg(&msg).unwrap().verify(&key));
+}
+
+#[tokio::test]
+async fn nonces_are_accepted_once_within_the_window() {
+    let key = signing::device_key("secret", UID);
+    let guard = ReplayGuard::default();
+    let msg = SignedMsg::from_msg(&signing::sign(&key, now(), "n1", &sensor_msg(now()))).unwrap();
+
+    assert_eq!(guard.check(UID, &msg, WINDOW_SECS).await, Ok(()));
+    assert_eq!(
+        guard.check(UID, &msg, WINDOW_SECS).await,
+        Err(Rejection::Replayed)
+    );
+    // nonces are tracked per device
+    assert_eq!(guard.check("other", &msg, WINDOW_SECS).await, Ok(()));
+}
+
+#[tokio::test]
+async fn messages_outside_the_window_are_stale() {
+    let key = signing::device_key("secret", UID);
+    let guard = ReplayGuard::default();
+    let old = now() - WINDOW_SECS - 1;
+    let msg = SignedMsg::from_msg(&signing::sign(&key, old, "n1", &sensor_msg(old))).unwrap();
+
+    assert_eq!(
+        guard.check(UID, &msg, WINDOW_SECS).await,
+        Err(Rejection::Stale)
+    );
+}
@@ -0,0 +1,154 @@
+use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+use cloud::{
+    config::{SinkUrl, SinkUrls},
+    events::StreamEvent,
+    sinks::{self, influx},
+};
+use std::{
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod common;
+
+#[test]
+fn sink_urls_name_the_kind_of_sink() {
+    assert_eq!(
+        "file:///var/lib/cloud/readings.ndjson, influx://influx:8086/api/v2/write?bucket=b"
+            .parse::<SinkUrls>(),
+        Ok(SinkUrls(vec![
+            SinkUrl::File("/var/lib/cloud/readings.ndjson".to_string()),
+            SinkUrl::Influx("http://influx:8086/api/v2/write?bucket=b".to_string()),
+        ]))
+    );
+    assert!("file://".parse::<SinkUrl>().is_err());
+    assert!("http://influx:8086/write".parse::<SinkUrl>().is_err());
+    assert_eq!(
+        SinkUrl::Influx("http://influx:8086/write".to_string()).to_string(),
+        "influx://influx:8086/write"
+    );
+}
+
+#[test]
+fn influx_lines_escape_the_tags() {
+    let reading = StreamEvent::Sensor {
+        uid: "a".to_string(),
+        timestamp: 1700000000,
+        data: 21.5,
+        channel: "room temperature".to_string(),
+        alarm: false,
+        attributes: [
+            ("site".to_string(), "hall 3".to_string()),
+            ("asset_tag".to_string(), String::new()),
+        ]
+        .into(),
+        tenant: None,
+    };
+    assert_eq!(
+        influx::line(&reading),
+        "readings,uid=a,channel=room\\ temperature,site=hall\\ 3 value=21.5,alarm=false 1700000000000000000"
+    );
+
+    let avg = StreamEvent::Avg {
+        timestamp: 1700000000,
+        data: 40.0,
+        channel: "humidity".to_string(),
+        tenant: Some("acme,inc".to_string()),
+    };
+    assert_eq!(
+        influx::line(&avg),
+        "averages,channel=humidity,tenant=acme\\,inc value=40 1700000000000000000"
+    );
+}
+
+// bodies and authorization headers the fake InfluxDB received, the first write fails
+#[derive(Default)]
+struct Influx {
+    received: Mutex<Vec<(Option<String>, String)>>,
+}
+
+async fn write(State(influx): State<Arc<Influx>>, headers: HeaderMap, body: String) -> StatusCode {
+    let mut received = influx.received.lock().unwrap();
+    let token = headers
+        .get("authorization")
+        .map(|value| value.to_str().unwrap().to_string());
+    received.push((token, body));
+    if received.len() == 1 {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::NO_CONTENT
+    }
+}
+
+#[tokio::test]
+async fn events_are_exported_to_every_sink_and_failed_writes_retried() {
+    let influx = Arc::new(Influx::default());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route("/write", post(write))
+        .with_state(influx.clone());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+
+    let path = std::env::temp_dir().join(format!("sink-{}.ndjson", uuid::Uuid::new_v4()));
+    let mut config = common::config();
+    config.sink_urls = Some(SinkUrls(vec![
+        SinkUrl::File(path.display().to_string()),
+        SinkUrl::Influx(format!("http://{}/write", addr)),
+    ]));
+    config.sink_influx_token = Some("influx token".parse().unwrap());
+    let state = common::state_with(config).await;
+    tokio::spawn(sinks::sink_service(state.clone()));
+    // let the service subscribe before anything is published
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    state.events.publish(StreamEvent::Sensor {
+        uid: "a".to_string(),
+        timestamp: 1700000000,
+        data: 21.5,
+        channel: "temperature".to_string(),
+        alarm: false,
+        attributes: Default::default(),
+        tenant: None,
+    });
+    state.events.publish(StreamEvent::Avg {
+        timestamp: 1700000000,
+        data: 21.5,
+        channel: "temperature".to_string(),
+        tenant: None,
+    });
+
+    // the batch is written again after the backoff
+    for _ in 0..50 {
+        if state.sinks.stats().values().map(|s| s.written).sum::<u64>() == 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let stats = state.sinks.stats();
+    let file = &stats[&format!("file://{}", path.display())];
+    assert_eq!((file.written, file.failed_writes), (2, 0));
+    let influx_stats = &stats[&format!("influx://{}/write", addr)];
+    assert_eq!((influx_stats.written, influx_stats.failed_writes), (2, 1));
+
+    let lines = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = lines
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["uid"], "a");
+    assert_eq!(lines[1]["channel"], "temperature");
+    std::fs::remove_file(&path).unwrap();
+
+    let received = influx.received.lock().unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0], received[1]);
+    assert_eq!(received[1].0.as_deref(), Some("Token influx token"));
+    assert_eq!(received[1].1.lines().count(), 2);
+}
@@ -0,0 +1,35 @@
+use cloud::sniffer::{sniff, Sniffed};
+
+#[test]
+fn http_requests_are_told_apart_from_websocket_upgrades() {
+    let upgrade =
+        b"GET /ws HTTP/1.1\r\nHost: fog\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    assert_eq!(sniff(upgrade), Some(Sniffed::WebSocket));
+
+    let call = b"GET /api/connections HTTP/1.1\r\nHost: fog\r\n\r\n";
+    assert_eq!(sniff(call), Some(Sniffed::Http));
+    assert_eq!(
+        sniff(b"POST /api/flags/x HTTP/1.1\r\n\r\n{}"),
+        Some(Sniffed::Http)
+    );
+
+    // the upgrade header may still be coming
+    assert_eq!(sniff(b"GET /ws HTTP/1.1\r\nHost: fog\r\n"), None);
+}
+
+#[test]
+fn line_protocol_messages_are_recognized_by_their_header() {
+    assert_eq!(
+        sniff(b"CONN#0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93\n"),
+        Some(Sniffed::Lines)
+    );
+    assert_eq!(sniff(b"SENSOR#"), Some(Sniffed::Lines));
+
+    // too short to tell CONN from a method or a header
+    assert_eq!(sniff(b""), None);
+    assert_eq!(sniff(b"GE"), None);
+    assert_eq!(sniff(b"CONN"), None);
+
+    assert_eq!(sniff(b"HELLO#world\n"), Some(Sniffed::Unknown));
+    assert_eq!(sniff(b"garbage\n"), Some(Sniffed::Unknown));
+}
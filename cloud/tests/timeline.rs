@@ -0,0 +1,74 @@
+use cloud::{admission::Priority, db, probes, routes, timeline};
+use std::{net::TcpListener, time::Duration};
+
+mod common;
+
+// waits until the timeline holds an event of the kind
+async fn wait_for_event(state: &cloud::AppState, kind: &str) -> db::SystemEvent {
+    for _ in 0..50 {
+        let events = db::get_system_events(&state.pool, 0, i64::MAX, 100)
+            .await
+            .unwrap();
+        if let Some(event) = events.into_iter().find(|event| event.kind == kind) {
+            return event;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("no {} event", kind);
+}
+
+#[tokio::test]
+async fn operational_events_are_kept_in_a_timeline() {
+    let state = common::state().await;
+
+    // the fresh db was migrated
+    let migration = wait_for_event(&state, "migration").await;
+    assert!(migration.detail.starts_with("applied "), "{:?}", migration);
+
+    tokio::spawn(probes::supervise(state.clone(), "broken", |_| async {
+        panic!("the service broke")
+    }));
+    let restart = wait_for_event(&state, "service_restart").await;
+    assert!(
+        restart.detail.starts_with("broken panicked"),
+        "{:?}",
+        restart
+    );
+
+    // an episode lasts while readings are shed
+    tokio::spawn(timeline::shedding_service(state.clone()));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for _ in 0..3 {
+        assert!(state.admission.try_admit(Priority::Routine, 0).is_none());
+    }
+    let started = wait_for_event(&state, "shedding_started").await;
+    assert_eq!(started.detail, "3 routine readings shed");
+    let ended = wait_for_event(&state, "shedding_ended").await;
+    assert_eq!(ended.detail, "3 routine readings shed in the episode");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+    let get = |query: String| async move {
+        let uri = format!("http://{}/api/events?{}", addr, query);
+        let res = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap()
+    };
+
+    let events = get(String::new()).await;
+    assert_eq!(events[0]["kind"], "migration");
+    assert!(events.iter().any(|event| event["kind"] == "shedding_ended"));
+    let events = get(format!("from={}", ended.created_at + 1)).await;
+    assert!(events
+        .iter()
+        .all(|event| event["kind"] == "service_restart"));
+    assert!(get(format!("to={}", migration.created_at)).await.is_empty());
+}
@@ -0,0 +1,88 @@
+use cloud::{
+    config::TimestampPolicy,
+    protocols::{ConnMsg, ErrorCode, ParseError, SensorMsg, TimestampFormat},
+};
+
+const UID: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+// 2023-07-22T04:26:40Z
+const SECONDS: i64 = 1690000000;
+
+fn timestamp_of(timestamp: &str, format: TimestampFormat) -> Option<i64> {
+    let msg = format!("SENSOR#{}#{}#21.5", UID, timestamp);
+    SensorMsg::parse(&msg, format).ok().map(|m| m.timestamp)
+}
+
+#[test]
+fn auto_detects_seconds_milliseconds_and_iso8601() {
+    let auto = TimestampFormat::Auto;
+    assert_eq!(timestamp_of("1690000000", auto), Some(SECONDS));
+    assert_eq!(timestamp_of("1690000000123", auto), Some(SECONDS));
+    assert_eq!(timestamp_of("2023-07-22T04:26:40Z", auto), Some(SECONDS));
+    assert_eq!(
+        timestamp_of("2023-07-22T06:26:40.5+02:00", auto),
+        Some(SECONDS)
+    );
+    assert_eq!(timestamp_of("yesterday", auto), None);
+}
+
+#[test]
+fn negotiated_formats_are_not_guessed() {
+    assert_eq!(
+        timestamp_of("1690000000", TimestampFormat::Millis),
+        Some(1690000)
+    );
+    assert_eq!(
+        timestamp_of("1690000000123", TimestampFormat::Seconds),
+        Some(1690000000123)
+    );
+    assert_eq!(timestamp_of("1690000000", TimestampFormat::Iso8601), None);
+    assert_eq!(
+        timestamp_of("2023-07-22T04:26:40Z", TimestampFormat::Seconds),
+        None
+    );
+}
+
+#[test]
+fn timestamp_format_is_a_conn_option() {
+    let conn = ConnMsg::from_msg(&format!("CONN#{}#timestamps=ms", UID)).unwrap();
+    assert_eq!(conn.timestamps, TimestampFormat::Millis);
+
+    let conn = ConnMsg::from_msg(&format!("CONN#{}", UID)).unwrap();
+    assert_eq!(conn.timestamps, TimestampFormat::Auto);
+
+    assert!(ConnMsg::from_msg(&format!("CONN#{}#timestamps=us", UID)).is_err());
+}
+
+#[test]
+fn skewed_timestamps_are_accepted_clamped_or_rejected() {
+    let now = SECONDS + 3 * 365 * 86400;
+    let reading = || SensorMsg::from_msg(&format!("SENSOR#{}#{}#21.5", UID, SECONDS)).unwrap();
+
+    let mut accepted = reading();
+    assert!(accepted
+        .check_timestamp(now, 86400, TimestampPolicy::Accept)
+        .is_ok());
+    assert_eq!(accepted.timestamp, SECONDS);
+
+    let mut clamped = reading();
+    assert!(clamped
+        .check_timestamp(now, 86400, TimestampPolicy::Clamp)
+        .is_ok());
+    assert_eq!(clamped.timestamp, now);
+
+    let err = reading()
+        .check_timestamp(now, 86400, TimestampPolicy::Reject)
+        .unwrap_err();
+    assert_eq!(err, ParseError::ClockSkew(SECONDS - now));
+    assert_eq!(err.code(), ErrorCode::ClockSkew);
+
+    // within the allowed skew nothing changes whatever the policy
+    let mut close = reading();
+    assert!(close
+        .check_timestamp(SECONDS + 60, 86400, TimestampPolicy::Reject)
+        .is_ok());
+    assert_eq!(close.timestamp, SECONDS);
+
+    assert_eq!("clamp".parse(), Ok(TimestampPolicy::Clamp));
+    assert!("ignore".parse::<TimestampPolicy>().is_err());
+}
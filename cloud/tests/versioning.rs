@@ -0,0 +1,76 @@
+use cloud::{config, db, routes};
+use hyper::{Body, Request, Response, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+
+async fn start(config: config::Config) -> SocketAddr {
+    let state = common::state_with(config).await;
+    db::add_connection(&state.pool, A).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state).into_make_service());
+    tokio::spawn(server);
+    addr
+}
+
+async fn get(uri: String, version: Option<&str>) -> Response<Body> {
+    let mut req = Request::builder().uri(uri);
+    if let Some(version) = version {
+        req = req.header("api-version", version);
+    }
+    hyper::Client::new()
+        .request(req.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+fn header<'a>(res: &'a Response<Body>, name: &str) -> Option<&'a str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn unversioned_routes_are_deprecated_in_favour_of_v1() {
+    let mut config = common::config();
+    // 2027-01-01
+    config.api_sunset = Some(1798761600);
+    let addr = start(config).await;
+
+    let res = get(format!("http://{}/api/v1/connections/{}", addr, A), None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "api-version"), Some("1"));
+    assert_eq!(header(&res, "deprecation"), None);
+
+    let res = get(format!("http://{}/api/connections/{}", addr, A), None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(header(&res, "deprecation").unwrap().starts_with('@'));
+    assert_eq!(
+        header(&res, "sunset"),
+        Some("Fri, 01 Jan 2027 00:00:00 GMT")
+    );
+    assert_eq!(
+        header(&res, "link"),
+        Some(format!("</api/v1/connections/{}>; rel=\"successor-version\"", A).as_str())
+    );
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let connection: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(connection["uid"], A);
+
+    // clients naming the version they were built against aren't told about it
+    let res = get(format!("http://{}/api/connections/{}", addr, A), Some("1")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "deprecation"), None);
+
+    let res = get(format!("http://{}/api/v1/connections", addr), Some("2")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // the docs aren't versioned
+    let res = get(format!("http://{}/api/docs/openapi.json", addr), None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "deprecation"), None);
+}
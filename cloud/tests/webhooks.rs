@@ -0,0 +1,233 @@
+use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+use cloud::{
+    budget::{Class, WINDOW_SECS},
+    clock::{Clock, MockClock},
+    config::{self, WebhookUrls},
+    db,
+    webhooks::{self, WebhookEvent},
+    AppState,
+};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod common;
+
+const SECRET: &str = "webhook secret";
+
+// requests a webhook received, it answers them with the statuses in order
+#[derive(Default)]
+struct Receiver {
+    statuses: Vec<StatusCode>,
+    received: Mutex<Vec<(HeaderMap, String)>>,
+}
+
+async fn receive(
+    State(receiver): State<Arc<Receiver>>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let mut received = receiver.received.lock().unwrap();
+    received.push((headers, body));
+    receiver
+        .statuses
+        .get(received.len() - 1)
+        .copied()
+        .unwrap_or(StatusCode::OK)
+}
+
+async fn start_receiver(statuses: Vec<StatusCode>) -> (SocketAddr, Arc<Receiver>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    (addr, serve_receiver(listener, statuses))
+}
+
+fn serve_receiver(listener: TcpListener, statuses: Vec<StatusCode>) -> Arc<Receiver> {
+    let receiver = Arc::new(Receiver {
+        statuses,
+        ..Default::default()
+    });
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state(receiver.clone());
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    receiver
+}
+
+// a server posting to the receiver, with the webhook service running
+async fn start(addr: SocketAddr) -> Arc<AppState> {
+    start_with(WebhookUrls(vec![format!("http://{}/hook", addr)])).await
+}
+
+async fn start_with(urls: WebhookUrls) -> Arc<AppState> {
+    serve(common::state_with(config(urls)).await).await
+}
+
+fn config(urls: WebhookUrls) -> config::Config {
+    let mut config = common::config();
+    config.webhook_urls = Some(urls);
+    config.webhook_health_ms = 20;
+    config.webhook_secret = Some(SECRET.parse().unwrap());
+    config.webhook_max_attempts = 3;
+    config.webhook_backoff_ms = 10;
+    config
+}
+
+async fn serve(state: Arc<AppState>) -> Arc<AppState> {
+    tokio::spawn(webhooks::webhook_service(state.clone()));
+    // let the service subscribe before anything is published
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    state
+}
+
+// waits until the single delivery is no longer retried
+async fn settled_delivery(state: &AppState) -> db::WebhookDelivery {
+    for _ in 0..50 {
+        let mut deliveries = db::get_webhook_deliveries(&state.pool, 10).await.unwrap();
+        assert!(deliveries.len() <= 1);
+        if deliveries.first().is_some_and(|d| d.status != "pending") {
+            return deliveries.remove(0);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the delivery was not settled");
+}
+
+#[tokio::test]
+async fn events_are_signed_and_retried_until_delivered() {
+    let (addr, receiver) = start_receiver(vec![StatusCode::SERVICE_UNAVAILABLE]).await;
+    let state = start(addr).await;
+
+    state.webhooks.publish(WebhookEvent::Avg {
+        timestamp: 1_700_000_000,
+        data: 21.5,
+        channel: "temperature".to_string(),
+        tenant: None,
+    });
+
+    let delivery = settled_delivery(&state).await;
+    assert_eq!(delivery.status, "delivered");
+    assert_eq!(delivery.event, "avg");
+    assert_eq!(delivery.attempts, 2);
+    assert_eq!(delivery.last_status, Some(200));
+
+    let received = receiver.received.lock().unwrap();
+    assert_eq!(received.len(), 2);
+    let (headers, body) = &received[1];
+    let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(payload["event"], "avg");
+    assert_eq!(payload["channel"], "temperature");
+    assert_eq!(payload["data"], 21.5);
+
+    let timestamp: i64 = headers["x-webhook-timestamp"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        headers["x-webhook-signature"].to_str().unwrap(),
+        format!(
+            "sha256={}",
+            webhooks::sign(SECRET.as_bytes(), timestamp, body)
+        )
+    );
+    assert_eq!(
+        headers["x-webhook-delivery"].to_str().unwrap(),
+        delivery.id.to_string()
+    );
+}
+
+#[tokio::test]
+async fn rejected_events_are_not_retried() {
+    let (addr, receiver) = start_receiver(vec![StatusCode::BAD_REQUEST]).await;
+    let state = start(addr).await;
+
+    state.webhooks.publish(WebhookEvent::Alert(db::Alert {
+        id: 1,
+        rule_id: 1,
+        uid: "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93".to_string(),
+        channel: "temperature".to_string(),
+        data: 80.0,
+        triggered_at: 1_700_000_000,
+        resolved_at: None,
+    }));
+
+    let delivery = settled_delivery(&state).await;
+    assert_eq!(delivery.status, "failed");
+    assert_eq!(delivery.event, "alert");
+    assert_eq!(delivery.attempts, 1);
+    assert_eq!(delivery.last_status, Some(400));
+    assert_eq!(receiver.received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn events_fail_over_and_return_once_the_primary_answers_again() {
+    // nothing listens on the primary yet
+    let primary = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (fallback, fallback_receiver) = start_receiver(Vec::new()).await;
+    let urls = format!("http://{}/hook | http://{}/hook", primary, fallback);
+    let state = start_with(urls.parse().unwrap()).await;
+    let avg = WebhookEvent::Avg {
+        timestamp: 1_700_000_000,
+        data: 21.5,
+        channel: "temperature".to_string(),
+        tenant: None,
+    };
+
+    state.webhooks.publish(avg.clone());
+    let delivery = settled_delivery(&state).await;
+    assert_eq!(delivery.status, "delivered");
+    assert_eq!(delivery.url, format!("http://{}/hook", primary));
+    assert_eq!(delivery.attempts, 2);
+    assert_eq!(fallback_receiver.received.lock().unwrap().len(), 1);
+
+    // the primary is back, the health check notices before the next event
+    let primary_receiver = serve_receiver(TcpListener::bind(primary).unwrap(), Vec::new());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    state.webhooks.publish(avg);
+    for _ in 0..50 {
+        if !primary_receiver.received.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(primary_receiver.received.lock().unwrap().len(), 1);
+    assert_eq!(fallback_receiver.received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn events_over_the_upstream_budget_wait_for_the_next_window_of_the_state_clock() {
+    let (addr, receiver) = start_receiver(Vec::new()).await;
+    let mut config = config(WebhookUrls(vec![format!("http://{}/hook", addr)]));
+    config.upstream_budget_bytes = Some(500);
+    let clock = Arc::new(MockClock::new(1_700_000_000));
+    let state = serve(common::state_with_clock(config, clock.clone()).await).await;
+    // alerts are never held back, but they use up the budget of the window
+    assert!(state
+        .budget
+        .try_spend_at(Class::Alert, 1000, clock.unix_now()));
+
+    state.webhooks.publish(WebhookEvent::Avg {
+        timestamp: 1_700_000_000,
+        data: 21.5,
+        channel: "temperature".to_string(),
+        tenant: None,
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(receiver.received.lock().unwrap().is_empty());
+    assert_eq!(state.budget.report_at(clock.unix_now()).deferred, 1);
+
+    clock.advance(Duration::from_secs(WINDOW_SECS as u64));
+    let delivery = settled_delivery(&state).await;
+    assert_eq!(delivery.status, "delivered");
+    assert_eq!(receiver.received.lock().unwrap().len(), 1);
+}
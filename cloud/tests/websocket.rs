@@ -0,0 +1,1221 @@
+use cloud::{alerts, clock, codec, config, db, protocols, routes, AppState};
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+mod common;
+
+const A: &str = "0b7e2c1a-5d3f-4a8e-9c6b-2f1d0e4a7b93";
+const B: &str = "5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a54";
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// boots the app on a free port against an in-memory db
+async fn start() -> (SocketAddr, Arc<AppState>) {
+    start_with(common::config()).await
+}
+
+async fn start_with(config: config::Config) -> (SocketAddr, Arc<AppState>) {
+    serve(common::state_with(config).await)
+}
+
+fn serve(state: Arc<AppState>) -> (SocketAddr, Arc<AppState>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(routes::router(state.clone()).into_make_service());
+    tokio::spawn(server);
+    (addr, state)
+}
+
+async fn connect(addr: SocketAddr) -> Client {
+    let (ws, _) = connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    ws
+}
+
+async fn send(ws: &mut Client, msg: &str) {
+    ws.send(Message::Text(msg.to_string())).await.unwrap();
+}
+
+// the next text message, None once the server closed the connection
+async fn recv(ws: &mut Client) -> Option<String> {
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("no message from the server");
+        match msg {
+            Some(Ok(Message::Text(text))) => return Some(text),
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+            Some(Ok(_)) => continue,
+        }
+    }
+}
+
+// connects as the device and checks the session it got
+async fn connect_as(addr: SocketAddr, conn: &str) -> Client {
+    let mut ws = connect(addr).await;
+    send(&mut ws, conn).await;
+    let session = recv(&mut ws).await.unwrap();
+    assert!(session.starts_with("SESSION#"), "{}", session);
+    ws
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// waits until the reader stored what it was sent, it writes to the db in the background
+async fn wait_for_count(state: &AppState, query: &str, uid: &str, expected: i64) {
+    for _ in 0..50 {
+        let count: i64 = sqlx::query_scalar(query)
+            .bind(uid)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        if count == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("expected {} rows for {:?}", expected, query);
+}
+
+const READINGS: &str = "SELECT COUNT(*) FROM received_messages WHERE uid = ?";
+
+#[tokio::test]
+async fn readings_are_stored_until_the_device_disconnects() {
+    let (addr, state) = start().await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    sqlx::query("UPDATE connections SET last_seen = 0")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+
+    let now = unix_now();
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5#temperature", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#40#humidity#alarm", A, now)).await;
+    wait_for_count(&state, READINGS, A, 2).await;
+
+    // every reading marks the device as seen
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM connections WHERE uid = ? AND last_seen >= strftime('%s', 'now') - 5",
+        A,
+        1,
+    )
+    .await;
+
+    let latest = db::get_latest_readings(&state.pool, None).await.unwrap();
+    assert_eq!(latest.len(), 2);
+    assert!(latest.iter().all(|r| r.uid == A));
+
+    send(&mut ws, &format!("DISCONN#{}", A)).await;
+    assert_eq!(recv(&mut ws).await, None);
+
+    let connection = db::get_connection(&state.pool, A).await.unwrap();
+    assert!(connection.disconnected_at.is_some());
+}
+
+#[tokio::test]
+async fn readings_re_sent_after_a_reconnect_are_stored_once() {
+    let (addr, state) = start().await;
+    let now = unix_now();
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5#seq=1", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.7#seq=2", A, now + 1)).await;
+    wait_for_count(&state, READINGS, A, 2).await;
+    drop(ws);
+
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.7#seq=2", A, now + 1)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.9#seq=3", A, now + 2)).await;
+    wait_for_count(&state, READINGS, A, 3).await;
+    // a device that counts from 1 again after a reboot
+    send(&mut ws, &format!("SENSOR#{}#{}#22.1#seq=1", A, now + 3)).await;
+    wait_for_count(&state, READINGS, A, 4).await;
+    assert_eq!(state.admission.duplicates(), 1);
+}
+
+#[tokio::test]
+async fn averages_are_delivered_until_acknowledged() {
+    let (addr, state) = start().await;
+    let mut ws = connect_as(addr, &format!("CONN#{}#interval=1", A)).await;
+
+    let now = unix_now();
+    send(&mut ws, &format!("SENSOR#{}#{}#20#temperature", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#22#temperature", A, now)).await;
+    wait_for_count(&state, READINGS, A, 2).await;
+
+    // the first tick of the service averages right away
+    tokio::spawn(protocols::avg_msg_service(state.clone()));
+    let avg = recv(&mut ws).await.unwrap();
+    let (avg, id) = avg.rsplit_once('#').unwrap();
+    let avg = protocols::AvgMsg::from_msg(avg).unwrap();
+    assert_eq!(avg.data, 21.0);
+    assert_eq!(avg.channel, "temperature");
+
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM pending_deliveries WHERE uid = ?",
+        A,
+        1,
+    )
+    .await;
+    send(&mut ws, &format!("ACK#{}#{}", A, id)).await;
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM pending_deliveries WHERE uid = ?",
+        A,
+        0,
+    )
+    .await;
+    assert!(db::get_new_queued_messages(&state.pool, A, 0, i64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn unacknowledged_averages_are_resent_once_the_ack_timeout_passed_on_the_clock() {
+    const NOW: i64 = 1700000000;
+    let clock = Arc::new(clock::MockClock::new(NOW));
+    let (addr, state) = serve(common::state_with_clock(common::config(), clock.clone()).await);
+    db::add_queued_message(
+        &state.pool,
+        None,
+        "AVG#1700000000#21.5#temperature".to_string(),
+    )
+    .await
+    .unwrap();
+    let mut ws = connect_as(addr, &format!("CONN#{}#interval=1", A)).await;
+    let sent = recv(&mut ws).await.unwrap();
+    assert!(sent.starts_with("AVG#"), "{}", sent);
+
+    // the writer polls every second of the clock, but the delivery isn't due yet
+    for _ in 0..29 {
+        clock.advance(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(200), ws.next())
+        .await
+        .is_err());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(recv(&mut ws).await.unwrap(), sent);
+
+    // acknowledged deliveries stay delivered
+    let (_, id) = sent.rsplit_once('#').unwrap();
+    send(&mut ws, &format!("ACK#{}#{}", A, id)).await;
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM pending_deliveries WHERE uid = ?",
+        A,
+        0,
+    )
+    .await;
+    clock.advance(Duration::from_secs(60));
+    assert!(tokio::time::timeout(Duration::from_millis(200), ws.next())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn messages_for_another_uid_close_the_connection() {
+    let (addr, state) = start().await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", B, unix_now())).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#UID_MISMATCH#"), "{}", err);
+    assert_eq!(recv(&mut ws).await, None);
+
+    wait_for_count(&state, READINGS, A, 0).await;
+    wait_for_count(&state, READINGS, B, 0).await;
+    assert!(matches!(
+        db::get_connection(&state.pool, B).await,
+        Err(cloud::Error::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn a_second_connection_of_a_device_kicks_the_first_or_is_rejected() {
+    let (addr, state) = start().await;
+    let mut first = connect_as(addr, &format!("CONN#{}", A)).await;
+    let mut second = connect(addr).await;
+    send(&mut second, &format!("CONN#{}", A)).await;
+    let session = recv(&mut second).await.unwrap();
+    let (_, session_id) = session.split_once('#').unwrap();
+    while state.sessions.stats(A).await.map(|(id, _)| id) != Some(session_id.to_string()) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // the old session stops reading right away, before its device saw the close
+    send(&mut first, &format!("SENSOR#{}#{}#21.5", A, unix_now())).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    wait_for_count(&state, READINGS, A, 0).await;
+    assert_eq!(recv(&mut first).await, None);
+
+    let mut config = common::config();
+    config.duplicate_sessions = config::DuplicateSessions::RejectNew;
+    let (addr, state) = start_with(config).await;
+    let mut first = connect_as(addr, &format!("CONN#{}#firmware=1.0", A)).await;
+    // the session is registered right after SESSION was sent
+    while !state.sessions.is_connected(A).await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut second = connect(addr).await;
+    send(&mut second, &format!("CONN#{}#firmware=2.0", A)).await;
+    let err = recv(&mut second).await.unwrap();
+    assert!(err.starts_with("ERR#SESSION_EXISTS#"), "{}", err);
+    assert_eq!(recv(&mut second).await, None);
+
+    // the first session is left alone, in memory and in the db
+    assert_eq!(state.sessions.count().await, 1);
+    let open: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM device_sessions WHERE uid = ? AND ended_at IS NULL",
+    )
+    .bind(A)
+    .fetch_one(&state.pool)
+    .await
+    .unwrap();
+    assert_eq!(open, 1);
+    let device = db::get_device(&state.pool, A).await.unwrap();
+    assert_eq!(device.firmware.as_deref(), Some("1.0"));
+    send(&mut first, &format!("SENSOR#{}#{}#21.5", A, unix_now())).await;
+    wait_for_count(&state, READINGS, A, 1).await;
+
+    // of two CONNs racing each other only one gets a session
+    let (mut one, mut other) = (connect(addr).await, connect(addr).await);
+    let conn = format!("CONN#{}", B);
+    tokio::join!(send(&mut one, &conn), send(&mut other, &conn));
+    let (one, other) = tokio::join!(recv(&mut one), recv(&mut other));
+    let mut answers = [one.unwrap(), other.unwrap()];
+    answers.sort();
+    assert!(
+        answers[0].starts_with("ERR#SESSION_EXISTS#"),
+        "{:?}",
+        answers
+    );
+    assert!(answers[1].starts_with("SESSION#"), "{:?}", answers);
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM device_sessions WHERE uid = ? AND ended_at IS NULL",
+        B,
+        1,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn malformed_messages_are_rejected() {
+    let (addr, state) = start().await;
+
+    // a session has to start with CONN
+    let mut ws = connect(addr).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, unix_now())).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    assert_eq!(recv(&mut ws).await, None);
+
+    // so does a first frame that isn't text
+    let mut ws = connect(addr).await;
+    ws.send(Message::Binary(vec![0xff, 0xfe, 0x00]))
+        .await
+        .unwrap();
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    assert_eq!(recv(&mut ws).await, None);
+
+    // invalid messages are reported, too many in a row close the connection
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    send(&mut ws, &format!("SENSOR#{}#yesterday#21.5", A)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+
+    for _ in 1..state.config.max_consecutive_errors {
+        send(&mut ws, "HELLO").await;
+    }
+    for _ in 1..state.config.max_consecutive_errors {
+        let err = recv(&mut ws).await.unwrap();
+        assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    }
+    assert_eq!(recv(&mut ws).await, None);
+    wait_for_count(&state, READINGS, A, 0).await;
+}
+
+#[tokio::test]
+async fn connection_stats_are_counted_live_and_kept_after_disconnecting() {
+    let (addr, state) = start().await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    let now = unix_now();
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5#temperature", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#40#humidity", A, now)).await;
+    send(&mut ws, "HELLO").await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#BAD_PROTOCOL#"), "{}", err);
+    wait_for_count(&state, READINGS, A, 2).await;
+
+    let (_, stats) = state.sessions.stats(A).await.unwrap();
+    // the CONN counts as well
+    assert_eq!(stats.frames_in, 4);
+    assert_eq!(stats.parse_errors, 1);
+    assert!(stats.bytes_in > 0 && stats.bytes_out > 0);
+    assert!(stats.last_activity.unwrap() >= now);
+
+    send(&mut ws, &format!("DISCONN#{}", A)).await;
+    assert_eq!(recv(&mut ws).await, None);
+
+    // the last snapshot is written once the session ended
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM connection_stats WHERE uid = ? AND frames_in = 5",
+        A,
+        1,
+    )
+    .await;
+    let saved = db::get_connection_stats(&state.pool, A)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(saved.parse_errors, 1);
+    assert!(!saved.live);
+}
+
+#[tokio::test]
+async fn readings_too_far_off_the_server_clock_are_rejected() {
+    let mut config = common::config();
+    config.timestamp_policy = config::TimestampPolicy::Reject;
+    config.max_timestamp_skew_secs = 3600;
+    let (addr, state) = start_with(config).await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    send(&mut ws, &format!("SENSOR#{}#1000#21.5", A)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#CLOCK_SKEW#"), "{}", err);
+
+    // the connection stays usable
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, unix_now())).await;
+    wait_for_count(&state, READINGS, A, 1).await;
+}
+
+// posts a message to simulate as the admin, the status and body of the response
+async fn simulate(addr: SocketAddr, uid: &str, message: &str) -> (hyper::StatusCode, String) {
+    let req = hyper::Request::post(format!("http://{}/admin/simulate-message", addr))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::AUTHORIZATION, "Bearer operator")
+        .body(hyper::Body::from(
+            serde_json::json!({ "uid": uid, "message": message }).to_string(),
+        ))
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn simulated_readings_are_parsed_and_validated_like_the_devices_own() {
+    let mut config = common::config();
+    config.admin_token = Some("operator".parse().unwrap());
+    config.timestamp_policy = config::TimestampPolicy::Reject;
+    config.max_timestamp_skew_secs = 3600;
+    let (addr, state) = start_with(config).await;
+    let _ws = connect_as(addr, &format!("CONN#{}#timestamps=s", A)).await;
+    while !state.sessions.is_connected(A).await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // milliseconds are seconds far in the future for this device, it would have been
+    // answered with an ERR
+    let now = unix_now();
+    let (status, body) = simulate(addr, A, &format!("SENSOR#{}#{}#21.5", A, now * 1000)).await;
+    assert_eq!(status, hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body.starts_with("ERR#CLOCK_SKEW#"), "{}", body);
+
+    let (status, _) = simulate(addr, A, &format!("SENSOR#{}#{}#21.5", A, now)).await;
+    assert_eq!(status, hyper::StatusCode::ACCEPTED);
+    wait_for_count(&state, READINGS, A, 1).await;
+
+    let (status, _) = simulate(addr, A, &format!("DISCONN#{}", A)).await;
+    assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    wait_for_count(&state, READINGS, A, 1).await;
+}
+
+#[tokio::test]
+async fn clock_skew_and_session_start_follow_the_server_clock() {
+    const NOW: i64 = 1700000000;
+    let clock = Arc::new(clock::MockClock::new(NOW));
+    let (addr, state) = serve(common::state_with_clock(common::config(), clock).await);
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    while !state.sessions.is_connected(A).await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, NOW - 30)).await;
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM connections WHERE uid = ? AND clock_skew = -30",
+        A,
+        1,
+    )
+    .await;
+    assert_eq!(state.sessions.list().await[0].connected_at, NOW);
+}
+
+#[tokio::test]
+async fn fired_rules_send_their_action_to_the_actuator_right_away() {
+    let (addr, state) = start().await;
+    // the actuator would only poll for queued messages once a minute
+    let mut actuator = connect_as(addr, &format!("CONN#{}#interval=60", B)).await;
+
+    let rule = db::AlertRule {
+        id: 0,
+        uid: A.to_string(),
+        channel: Some("temperature".to_string()),
+        op: ">".to_string(),
+        threshold: 30.0,
+        duration_secs: 0,
+        created_at: 0,
+        action_uid: Some(B.to_string()),
+        action_command: Some("fan".to_string()),
+        action_argument: Some("on".to_string()),
+    };
+    db::add_alert_rule(&state.pool, &rule).await.unwrap();
+    tokio::spawn(alerts::alert_service(state.clone()));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut sensor = connect_as(addr, &format!("CONN#{}", A)).await;
+    send(
+        &mut sensor,
+        &format!("SENSOR#{}#{}#35#temperature", A, unix_now()),
+    )
+    .await;
+
+    let cmd = recv(&mut actuator).await.unwrap();
+    let (cmd, id) = cmd.rsplit_once('#').unwrap();
+    assert_eq!(cmd, "CMD#fan#on");
+    send(&mut actuator, &format!("CMD_ACK#{}#{}", B, id)).await;
+
+    // the alert is stored after the actuator got its command
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM alerts WHERE uid = ? AND resolved_at IS NULL",
+        A,
+        1,
+    )
+    .await;
+}
+
+// answers with the body of the response
+async fn request(method: &str, uri: String, body: &str) -> String {
+    let req = hyper::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body.to_string()))
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    assert!(res.status().is_success(), "{}", res.status());
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+// the version and the rules of a RULES message
+fn parse_rules(msg: &str) -> (i64, Vec<protocols::EdgeRule>) {
+    let (version, rules) = msg
+        .strip_prefix("RULES#")
+        .and_then(|rest| rest.split_once('#'))
+        .unwrap_or_else(|| panic!("expected RULES instead of {}", msg));
+    (
+        version.parse().unwrap(),
+        serde_json::from_str(rules).unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn rules_are_pushed_to_the_device_until_it_acknowledges_them() {
+    let (addr, state) = start().await;
+    const ACKED: &str = "SELECT acked_version FROM rule_versions WHERE uid = ?";
+
+    // the rule is added while the device is away
+    let id = request(
+        "POST",
+        format!("http://{}/api/devices/{}/alert-rules", addr, A),
+        &format!(
+            r#"{{"channel": "temperature", "op": ">", "threshold": 30, "action_uid": "{}",
+                "action_command": "fan", "action_argument": "on"}}"#,
+            A
+        ),
+    )
+    .await;
+
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    let (version, rules) = parse_rules(&recv(&mut ws).await.unwrap());
+    assert_eq!(version, 1);
+    assert_eq!(
+        rules,
+        vec![protocols::EdgeRule {
+            id: id.parse().unwrap(),
+            channel: Some("temperature".to_string()),
+            op: ">".to_string(),
+            threshold: 30.0,
+            duration_secs: 0,
+            action_command: Some("fan".to_string()),
+            action_argument: Some("on".to_string()),
+        }]
+    );
+
+    // unacknowledged rules are sent again on the next CONN
+    drop(ws);
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    let (version, _) = parse_rules(&recv(&mut ws).await.unwrap());
+    assert_eq!(version, 1);
+    send(&mut ws, &format!("RULES_ACK#{}#1", A)).await;
+    wait_for_count(&state, ACKED, A, 1).await;
+
+    // changes are pushed to a connected device right away
+    request(
+        "DELETE",
+        format!("http://{}/api/alert-rules/{}", addr, id),
+        "",
+    )
+    .await;
+    let (version, rules) = parse_rules(&recv(&mut ws).await.unwrap());
+    assert_eq!(version, 2);
+    assert!(rules.is_empty());
+    send(&mut ws, &format!("RULES_ACK#{}#2", A)).await;
+    wait_for_count(&state, ACKED, A, 2).await;
+
+    let version = request(
+        "GET",
+        format!("http://{}/api/devices/{}/alert-rules/version", addr, A),
+        "",
+    )
+    .await;
+    let version: serde_json::Value = serde_json::from_str(&version).unwrap();
+    assert_eq!(version["version"], 2);
+    assert_eq!(version["acked_version"], 2);
+}
+
+#[tokio::test]
+async fn tenants_get_averages_of_their_own_devices() {
+    let (addr, state) = start().await;
+    assert!(db::add_tenant(&state.pool, "acme", "Acme Corp")
+        .await
+        .unwrap());
+    assert!(db::add_tenant(&state.pool, "globex", "Globex")
+        .await
+        .unwrap());
+
+    // devices can only join known tenants
+    let mut ws = connect(addr).await;
+    send(&mut ws, &format!("CONN#{}#tenant=initech", A)).await;
+    assert!(recv(&mut ws).await.unwrap().starts_with("ERR#"));
+    assert_eq!(recv(&mut ws).await, None);
+
+    let mut a = connect_as(addr, &format!("CONN#{}#tenant=acme,interval=1", A)).await;
+    let mut b = connect_as(addr, &format!("CONN#{}#interval=1", B)).await;
+
+    let now = unix_now();
+    send(&mut a, &format!("SENSOR#{}#{}#20#temperature", A, now)).await;
+    send(&mut a, &format!("SENSOR#{}#{}#22#temperature", A, now)).await;
+    send(&mut b, &format!("SENSOR#{}#{}#10#temperature", B, now)).await;
+    wait_for_count(&state, READINGS, A, 2).await;
+    wait_for_count(&state, READINGS, B, 1).await;
+
+    let devices = request(
+        "GET",
+        format!("http://{}/api/connections?tenant=acme", addr),
+        "",
+    )
+    .await;
+    let devices: serde_json::Value = serde_json::from_str(&devices).unwrap();
+    assert_eq!(devices.as_array().unwrap().len(), 1);
+    assert_eq!(devices[0]["uid"], A);
+    assert_eq!(devices[0]["tenant"], "acme");
+
+    // the readings of each tenant are averaged separately
+    tokio::spawn(protocols::avg_msg_service(state.clone()));
+    for (ws, expected) in [(&mut a, 21.0), (&mut b, 10.0)] {
+        let avg = recv(ws).await.unwrap();
+        let (avg, _) = avg.rsplit_once('#').unwrap();
+        let avg = protocols::AvgMsg::from_msg(avg).unwrap();
+        assert_eq!(avg.data, expected);
+        assert_eq!(avg.channel, "temperature");
+    }
+
+    // a device can't move to another tenant
+    let mut ws = connect(addr).await;
+    send(&mut ws, &format!("CONN#{}#tenant=globex", A)).await;
+    assert!(recv(&mut ws).await.unwrap().starts_with("ERR#"));
+    let connection = db::get_connection(&state.pool, A).await.unwrap();
+    assert_eq!(connection.tenant.as_deref(), Some("acme"));
+}
+
+#[tokio::test]
+async fn fog_nodes_forward_the_readings_of_their_devices() {
+    let (addr, state) = start().await;
+    // only registered fog nodes take the role
+    let mut ws = connect(addr).await;
+    send(&mut ws, &format!("CONN#{}#role=fog", A)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#AUTH_FAILED"), "{}", err);
+    db::add_fog_node(&state.pool, A).await.unwrap();
+    db::add_fog_device(&state.pool, B, A).await.unwrap();
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+
+    let now = unix_now();
+    send(&mut fog, &format!("SENSOR#{}#{}#21.5#via={}", B, now, A)).await;
+    wait_for_count(&state, READINGS, B, 1).await;
+
+    let nodes: serde_json::Value =
+        serde_json::from_str(&request("GET", format!("http://{}/api/fog-nodes", addr), "").await)
+            .unwrap();
+    assert_eq!(nodes[0]["uid"], A);
+    assert_eq!(nodes[0]["devices"], 1);
+    let devices: serde_json::Value = serde_json::from_str(
+        &request(
+            "GET",
+            format!("http://{}/api/fog-nodes/{}/devices", addr, A),
+            "",
+        )
+        .await,
+    )
+    .unwrap();
+    assert_eq!(devices[0]["uid"], B);
+    let readings: serde_json::Value = serde_json::from_str(
+        &request(
+            "GET",
+            format!("http://{}/api/fog-nodes/{}/readings/latest", addr, A),
+            "",
+        )
+        .await,
+    )
+    .unwrap();
+    assert_eq!(readings[0]["uid"], B);
+    assert_eq!(readings[0]["fog_uid"], A);
+
+    // only fog nodes forward, and only in their own name
+    let mut device = connect_as(addr, &format!("CONN#{}", B)).await;
+    send(&mut device, &format!("SENSOR#{}#{}#1#via={}", A, now, B)).await;
+    let err = recv(&mut device).await.unwrap();
+    assert!(err.starts_with("ERR#UID_MISMATCH"), "{}", err);
+    send(&mut fog, &format!("SENSOR#{}#{}#1#via={}", A, now, B)).await;
+    let err = recv(&mut fog).await.unwrap();
+    assert!(err.starts_with("ERR#UID_MISMATCH"), "{}", err);
+}
+
+#[tokio::test]
+async fn fog_nodes_only_forward_for_the_devices_registered_behind_them() {
+    const C: &str = "7c2e9f4a-1b5d-4e3c-8a6f-0d9b2e5c1a74";
+    let (addr, state) = start().await;
+    db::add_tenant(&state.pool, "acme", "Acme").await.unwrap();
+    db::add_fog_node(&state.pool, A).await.unwrap();
+    db::add_fog_node(&state.pool, C).await.unwrap();
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog,tenant=acme", A)).await;
+    // devices join the tenant of their fog node
+    db::add_fog_device(&state.pool, B, A).await.unwrap();
+    let mut other = connect_as(addr, &format!("CONN#{}#role=fog", C)).await;
+
+    // another fog node can't take the device over
+    let now = unix_now();
+    send(&mut other, &format!("SENSOR#{}#{}#21.5#via={}", B, now, C)).await;
+    let err = recv(&mut other).await.unwrap();
+    assert!(err.starts_with("ERR#AUTH_FAILED"), "{}", err);
+    let device = db::get_connection(&state.pool, B).await.unwrap();
+    assert_eq!(device.fog_uid.as_deref(), Some(A));
+    assert_eq!(device.tenant.as_deref(), Some("acme"));
+    // and an admin can't move it out of its tenant either
+    assert!(!db::add_fog_device(&state.pool, B, C).await.unwrap());
+
+    // readings of the device are stored in its tenant
+    send(&mut fog, &format!("SENSOR#{}#{}#21.5#via={}", B, now, A)).await;
+    wait_for_count(
+        &state,
+        "SELECT COUNT(*) FROM received_messages WHERE uid = ? AND tenant = 'acme'",
+        B,
+        1,
+    )
+    .await;
+
+    // nobody forwards for a device in maintenance
+    sqlx::query("UPDATE connections SET maintenance = 1 WHERE uid = ?")
+        .bind(B)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    send(
+        &mut fog,
+        &format!("SENSOR#{}#{}#21.7#via={}", B, now + 1, A),
+    )
+    .await;
+    let err = recv(&mut fog).await.unwrap();
+    assert!(err.starts_with("ERR#AUTH_FAILED"), "{}", err);
+    wait_for_count(&state, READINGS, B, 1).await;
+}
+
+#[tokio::test]
+async fn readings_forwarded_by_a_fog_node_are_rate_limited_per_device() {
+    let mut config = common::config();
+    config.rate_limit_per_sec = 0.1;
+    config.rate_limit_burst = 2.0;
+    let (addr, state) = start_with(config).await;
+    db::add_fog_node(&state.pool, A).await.unwrap();
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+
+    // more readings than the burst of one device, but within the burst of each of them
+    let devices: Vec<String> = (0..5)
+        .map(|i| format!("5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a5{}", i))
+        .collect();
+    for device in &devices {
+        db::add_fog_device(&state.pool, device, A).await.unwrap();
+    }
+    let now = unix_now();
+    for device in &devices {
+        for value in [21.5, 21.7] {
+            send(
+                &mut fog,
+                &format!("SENSOR#{}#{}#{}#via={}", device, now, value, A),
+            )
+            .await;
+        }
+    }
+    for device in &devices {
+        wait_for_count(&state, READINGS, device, 2).await;
+    }
+
+    // a device past its own burst is still limited
+    send(
+        &mut fog,
+        &format!("SENSOR#{}#{}#21.9#via={}", devices[0], now, A),
+    )
+    .await;
+    let err = recv(&mut fog).await.unwrap();
+    assert!(err.starts_with("ERR#RATE_LIMITED#"), "{}", err);
+    wait_for_count(&state, READINGS, &devices[0], 2).await;
+}
+
+#[tokio::test]
+async fn fog_nodes_are_rate_limited_in_total() {
+    let mut config = common::config();
+    config.rate_limit_per_sec = 0.1;
+    config.fog_rate_limit_per_sec = 0.1;
+    config.fog_rate_limit_burst = 3.0;
+    let (addr, state) = start_with(config).await;
+    db::add_fog_node(&state.pool, A).await.unwrap();
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+
+    // a device nobody registered behind the node gets no bucket, the node is cut off
+    let now = unix_now();
+    let device = "9e4b2c7d-3a1f-4d6e-b8c0-5f2a7d9e1b30";
+    send(
+        &mut fog,
+        &format!("SENSOR#{}#{}#21.5#via={}", device, now, A),
+    )
+    .await;
+    let err = recv(&mut fog).await.unwrap();
+    assert!(err.starts_with("ERR#AUTH_FAILED"), "{}", err);
+    assert_eq!(recv(&mut fog).await, None);
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+
+    // each device is within its own burst, but the node is past its total
+    let devices: Vec<String> = (0..4)
+        .map(|i| format!("5c1f3e9a-7b2d-4c8e-a6f0-3d9b1e7c2a5{}", i))
+        .collect();
+    for device in &devices {
+        db::add_fog_device(&state.pool, device, A).await.unwrap();
+        send(
+            &mut fog,
+            &format!("SENSOR#{}#{}#21.5#via={}", device, now, A),
+        )
+        .await;
+    }
+    let err = recv(&mut fog).await.unwrap();
+    assert!(err.starts_with("ERR#RATE_LIMITED#"), "{}", err);
+    for device in &devices[..3] {
+        wait_for_count(&state, READINGS, device, 1).await;
+    }
+    wait_for_count(&state, READINGS, &devices[3], 0).await;
+}
+
+const CONFIG_ACKED: &str = "SELECT acked_version FROM device_configs WHERE uid = ?";
+
+#[tokio::test]
+async fn configs_are_pushed_to_the_device_until_it_acknowledges_them() {
+    let (addr, state) = start().await;
+    db::add_connection(&state.pool, A).await.unwrap();
+
+    // the config is set while the device is away
+    request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}/config", addr, A),
+        r#"{"params": {"interval": 30, "threshold": 21.5}}"#,
+    )
+    .await;
+
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    assert_eq!(
+        recv(&mut ws).await.unwrap(),
+        format!(r#"CONFIG#{}#1#{{"interval":30,"threshold":21.5}}"#, A)
+    );
+    send(&mut ws, &format!("CONFIG_ACK#{}#1", A)).await;
+    wait_for_count(&state, CONFIG_ACKED, A, 1).await;
+
+    // changes are pushed to a connected device right away
+    let config = request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}/config", addr, A),
+        r#"{"params": {"interval": 60}}"#,
+    )
+    .await;
+    let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(
+        (config["version"].as_i64(), config["acked_version"].as_i64()),
+        (Some(2), Some(1))
+    );
+    assert_eq!(
+        recv(&mut ws).await.unwrap(),
+        format!(r#"CONFIG#{}#2#{{"interval":60}}"#, A)
+    );
+
+    // devices only acknowledge their own config
+    send(&mut ws, &format!("CONFIG_ACK#{}#2", B)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#UID_MISMATCH"), "{}", err);
+}
+
+#[tokio::test]
+async fn fog_nodes_pass_on_the_configs_of_their_devices() {
+    let (addr, state) = start().await;
+    db::add_fog_node(&state.pool, A).await.unwrap();
+    db::add_fog_device(&state.pool, B, A).await.unwrap();
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+    send(
+        &mut fog,
+        &format!("SENSOR#{}#{}#21.5#via={}", B, unix_now(), A),
+    )
+    .await;
+    wait_for_count(&state, READINGS, B, 1).await;
+
+    request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}/config", addr, B),
+        r#"{"params": {"interval": 30}}"#,
+    )
+    .await;
+    assert_eq!(
+        recv(&mut fog).await.unwrap(),
+        format!(r#"CONFIG#{}#1#{{"interval":30}}"#, B)
+    );
+
+    // unacknowledged configs of the devices behind it are sent on the next CONN
+    drop(fog);
+    let mut fog = connect_as(addr, &format!("CONN#{}#role=fog", A)).await;
+    assert_eq!(
+        recv(&mut fog).await.unwrap(),
+        format!(r#"CONFIG#{}#1#{{"interval":30}}"#, B)
+    );
+    send(&mut fog, &format!("CONFIG_ACK#{}#1", B)).await;
+    wait_for_count(&state, CONFIG_ACKED, B, 1).await;
+}
+
+#[tokio::test]
+async fn shutting_down_waits_for_the_acks_of_pending_deliveries() {
+    let mut config = common::config();
+    config.shutdown_grace_secs = 30;
+    let (addr, state) = start_with(config).await;
+    // queued messages would only be delivered once a minute
+    let mut ws = connect_as(addr, &format!("CONN#{}#interval=60", A)).await;
+    request(
+        "POST",
+        format!("http://{}/api/v1/devices/{}/commands", addr, A),
+        r#"{"command":"reboot"}"#,
+    )
+    .await;
+
+    let started = std::time::Instant::now();
+    let shutdown = {
+        let state = state.clone();
+        tokio::spawn(async move { state.sessions.shut_down(Duration::from_secs(35)).await })
+    };
+
+    // the command is sent again after the notice, unless it was acknowledged before
+    let mut shutdown_seen = false;
+    let id = loop {
+        let msg = recv(&mut ws).await.unwrap();
+        if msg == "SHUTDOWN#30" {
+            shutdown_seen = true;
+        } else if let Some(id) = msg.strip_prefix("CMD#reboot#") {
+            if shutdown_seen {
+                break id.to_string();
+            }
+        }
+    };
+    assert!(connect_async(format!("ws://{}/ws", addr)).await.is_err());
+
+    send(&mut ws, &format!("CMD_ACK#{}#{}", A, id)).await;
+    // the server closes the websocket once it is acknowledged
+    while recv(&mut ws).await.is_some() {}
+    drop(ws);
+    shutdown.await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(
+        db::count_pending_deliveries(&state.pool, A).await.unwrap(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn throttled_readings_wait_for_the_bucket_to_refill() {
+    let mut config = common::config();
+    config.rate_limit_mode = config::RateLimitMode::Throttle;
+    config.rate_limit_per_sec = 0.5;
+    config.rate_limit_burst = 1.0;
+    let (addr, state) = start_with(config).await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    let started = tokio::time::Instant::now();
+    let now = unix_now();
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.7", A, now)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#RATE_LIMITED#"), "{}", err);
+    wait_for_count(&state, READINGS, A, 1).await;
+
+    // the second reading is stored once a token is back, two seconds later
+    wait_for_count(&state, READINGS, A, 2).await;
+    assert!(started.elapsed() >= Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn sessions_stamp_and_throttle_on_the_state_clock() {
+    const NOW: i64 = 1_600_000_000;
+    let mut config = common::config();
+    config.rate_limit_mode = config::RateLimitMode::Throttle;
+    config.rate_limit_per_sec = 0.5;
+    config.rate_limit_burst = 1.0;
+    let clock = Arc::new(clock::MockClock::new(NOW));
+    let (addr, state) = serve(common::state_with_clock(config, clock.clone()).await);
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, NOW)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#21.7", A, NOW)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#RATE_LIMITED#"), "{}", err);
+    wait_for_count(&state, READINGS, A, 1).await;
+
+    // the bucket doesn't refill while the clock stands still
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    wait_for_count(&state, READINGS, A, 1).await;
+    clock.advance(Duration::from_secs(2));
+    wait_for_count(&state, READINGS, A, 2).await;
+
+    let received: Vec<i64> =
+        sqlx::query_scalar("SELECT received_at FROM received_messages ORDER BY id")
+            .fetch_all(&state.pool)
+            .await
+            .unwrap();
+    assert_eq!(received, vec![NOW, NOW + 2]);
+
+    send(&mut ws, &format!("DISCONN#{}", A)).await;
+    assert_eq!(recv(&mut ws).await, None);
+    let connection = db::get_connection(&state.pool, A).await.unwrap();
+    assert_eq!(connection.last_seen, NOW + 2);
+    assert_eq!(connection.disconnected_at, Some(NOW + 2));
+}
+
+#[tokio::test]
+async fn compressed_batches_are_counted_raw_and_on_the_wire() {
+    let mut config = common::config();
+    config.rate_limit_burst = 20.0;
+    let (addr, state) = start_with(config).await;
+    let mut ws = connect_as(addr, &format!("CONN#{}#compression=zstd", A)).await;
+
+    let now = unix_now();
+    let readings: Vec<String> = (0..20)
+        .map(|i| format!("SENSOR#{}#{}#21.5#temperature", A, now - i))
+        .collect();
+    let framing = codec::Framing {
+        compression: protocols::Compression::Zstd,
+        ..Default::default()
+    };
+    let frame = codec::encode(&readings, framing).unwrap().remove(0);
+    ws.send(Message::Binary(frame.into_data())).await.unwrap();
+    wait_for_count(&state, READINGS, A, 20).await;
+
+    let (_, stats) = state.sessions.stats(A).await.unwrap();
+    let raw: usize = readings.iter().map(String::len).sum();
+    let conn = format!("CONN#{}#compression=zstd", A).len();
+    assert_eq!(stats.raw_bytes_in, (conn + raw) as u64);
+    assert!(stats.bytes_in < stats.raw_bytes_in / 2, "{:?}", stats);
+}
+
+#[tokio::test]
+async fn oversize_frames_are_rejected() {
+    let mut config = common::config();
+    config.max_frame_bytes = 1024;
+    let (addr, state) = start_with(config).await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+
+    let now = unix_now();
+    let huge = format!("SENSOR#{}#{}#{}", A, now, "1".repeat(2000));
+    send(&mut ws, &huge).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#TOO_LARGE#"), "{}", err);
+
+    // the connection stays usable
+    send(&mut ws, &format!("SENSOR#{}#{}#21.5", A, now)).await;
+    wait_for_count(&state, READINGS, A, 1).await;
+
+    // far longer frames aren't read at all
+    send(&mut ws, &"1".repeat(1024 * 1024)).await;
+    while recv(&mut ws).await.is_some() {}
+}
+
+#[tokio::test]
+async fn devices_register_their_metadata() {
+    let (addr, _state) = start().await;
+    let ws = connect_as(
+        addr,
+        &format!("CONN#{}#name=Greenhouse 2,location=north,type=bme280", A),
+    )
+    .await;
+    drop(ws);
+
+    // operators register devices before they first connect
+    request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}", addr, B),
+        r#"{"name": "Cellar", "location": "north", "sensor_type": "ds18b20"}"#,
+    )
+    .await;
+
+    let devices = request(
+        "GET",
+        format!("http://{}/api/v1/devices?location=north", addr),
+        "",
+    )
+    .await;
+    let devices: serde_json::Value = serde_json::from_str(&devices).unwrap();
+    assert_eq!(devices.as_array().unwrap().len(), 2);
+
+    let devices = request(
+        "GET",
+        format!("http://{}/api/v1/devices?name=greenhouse", addr),
+        "",
+    )
+    .await;
+    let devices: serde_json::Value = serde_json::from_str(&devices).unwrap();
+    assert_eq!(devices.as_array().unwrap().len(), 1);
+    assert_eq!(devices[0]["uid"], A);
+    assert_eq!(devices[0]["sensor_type"], "bme280");
+    assert_eq!(devices[0]["firmware"], serde_json::Value::Null);
+
+    // fields left out are kept
+    let device = request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}", addr, A),
+        r#"{"firmware": "1.4.0"}"#,
+    )
+    .await;
+    let device: serde_json::Value = serde_json::from_str(&device).unwrap();
+    assert_eq!(
+        (device["name"].as_str(), device["firmware"].as_str()),
+        (Some("Greenhouse 2"), Some("1.4.0"))
+    );
+}
+
+#[tokio::test]
+async fn readings_are_converted_before_they_are_stored() {
+    let (addr, state) = start().await;
+    // raw ADC counts of a thermistor, 10 counts per °C from -40 °C
+    request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}/conversions/default", addr, A),
+        r#"{"kind": "linear", "scale": 0.1, "offset": -40, "unit": "°C", "keep_raw": true}"#,
+    )
+    .await;
+    request(
+        "PUT",
+        format!("http://{}/api/v1/devices/{}/conversions/outside", addr, A),
+        r#"{"kind": "fahrenheit_to_celsius"}"#,
+    )
+    .await;
+
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    let now = unix_now();
+    send(&mut ws, &format!("SENSOR#{}#{}#650", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#212#outside", A, now)).await;
+    send(&mut ws, &format!("SENSOR#{}#{}#55#humidity", A, now)).await;
+    wait_for_count(&state, READINGS, A, 3).await;
+
+    let readings: Vec<(String, f64, Option<f64>)> = sqlx::query_as(
+        "SELECT channel, data, raw_data FROM received_messages WHERE uid = ? ORDER BY channel",
+    )
+    .bind(A)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        readings,
+        vec![
+            ("default".to_string(), 25.0, Some(650.0)),
+            ("humidity".to_string(), 55.0, None),
+            ("outside".to_string(), 100.0, None),
+        ]
+    );
+
+    let conversions = request(
+        "GET",
+        format!("http://{}/api/v1/devices/{}/conversions", addr, A),
+        "",
+    )
+    .await;
+    let conversions: serde_json::Value = serde_json::from_str(&conversions).unwrap();
+    assert_eq!(conversions[0]["unit"], "°C");
+    assert_eq!(conversions[1]["scale"], 1.0);
+}
+
+#[tokio::test]
+async fn drained_devices_stay_away_until_released() {
+    let (addr, state) = start().await;
+    let mut ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    while !state.sessions.is_connected(A).await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    request(
+        "POST",
+        format!("http://{}/api/v1/devices/{}/drain", addr, A),
+        "",
+    )
+    .await;
+    assert!(recv(&mut ws).await.unwrap().starts_with("DRAIN#"));
+    while recv(&mut ws).await.is_some() {}
+    drop(ws);
+
+    // reconnecting doesn't end the maintenance
+    let mut ws = connect(addr).await;
+    send(&mut ws, &format!("CONN#{}", A)).await;
+    let err = recv(&mut ws).await.unwrap();
+    assert!(err.starts_with("ERR#MAINTENANCE#"), "{}", err);
+    assert_eq!(recv(&mut ws).await, None);
+
+    request(
+        "DELETE",
+        format!("http://{}/api/v1/devices/{}/maintenance", addr, A),
+        "",
+    )
+    .await;
+    let ws = connect_as(addr, &format!("CONN#{}", A)).await;
+    drop(ws);
+}